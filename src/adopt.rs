@@ -0,0 +1,493 @@
+//! This module absorbs existing files into a module.
+//!
+//! Adopting a file moves its current content into the module directory, adds a `files` entry to
+//! the `config.toml` of the module and replaces the file with a link managed by dotdeploy. The
+//! entry is appended to the config, so comments and formatting of the existing entries are kept.
+//! The content is copied before the config is changed and the original file is only removed once
+//! everything else succeeded.
+//!
+//! Adding a file does the same, but deploys the new entry right away like any other file, backing up
+//! the original file in the store.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+
+use crate::phases::destination::Destination;
+use crate::phases::file_operations::{FileOperation, ManagedFile};
+use crate::store::db::Store;
+use crate::store::files::StoreFile;
+use crate::store::modules::StoreModule;
+use crate::utils::{file_checksum, file_fs};
+use crate::Stores;
+
+/// Returns the destination of a file as written to a module config.
+///
+/// Paths inside HOME are written relative to `~`, so the module works for other users as well.
+fn config_destination(target: &Path, home: &Path) -> String {
+    match target.strip_prefix(home) {
+        Ok(relative) => format!("~/{}", relative.display()),
+        Err(_) => target.display().to_string(),
+    }
+}
+
+/// Appends a `files` entry to the content of a module config.
+///
+/// The edited config is parsed again to make sure it is valid and contains the new entry, e.g.
+/// appending fails if `files` is an inline table.
+///
+/// # Arguments
+///
+/// * `content` - The content of the `config.toml` of the module
+/// * `config_path` - Path of the `config.toml` of the module, used for messages
+/// * `target` - The absolute destination of the file
+/// * `destination` - The destination as written to the config, see [config_destination]
+/// * `source` - The source of the file, relative to the module directory
+///
+/// # Returns
+///
+/// A Result containing the edited config, or an error if the config already has an entry for the
+/// target or the entry could not be added.
+fn add_file_entry(
+    content: &str,
+    config_path: &Path,
+    target: &Path,
+    destination: &str,
+    source: &str,
+) -> Result<String> {
+    let table: toml::Table = toml::from_str(content)
+        .with_context(|| format!("Failed to parse module config {:?}", config_path))?;
+
+    if let Some(files) = table.get("files").and_then(|f| f.as_table()) {
+        for key in files.keys() {
+            if shellexpand::full(key).is_ok_and(|k| Path::new(k.as_ref()) == target) {
+                bail!("{:?} is already managed by {:?}", target, config_path);
+            }
+        }
+    }
+
+    let entry = format!(
+        "[files.{}]\nsource = {}\n",
+        toml::Value::String(destination.to_string()),
+        toml::Value::String(source.to_string())
+    );
+    let mut content = content.to_string();
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push('\n');
+    content.push_str(&entry);
+
+    let added = toml::from_str::<toml::Table>(&content)
+        .ok()
+        .and_then(|t| t.get("files")?.get(destination)?.get("source").cloned());
+    if added.as_ref().and_then(|s| s.as_str()) != Some(source) {
+        bail!(
+            "Failed to add an entry to {:?}, please add it manually:\n{}",
+            config_path,
+            entry
+        );
+    }
+    Ok(content)
+}
+
+/// Replaces a module config atomically by renaming a temporary file in the same directory.
+fn write_config(config_path: &Path, content: &str) -> Result<()> {
+    let dir = config_path
+        .parent()
+        .with_context(|| format!("Could not get parent of {:?}", config_path))?;
+    let mut temp = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("Failed to create temporary file in {:?}", dir))?;
+    std::io::Write::write_all(&mut temp, content.as_bytes())
+        .with_context(|| format!("Failed to write module config {:?}", config_path))?;
+    let permissions = std::fs::metadata(config_path)
+        .with_context(|| format!("Failed to read metadata of {:?}", config_path))?
+        .permissions();
+    temp.as_file().set_permissions(permissions)?;
+    temp.persist(config_path)
+        .with_context(|| format!("Failed to write module config {:?}", config_path))?;
+    Ok(())
+}
+
+/// A file copied into a module by [absorb].
+struct Absorbed {
+    /// The absolute target of the file
+    target: PathBuf,
+    /// The new source of the file in the module directory
+    source: PathBuf,
+    /// Path of the `config.toml` of the module
+    config_path: PathBuf,
+    /// The content of the module config before the entry was added
+    config: String,
+}
+
+impl Absorbed {
+    /// Restores the module config and removes the copied source, if a later step failed.
+    async fn revert(&self) {
+        if let Err(e) = write_config(&self.config_path, &self.config) {
+            error!("Failed to restore {:?}: {:?}", self.config_path, e);
+        }
+        if let Err(e) = tokio::fs::remove_file(&self.source).await {
+            error!("Failed to remove {:?}: {}", self.source, e);
+        }
+    }
+}
+
+/// Moves an existing file into a module.
+///
+/// The file is copied into the module directory first. Only then the `files` entry is added to the
+/// module config, which is replaced atomically. The file itself is left untouched.
+///
+/// # Arguments
+///
+/// * `dotdeploy_config` - Configuration providing the module directories
+/// * `target` - The absolute path of the file
+/// * `module` - Name of the module adopting the file
+/// * `source` - Source path inside the module directory, defaults to the file name of the target
+///
+/// # Returns
+///
+/// A Result containing the [Absorbed] file, which can be reverted if a later step fails.
+async fn absorb(
+    dotdeploy_config: &crate::config::DotdeployConfig,
+    target: &Path,
+    module: &str,
+    source: Option<&Path>,
+) -> Result<Absorbed> {
+    let metadata = std::fs::symlink_metadata(target)
+        .with_context(|| format!("Failed to read metadata of {:?}", target))?;
+    if !metadata.is_file() {
+        bail!("{:?} is not a regular file", target);
+    }
+
+    let module_dir = crate::modules::module_path(module, dotdeploy_config);
+    let config_path = module_dir.join("config.toml");
+    if !config_path.is_file() {
+        return Err(crate::modules::queue::module_not_found(
+            module,
+            dotdeploy_config,
+        ));
+    }
+
+    let relative_source = match source {
+        Some(s) if s.is_absolute() => bail!("Source {:?} must be relative to the module", s),
+        Some(s) => s.to_path_buf(),
+        None => PathBuf::from(target.file_name().context("Target has no file name")?),
+    };
+    let source = module_dir.join(&relative_source);
+    if file_fs::check_file_exists(&source).await? {
+        bail!("Source {:?} already exists", source);
+    }
+
+    let config = std::fs::read_to_string(&config_path)
+        .with_context(|| format!("Failed to read module config file: {:?}", config_path))?;
+    let home = PathBuf::from(shellexpand::tilde("~").as_ref());
+    let edited = add_file_entry(
+        &config,
+        &config_path,
+        target,
+        &config_destination(target, &home),
+        &relative_source.display().to_string(),
+    )?;
+
+    // Copy the content into the module before it is referenced by the config
+    if let Some(parent) = source.parent() {
+        file_fs::ensure_dir_exists(parent).await?;
+    }
+    file_fs::copy_file(target, &source).await?;
+
+    let absorbed = Absorbed {
+        target: target.to_path_buf(),
+        source,
+        config_path,
+        config,
+    };
+    if let Err(e) = write_config(&absorbed.config_path, &edited) {
+        absorbed.revert().await;
+        return Err(e);
+    }
+    Ok(absorbed)
+}
+
+/// Makes sure a module is known to a store, so files can be recorded for it.
+async fn ensure_module(
+    store: &Store,
+    module: &str,
+    dotdeploy_config: &crate::config::DotdeployConfig,
+) -> Result<()> {
+    if store.get_module(module).await.is_err() {
+        store
+            .add_module(StoreModule {
+                name: module.to_string(),
+                location: file_fs::path_to_string(crate::modules::module_path(
+                    module,
+                    dotdeploy_config,
+                ))?,
+                user: Some(std::env::var("USER")?),
+                reason: "manual".to_string(),
+                depends: None,
+                date: chrono::offset::Local::now(),
+            })
+            .await
+            .map_err(|e| e.into_anyhow())?;
+    }
+    Ok(())
+}
+
+/// Replaces an absorbed file with a link to its new source and records it in the user store.
+///
+/// The original file is kept as a backup next to the target until every step succeeded, and is
+/// restored otherwise.
+async fn replace_with_link(
+    stores: &Stores,
+    dotdeploy_config: &crate::config::DotdeployConfig,
+    module: &str,
+    absorbed: &Absorbed,
+) -> Result<()> {
+    let Absorbed { target, source, .. } = absorbed;
+    ensure_module(&stores.user_store, module, dotdeploy_config).await?;
+
+    let mut backup = target.clone().into_os_string();
+    backup.push(".dotdeploy-adopt");
+    let backup = PathBuf::from(backup);
+    tokio::fs::rename(target, &backup)
+        .await
+        .with_context(|| format!("Failed to move {:?} to {:?}", target, backup))?;
+
+    let res: Result<()> = async {
+        tokio::fs::symlink(source, target)
+            .await
+            .with_context(|| format!("Failed to link {:?} to {:?}", source, target))?;
+
+        stores
+            .user_store
+            .add_file(StoreFile {
+                module: module.to_string(),
+                source: Some(source.display().to_string()),
+                source_checksum: Some(file_checksum::calculate_sha256_checksum(source).await?),
+                destination: target.display().to_string(),
+                destination_checksum: None,
+                operation: "link".to_string(),
+                user: Some(std::env::var("USER")?),
+                date: chrono::offset::Local::now(),
+            })
+            .await
+            .map_err(|e| e.into_anyhow())
+    }
+    .await;
+
+    match res {
+        Ok(()) => tokio::fs::remove_file(&backup)
+            .await
+            .with_context(|| format!("Failed to remove backup {:?}", backup)),
+        Err(e) => {
+            // Put the original file back in place
+            if file_fs::check_link_exists(target, None)
+                .await
+                .unwrap_or(false)
+            {
+                let _ = tokio::fs::remove_file(target).await;
+            }
+            if let Err(e) = tokio::fs::rename(&backup, target).await {
+                error!("Failed to restore {:?} from {:?}: {}", target, backup, e);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Absorbs an existing file into a module.
+///
+/// The file is copied into the module directory, a `files` entry is added to the module config,
+/// the file is recorded in the user store and finally replaced with a link to its new source. If
+/// any step fails, the original file and module config are restored. Only regular files in HOME can
+/// be adopted.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `dotdeploy_config` - Configuration providing the module directories
+/// * `target` - The file to adopt
+/// * `module` - Name of the module adopting the file
+/// * `source` - Source path inside the module directory, defaults to the file name of the target
+///
+/// # Returns
+///
+/// A Result indicating success or failure of the adoption
+pub(crate) async fn adopt(
+    stores: Arc<Stores>,
+    dotdeploy_config: &crate::config::DotdeployConfig,
+    target: &Path,
+    module: &str,
+    source: Option<&Path>,
+) -> Result<()> {
+    let home = PathBuf::from(shellexpand::tilde("~").as_ref());
+    let target = std::path::absolute(target)
+        .with_context(|| format!("Failed to get absolute path of {:?}", target))?;
+    if !target.starts_with(&home) {
+        bail!(
+            "{:?} is outside of HOME, use `dotdeploy add` for system files",
+            target
+        );
+    }
+
+    let absorbed = absorb(dotdeploy_config, &target, module, source).await?;
+    if let Err(e) = replace_with_link(&stores, dotdeploy_config, module, &absorbed).await {
+        absorbed.revert().await;
+        return Err(e);
+    }
+
+    info!("Adopted {:?} into module {}", target, module);
+
+    Ok(())
+}
+
+/// Adds an existing file to a module and deploys it right away.
+///
+/// Works like [adopt], but the link is created by a regular deployment of the single file. This
+/// way, the original file is backed up in the store and files outside of HOME are supported. The
+/// other files of the module are not deployed.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `dotdeploy_config` - Configuration providing the module directories
+/// * `module` - Name of the module adding the file
+/// * `target` - The file to add
+/// * `source` - Source path inside the module directory, defaults to the file name of the target
+/// * `context` - The template context
+/// * `hb` - The Handlebars registry
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn add(
+    stores: Arc<Stores>,
+    dotdeploy_config: &crate::config::DotdeployConfig,
+    module: &str,
+    target: &Path,
+    source: Option<&Path>,
+    context: &serde_json::Value,
+    hb: &handlebars::Handlebars<'static>,
+) -> Result<()> {
+    let home = PathBuf::from(shellexpand::tilde("~").as_ref());
+    let target = std::path::absolute(target)
+        .with_context(|| format!("Failed to get absolute path of {:?}", target))?;
+    let (destination, store) = if target.starts_with(&home) {
+        (Destination::Home(target.clone()), &stores.user_store)
+    } else {
+        match &stores.system_store {
+            Some(sys_store) => (Destination::Root(target.clone()), sys_store),
+            None => bail!(
+                "{:?} is outside of HOME, but system files are not deployed",
+                target
+            ),
+        }
+    };
+
+    let absorbed = absorb(dotdeploy_config, &target, module, source).await?;
+    let res = async {
+        ensure_module(store, module, dotdeploy_config).await?;
+
+        ManagedFile {
+            module: module.to_string(),
+            operation: FileOperation::Symlink {
+                source: absorbed.source.clone(),
+                destination,
+                owner: None,
+                group: None,
+            },
+        }
+        .perform(&stores, context, hb)
+        .await
+    }
+    .await;
+    if let Err(e) = res {
+        absorbed.revert().await;
+        return Err(e);
+    }
+
+    info!("Added {:?} to module {}", target, module);
+
+    Ok(())
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_destination() {
+        let home = Path::new("/home/foo");
+        assert_eq!(
+            config_destination(Path::new("/home/foo/.config/bar"), home),
+            "~/.config/bar"
+        );
+        assert_eq!(config_destination(Path::new("/etc/bar"), home), "/etc/bar");
+    }
+
+    #[test]
+    fn test_add_file_entry() -> Result<()> {
+        let config_path = Path::new("config.toml");
+        let content = add_file_entry(
+            "# Comment\ndescription = \"Test\"",
+            config_path,
+            Path::new("/home/foo/.bar rc"),
+            "~/.bar rc",
+            "bar rc",
+        )?;
+        assert!(content.starts_with("# Comment\n"));
+
+        let table: toml::Table = toml::from_str(&content)?;
+        assert_eq!(
+            table["files"]["~/.bar rc"]["source"].as_str(),
+            Some("bar rc")
+        );
+
+        // Adding the same target twice fails
+        let content = add_file_entry(
+            &content,
+            config_path,
+            Path::new("/etc/bar"),
+            "/etc/bar",
+            "bar",
+        )?;
+        assert!(add_file_entry(
+            &content,
+            config_path,
+            Path::new("/etc/bar"),
+            "/etc/bar",
+            "other"
+        )
+        .is_err());
+
+        // Entries can not be appended to inline tables
+        assert!(add_file_entry(
+            "files = { \"/etc/foo\" = { source = \"foo\" } }\n",
+            config_path,
+            Path::new("/etc/bar"),
+            "/etc/bar",
+            "bar"
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_config() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let config_path = temp_dir.path().join("config.toml");
+        std::fs::write(&config_path, "old")?;
+
+        write_config(&config_path, "new")?;
+        assert_eq!(std::fs::read_to_string(&config_path)?, "new");
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+
+        Ok(())
+    }
+}
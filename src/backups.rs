@@ -0,0 +1,334 @@
+//! This module handles the inspection and maintenance of backups stored by dotdeploy.
+//!
+//! Backups are created whenever a deployed file replaces an existing one and are restored once the
+//! file is removed again. Backups of files which are no longer tracked are considered stale and can
+//! be pruned according to the configured retention policy.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::BackupRetention;
+use crate::store::backups::StoreBackup;
+use crate::store::db::Store;
+use crate::utils::common;
+use crate::utils::file_fs;
+use crate::utils::file_permissions;
+use crate::Stores;
+
+/// Selects the stale backups which should be pruned according to the retention policy.
+///
+/// # Arguments
+///
+/// * `stale` - Path and date of stale backups, ordered from newest to oldest
+/// * `retention` - The retention policy to apply
+/// * `now` - The point in time used to calculate the age of a backup
+///
+/// # Returns
+///
+/// The paths of all backups which should be removed.
+fn select_prunable(
+    stale: Vec<(String, chrono::DateTime<chrono::Local>)>,
+    retention: &BackupRetention,
+    now: chrono::DateTime<chrono::Local>,
+) -> Vec<String> {
+    stale
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, (_, date))| {
+            let too_many = retention.max_count.is_some_and(|max| *idx >= max);
+            let too_old = retention
+                .max_age
+                .is_some_and(|max| now.signed_duration_since(*date).num_days() > max);
+
+            // Without any limits, all stale backups are pruned
+            (retention.max_count.is_none() && retention.max_age.is_none()) || too_many || too_old
+        })
+        .map(|(_, (path, _))| path)
+        .collect()
+}
+
+/// Prunes the stale backups of a single store.
+async fn prune_store(store: &Store, retention: &BackupRetention, dry_run: bool) -> Result<usize> {
+    let stale = store
+        .get_stale_backups()
+        .await
+        .map_err(|e| e.into_anyhow())?;
+    let prunable = select_prunable(stale, retention, chrono::offset::Local::now());
+
+    for path in prunable.iter() {
+        if dry_run {
+            info!("Would remove backup of '{}'", path);
+        } else {
+            store
+                .remove_backup(path)
+                .await
+                .map_err(|e| e.into_anyhow())?;
+            info!("Removed backup of '{}'", path);
+        }
+    }
+
+    Ok(prunable.len())
+}
+
+/// Removes stale backups from the user and system store.
+///
+/// A backup is stale if its path is no longer tracked in the files table of the store. Which stale
+/// backups get removed is determined by the `backup_retention` setting of the configuration.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `dotdeploy_config` - Configuration for the deployment process
+/// * `dry_run` - Only report which backups would be removed
+///
+/// # Returns
+///
+/// A Result indicating success or failure of the pruning process
+pub(crate) async fn prune(
+    stores: Arc<Stores>,
+    dotdeploy_config: &crate::config::DotdeployConfig,
+    dry_run: bool,
+) -> Result<()> {
+    let retention = &dotdeploy_config.backup_retention;
+
+    let mut pruned = prune_store(&stores.user_store, retention, dry_run).await?;
+    if let Some(sys_store) = &stores.system_store {
+        pruned += prune_store(sys_store, retention, dry_run).await?;
+    }
+
+    if dry_run {
+        info!("{} stale backup(s) would be removed", pruned);
+    } else {
+        info!("Removed {} stale backup(s)", pruned);
+    }
+
+    Ok(())
+}
+
+/// Looks up the backup of a file, checking the user store first and the system store second.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `path` - The original path of the backed-up file
+///
+/// # Returns
+///
+/// A Result containing the store holding the backup and the backup itself.
+async fn find_backup<'a>(stores: &'a Stores, path: &Path) -> Result<(&'a Store, StoreBackup)> {
+    let mut candidates = vec![&stores.user_store];
+    if let Some(sys_store) = &stores.system_store {
+        candidates.push(sys_store);
+    }
+
+    for store in candidates {
+        if store
+            .check_backup_exists(path)
+            .await
+            .map_err(|e| e.into_anyhow())?
+        {
+            let backup = store.get_backup(path).await.map_err(|e| e.into_anyhow())?;
+            return Ok((store, backup));
+        }
+    }
+
+    bail!("No backup of {:?} found", path)
+}
+
+/// Turns a user supplied path into an absolute path, as backups are stored with absolute paths.
+fn absolute_path(path: &Path) -> Result<PathBuf> {
+    std::path::absolute(path).with_context(|| format!("Failed to get absolute path of {:?}", path))
+}
+
+/// Prints all backups of the user and system store.
+///
+/// For each backup, the store, file type, size, owner, date and path are shown. The size of
+/// symlinks is always zero.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+///
+/// # Returns
+///
+/// A Result indicating success or failure of the listing
+pub(crate) async fn list(stores: Arc<Stores>) -> Result<()> {
+    let mut backups: Vec<(&str, StoreBackup)> = vec![];
+    for b in stores
+        .user_store
+        .get_all_backups()
+        .await
+        .map_err(|e| e.into_anyhow())?
+    {
+        backups.push(("user", b));
+    }
+    if let Some(sys_store) = &stores.system_store {
+        for b in sys_store
+            .get_all_backups()
+            .await
+            .map_err(|e| e.into_anyhow())?
+        {
+            backups.push(("system", b));
+        }
+    }
+
+    if backups.is_empty() {
+        info!("No backups found");
+        return Ok(());
+    }
+
+    println!(
+        "{:<6}  {:<7}  {:>10}  {:<11}  {:<19}  PATH",
+        "STORE", "TYPE", "SIZE", "OWNER", "DATE"
+    );
+    for (store, b) in backups.iter() {
+        println!(
+            "{:<6}  {:<7}  {:>10}  {:<11}  {:<19}  {}",
+            store,
+            b.file_type,
+            b.content.as_ref().map_or(0, |c| c.len()),
+            file_permissions::display_owner(&b.owner),
+            b.date.format("%Y-%m-%d %H:%M:%S"),
+            b.path
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints the content of a single backup to stdout.
+///
+/// For regular files, the raw content is written. For symlinks, the link source is shown.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `path` - The original path of the backed-up file
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn show(stores: Arc<Stores>, path: &Path) -> Result<()> {
+    let path = absolute_path(path)?;
+    let (_, backup) = find_backup(&stores, &path).await?;
+
+    match backup.file_type.as_str() {
+        "link" => println!(
+            "{} -> {}",
+            backup.path,
+            backup.link_source.unwrap_or_default()
+        ),
+        _ => {
+            let mut stdout = std::io::stdout().lock();
+            stdout
+                .write_all(&backup.content.unwrap_or_default())
+                .and_then(|_| stdout.flush())
+                .context("Failed to write backup content to stdout")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Restores a single backup to its original path or a chosen destination.
+///
+/// If the destination already exists, the user is asked for confirmation before it gets
+/// overwritten. The backup is kept in the store.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `path` - The original path of the backed-up file
+/// * `to` - Optional destination, defaults to the original path
+///
+/// # Returns
+///
+/// A Result containing `true` if the backup was restored and `false` if the user aborted
+pub(crate) async fn restore(stores: Arc<Stores>, path: &Path, to: Option<&Path>) -> Result<bool> {
+    let path = absolute_path(path)?;
+    let to = match to {
+        Some(to) => absolute_path(to)?,
+        None => path.clone(),
+    };
+    let (store, _) = find_backup(&stores, &path).await?;
+
+    if file_fs::check_link_exists(&to, None).await? || file_fs::check_file_exists(&to).await? {
+        if !common::ask_boolean(&format!("{:?} already exists. Overwrite? [y/N]", &to)) {
+            warn!("Restoring backup of {:?} aborted", &path);
+            return Ok(false);
+        }
+        file_fs::delete_file(&to).await?;
+    }
+
+    if let Some(parent) = to.parent() {
+        file_fs::ensure_dir_exists(parent).await?;
+    }
+
+    store
+        .restore_backup(&path, &to)
+        .await
+        .map_err(|e| e.into_anyhow())?;
+    info!("Restored backup of {:?} to {:?}", &path, &to);
+
+    Ok(true)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::{Duration, Local};
+
+    fn stale_backups(now: chrono::DateTime<Local>) -> Vec<(String, chrono::DateTime<Local>)> {
+        vec![
+            ("/home/foo0.txt".to_string(), now - Duration::days(1)),
+            ("/home/foo1.txt".to_string(), now - Duration::days(10)),
+            ("/home/foo2.txt".to_string(), now - Duration::days(100)),
+        ]
+    }
+
+    #[test]
+    fn test_select_prunable() {
+        let now = Local::now();
+
+        // No limits, prune everything
+        let result = select_prunable(stale_backups(now), &BackupRetention::default(), now);
+        assert_eq!(result.len(), 3);
+
+        // Keep the newest two
+        let retention = BackupRetention {
+            max_count: Some(2),
+            max_age: None,
+        };
+        let result = select_prunable(stale_backups(now), &retention, now);
+        assert_eq!(result, vec!["/home/foo2.txt".to_string()]);
+
+        // Remove everything older than 5 days
+        let retention = BackupRetention {
+            max_count: None,
+            max_age: Some(5),
+        };
+        let result = select_prunable(stale_backups(now), &retention, now);
+        assert_eq!(
+            result,
+            vec!["/home/foo1.txt".to_string(), "/home/foo2.txt".to_string()]
+        );
+
+        // Both limits, either one triggers pruning
+        let retention = BackupRetention {
+            max_count: Some(1),
+            max_age: Some(50),
+        };
+        let result = select_prunable(stale_backups(now), &retention, now);
+        assert_eq!(
+            result,
+            vec!["/home/foo1.txt".to_string(), "/home/foo2.txt".to_string()]
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! This module generates a bootstrap script for fresh machines.
+//!
+//! `dotdeploy bootstrap` emits a self-contained POSIX shell script which installs dotdeploy, clones
+//! the dotfiles into `config_root` and runs the initial deployment. dotdeploy is downloaded from
+//! `bootstrap.binary_url` if set and built with cargo otherwise. A downloaded binary is verified
+//! against `bootstrap.binary_sha256`, or against the checksum published next to it as
+//! `<binary_url>.sha256` if unset. The dotfiles are cloned from `bootstrap.repository`, which
+//! defaults to the `origin` remote of `config_root`.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::config::DotdeployConfig;
+
+/// Repository dotdeploy is built from if no binary is configured.
+const DOTDEPLOY_REPOSITORY: &str = "https://github.com/FrauH0lle/dotdeploy-rs";
+
+/// Quotes a string for POSIX shells.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Returns the URL of the `origin` remote of a git repository.
+fn origin_url(repo: &Path) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["remote", "get-url", "origin"])
+        .output()
+        .context("Failed to run git remote get-url origin")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to get the origin remote of {:?}: {}",
+            repo,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Returns a shell expression for a path, relative to HOME if the path is inside it.
+fn path_expr(path: &Path, home: Option<&Path>) -> Result<String> {
+    let path_str = |p: &Path| {
+        p.to_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("Path {:?} contains invalid Unicode characters", p))
+    };
+    match home.and_then(|h| path.strip_prefix(h).ok()) {
+        Some(relative) if relative.as_os_str().is_empty() => Ok("\"$HOME\"".to_string()),
+        Some(relative) => Ok(format!("\"$HOME\"/{}", quote(&path_str(relative)?))),
+        None => Ok(quote(&path_str(path)?)),
+    }
+}
+
+/// Renders the bootstrap script.
+///
+/// # Arguments
+///
+/// * `repository` - Git URL of the dotfiles
+/// * `dotfiles_dir` - Shell expression of the folder the dotfiles are cloned into
+/// * `binary_url` - URL of a dotdeploy binary, built with cargo if unset
+/// * `binary_sha256` - SHA-256 checksum of the binary, downloaded from `<binary_url>.sha256` if
+///   unset
+/// * `modules` - Modules deployed initially, the default modules if empty
+fn script(
+    repository: &str,
+    dotfiles_dir: &str,
+    binary_url: Option<&str>,
+    binary_sha256: Option<&str>,
+    modules: &[String],
+) -> String {
+    let install = match binary_url {
+        Some(url) => format!(
+            r#"    fetch() {{
+        if command -v curl >/dev/null 2>&1; then
+            curl -fsSL "$1"
+        else
+            wget -qO- "$1"
+        fi
+    }}
+    mkdir -p "$HOME/.local/bin"
+    BINARY="$(mktemp "$HOME/.local/bin/dotdeploy.XXXXXX")"
+    fetch {url} > "$BINARY"
+    EXPECTED={expected}
+    ACTUAL="$(sha256sum "$BINARY" | cut -d ' ' -f 1)"
+    if [ "$ACTUAL" != "$EXPECTED" ]; then
+        rm -f "$BINARY"
+        echo "Checksum of dotdeploy does not match: expected $EXPECTED, got $ACTUAL" >&2
+        exit 1
+    fi
+    chmod +x "$BINARY"
+    mv "$BINARY" "$HOME/.local/bin/dotdeploy"
+    PATH="$HOME/.local/bin:$PATH"
+"#,
+            url = quote(url),
+            expected = match binary_sha256 {
+                Some(sha256) => quote(&sha256.to_lowercase()),
+                None => format!(
+                    "\"$(fetch {} | cut -d ' ' -f 1)\"",
+                    quote(&format!("{}.sha256", url))
+                ),
+            }
+        ),
+        None => format!(
+            r#"    if ! command -v cargo >/dev/null 2>&1; then
+        echo "cargo is required to build dotdeploy, see https://rustup.rs" >&2
+        exit 1
+    fi
+    cargo install --locked --git {}
+    PATH="${{CARGO_HOME:-$HOME/.cargo}}/bin:$PATH"
+"#,
+            quote(DOTDEPLOY_REPOSITORY)
+        ),
+    };
+    let modules: String = modules.iter().map(|m| format!(" {}", quote(m))).collect();
+
+    format!(
+        r#"#!/bin/sh
+# Bootstrap script generated by dotdeploy {version}.
+# Installs dotdeploy, clones the dotfiles and runs the initial deployment.
+set -eu
+
+DOTFILES_REPO={repository}
+DOTFILES_DIR={dotfiles_dir}
+
+echo "Installing dotdeploy"
+if ! command -v dotdeploy >/dev/null 2>&1; then
+{install}fi
+export PATH
+
+echo "Cloning $DOTFILES_REPO into $DOTFILES_DIR"
+if [ -d "$DOTFILES_DIR/.git" ]; then
+    git -C "$DOTFILES_DIR" pull --ff-only
+else
+    git clone "$DOTFILES_REPO" "$DOTFILES_DIR"
+fi
+
+# Point dotdeploy at the dotfiles unless a config exists already. Backslashes and quotes are
+# escaped to keep the path a valid TOML string.
+CONFIG_FILE="${{XDG_CONFIG_HOME:-$HOME/.config}}/dotdeploy/config.toml"
+if [ ! -e "$CONFIG_FILE" ]; then
+    mkdir -p "$(dirname "$CONFIG_FILE")"
+    CONFIG_ROOT="$(printf '%s' "$DOTFILES_DIR" | sed 's/[\\"]/\\&/g')"
+    printf 'config_root = "%s"\n' "$CONFIG_ROOT" > "$CONFIG_FILE"
+fi
+
+echo "Deploying"
+dotdeploy deploy{modules}
+"#,
+        version = env!("CARGO_PKG_VERSION"),
+        repository = quote(repository),
+    )
+}
+
+/// Generates the bootstrap script.
+///
+/// # Arguments
+///
+/// * `dotdeploy_config` - Configuration providing `config_root` and the `[bootstrap]` table
+/// * `output` - Write the script to this file instead of printing it
+///
+/// # Returns
+///
+/// A Result indicating success, or an error if the git remote of the dotfiles is unknown
+pub(crate) fn bootstrap(dotdeploy_config: &DotdeployConfig, output: Option<&Path>) -> Result<()> {
+    let settings = &dotdeploy_config.bootstrap;
+    let repository = match &settings.repository {
+        Some(r) => r.clone(),
+        None => origin_url(&dotdeploy_config.config_root).context(
+            "Failed to determine the git remote of the dotfiles, set bootstrap.repository",
+        )?,
+    };
+    let home = std::env::var_os("HOME").map(std::path::PathBuf::from);
+    let content = script(
+        &repository,
+        &path_expr(&dotdeploy_config.config_root, home.as_deref())?,
+        settings.binary_url.as_deref(),
+        settings.binary_sha256.as_deref(),
+        &settings.modules,
+    );
+
+    match output {
+        Some(path) => {
+            std::fs::write(path, content)
+                .with_context(|| format!("Failed to write bootstrap script to {:?}", path))?;
+            std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+                .with_context(|| format!("Failed to make {:?} executable", path))?;
+            info!("Wrote bootstrap script to {}", path.display());
+        }
+        None => print!("{}", content),
+    }
+    Ok(())
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("plain"), "'plain'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_path_expr() -> Result<()> {
+        let home = Path::new("/home/user");
+        assert_eq!(
+            path_expr(Path::new("/home/user/.dotfiles"), Some(home))?,
+            "\"$HOME\"/'.dotfiles'"
+        );
+        assert_eq!(
+            path_expr(Path::new("/srv/dotfiles"), Some(home))?,
+            "'/srv/dotfiles'"
+        );
+        assert_eq!(path_expr(home, Some(home))?, "\"$HOME\"");
+        Ok(())
+    }
+
+    #[test]
+    fn test_script() {
+        let s = script(
+            "https://example.com/dotfiles.git",
+            "\"$HOME\"/'.dotfiles'",
+            None,
+            None,
+            &["zsh".to_string(), "git".to_string()],
+        );
+        assert!(s.starts_with("#!/bin/sh\n"));
+        assert!(s.contains("DOTFILES_REPO='https://example.com/dotfiles.git'\n"));
+        assert!(s.contains("cargo install --locked --git"));
+        assert!(s.ends_with("dotdeploy deploy 'zsh' 'git'\n"));
+
+        let s = script(
+            "https://example.com/dotfiles.git",
+            "'/srv/dotfiles'",
+            Some("https://example.com/dotdeploy"),
+            None,
+            &[],
+        );
+        assert!(s.contains("fetch 'https://example.com/dotdeploy' > \"$BINARY\""));
+        assert!(s.contains("EXPECTED=\"$(fetch 'https://example.com/dotdeploy.sha256' | cut"));
+        assert!(!s.contains("cargo install"));
+        assert!(s.ends_with("dotdeploy deploy\n"));
+
+        let s = script(
+            "https://example.com/dotfiles.git",
+            "'/srv/dotfiles'",
+            Some("https://example.com/dotdeploy"),
+            Some("ABCD"),
+            &[],
+        );
+        assert!(s.contains("EXPECTED='abcd'\n"));
+    }
+
+    #[test]
+    fn test_script_config_root() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let s = script(
+            "https://example.com/dotfiles.git",
+            &quote(r#"/srv/dot"files\"#),
+            None,
+            None,
+            &[],
+        );
+        // Run only the part writing the config
+        let start = s.find("DOTFILES_DIR=").unwrap();
+        let snippet = s[start..].lines().next().unwrap().to_string()
+            + "\n"
+            + &s[s.find("CONFIG_FILE=").unwrap()..s.find("echo \"Deploying\"").unwrap()];
+        let status = Command::new("sh")
+            .args(["-c", &snippet])
+            .env("XDG_CONFIG_HOME", temp_dir.path())
+            .status()?;
+        assert!(status.success());
+
+        let content = std::fs::read_to_string(temp_dir.path().join("dotdeploy/config.toml"))?;
+        let table: toml::Table = toml::from_str(&content)?;
+        assert_eq!(table["config_root"].as_str(), Some(r#"/srv/dot"files\"#));
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! This module reports pending changes of deployed modules.
+//!
+//! A check compares the files recorded in the stores with the current state of their sources and
+//! destinations, without changing anything. Only files which have been deployed before are
+//! compared, new files of a module and changes of template variables are not detected.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::cli::OutputFormat;
+use crate::report::{emit, Report};
+use crate::store::db::Store;
+use crate::store::files::StoreFile;
+use crate::utils::{file_checksum, file_fs};
+use crate::Stores;
+
+/// A deployed file which would change on the next deployment.
+#[derive(Serialize, Debug)]
+struct CheckEntry {
+    store: &'static str,
+    module: String,
+    operation: String,
+    destination: String,
+    change: &'static str,
+}
+
+/// Result of a check.
+#[derive(Serialize, Debug)]
+struct CheckReport {
+    changes: Vec<CheckEntry>,
+}
+
+impl Report for CheckReport {
+    fn print_text(&self) {
+        let mut module = None;
+        for c in self.changes.iter() {
+            if module != Some(&c.module) {
+                println!("{}:", c.module);
+                module = Some(&c.module);
+            }
+            println!("  {:<16}  {}", c.change, c.destination);
+        }
+
+        if self.changes.is_empty() {
+            info!("All deployed files are up to date");
+        }
+    }
+}
+
+/// Compares a deployed file with its source and destination.
+///
+/// # Returns
+///
+/// A Result containing a short description of the pending change, or `None` if the file is up to
+/// date.
+pub(crate) async fn pending_change(file: &StoreFile) -> Result<Option<&'static str>> {
+    let destination = Path::new(&file.destination);
+
+    if let Some(source) = file.source.as_deref() {
+        if !file_fs::check_file_exists(source).await? {
+            return Ok(Some("source removed"));
+        }
+    }
+
+    // Links are checked without following them
+    let exists = match tokio::fs::symlink_metadata(destination).await {
+        Ok(_) => true,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(_) => file_fs::check_file_exists(destination).await?,
+    };
+    if !exists {
+        return Ok(Some("missing"));
+    }
+
+    let change = match (file.operation.as_str(), file.source.as_deref()) {
+        ("link", Some(source)) => {
+            if file_fs::check_link_exists(&file.destination, Some(&source.to_string())).await? {
+                None
+            } else {
+                Some("link changed")
+            }
+        }
+        ("hardlink", Some(source)) => {
+            if file_fs::check_hardlink_exists(&file.destination, &source.to_string()).await? {
+                None
+            } else {
+                Some("hardlink broken")
+            }
+        }
+        ("directory", Some(source)) => {
+            if file_fs::check_link_exists(&file.destination, None).await? {
+                // Linked directory
+                if file_fs::check_link_exists(&file.destination, Some(&source.to_string())).await? {
+                    None
+                } else {
+                    Some("link changed")
+                }
+            } else if file.source_checksum.is_some()
+                && file.source_checksum.as_deref()
+                    != Some(
+                        file_checksum::calculate_dir_checksum(source)
+                            .await?
+                            .as_str(),
+                    )
+            {
+                Some("source changed")
+            } else {
+                None
+            }
+        }
+        (_, source) => {
+            if let (Some(source), Some(checksum)) = (source, file.source_checksum.as_deref()) {
+                if file_checksum::calculate_sha256_checksum(source).await? != checksum {
+                    return Ok(Some("source changed"));
+                }
+            }
+            match file.destination_checksum.as_deref() {
+                Some(checksum)
+                    if file_checksum::calculate_sha256_checksum(destination).await? != checksum =>
+                {
+                    Some("modified locally")
+                }
+                _ => None,
+            }
+        }
+    };
+
+    Ok(change)
+}
+
+/// Collects the pending changes of the files in a store.
+async fn check_store(
+    store: &Store,
+    name: &'static str,
+    modules: &[String],
+) -> Result<Vec<CheckEntry>> {
+    let mut changes = vec![];
+    for file in store
+        .find_files(None, None)
+        .await
+        .map_err(|e| e.into_anyhow())?
+        .into_iter()
+        .filter(|f| modules.is_empty() || modules.contains(&f.module))
+    {
+        if let Some(change) = pending_change(&file).await? {
+            changes.push(CheckEntry {
+                store: name,
+                module: file.module,
+                operation: file.operation,
+                destination: file.destination,
+                change,
+            });
+        }
+    }
+    Ok(changes)
+}
+
+/// Reports which deployed files of the given modules would change, without executing anything.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `modules` - Names of the modules to check, all deployed modules if empty
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result containing `true` if all deployed files are up to date
+pub(crate) async fn check(
+    stores: Arc<Stores>,
+    modules: &[String],
+    format: OutputFormat,
+) -> Result<bool> {
+    let mut changes = check_store(&stores.user_store, "user", modules).await?;
+    if let Some(sys_store) = &stores.system_store {
+        changes.extend(check_store(sys_store, "system", modules).await?);
+    }
+    changes.sort_by(|a, b| (&a.module, &a.destination).cmp(&(&b.module, &b.destination)));
+
+    let up_to_date = changes.is_empty();
+    emit(&CheckReport { changes }, format)?;
+
+    Ok(up_to_date)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+
+    fn store_file(operation: &str, source: &Path, destination: &Path) -> StoreFile {
+        StoreFile {
+            module: "test".to_string(),
+            source: Some(source.display().to_string()),
+            source_checksum: None,
+            destination: destination.display().to_string(),
+            destination_checksum: None,
+            operation: operation.to_string(),
+            user: None,
+            date: Local::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pending_change() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("source");
+        let destination = temp_dir.path().join("destination");
+        tokio::fs::write(&source, "foo").await?;
+
+        // Missing destination
+        let mut file = store_file("copy", &source, &destination);
+        assert_eq!(pending_change(&file).await?, Some("missing"));
+
+        // Unchanged copy
+        tokio::fs::write(&destination, "foo").await?;
+        let checksum = file_checksum::calculate_sha256_checksum(&source).await?;
+        file.source_checksum = Some(checksum.clone());
+        file.destination_checksum = Some(checksum);
+        assert_eq!(pending_change(&file).await?, None);
+
+        // Local modification
+        tokio::fs::write(&destination, "bar").await?;
+        assert_eq!(pending_change(&file).await?, Some("modified locally"));
+
+        // Changed source
+        tokio::fs::write(&source, "baz").await?;
+        assert_eq!(pending_change(&file).await?, Some("source changed"));
+
+        // Links pointing elsewhere
+        let link = temp_dir.path().join("link");
+        tokio::fs::symlink(&destination, &link).await?;
+        let file = store_file("link", &source, &link);
+        assert_eq!(pending_change(&file).await?, Some("link changed"));
+
+        // Removed source
+        tokio::fs::remove_file(&source).await?;
+        assert_eq!(pending_change(&file).await?, Some("source removed"));
+
+        Ok(())
+    }
+}
@@ -1,14 +1,16 @@
 //! This module defines the command-line interface (CLI) structure for the application, using the
 //! clap crate for parsing and handling command-line arguments.
 
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
 
 // Represents the main command-line interface structure.
 // This struct defines the overall CLI, including global options and subcommands.
 /// dotdeploy -- System configuraton and dotfile manager
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
-pub(crate) struct Cli {
+pub struct Cli {
     /// The subcommand to be executed (Deploy or Remove).
     #[command(subcommand)]
     pub(crate) command: Commands,
@@ -19,9 +21,52 @@ pub(crate) struct Cli {
     #[clap(short = 'v', long = "verbose", action = clap::ArgAction::Count, global = true)]
     pub(crate) verbosity: u8,
 
+    /// Suppress terminal output except errors and critical module messages.
+    ///
+    /// This includes progress, the output of package managers and text previews. The log of the run
+    /// is still written, e.g. for use in shell startup files.
+    #[clap(short = 'q', long, action, global = true, conflicts_with = "verbosity")]
+    pub(crate) quiet: bool,
+
     /// Flag to skip package installation during deployment.
     #[clap(long, short, action)]
     pub(crate) skip_pkg_install: bool,
+
+    /// Wait for another running instance to finish instead of failing.
+    #[clap(long, action, global = true)]
+    pub(crate) wait: bool,
+
+    /// Mark this run as automatic, e.g. started by a systemd timer.
+    ///
+    /// Automatic deployments are skipped if the conditions in the `[schedule]` section of the
+    /// config are not met.
+    #[clap(long, action, global = true)]
+    pub(crate) auto: bool,
+
+    /// Show the progress of file operations and package installations during deployment.
+    #[clap(long, action, global = true)]
+    pub(crate) progress: bool,
+
+    /// Show the full diff of locally modified files instead of a one-line summary.
+    #[clap(long, action, global = true)]
+    pub(crate) diff: bool,
+
+    /// Work on an in-memory copy of the stores, leaving the databases on disk untouched.
+    ///
+    /// Used automatically by dry runs.
+    #[clap(long, action, global = true)]
+    pub(crate) ephemeral_store: bool,
+
+    /// Deploy all targets below this folder instead of the live system, e.g. into a chroot.
+    ///
+    /// Overrides `target_root` of the config. Packages are not installed and actions and hooks are
+    /// skipped.
+    #[clap(long, value_name = "PATH", global = true)]
+    pub(crate) target_root: Option<PathBuf>,
+
+    /// Output format of command results.
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    pub(crate) format: OutputFormat,
 }
 
 /// Enumerates the available subcommands for the application.
@@ -31,13 +76,467 @@ pub(crate) enum Commands {
     Deploy {
         /// Optional list of module names to deploy.
         modules: Option<Vec<String>>,
+
+        /// Comma separated list of components to deploy. Defaults to all components.
+        #[clap(long, value_enum, value_delimiter = ',')]
+        components: Option<Vec<Component>>,
+
+        /// Pick the modules to deploy from a list of all available modules.
+        #[clap(long, short, action, conflicts_with = "modules")]
+        interactive: bool,
+
+        /// Also deploy the modules of this profile, as defined in the config.
+        #[clap(long)]
+        profile: Option<String>,
+
+        /// Also deploy all modules with this tag. May be given multiple times.
+        #[clap(long = "tag")]
+        tags: Vec<String>,
+
+        /// Only report which deployed files would change, without deploying anything.
+        ///
+        /// Files which have not been deployed before are not reported. Exits with a non-zero
+        /// status if changes are pending.
+        #[clap(long, action)]
+        check: bool,
+
+        /// Deploy the modules for another user. Requires root.
+        ///
+        /// Targets in HOME are expanded against the home directory of the user, who also owns the
+        /// deployed files. The files are recorded in the user store of the user. Symbolic links
+        /// inside the home directory of the user are never followed.
+        #[clap(long, value_name = "USER")]
+        for_user: Option<String>,
+
+        /// Print a breakdown of the time spent per phase and module at the end of the run.
+        #[clap(long, action)]
+        timings: bool,
     },
 
     /// Remove system configuration or specific modules.
     Remove {
         /// Optional list of module names to remove.
         modules: Option<Vec<String>>,
+
+        /// Keep the packages of the modules installed.
+        #[clap(long, action, conflicts_with = "keep_files")]
+        keep_packages: bool,
+
+        /// Keep the deployed files of the modules in place. They are no longer tracked afterwards.
+        #[clap(long, action)]
+        keep_files: bool,
+
+        /// Also delete the backups, baselines, notes and statistics of the modules from the store.
+        #[clap(long, action)]
+        purge: bool,
+
+        /// Only list the files, backups and packages which would be removed or restored.
+        #[clap(long, action)]
+        dry_run: bool,
+    },
+
+    /// Preview a module in a throwaway HOME overlay.
+    ///
+    /// The module is deployed into a temporary directory which replaces HOME and the XDG base
+    /// directories. Afterwards, a subshell is started inside the overlay. System files and packages
+    /// are skipped. The overlay is deleted once the subshell exits.
+    Try {
+        /// Name of the module to preview.
+        module: String,
+    },
+
+    /// Render a template of a module with the deployment context and print the result.
+    ///
+    /// Nothing is deployed. The template is read from stdin if no file is given.
+    Render {
+        /// Name of the module providing the context.
+        module: String,
+
+        /// Template file, relative to the module directory. Use `-` to read stdin.
+        file: Option<PathBuf>,
+
+        /// Override a context value, e.g. `--context editor=vim`. May be given multiple times.
+        #[clap(long = "context", value_parser = parse_key_value)]
+        overrides: Vec<(String, String)>,
+    },
+
+    /// Show which module deploys a path and whether it still matches the store.
+    ///
+    /// Lists the deploying module, operation, source and date of the last deployment from the
+    /// stores, and all modules declaring the path in their config.
+    Why {
+        /// The path to explain, e.g. `~/.zshrc`.
+        path: String,
+    },
+
+    /// Show the eval_when conditions of a module and why they are true or false.
+    ///
+    /// Lists the files, actions, packages and messages of the module with the result of their
+    /// condition in the current context and the values of the variables it references.
+    Explain {
+        /// Name of the module to explain.
+        module: String,
     },
+
+    /// Serve status, lookup and deploy requests on a Unix socket.
+    ///
+    /// Requests are JSON-RPC 2.0 objects, one per line. The stores and the sudo session stay open
+    /// until the daemon is stopped with SIGINT or SIGTERM.
+    Daemon {
+        /// Path of the socket. Defaults to `$XDG_RUNTIME_DIR/dotdeploy.sock`.
+        #[clap(long)]
+        socket: Option<PathBuf>,
+    },
+
+    /// Show deployment statistics of modules.
+    Stats {
+        /// Show the full history of this module instead of the latest run of all modules.
+        module: Option<String>,
+    },
+
+    /// Verify the integrity of the stores.
+    Fsck {
+        /// Remove orphaned file entries, stale rows and invalid backups.
+        #[clap(long, action)]
+        fix: bool,
+    },
+
+    /// Inspect and manage backups of replaced files.
+    Backups {
+        /// The backups subcommand to be executed.
+        #[command(subcommand)]
+        command: BackupsCommands,
+    },
+
+    /// List deployed modules with reason, date and number of files and packages.
+    List {
+        /// Also list the files of each module.
+        #[clap(long, action)]
+        files: bool,
+
+        /// Also list the packages of each module.
+        #[clap(long, action)]
+        packages: bool,
+    },
+
+    /// Search the available modules by name, description, author and tags.
+    Search {
+        /// Search term, matched case-insensitively. Lists all modules if omitted.
+        term: Option<String>,
+    },
+
+    /// Write the metadata of all available modules to an index file.
+    Index {
+        /// Path of the index file. Defaults to `index.json` in `modules_root`.
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Look up deployed files by destination or module.
+    Lookup {
+        /// Destination path or glob pattern, e.g. "~/.config/*".
+        target: Option<String>,
+
+        /// Only show files owned by this module.
+        #[clap(long)]
+        module: Option<String>,
+
+        /// Show module, operation, source and checksums of each file.
+        #[clap(long, short, action)]
+        long: bool,
+    },
+
+    /// Find links into the dotfiles which are not tracked by a store.
+    ///
+    /// The folders listed in `orphan_paths` of the config are searched, e.g. for leftovers of a
+    /// previous GNU Stow setup.
+    Orphans {
+        /// Ask to remove each orphaned link.
+        #[clap(long, action)]
+        remove: bool,
+    },
+
+    /// Show the logs of earlier runs.
+    ///
+    /// Shows the log of dotdeploy and the output of the actions of the latest run by default.
+    Logs {
+        /// List all runs with logs instead.
+        #[clap(long, action, conflicts_with_all = ["run", "file"])]
+        list: bool,
+
+        /// Show the run with this ID, as listed by --list.
+        #[clap(long, conflicts_with = "file")]
+        run: Option<String>,
+
+        /// Show the run which last deployed this file.
+        #[clap(long)]
+        file: Option<PathBuf>,
+
+        /// Only show messages of at least this level: error, warn, info, debug or trace.
+        #[clap(long)]
+        level: Option<log::Level>,
+
+        /// Only show the logs of this module.
+        #[clap(long)]
+        module: Option<String>,
+
+        /// Only show the last lines of each log.
+        #[clap(long, short = 'n')]
+        lines: Option<usize>,
+    },
+
+    /// Show the history of runs and file changes.
+    ///
+    /// Lists the latest runs by default. Given a file, shows when it changed and by which run.
+    History {
+        /// Show the changes of this file.
+        file: Option<PathBuf>,
+
+        /// Only show the changes of files of this module.
+        #[clap(long)]
+        module: Option<String>,
+
+        /// Maximum number of entries to show.
+        #[clap(long, short = 'n', default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Absorb an existing file into a module.
+    ///
+    /// The file is moved into the module directory, added to the module config and replaced with
+    /// a link.
+    Adopt {
+        /// The file to adopt.
+        target: PathBuf,
+
+        /// Name of the module adopting the file.
+        module: String,
+
+        /// Source path inside the module directory. Defaults to the file name of the target.
+        #[clap(long)]
+        source: Option<PathBuf>,
+    },
+
+    /// Add an existing file to a module and deploy it right away.
+    ///
+    /// Like `adopt`, but the original file is backed up and files outside of HOME are supported.
+    Add {
+        /// Name of the module adding the file.
+        module: String,
+
+        /// The file to add.
+        target: PathBuf,
+
+        /// Source path inside the module directory. Defaults to the file name of the target.
+        #[clap(long)]
+        source: Option<PathBuf>,
+    },
+
+    /// Exclude single targets from deployment without editing their module.
+    Exclude {
+        /// The exclude subcommand to be executed.
+        #[command(subcommand)]
+        command: ExcludeCommands,
+    },
+
+    /// Print a shell completion script.
+    ///
+    /// Module names are completed dynamically from `modules_root` and `hosts_root`, e.g. for bash:
+    /// `source <(dotdeploy completions bash)`.
+    Completions {
+        /// The shell to generate the script for.
+        #[clap(value_enum)]
+        shell: Shell,
+    },
+
+    /// Inspect the configuration.
+    Config {
+        /// The config subcommand to be executed.
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    /// Print the `DOD_*` variables given to actions, e.g. `eval "$(dotdeploy env)"`.
+    ///
+    /// With `--format json`, the variables are printed as a JSON object.
+    Env {
+        /// Shell syntax of the printed variables.
+        #[clap(long, value_enum, default_value_t = Shell::Bash)]
+        shell: Shell,
+    },
+
+    /// Generate a script reproducing the setup on a fresh machine.
+    ///
+    /// The script installs dotdeploy, clones the dotfiles from the git remote set in the
+    /// `[bootstrap]` section of the config and runs the initial deployment.
+    Bootstrap {
+        /// Write the script to this file instead of printing it.
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+
+    /// Update dotdeploy to the latest release.
+    ///
+    /// The binary matching this machine is downloaded from GitHub, verified against the published
+    /// SHA-256 checksum and replaces the running binary. Only use it for prebuilt binaries.
+    SelfUpdate {
+        /// Only check whether a newer release is available.
+        #[clap(long, action)]
+        check: bool,
+    },
+
+    /// Generate the man page.
+    GenMan {
+        /// Write the man page to `dotdeploy.1` in this directory instead of printing it.
+        #[clap(long)]
+        out_dir: Option<PathBuf>,
+    },
+
+    /// Print completion candidates, used by the completion scripts.
+    #[command(name = "_complete", hide = true)]
+    Complete {
+        /// The kind of candidates to print.
+        #[clap(value_enum)]
+        kind: CompleteKind,
+    },
+
+    /// Attach notes to modules or deployed files.
+    Note {
+        /// The note subcommand to be executed.
+        #[command(subcommand)]
+        command: NoteCommands,
+    },
+}
+
+/// Enumerates the output formats of command results.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    /// Human readable text.
+    Text,
+    /// JSON for scripting.
+    Json,
+}
+
+/// Enumerates the shells for which completion scripts and environment variables can be printed.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Enumerates the kinds of dynamic completion candidates.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CompleteKind {
+    /// Names of modules and hosts.
+    Modules,
+}
+
+/// Enumerates the components of a deployment which can be selected individually.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Component {
+    /// File operations, including generated files.
+    Files,
+    /// Pre, main and post stage actions.
+    Actions,
+    /// Package installations.
+    Packages,
+}
+
+/// Enumerates the available subcommands for managing backups.
+#[derive(Subcommand)]
+pub(crate) enum BackupsCommands {
+    /// Remove stale backups of files which are no longer tracked.
+    Prune {
+        /// Only show which backups would be removed.
+        #[clap(long, action)]
+        dry_run: bool,
+    },
+
+    /// List all backups with their type, size, owner and date.
+    List,
+
+    /// Print the content of a single backup.
+    Show {
+        /// Original path of the backed-up file.
+        path: PathBuf,
+    },
+
+    /// Restore a single backup to its original path or a chosen destination.
+    Restore {
+        /// Original path of the backed-up file.
+        path: PathBuf,
+
+        /// Restore the backup to this path instead of its original location.
+        #[clap(long)]
+        to: Option<PathBuf>,
+    },
+}
+
+/// Enumerates the available subcommands for managing excluded targets.
+#[derive(Subcommand)]
+pub(crate) enum ExcludeCommands {
+    /// Exclude a target from deployment.
+    Add {
+        /// Path of the target, e.g. "~/.config/foo.conf".
+        path: String,
+    },
+
+    /// List all excluded targets.
+    List,
+
+    /// Deploy a previously excluded target again.
+    Remove {
+        /// Path of the target.
+        path: String,
+    },
+}
+
+/// Enumerates the available subcommands for inspecting the configuration.
+#[derive(Subcommand)]
+pub(crate) enum ConfigCommands {
+    /// Report unknown keys, invalid paths and contradictory settings.
+    Check,
+
+    /// Print the configured values and where they were set.
+    Show {
+        /// Also print default values.
+        #[clap(long, action)]
+        effective: bool,
+    },
+}
+
+/// Enumerates the available subcommands for managing notes.
+#[derive(Subcommand)]
+pub(crate) enum NoteCommands {
+    /// Add a note to a module or file.
+    Add {
+        /// Module name or file path.
+        target: String,
+
+        /// The note text.
+        text: String,
+    },
+
+    /// List all notes or the notes of a module or file.
+    List {
+        /// Module name or file path.
+        target: Option<String>,
+    },
+
+    /// Remove a note.
+    Remove {
+        /// ID of the note, as shown by `note list`.
+        id: i64,
+    },
+}
+
+/// Parses a `key=value` pair.
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected key=value, got {:?}", s)),
+    }
 }
 
 /// Parses command-line arguments and returns a configured Cli instance.
@@ -48,7 +547,7 @@ pub(crate) enum Commands {
 /// # Returns
 ///
 /// A Cli struct representing the parsed command-line arguments.
-pub(crate) fn get_cli() -> Cli {
+pub fn get_cli() -> Cli {
     let mut cli = Cli::parse();
 
     // Cap the verbosity level at 2
@@ -0,0 +1,229 @@
+//! This module generates shell completion scripts.
+//!
+//! Subcommands and options are taken from the CLI definition. Arguments expecting module names
+//! call back into `dotdeploy _complete modules`, which prints the modules and hosts found in
+//! `modules_root` and `hosts_root`. This way, completions always reflect the current dotfiles.
+
+use clap::CommandFactory;
+
+use crate::cli::{Cli, CompleteKind, Shell};
+use crate::config::DotdeployConfig;
+use crate::modules::find_modules;
+
+/// Completion data of a single subcommand.
+#[derive(Debug, PartialEq, Eq)]
+struct SubcommandSpec {
+    /// Name of the subcommand
+    name: String,
+    /// Long options, including the leading dashes
+    options: Vec<String>,
+    /// Names of nested subcommands
+    subcommands: Vec<String>,
+    /// Whether a positional argument expects module names
+    modules: bool,
+}
+
+/// Collects the completion data of all visible subcommands.
+fn subcommands() -> Vec<SubcommandSpec> {
+    Cli::command()
+        .get_subcommands()
+        .filter(|c| !c.is_hide_set())
+        .map(|c| SubcommandSpec {
+            name: c.get_name().to_string(),
+            options: c
+                .get_arguments()
+                .filter_map(|a| a.get_long())
+                .map(|l| format!("--{}", l))
+                .collect(),
+            subcommands: c
+                .get_subcommands()
+                .map(|s| s.get_name().to_string())
+                .collect(),
+            modules: c
+                .get_positionals()
+                .any(|a| matches!(a.get_id().as_str(), "modules" | "module")),
+        })
+        .collect()
+}
+
+/// Collects the global long options.
+fn global_options() -> Vec<String> {
+    Cli::command()
+        .get_arguments()
+        .filter_map(|a| a.get_long())
+        .map(|l| format!("--{}", l))
+        .collect()
+}
+
+/// Generates the bash completion script.
+fn bash_script() -> String {
+    let subcommands = subcommands();
+    let mut cases = String::new();
+    for s in subcommands.iter() {
+        let mut words = [s.subcommands.as_slice(), s.options.as_slice()]
+            .concat()
+            .join(" ");
+        if s.modules {
+            words = format!("$(dotdeploy _complete modules 2>/dev/null) {}", words);
+        }
+        cases.push_str(&format!("        {}) words=\"{}\" ;;\n", s.name, words));
+    }
+
+    format!(
+        r#"_dotdeploy() {{
+    local cur prev cmd words i
+    cur="${{COMP_WORDS[COMP_CWORD]}}"
+    prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+
+    # Find the subcommand
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case "${{COMP_WORDS[i]}}" in
+            -*) ;;
+            *) cmd="${{COMP_WORDS[i]}}"; break ;;
+        esac
+    done
+
+    if [[ "$prev" == "--module" ]]; then
+        COMPREPLY=($(compgen -W "$(dotdeploy _complete modules 2>/dev/null)" -- "$cur"))
+        return
+    fi
+
+    case "$cmd" in
+        "") words="{}" ;;
+{}        *) words="" ;;
+    esac
+    COMPREPLY=($(compgen -W "$words {}" -- "$cur"))
+}}
+complete -F _dotdeploy dotdeploy
+"#,
+        subcommands
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        cases,
+        global_options().join(" "),
+    )
+}
+
+/// Generates the zsh completion script, reusing the bash completion.
+fn zsh_script() -> String {
+    format!(
+        "autoload -U +X bashcompinit && bashcompinit\n{}",
+        bash_script()
+    )
+}
+
+/// Generates the fish completion script.
+fn fish_script() -> String {
+    let subcommands = subcommands();
+    let names: Vec<&str> = subcommands.iter().map(|s| s.name.as_str()).collect();
+    let mut script = String::from("complete -c dotdeploy -f\n");
+
+    for o in global_options().iter() {
+        script.push_str(&format!(
+            "complete -c dotdeploy -l {}\n",
+            o.trim_start_matches("--")
+        ));
+    }
+    script.push_str(&format!(
+        "complete -c dotdeploy -n \"__fish_use_subcommand\" -a \"{}\"\n",
+        names.join(" ")
+    ));
+    for s in subcommands.iter() {
+        if !s.subcommands.is_empty() {
+            script.push_str(&format!(
+                "complete -c dotdeploy -n \"__fish_seen_subcommand_from {}\" -a \"{}\"\n",
+                s.name,
+                s.subcommands.join(" ")
+            ));
+        }
+        for o in s.options.iter() {
+            script.push_str(&format!(
+                "complete -c dotdeploy -n \"__fish_seen_subcommand_from {}\" -l {}\n",
+                s.name,
+                o.trim_start_matches("--")
+            ));
+        }
+        if s.modules {
+            script.push_str(&format!(
+                "complete -c dotdeploy -n \"__fish_seen_subcommand_from {}\" \
+                 -a \"(dotdeploy _complete modules 2>/dev/null)\"\n",
+                s.name
+            ));
+        }
+    }
+    script.push_str(
+        "complete -c dotdeploy -l module -x -a \"(dotdeploy _complete modules 2>/dev/null)\"\n",
+    );
+
+    script
+}
+
+/// Prints the completion script for a shell.
+///
+/// # Arguments
+///
+/// * `shell` - The shell to generate the script for
+pub(crate) fn print_script(shell: Shell) {
+    match shell {
+        Shell::Bash => print!("{}", bash_script()),
+        Shell::Zsh => print!("{}", zsh_script()),
+        Shell::Fish => print!("{}", fish_script()),
+    }
+}
+
+/// Prints completion candidates, one per line.
+///
+/// Errors are never printed, as the output is read by the shell.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of candidates to print
+/// * `dotdeploy_config` - Configuration providing `modules_root` and `hosts_root`
+pub(crate) fn complete(kind: CompleteKind, dotdeploy_config: &DotdeployConfig) {
+    match kind {
+        CompleteKind::Modules => {
+            // Missing roots simply yield no candidates
+            for m in find_modules(&dotdeploy_config.modules_root).unwrap_or_default() {
+                println!("{}", m);
+            }
+            for h in find_modules(&dotdeploy_config.hosts_root).unwrap_or_default() {
+                println!("hosts/{}", h);
+            }
+        }
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subcommands() {
+        let subcommands = subcommands();
+        let deploy = subcommands.iter().find(|s| s.name == "deploy").unwrap();
+        assert!(deploy.modules);
+        assert!(deploy.options.contains(&"--components".to_string()));
+        let note = subcommands.iter().find(|s| s.name == "note").unwrap();
+        assert_eq!(note.subcommands, vec!["add", "list", "remove"]);
+        assert!(
+            !subcommands
+                .iter()
+                .find(|s| s.name == "fsck")
+                .unwrap()
+                .modules
+        );
+        // Hidden subcommands are not completed
+        assert!(!subcommands.iter().any(|s| s.name == "_complete"));
+    }
+
+    #[test]
+    fn test_scripts() {
+        assert!(bash_script().contains("deploy) words=\"$(dotdeploy _complete modules"));
+        assert!(fish_script().contains("__fish_seen_subcommand_from try\" -a"));
+    }
+}
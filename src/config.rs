@@ -3,30 +3,265 @@
 //! It provides functionality to read, parse, and initialize the configuration from a TOML file or
 //! use default values when necessary.
 
-use anyhow::{Context, Result};
-use serde::Deserialize;
-use std::collections::VecDeque;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
 use std::env;
 use std::io::BufRead;
 use std::path::PathBuf;
 
+use crate::modules::actions::ModuleAction;
+use crate::modules::messages::MessageLevel;
+
+/// Path of the system-wide config file.
+const SYSTEM_CONFIG_FILE: &str = "/etc/dotdeploy/config.toml";
+
+/// Top-level keys of the config file and their descriptions.
+pub(crate) const CONFIG_KEYS: &[(&str, &str)] = &[
+    (
+        "config_root",
+        "Root folder of the dotfiles. Defaults to ~/.dotfiles.",
+    ),
+    (
+        "modules_root",
+        "Folder of the module declarations. Defaults to config_root/modules.",
+    ),
+    (
+        "hosts_root",
+        "Folder of the host declarations. Defaults to config_root/hosts.",
+    ),
+    (
+        "state_dir",
+        "Folder of the run state, e.g. the run lock. Defaults to $XDG_STATE_HOME/dotdeploy.",
+    ),
+    (
+        "cache_dir",
+        "Folder of caches, e.g. clones of remote modules. Defaults to $XDG_CACHE_HOME/dotdeploy.",
+    ),
+    (
+        "logs_dir",
+        "Folder of the output logs of actions. Defaults to state_dir/logs.",
+    ),
+    (
+        "hostname",
+        "Hostname of the device. Detected automatically by default.",
+    ),
+    (
+        "auto_host_module",
+        "Always deploy the module hosts/<hostname> if it exists. Defaults to false.",
+    ),
+    (
+        "distribution",
+        "Linux distribution of the device. Detected automatically by default.",
+    ),
+    (
+        "use_sudo",
+        "Use sudo to elevate privileges. Defaults to true.",
+    ),
+    (
+        "sudo_cmd",
+        "Command used to elevate privileges: sudo, pkexec or run0. Defaults to sudo.",
+    ),
+    (
+        "sudo_askpass_cmd",
+        "Askpass helper asking for the sudo password if no terminal is attached.",
+    ),
+    (
+        "never_sudo_paths",
+        "Paths in addition to HOME where privileges are never elevated.",
+    ),
+    (
+        "deploy_sys_files",
+        "Deploy files outside of HOME. Defaults to true.",
+    ),
+    (
+        "target_root",
+        "Folder all targets are deployed below instead of the live system, e.g. /mnt/newsys. \
+         Packages, actions and hooks are skipped.",
+    ),
+    ("intall_pkg_cmd", "Command used to install packages."),
+    ("remove_pkg_cmd", "Command used to remove packages."),
+    (
+        "skip_pkg_install",
+        "Skip package installation during deployment. Defaults to false.",
+    ),
+    (
+        "backup_retention",
+        "Table with max_count and max_age (days) of stale backups to keep.",
+    ),
+    (
+        "template_default",
+        "Value of the template field of files which do not set it.",
+    ),
+    (
+        "require_template",
+        "Require the template field to be set for copied and created files.",
+    ),
+    (
+        "file_defaults",
+        "Table with permissions, system_owner and system_group of files which don't set them.",
+    ),
+    (
+        "pkg_lock_retry",
+        "Table with timeout and interval (seconds) to wait for a locked package database.",
+    ),
+    (
+        "schedule",
+        "Table with min_battery, skip_metered and window conditions for automatic runs.",
+    ),
+    (
+        "bootstrap",
+        "Table with repository, binary_url, binary_sha256 and modules used by dotdeploy bootstrap.",
+    ),
+    (
+        "progress",
+        "Show the progress of file operations during deployment. Defaults to false.",
+    ),
+    (
+        "diff",
+        "Show the full diff of locally modified files during deployment. Defaults to false.",
+    ),
+    (
+        "notify",
+        "Send a desktop notification when an automatic run finishes. Defaults to false.",
+    ),
+    (
+        "journal",
+        "Also send log messages to the systemd journal. Defaults to false.",
+    ),
+    (
+        "store",
+        "Table with pool_size, busy_timeout (ms), wal_autocheckpoint and vacuum of the stores.",
+    ),
+    (
+        "show_messages",
+        "Show the messages of modules. Critical messages are always shown. Defaults to true.",
+    ),
+    (
+        "message_level",
+        "Minimum level of shown messages: info, warning or critical. Defaults to info.",
+    ),
+    (
+        "max_parallel_actions",
+        "Maximum number of parallel actions running at once. Defaults to the number of CPUs.",
+    ),
+    (
+        "orphan_paths",
+        "Folders searched for links into config_root by dotdeploy orphans. Defaults to ~/.config.",
+    ),
+    (
+        "context_cmds",
+        "Table of shell commands run once at startup, their output becomes a template value.",
+    ),
+    (
+        "facts_ttl",
+        "Seconds the output of the scripts in config_root/facts.d is cached. Defaults to 3600.",
+    ),
+    (
+        "helper",
+        "Table of template helpers implemented by external scripts, keyed by helper name.",
+    ),
+    (
+        "remotes",
+        "Table of git URLs of remote modules, keyed by name. Deployed as remotes/<name>.",
+    ),
+    (
+        "hooks",
+        "Table with pre_deploy, post_deploy and post_remove lists of actions run once per run.",
+    ),
+    (
+        "profiles",
+        "Tables named after profiles, each with a modules list deployed by deploy --profile.",
+    ),
+    (
+        "hosts",
+        "Tables named after hostnames, overriding the keys above on the matching host.",
+    ),
+];
+
+/// Config keys holding no strings, with tables separated by two underscores as in `DOTDEPLOY_*`
+/// variables. A table covers all of its keys.
+const TYPED_ENV_KEYS: &[&str] = &[
+    "auto_host_module",
+    "use_sudo",
+    "never_sudo_paths",
+    "deploy_sys_files",
+    "intall_pkg_cmd",
+    "remove_pkg_cmd",
+    "skip_pkg_install",
+    "backup_retention",
+    "template_default",
+    "require_template",
+    "pkg_lock_retry",
+    "schedule__min_battery",
+    "schedule__skip_metered",
+    "bootstrap__modules",
+    "progress",
+    "diff",
+    "notify",
+    "journal",
+    "store",
+    "show_messages",
+    "max_parallel_actions",
+    "orphan_paths",
+    "hooks",
+    "profiles",
+    "facts_ttl",
+];
+
 /// Representation of the Dotdeploy configuration.
 ///
 /// This struct deserializes the configuration file. The file is expected to be found under
-/// `$HOME/.config/dotdeploy/config.toml`.
+/// `$HOME/.config/dotdeploy/config.toml`. Administrators can set defaults for all users in
+/// `/etc/dotdeploy/config.toml`, which are overridden by the user config.
 ///
 /// # Defaults
 ///
 /// - `config_root`: `"~/.dotfiles/"`
 /// - `modules_root`: `"~/.dotfiles/modules/"`
 /// - `hosts_root`: `"~/.dotfiles/hosts/"`
+/// - `state_dir`: `"$XDG_STATE_HOME/dotdeploy"` or `"~/.local/state/dotdeploy"`
+/// - `cache_dir`: `"$XDG_CACHE_HOME/dotdeploy"` or `"~/.cache/dotdeploy"`
+/// - `logs_dir`: `"<state_dir>/logs"`
 /// - `hostname`: Automatically detected by default if possible.
+/// - `auto_host_module`: false
 /// - `distribution`: Automatically detected by default if possible.
 /// - `use_sudo`: true
+/// - `sudo_cmd`: `"sudo"`
+/// - `sudo_askpass_cmd`: None. Automatic runs without a terminal fail if sudo needs a password.
+/// - `never_sudo_paths`: None. Privileges are never elevated for paths inside HOME.
 /// - `deploy_sys_files`: true
+/// - `target_root`: None. Targets are deployed to the live system. If set, packages are not
+///   installed and actions and hooks are skipped.
 /// - `intall_pkg_cmd`: None. Will choose appropiate commands for supported distributions.
 /// - `remove_pkg_cmd`: None. Will choose appropiate commands for supported distributions.
 /// - `skip_pkg_install`: false
+/// - `backup_retention`: None. Stale backups are pruned regardless of their age or count.
+/// - `template_default`: false
+/// - `require_template`: false
+/// - `file_defaults`: None. Files keep the permissions of their source or the umask and are owned
+///   by the user running dotdeploy.
+/// - `pkg_lock_retry`: Wait up to 300 seconds for a locked package database, retrying every 10
+///   seconds.
+/// - `schedule`: None. Automatic runs (`--auto`) always proceed.
+/// - `bootstrap`: The `origin` remote of `config_root`, dotdeploy built with cargo and the default
+///   modules.
+/// - `progress`: false
+/// - `diff`: false
+/// - `notify`: false
+/// - `journal`: false
+/// - `store`: Pools of one connection per CPU, a busy timeout of 5000 ms, a WAL checkpoint every
+///   1000 pages and a VACUUM at the end of every run.
+/// - `show_messages`: true
+/// - `message_level`: `"info"`
+/// - `max_parallel_actions`: The number of available CPUs
+/// - `orphan_paths`: `["~/.config"]`
+/// - `hooks`: None
+/// - `profiles`: None
+/// - `context_cmds`: None
+/// - `facts_ttl`: 3600
+/// - `helper`: None
+/// - `remotes`: None
 ///
 /// # Example Configuration
 /// To override options, your `config.toml` might look like this:
@@ -35,10 +270,63 @@ use std::path::PathBuf;
 /// config_root = "/path/to/my/dotfiles"
 /// modules_root = "/path/to/my/dotfiles/modules"
 /// hosts_root = "/path/to/my/dotfiles/hosts"
+/// auto_host_module = true
 /// use_sudo = true
+/// never_sudo_paths = ["/mnt/data"]
+/// deploy_sys_files = false
+/// progress = true
+/// notify = true
+/// journal = true
+/// message_level = "warning"
+/// orphan_paths = ["~/.config", "~/.local/bin"]
+/// facts_ttl = 86400
+///
+/// [backup_retention]
+/// max_count = 20
+/// max_age = 90
+///
+/// [file_defaults]
+/// permissions = "go-w"
+/// system_owner = "root"
+/// system_group = "root"
+///
+/// [pkg_lock_retry]
+/// timeout = 600
+/// interval = 30
+///
+/// [store]
+/// busy_timeout = 10000
+/// vacuum = false
+///
+/// [schedule]
+/// min_battery = 30
+/// skip_metered = true
+/// window = "22:00-06:00"
+///
+/// [bootstrap]
+/// repository = "https://github.com/user/dotfiles"
+/// modules = ["hosts/laptop"]
+///
+/// [hooks]
+/// post_deploy = [{ exec = "systemctl --user daemon-reload" }]
+///
+/// [profiles.work]
+/// modules = ["git", "kube", "vpn"]
+///
+/// [context_cmds]
+/// gpu = "lspci | grep -i vga"
+///
+/// [helper]
+/// vault = "~/.local/bin/dotdeploy-vault"
+///
+/// [remotes]
+/// nvim = "https://github.com/user/dotdeploy-nvim#main"
+///
+/// # Overrides for the host "laptop"
+/// [hosts.laptop]
 /// deploy_sys_files = false
 /// ```
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub(crate) struct DotdeployConfig {
     /// Root folder of dotfiles.
     pub(crate) config_root: PathBuf,
@@ -46,20 +334,202 @@ pub(crate) struct DotdeployConfig {
     pub(crate) modules_root: PathBuf,
     /// Root folder of hosts. This path stores the hosts declarations.
     pub(crate) hosts_root: PathBuf,
+    /// Folder of the run state like the run lock, which is not worth backing up.
+    pub(crate) state_dir: PathBuf,
+    /// Folder of caches like the clones of remote modules, which can be deleted at any time.
+    pub(crate) cache_dir: PathBuf,
+    /// Folder of the output logs of actions, one subfolder per run.
+    pub(crate) logs_dir: PathBuf,
     /// Host device's hostname.
     pub(crate) hostname: String,
+    /// Deploy the host module along with any requested modules, not only if none are requested.
+    pub(crate) auto_host_module: bool,
     /// Host device's Linux distribution.
     pub(crate) distribution: String,
     /// Use sudo to elevate privileges.
     pub(crate) use_sudo: bool,
+    /// Command used to elevate privileges, e.g. `run0` on systems without sudo.
+    pub(crate) sudo_cmd: String,
+    /// Askpass helper for sudo, used if no terminal is attached, e.g. in automatic runs.
+    pub(crate) sudo_askpass_cmd: Option<PathBuf>,
+    /// Paths which are never accessed with elevated privileges, in addition to HOME.
+    pub(crate) never_sudo_paths: Vec<PathBuf>,
     /// Deploy files to directories other than the user's HOME.
     pub(crate) deploy_sys_files: bool,
+    /// Folder all targets are deployed below, e.g. while provisioning a chroot.
+    pub(crate) target_root: Option<PathBuf>,
     /// Command used to install packages.
     pub(crate) intall_pkg_cmd: Option<VecDeque<String>>,
     /// Command used to remove packages.
     pub(crate) remove_pkg_cmd: Option<VecDeque<String>>,
     /// Skip package installation during deployment
     pub(crate) skip_pkg_install: bool,
+    /// Retention policy applied when pruning backups of files which are no longer tracked.
+    pub(crate) backup_retention: BackupRetention,
+    /// Value used for the `template` field of files which do not set it.
+    pub(crate) template_default: bool,
+    /// Require the `template` field to be set explicitly for all copied and created files.
+    pub(crate) require_template: bool,
+    /// Ownership and permissions of files which do not set them.
+    pub(crate) file_defaults: FileDefaults,
+    /// Wait and retry policy if the package database is locked by another process.
+    pub(crate) pkg_lock_retry: PkgLockRetry,
+    /// Conditions under which automatic runs are skipped.
+    pub(crate) schedule: Schedule,
+    /// Sources and modules of the script generated by `dotdeploy bootstrap`.
+    pub(crate) bootstrap: Bootstrap,
+    /// Show the progress of file operations and package installations during deployment.
+    pub(crate) progress: bool,
+    /// Show the full diff of locally modified files instead of a one-line summary.
+    pub(crate) diff: bool,
+    /// Send a desktop notification when an automatic run finishes or fails.
+    pub(crate) notify: bool,
+    /// Send log messages to the systemd journal, e.g. for runs started by a timer.
+    pub(crate) journal: bool,
+    /// Connection and maintenance settings of the store databases.
+    pub(crate) store: StoreSettings,
+    /// Show the messages of modules. Critical messages are shown regardless.
+    pub(crate) show_messages: bool,
+    /// Minimum level of shown messages. Critical messages are shown regardless.
+    pub(crate) message_level: MessageLevel,
+    /// Maximum number of actions with `parallel = true` running at once.
+    pub(crate) max_parallel_actions: usize,
+    /// Folders searched for links into `config_root` which are not tracked by a store.
+    pub(crate) orphan_paths: Vec<PathBuf>,
+    /// Actions run once before or after all modules are deployed or removed.
+    #[serde(skip_serializing)]
+    pub(crate) hooks: Hooks,
+    /// Named sets of modules which can be deployed together.
+    pub(crate) profiles: BTreeMap<String, Profile>,
+    /// Shell commands whose output is added to the template context, keyed by variable name.
+    pub(crate) context_cmds: BTreeMap<String, String>,
+    /// Seconds the output of the scripts in `facts.d` is reused before they are run again.
+    pub(crate) facts_ttl: u64,
+    /// Scripts implementing template helpers, keyed by helper name.
+    pub(crate) helper: BTreeMap<String, PathBuf>,
+    /// Git repositories of remote modules, keyed by name.
+    pub(crate) remotes: BTreeMap<String, String>,
+}
+
+/// Retention policy for backups of files which are no longer tracked by a store.
+///
+/// A stale backup is pruned if it is older than `max_age` days or if it is not among the
+/// `max_count` most recent stale backups. If neither limit is set, all stale backups are pruned.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct BackupRetention {
+    /// Number of stale backups to keep, newest first.
+    pub(crate) max_count: Option<usize>,
+    /// Maximum age of stale backups in days.
+    pub(crate) max_age: Option<i64>,
+}
+
+/// Ownership and permissions applied to files of modules which do not set them.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct FileDefaults {
+    /// Octal or symbolic mode of copied and created files, e.g. "644" or "go-w".
+    pub(crate) permissions: Option<String>,
+    /// Owner of files deployed outside of HOME.
+    pub(crate) system_owner: Option<String>,
+    /// Group of files deployed outside of HOME.
+    pub(crate) system_group: Option<String>,
+}
+
+/// Wait and retry policy for package manager commands failing due to a locked package database.
+///
+/// Setting `timeout` to 0 disables retrying.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub(crate) struct PkgLockRetry {
+    /// Maximum time to wait for the lock in seconds.
+    pub(crate) timeout: u64,
+    /// Time between retries in seconds.
+    pub(crate) interval: u64,
+}
+
+impl Default for PkgLockRetry {
+    fn default() -> Self {
+        PkgLockRetry {
+            timeout: 300,
+            interval: 10,
+        }
+    }
+}
+
+/// Connection and maintenance settings of the SQLite store databases.
+///
+/// Disabling `vacuum` avoids rewriting the whole database at the end of every run, which takes a
+/// while for large stores. The WAL is still checkpointed and truncated instead.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub(crate) struct StoreSettings {
+    /// Maximum number of connections of each store. Defaults to the pool default, which depends on
+    /// the number of CPUs.
+    pub(crate) pool_size: Option<usize>,
+    /// Time to wait for a locked database in milliseconds.
+    pub(crate) busy_timeout: u64,
+    /// Number of WAL pages after which the WAL is checkpointed automatically. 0 disables automatic
+    /// checkpoints.
+    pub(crate) wal_autocheckpoint: u32,
+    /// Run VACUUM when the stores are closed at the end of a run.
+    pub(crate) vacuum: bool,
+}
+
+impl Default for StoreSettings {
+    fn default() -> Self {
+        StoreSettings {
+            pool_size: None,
+            busy_timeout: 5000,
+            wal_autocheckpoint: 1000,
+            vacuum: true,
+        }
+    }
+}
+
+/// Global actions, run once per deployment or removal instead of once per module.
+///
+/// The actions use the same syntax as the actions of modules.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub(crate) struct Hooks {
+    /// Run before the modules are deployed.
+    pub(crate) pre_deploy: Vec<ModuleAction>,
+    /// Run after the modules have been deployed.
+    pub(crate) post_deploy: Vec<ModuleAction>,
+    /// Run after the modules have been removed.
+    pub(crate) post_remove: Vec<ModuleAction>,
+}
+
+/// A named set of modules, deployed with `dotdeploy deploy --profile <name>`.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Profile {
+    /// Names of the modules belonging to the profile.
+    pub(crate) modules: Vec<String>,
+}
+
+/// Conditions for automatic, e.g. timer-driven, runs. Manual runs ignore them.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Schedule {
+    /// Skip if running on battery with a charge below this percentage.
+    pub(crate) min_battery: Option<u8>,
+    /// Skip if the network connection is metered.
+    #[serde(default)]
+    pub(crate) skip_metered: bool,
+    /// Only run within this local time window, e.g. "22:00-06:00".
+    pub(crate) window: Option<String>,
+}
+
+/// Settings of the bootstrap script reproducing the setup on a fresh machine.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub(crate) struct Bootstrap {
+    /// Git URL the dotfiles are cloned from. Defaults to the `origin` remote of `config_root`.
+    pub(crate) repository: Option<String>,
+    /// URL of a dotdeploy binary. dotdeploy is built with cargo if unset.
+    pub(crate) binary_url: Option<String>,
+    /// SHA-256 checksum of the binary. Downloaded from `<binary_url>.sha256` if unset.
+    pub(crate) binary_sha256: Option<String>,
+    /// Modules deployed by the script. The default modules are deployed if empty.
+    pub(crate) modules: Vec<String>,
 }
 
 impl DotdeployConfig {
@@ -71,8 +541,13 @@ impl DotdeployConfig {
     /// # Errors
     /// Returns an error if reading the config file fails.
     fn read_config_file() -> Result<String> {
-        // Determine the config file path based on environment variables
-        let config_file_path: PathBuf = if let Ok(xdg_dir) = env::var("XDG_CONFIG_HOME") {
+        // Determine the config file path based on environment variables. Deploying for another
+        // user keeps the config of the administrator, previewing in a HOME overlay the real one.
+        let config_file_path: PathBuf = if let Some(user) = crate::target_user::get() {
+            user.admin_config_dir.join("dotdeploy")
+        } else if let Some(origin) = crate::sandbox::origin() {
+            origin.config_dir.join("dotdeploy")
+        } else if let Ok(xdg_dir) = env::var("XDG_CONFIG_HOME") {
             [xdg_dir.as_str(), "dotdeploy"].iter().collect()
         } else if let Ok(home_dir) = env::var("HOME") {
             [home_dir.as_str(), ".config", "dotdeploy"].iter().collect()
@@ -148,58 +623,240 @@ impl DotdeployConfig {
         }
     }
 
-    /// Initialize the [DotdeployConfig] struct.
+    /// Reads the system-wide config file.
     ///
-    /// If found, it parses the config file and tries to expand all paths. If the config file is
-    /// absent or fields are missing it will use default values (see [DotdeployConfig]).
-    pub(crate) fn init() -> Result<DotdeployConfig> {
-        // Attempt to read the config file, use an empty string if not found
-        let conf_string = match Self::read_config_file() {
-            Ok(s) => s,
+    /// Returns `None` if the file does not exist or can't be read.
+    fn read_system_config_file() -> Option<String> {
+        match std::fs::read_to_string(SYSTEM_CONFIG_FILE) {
+            Ok(s) => Some(s),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No system config found at {:?}", SYSTEM_CONFIG_FILE);
+                None
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to read config from {:?}: {:?}",
+                    SYSTEM_CONFIG_FILE, e
+                );
+                None
+            }
+        }
+    }
+
+    /// Merges the keys of `overlay` into `base`.
+    ///
+    /// Keys of `overlay` take precedence. Tables present in both are merged recursively, all other
+    /// values are replaced.
+    fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+        for (key, value) in overlay.into_iter() {
+            match (base.get_mut(&key), value) {
+                (Some(toml::Value::Table(b)), toml::Value::Table(o)) => Self::merge_tables(b, o),
+                (_, value) => {
+                    base.insert(key, value);
+                }
+            }
+        }
+    }
+
+    /// Extracts the `[hosts.<hostname>]` table of the config.
+    ///
+    /// The hostname is taken from the `hostname` key or detected automatically. The `hosts` table
+    /// itself is removed from the config.
+    fn host_overrides(conf_table: &mut toml::Table) -> Result<Option<toml::Table>> {
+        let hosts = match conf_table.remove("hosts") {
+            Some(toml::Value::Table(hosts)) => hosts,
+            Some(_) => bail!("The hosts key of the config must be a table"),
+            None => return Ok(None),
+        };
+
+        let hostname = match conf_table.get("hostname").and_then(|h| h.as_str()) {
+            Some(h) => h.to_string(),
+            None => Self::get_hostname()?,
+        };
+
+        match hosts.get(&hostname) {
+            Some(toml::Value::Table(overrides)) => {
+                debug!("Applying config overrides for host {}", hostname);
+                Ok(Some(overrides.clone()))
+            }
+            Some(_) => bail!("The hosts.{} key of the config must be a table", hostname),
+            None => Ok(None),
+        }
+    }
+
+    /// Collects config keys set by `DOTDEPLOY_*` environment variables.
+    ///
+    /// The variable name without prefix is lowercased to get the key, e.g. `DOTDEPLOY_MODULES_ROOT`
+    /// sets `modules_root`. Keys of tables are separated by two underscores, e.g.
+    /// `DOTDEPLOY_PKG_LOCK_RETRY__TIMEOUT`. Values of keys holding booleans, numbers or arrays
+    /// are parsed as TOML values if possible, so `true`, `10` or `["apt", "install"]` work as
+    /// expected. Values of all other keys are used as strings, e.g. `DOTDEPLOY_HOSTNAME=1234`.
+    fn env_overrides<I: IntoIterator<Item = (String, String)>>(vars: I) -> toml::Table {
+        let mut overrides = toml::Table::new();
+        for (name, value) in vars.into_iter() {
+            let Some(key) = name.strip_prefix("DOTDEPLOY_") else {
+                continue;
+            };
+            let key = key.to_lowercase();
+            let typed = TYPED_ENV_KEYS
+                .iter()
+                .any(|k| key == *k || key.starts_with(&format!("{}__", k)));
+            let value = if typed {
+                toml::from_str::<toml::Table>(&format!("v = {}", value))
+                    .ok()
+                    .and_then(|mut t| t.remove("v"))
+                    .unwrap_or(toml::Value::String(value))
+            } else {
+                toml::Value::String(value)
+            };
+            debug!("Setting config key {} from {}", key, name);
+
+            // Build a nested table from the key path and merge it
+            let overlay = key.rsplit("__").fold(value, |v, k| {
+                toml::Value::Table(toml::Table::from_iter([(k.to_string(), v)]))
+            });
+            if let toml::Value::Table(overlay) = overlay {
+                Self::merge_tables(&mut overrides, overlay);
+            }
+        }
+        overrides
+    }
+
+    /// Merges a config layer into `conf_table` and records the origin of its top-level keys.
+    fn merge_layer(
+        conf_table: &mut toml::Table,
+        origins: &mut BTreeMap<String, &'static str>,
+        layer: toml::Table,
+        origin: &'static str,
+    ) {
+        for key in layer.keys() {
+            origins.insert(key.to_string(), origin);
+        }
+        Self::merge_tables(conf_table, layer);
+    }
+
+    /// Reads and merges all config layers.
+    ///
+    /// The system-wide config file `/etc/dotdeploy/config.toml` is read first, then the user config
+    /// file is merged on top of it, followed by the overrides for the current host and the
+    /// `DOTDEPLOY_*` environment variables.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the merged config and the origin of each top-level key.
+    pub(crate) fn load_table() -> Result<(toml::Table, BTreeMap<String, &'static str>)> {
+        let mut conf_table = toml::Table::new();
+        let mut origins = BTreeMap::new();
+
+        if let Some(s) = Self::read_system_config_file() {
+            let layer = toml::from_str(&s)
+                .with_context(|| format!("Failed to parse config from {:?}", SYSTEM_CONFIG_FILE))?;
+            Self::merge_layer(&mut conf_table, &mut origins, layer, "system file");
+        }
+
+        // Attempt to read the user config file, keep the system config if not found
+        match Self::read_config_file() {
+            Ok(s) => {
+                let layer = toml::from_str(&s).context("Failed to parse user config")?;
+                Self::merge_layer(&mut conf_table, &mut origins, layer, "user file");
+            }
             Err(e) => {
                 warn!("{:?}", e);
-                warn!("Default config values will be used");
-                "".to_string()
+                if conf_table.is_empty() {
+                    warn!("Default config values will be used");
+                }
             }
         };
 
+        origins.remove("hosts");
+        if let Some(layer) = Self::host_overrides(&mut conf_table)? {
+            Self::merge_layer(&mut conf_table, &mut origins, layer, "host override");
+        }
+        let layer = Self::env_overrides(env::vars());
+        Self::merge_layer(&mut conf_table, &mut origins, layer, "environment");
+
+        Ok((conf_table, origins))
+    }
+
+    /// Initialize the [DotdeployConfig] struct.
+    ///
+    /// All config layers are merged (see [DotdeployConfig::load_table]). Command line flags are
+    /// applied by the caller and take precedence over all of them. All paths are expanded. If no
+    /// config file is found or fields are missing, default values will be used (see
+    /// [DotdeployConfig]).
+    pub(crate) fn init() -> Result<DotdeployConfig> {
+        let (conf_table, _) = Self::load_table()?;
+        Self::from_table(conf_table)
+    }
+
+    /// Builds the [DotdeployConfig] struct from a merged config table, using default values for
+    /// missing fields.
+    fn from_table(conf_table: toml::Table) -> Result<DotdeployConfig> {
         // Intermediate struct for the parsed config file data
         #[derive(Deserialize)]
         struct ParsedFile {
             config_root: Option<String>,
             modules_root: Option<String>,
             hosts_root: Option<String>,
+            state_dir: Option<String>,
+            cache_dir: Option<String>,
+            logs_dir: Option<String>,
             hostname: Option<String>,
+            auto_host_module: Option<bool>,
             distribution: Option<String>,
             use_sudo: Option<bool>,
+            sudo_cmd: Option<String>,
+            sudo_askpass_cmd: Option<String>,
+            never_sudo_paths: Option<Vec<String>>,
             deploy_sys_files: Option<bool>,
+            target_root: Option<String>,
             intall_pkg_cmd: Option<VecDeque<String>>,
             remove_pkg_cmd: Option<VecDeque<String>>,
             skip_pkg_install: Option<bool>,
+            backup_retention: Option<BackupRetention>,
+            template_default: Option<bool>,
+            require_template: Option<bool>,
+            file_defaults: Option<FileDefaults>,
+            pkg_lock_retry: Option<PkgLockRetry>,
+            schedule: Option<Schedule>,
+            bootstrap: Option<Bootstrap>,
+            progress: Option<bool>,
+            diff: Option<bool>,
+            notify: Option<bool>,
+            journal: Option<bool>,
+            store: Option<StoreSettings>,
+            show_messages: Option<bool>,
+            message_level: Option<MessageLevel>,
+            max_parallel_actions: Option<usize>,
+            orphan_paths: Option<Vec<String>>,
+            hooks: Option<Hooks>,
+            profiles: Option<BTreeMap<String, Profile>>,
+            context_cmds: Option<BTreeMap<String, String>>,
+            facts_ttl: Option<u64>,
+            helper: Option<BTreeMap<String, String>>,
+            remotes: Option<BTreeMap<String, String>>,
         }
 
         // Parse the configuration string
-        let parsed_data: ParsedFile = toml::from_str(&conf_string)?;
+        let parsed_data: ParsedFile = toml::Value::Table(conf_table).try_into()?;
 
         // Set config_root to ~/.dotfiles if empty
         let config_root = parsed_data
             .config_root
             .map(|path| {
-                shellexpand::full(&path)
+                expand_dotfiles_path(&path)
                     .context("Failed to expand file path")
                     .unwrap()
-                    .to_string()
             })
-            .unwrap_or_else(|| shellexpand::full("~/.dotfiles").unwrap().to_string());
+            .unwrap_or_else(|| expand_dotfiles_path("~/.dotfiles").unwrap());
 
         // Set modules_root based on config_root if not already set
         let modules_root = parsed_data
             .modules_root
             .map(|path| {
-                shellexpand::full(&path)
+                expand_dotfiles_path(&path)
                     .context("Failed to expand file path")
                     .unwrap()
-                    .to_string()
             })
             .unwrap_or_else(|| {
                 PathBuf::from(&config_root)
@@ -212,10 +869,9 @@ impl DotdeployConfig {
         let hosts_root = parsed_data
             .hosts_root
             .map(|path| {
-                shellexpand::full(&path)
+                expand_dotfiles_path(&path)
                     .context("Failed to expand file path")
                     .unwrap()
-                    .to_string()
             })
             .unwrap_or_else(|| {
                 PathBuf::from(&config_root)
@@ -224,24 +880,376 @@ impl DotdeployConfig {
                     .to_string()
             });
 
+        // Set state_dir and cache_dir to the XDG directories if not already set
+        let xdg_dir = |path: Option<String>, var: &str, default: &str| -> Result<PathBuf> {
+            Ok(match path {
+                Some(path) => PathBuf::from(
+                    shellexpand::full(&path)
+                        .context("Failed to expand file path")?
+                        .as_ref(),
+                ),
+                None => match env::var(var) {
+                    Ok(dir) => PathBuf::from(dir),
+                    Err(_) => PathBuf::from(shellexpand::full(default)?.as_ref()),
+                }
+                .join("dotdeploy"),
+            })
+        };
+        let state_dir = xdg_dir(parsed_data.state_dir, "XDG_STATE_HOME", "~/.local/state")?;
+        let cache_dir = xdg_dir(parsed_data.cache_dir, "XDG_CACHE_HOME", "~/.cache")?;
+
+        // Set logs_dir to the state directory if not already set
+        let logs_dir = match parsed_data.logs_dir {
+            Some(path) => PathBuf::from(
+                shellexpand::full(&path)
+                    .context("Failed to expand file path")?
+                    .as_ref(),
+            ),
+            None => state_dir.join("logs"),
+        };
+
+        let sudo_askpass_cmd = parsed_data
+            .sudo_askpass_cmd
+            .map(|path| -> Result<PathBuf> {
+                Ok(PathBuf::from(
+                    shellexpand::full(&path)
+                        .context("Failed to expand file path")?
+                        .as_ref(),
+                ))
+            })
+            .transpose()?;
+
+        let target_root = parsed_data
+            .target_root
+            .map(|path| -> Result<PathBuf> {
+                Ok(PathBuf::from(
+                    shellexpand::full(&path)
+                        .context("Failed to expand file path")?
+                        .as_ref(),
+                ))
+            })
+            .transpose()?;
+
+        let never_sudo_paths = parsed_data
+            .never_sudo_paths
+            .unwrap_or_default()
+            .iter()
+            .map(|path| {
+                Ok(PathBuf::from(
+                    shellexpand::full(path)
+                        .context("Failed to expand file path")?
+                        .as_ref(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let orphan_paths = parsed_data
+            .orphan_paths
+            .unwrap_or_else(|| vec!["~/.config".to_string()])
+            .iter()
+            .map(|path| {
+                Ok(PathBuf::from(
+                    shellexpand::full(path)
+                        .context("Failed to expand file path")?
+                        .as_ref(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let helper = parsed_data
+            .helper
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, path)| {
+                // Helper scripts are part of the dotfiles
+                let path = expand_dotfiles_path(&path)
+                    .with_context(|| format!("Failed to expand path of helper {}", name))?;
+                Ok((name, PathBuf::from(path)))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+
         // Construct and return the final DotdeployConfig struct
         Ok(DotdeployConfig {
             config_root: PathBuf::from(config_root),
             modules_root: PathBuf::from(modules_root),
             hosts_root: PathBuf::from(hosts_root),
+            state_dir,
+            cache_dir,
+            logs_dir,
             distribution: parsed_data
                 .distribution
                 .unwrap_or_else(|| Self::get_distro().unwrap()),
             hostname: parsed_data
                 .hostname
                 .unwrap_or_else(|| Self::get_hostname().unwrap()),
+            auto_host_module: parsed_data.auto_host_module.unwrap_or(false),
             use_sudo: parsed_data.use_sudo.unwrap_or(true),
+            sudo_cmd: parsed_data.sudo_cmd.unwrap_or_else(|| "sudo".to_string()),
+            sudo_askpass_cmd,
+            never_sudo_paths,
             deploy_sys_files: parsed_data.deploy_sys_files.unwrap_or(true),
+            target_root,
             intall_pkg_cmd: parsed_data.intall_pkg_cmd,
             skip_pkg_install: parsed_data.skip_pkg_install.unwrap_or(false),
             remove_pkg_cmd: parsed_data.remove_pkg_cmd,
+            backup_retention: parsed_data.backup_retention.unwrap_or_default(),
+            template_default: parsed_data.template_default.unwrap_or(false),
+            require_template: parsed_data.require_template.unwrap_or(false),
+            file_defaults: parsed_data.file_defaults.unwrap_or_default(),
+            pkg_lock_retry: parsed_data.pkg_lock_retry.unwrap_or_default(),
+            schedule: parsed_data.schedule.unwrap_or_default(),
+            bootstrap: parsed_data.bootstrap.unwrap_or_default(),
+            progress: parsed_data.progress.unwrap_or(false),
+            diff: parsed_data.diff.unwrap_or(false),
+            notify: parsed_data.notify.unwrap_or(false),
+            journal: parsed_data.journal.unwrap_or(false),
+            store: parsed_data.store.unwrap_or_default(),
+            show_messages: parsed_data.show_messages.unwrap_or(true),
+            message_level: parsed_data.message_level.unwrap_or_default(),
+            max_parallel_actions: parsed_data
+                .max_parallel_actions
+                .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+                .max(1),
+            orphan_paths,
+            hooks: parsed_data.hooks.unwrap_or_default(),
+            profiles: parsed_data.profiles.unwrap_or_default(),
+            context_cmds: parsed_data.context_cmds.unwrap_or_default(),
+            facts_ttl: parsed_data.facts_ttl.unwrap_or(3600),
+            helper,
+            remotes: parsed_data.remotes.unwrap_or_default(),
         })
     }
+
+    /// Runs the commands of [DotdeployConfig::context_cmds] to obtain template context values.
+    ///
+    /// Each command is run once with `sh -c`. Its trimmed stdout becomes the value of the variable,
+    /// even if the command exits with a non-zero status, e.g. `grep` without a match.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the context values keyed by variable name, or an error if a command
+    /// can't be started
+    pub(crate) fn eval_context_cmds(&self) -> Result<BTreeMap<String, String>> {
+        let mut context = BTreeMap::new();
+        for (name, cmd) in self.context_cmds.iter() {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(cmd)
+                .stdin(std::process::Stdio::null())
+                .output()
+                .with_context(|| format!("Failed to run context_cmds.{}: {:?}", name, cmd))?;
+            if !output.status.success() {
+                warn!(
+                    "context_cmds.{} exited with {}: {}",
+                    name,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            context.insert(
+                name.to_string(),
+                String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            );
+        }
+
+        Ok(context)
+    }
+}
+
+/// Expands a path of the dotfiles, e.g. `config_root`.
+///
+/// When deploying for another user, `~` refers to the home directory of the administrator, whose
+/// dotfiles are deployed (see [crate::target_user]). Inside a HOME overlay, it refers to the real
+/// home directory (see [crate::sandbox]).
+fn expand_dotfiles_path(path: &str) -> Result<String> {
+    let home = crate::target_user::get()
+        .map(|user| user.admin_home)
+        .or_else(|| crate::sandbox::origin().map(|origin| origin.home));
+    let path = match home {
+        Some(home) if path == "~" || path.starts_with("~/") => {
+            format!("{}{}", home.display(), &path[1..])
+        }
+        _ => path.to_string(),
+    };
+    Ok(shellexpand::full(&path)?.to_string())
+}
+
+/// Checks a merged config table for problems.
+///
+/// Unknown keys, paths which can't be expanded or don't exist, invalid values and contradictory
+/// settings are reported.
+///
+/// # Arguments
+///
+/// * `conf_table` - The merged config table, see [DotdeployConfig::load_table]
+///
+/// # Returns
+///
+/// A list of problems, empty if the config is fine.
+fn check_table(conf_table: &toml::Table) -> Vec<String> {
+    let mut problems = vec![];
+
+    for key in conf_table.keys() {
+        if !CONFIG_KEYS.iter().any(|(k, _)| k == key) {
+            problems.push(format!("Unknown key {:?}", key));
+        }
+    }
+
+    for key in ["config_root", "modules_root", "hosts_root"] {
+        if let Some(path) = conf_table.get(key).and_then(|v| v.as_str()) {
+            match shellexpand::full(path) {
+                Ok(p) if !std::path::Path::new(p.as_ref()).is_dir() => {
+                    problems.push(format!("{} {:?} is not a directory", key, p))
+                }
+                Ok(_) => (),
+                Err(e) => problems.push(format!("{} {:?} can't be expanded: {}", key, path, e)),
+            }
+        }
+    }
+
+    // All further checks need a valid config
+    let config = match DotdeployConfig::from_table(conf_table.clone()) {
+        Ok(c) => c,
+        Err(e) => {
+            problems.push(format!("Invalid config: {:#}", e));
+            return problems;
+        }
+    };
+
+    if config.skip_pkg_install && config.intall_pkg_cmd.is_some() {
+        problems.push("intall_pkg_cmd is set, but skip_pkg_install disables it".to_string());
+    }
+    if config.require_template && conf_table.contains_key("template_default") {
+        problems.push("template_default has no effect as require_template is set".to_string());
+    }
+    if config.deploy_sys_files && !config.use_sudo && !nix::unistd::geteuid().is_root() {
+        problems.push(
+            "deploy_sys_files is set, but use_sudo is disabled and dotdeploy is not run as root"
+                .to_string(),
+        );
+    }
+    if !crate::utils::sudo::ROOT_CMDS.contains(&config.sudo_cmd.as_str()) {
+        problems.push(format!(
+            "sudo_cmd {:?} is not one of {}",
+            config.sudo_cmd,
+            crate::utils::sudo::ROOT_CMDS.join(", ")
+        ));
+    }
+    if config.pkg_lock_retry.timeout > 0 && config.pkg_lock_retry.interval == 0 {
+        problems.push("pkg_lock_retry.interval must be greater than 0".to_string());
+    }
+    if config.pkg_lock_retry.interval > config.pkg_lock_retry.timeout {
+        problems.push("pkg_lock_retry.interval is greater than pkg_lock_retry.timeout".to_string());
+    }
+    if config.store.pool_size == Some(0) {
+        problems.push("store.pool_size must be greater than 0".to_string());
+    }
+    if config.schedule.min_battery.is_some_and(|b| b > 100) {
+        problems.push("schedule.min_battery must be a percentage".to_string());
+    }
+    if let Some(window) = &config.schedule.window {
+        if let Err(e) = crate::schedule::in_window(window, chrono::NaiveTime::MIN) {
+            problems.push(format!("schedule.window: {}", e));
+        }
+    }
+    if let Some(root) = config.target_root.as_ref().filter(|p| !p.is_dir()) {
+        problems.push(format!("target_root {:?} is not a directory", root));
+    }
+    if let Some(askpass) = config.sudo_askpass_cmd.as_ref().filter(|p| !p.is_file()) {
+        problems.push(format!("sudo_askpass_cmd {:?} is not a file", askpass));
+    }
+    if let Some(permissions) = config.file_defaults.permissions.as_ref() {
+        if let Err(e) = crate::utils::file_permissions::apply_mode(permissions, 0) {
+            problems.push(format!("file_defaults.permissions: {}", e));
+        }
+    }
+    if let Some(owner) = config.file_defaults.system_owner.as_ref() {
+        if let Err(e) = crate::utils::file_permissions::user_to_uid(owner) {
+            problems.push(format!("file_defaults.system_owner: {}", e));
+        }
+    }
+    if let Some(group) = config.file_defaults.system_group.as_ref() {
+        if let Err(e) = crate::utils::file_permissions::group_to_gid(group) {
+            problems.push(format!("file_defaults.system_group: {}", e));
+        }
+    }
+    for path in config.never_sudo_paths.iter().filter(|p| !p.is_absolute()) {
+        problems.push(format!("never_sudo_paths entry {:?} is not absolute", path));
+    }
+    for path in config.orphan_paths.iter().filter(|p| !p.is_absolute()) {
+        problems.push(format!("orphan_paths entry {:?} is not absolute", path));
+    }
+    for name in config
+        .remotes
+        .keys()
+        .filter(|n| n.is_empty() || n.contains('/'))
+    {
+        problems.push(format!("remotes: invalid name {:?}", name));
+    }
+    for (name, path) in config.helper.iter() {
+        if !path.is_file() {
+            problems.push(format!("helper.{}: {:?} is not a file", name, path));
+        }
+    }
+
+    problems
+}
+
+/// Checks the config and prints all problems.
+///
+/// # Returns
+///
+/// A Result containing `true` if no problems were found
+pub(crate) fn check() -> Result<bool> {
+    let problems = match DotdeployConfig::load_table() {
+        Ok((conf_table, _)) => check_table(&conf_table),
+        // Syntax errors are problems of the config as well
+        Err(e) => vec![format!("{:#}", e)],
+    };
+
+    for p in problems.iter() {
+        warn!("{}", p);
+    }
+    if problems.is_empty() {
+        info!("No problems found");
+    }
+
+    Ok(problems.is_empty())
+}
+
+/// Prints the config together with the origin of each value.
+///
+/// # Arguments
+///
+/// * `config` - The effective config, including command line flags
+/// * `cli_keys` - Keys set by command line flags
+/// * `effective` - Also print default values
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) fn show(config: &DotdeployConfig, cli_keys: &[&str], effective: bool) -> Result<()> {
+    let (_, origins) = DotdeployConfig::load_table()?;
+    let values = toml::Table::try_from(config)?;
+
+    // Host overrides are already merged into the values
+    for (key, _) in CONFIG_KEYS.iter().filter(|(k, _)| *k != "hosts") {
+        let origin = if cli_keys.contains(key) {
+            "command line"
+        } else {
+            origins.get(*key).copied().unwrap_or("default")
+        };
+        if origin == "default" && !effective {
+            continue;
+        }
+        match values.get(*key) {
+            Some(v) => println!("{} = {}  # {}", key, v, origin),
+            None if effective => println!("# {} is unset", key),
+            None => (),
+        }
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -323,6 +1331,7 @@ mod tests {
         assert!(!conf.hostname.is_empty());
         assert!(conf.use_sudo);
         assert!(conf.deploy_sys_files);
+        assert!(!conf.auto_host_module);
 
         Ok(())
     }
@@ -367,4 +1376,217 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_host_overrides() -> Result<()> {
+        let mut conf: toml::Table = toml::from_str(
+            r#"
+hostname = "laptop"
+deploy_sys_files = true
+
+[hosts.laptop]
+deploy_sys_files = false
+
+[hosts.desktop]
+use_sudo = false
+"#,
+        )?;
+        let overrides = DotdeployConfig::host_overrides(&mut conf)?.unwrap();
+        DotdeployConfig::merge_tables(&mut conf, overrides);
+
+        assert_eq!(conf["deploy_sys_files"].as_bool(), Some(false));
+        assert!(!conf.contains_key("use_sudo"));
+        assert!(!conf.contains_key("hosts"));
+
+        let mut conf: toml::Table = toml::from_str("hosts = 1")?;
+        assert!(DotdeployConfig::host_overrides(&mut conf).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_overrides() -> Result<()> {
+        let mut conf: toml::Table = toml::from_str(
+            r#"
+use_sudo = true
+
+[pkg_lock_retry]
+timeout = 600
+interval = 30
+"#,
+        )?;
+        let overrides = DotdeployConfig::env_overrides(
+            [
+                ("DOTDEPLOY_USE_SUDO", "false"),
+                ("DOTDEPLOY_MODULES_ROOT", "/srv/modules"),
+                ("DOTDEPLOY_INTALL_PKG_CMD", r#"["apt", "install"]"#),
+                ("DOTDEPLOY_PKG_LOCK_RETRY__TIMEOUT", "10"),
+                ("DOTDEPLOY_HOSTNAME", "1234"),
+                ("DOTDEPLOY_FILE_DEFAULTS__PERMISSIONS", "600"),
+                ("HOME", "/home/foo"),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+        assert!(!overrides.contains_key("home"));
+        DotdeployConfig::merge_tables(&mut conf, overrides);
+
+        assert_eq!(conf["use_sudo"].as_bool(), Some(false));
+        assert_eq!(conf["modules_root"].as_str(), Some("/srv/modules"));
+        assert_eq!(conf["intall_pkg_cmd"][1].as_str(), Some("install"));
+        assert_eq!(conf["pkg_lock_retry"]["timeout"].as_integer(), Some(10));
+        assert_eq!(conf["pkg_lock_retry"]["interval"].as_integer(), Some(30));
+        // Values of string keys are never parsed
+        assert_eq!(conf["hostname"].as_str(), Some("1234"));
+        assert_eq!(conf["file_defaults"]["permissions"].as_str(), Some("600"));
+        DotdeployConfig::from_table(conf)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_table() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let conf: toml::Table = toml::from_str(&format!(
+            r#"
+config_root = "{}"
+modules_root = "/nonexistent"
+foo = 1
+skip_pkg_install = true
+intall_pkg_cmd = ["apt", "install"]
+
+[pkg_lock_retry]
+timeout = 5
+interval = 10
+
+[schedule]
+window = "22:00"
+"#,
+            temp_dir.path().display()
+        ))?;
+
+        let problems = check_table(&conf);
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems[0].contains("\"foo\""));
+        assert!(problems[1].starts_with("modules_root"));
+
+        let conf: toml::Table = toml::from_str("use_sudo = \"yes\"")?;
+        let problems = check_table(&conf);
+        assert!(problems[0].starts_with("Invalid config"));
+        assert!(problems[0].contains("use_sudo"), "{:?}", problems);
+
+        let conf: toml::Table = toml::from_str("[helper]\nfoo = \"/nonexistent/foo\"")?;
+        assert!(check_table(&conf)
+            .iter()
+            .any(|p| p.starts_with("helper.foo")));
+
+        let conf: toml::Table = toml::from_str(
+            "[file_defaults]\npermissions = \"u+y\"\nsystem_owner = \"dotdeploy-missing-user\"",
+        )?;
+        let problems = check_table(&conf);
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("file_defaults.permissions")));
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("file_defaults.system_owner")));
+
+        let conf: toml::Table = toml::from_str("orphan_paths = [\"relative/path\"]")?;
+        assert!(check_table(&conf)
+            .iter()
+            .any(|p| p.starts_with("orphan_paths")));
+
+        let conf: toml::Table = toml::from_str("[remotes]\n\"foo/bar\" = \"https://example.com\"")?;
+        assert!(check_table(&conf).iter().any(|p| p.starts_with("remotes")));
+
+        let conf: toml::Table = toml::from_str("[store]\npool_size = 0\nvacuum = false")?;
+        assert!(check_table(&conf)
+            .iter()
+            .any(|p| p.starts_with("store.pool_size")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_context_cmds() -> Result<()> {
+        let conf: toml::Table = toml::from_str(
+            r#"
+hostname = "foo"
+distribution = "bar"
+
+[context_cmds]
+greeting = "echo '  hello world  '"
+no_match = "echo foo | grep bar"
+"#,
+        )?;
+        let config = DotdeployConfig::from_table(conf)?;
+
+        let context = config.eval_context_cmds()?;
+        assert_eq!(context["greeting"], "hello world");
+        assert_eq!(context["no_match"], "");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hooks() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let conf: toml::Table = toml::from_str(&format!(
+            r#"
+hostname = "foo"
+distribution = "bar"
+
+[hooks]
+post_deploy = [
+    {{ exec = "touch {0}/run" }},
+    {{ exec = "touch {0}/skipped", eval_when = "false" }},
+]
+"#,
+            temp_dir.path().display()
+        ))?;
+        let config = DotdeployConfig::from_table(conf)?;
+        assert!(config.hooks.pre_deploy.is_empty());
+        assert_eq!(config.hooks.post_deploy.len(), 2);
+
+        crate::deploy::run_hooks(
+            "post_deploy",
+            &config.hooks.post_deploy,
+            &serde_json::json!({}),
+            &handlebars::Handlebars::new(),
+        )
+        .await?;
+        assert!(temp_dir.path().join("run").exists());
+        assert!(!temp_dir.path().join("skipped").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_tables() -> Result<()> {
+        let mut system: toml::Table = toml::from_str(
+            r#"
+use_sudo = false
+intall_pkg_cmd = ["apt", "install"]
+
+[pkg_lock_retry]
+timeout = 600
+interval = 30
+"#,
+        )?;
+        let user: toml::Table = toml::from_str(
+            r#"
+use_sudo = true
+
+[pkg_lock_retry]
+interval = 5
+"#,
+        )?;
+        DotdeployConfig::merge_tables(&mut system, user);
+
+        assert_eq!(system["use_sudo"].as_bool(), Some(true));
+        assert_eq!(system["intall_pkg_cmd"][0].as_str(), Some("apt"));
+        assert_eq!(system["pkg_lock_retry"]["timeout"].as_integer(), Some(600));
+        assert_eq!(system["pkg_lock_retry"]["interval"].as_integer(), Some(5));
+
+        Ok(())
+    }
 }
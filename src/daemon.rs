@@ -0,0 +1,342 @@
+//! This module provides the daemon mode of dotdeploy.
+//!
+//! The daemon keeps the stores and the sudo session open and serves requests on a Unix socket.
+//! Requests and responses are JSON-RPC 2.0 objects, one per line, e.g.
+//! `{"jsonrpc": "2.0", "id": 1, "method": "lookup", "params": {"target": "~/.zshrc"}}`.
+//!
+//! Supported methods are `status`, `lookup` with the optional parameters `target` and `module`,
+//! and `deploy` with an optional list of `modules`. Deployments are run one at a time.
+
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::cli::Component;
+use crate::config::DotdeployConfig;
+use crate::utils::signal;
+use crate::Stores;
+
+/// JSON-RPC error code of requests which are not valid JSON.
+const PARSE_ERROR: i64 = -32700;
+/// JSON-RPC error code of requests which are not valid request objects.
+const INVALID_REQUEST: i64 = -32600;
+/// JSON-RPC error code of unknown methods.
+const METHOD_NOT_FOUND: i64 = -32601;
+/// JSON-RPC error code of invalid method parameters.
+const INVALID_PARAMS: i64 = -32602;
+/// JSON-RPC error code of failed operations.
+const SERVER_ERROR: i64 = -32000;
+
+/// A JSON-RPC request.
+#[derive(Deserialize, Debug)]
+struct Request {
+    /// Identifier of the request, echoed in the response
+    #[serde(default)]
+    id: Value,
+    /// Name of the method to call
+    method: String,
+    /// Parameters of the method
+    #[serde(default)]
+    params: Value,
+}
+
+/// A JSON-RPC error.
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new<S: Into<String>>(code: i64, message: S) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        RpcError::new(SERVER_ERROR, format!("{:#}", e))
+    }
+}
+
+/// Parameters of the `lookup` method.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct LookupParams {
+    target: Option<String>,
+    module: Option<String>,
+}
+
+/// Parameters of the `deploy` method.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct DeployParams {
+    #[serde(default)]
+    modules: Vec<String>,
+}
+
+/// Parses the parameters of a method, treating missing parameters as empty.
+fn parse_params<T: for<'de> Deserialize<'de> + Default>(params: Value) -> Result<T, RpcError> {
+    match params {
+        Value::Null => Ok(T::default()),
+        params => {
+            serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+        }
+    }
+}
+
+/// Parses a request line.
+fn parse_request(line: &str) -> Result<Request, (Value, RpcError)> {
+    let value: Value = serde_json::from_str(line)
+        .map_err(|e| (Value::Null, RpcError::new(PARSE_ERROR, e.to_string())))?;
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    serde_json::from_value(value).map_err(|e| (id, RpcError::new(INVALID_REQUEST, e.to_string())))
+}
+
+/// Builds the response to a request.
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": e.code, "message": e.message},
+        }),
+    }
+}
+
+/// Returns the status of the daemon and the deployed modules.
+async fn status(stores: &Stores, started: Instant) -> Result<Value, RpcError> {
+    Ok(json!({
+        "pid": std::process::id(),
+        "uptime_s": started.elapsed().as_secs(),
+        "modules": crate::list::collect(stores, false, false).await?,
+    }))
+}
+
+/// Looks up deployed files.
+async fn lookup(stores: &Stores, params: Value) -> Result<Value, RpcError> {
+    let params: LookupParams = parse_params(params)?;
+    let files =
+        crate::lookup::find(stores, params.target.as_deref(), params.module.as_deref()).await?;
+    Ok(serde_json::to_value(files).map_err(anyhow::Error::from)?)
+}
+
+/// State shared by all connections.
+struct Daemon {
+    stores: Arc<Stores>,
+    dotdeploy_config: DotdeployConfig,
+    context: BTreeMap<String, String>,
+    handlebars: Arc<handlebars::Handlebars<'static>>,
+    started: Instant,
+    /// Held while a deployment is running
+    deploying: tokio::sync::Mutex<()>,
+}
+
+impl Daemon {
+    /// Deploys modules, or the host module if none are given.
+    async fn deploy(&self, params: Value) -> Result<Value, RpcError> {
+        let params: DeployParams = parse_params(params)?;
+        let mut module_names = params.modules;
+        if module_names.is_empty() {
+            module_names.push(format!("hosts/{}", self.dotdeploy_config.hostname));
+        }
+
+        let _deploying = self.deploying.lock().await;
+        let summary = crate::deploy_queue(
+            module_names,
+            self.context.clone(),
+            &self.dotdeploy_config,
+            &self.stores,
+            Arc::clone(&self.handlebars),
+            Component::value_variants(),
+        )
+        .await?;
+
+        Ok(serde_json::to_value(summary).map_err(anyhow::Error::from)?)
+    }
+
+    /// Handles a single request line and returns the response.
+    async fn handle(&self, line: &str) -> Value {
+        let request = match parse_request(line) {
+            Ok(r) => r,
+            Err((id, e)) => return response(id, Err(e)),
+        };
+        debug!("Daemon request: {:?}", request);
+
+        let result = match request.method.as_str() {
+            "status" => status(&self.stores, self.started).await,
+            "lookup" => lookup(&self.stores, request.params).await,
+            "deploy" => self.deploy(request.params).await,
+            m => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {}", m),
+            )),
+        };
+
+        response(request.id, result)
+    }
+
+    /// Serves the requests of a single connection until it is closed.
+    async fn serve(&self, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut response = self.handle(&line).await.to_string();
+            response.push('\n');
+            writer.write_all(response.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the default path of the daemon socket.
+///
+/// The socket is placed in `XDG_RUNTIME_DIR` or, as a fallback, in the temporary directory.
+pub(crate) fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("dotdeploy.sock"),
+        None => {
+            std::env::temp_dir().join(format!("dotdeploy-{}.sock", nix::unistd::getuid().as_raw()))
+        }
+    }
+}
+
+/// Runs the daemon until SIGINT or SIGTERM is received.
+///
+/// The run lock is held for the whole lifetime of the daemon, so other dotdeploy processes have
+/// to wait for it or use the socket.
+///
+/// # Arguments
+///
+/// * `socket` - Path of the socket, see [default_socket_path]
+/// * `stores` - Database stores, kept open while the daemon runs
+/// * `dotdeploy_config` - Configuration for deployments
+/// * `context` - Context values for template rendering
+/// * `handlebars` - Handlebars instance for template rendering
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn run(
+    socket: &Path,
+    stores: Arc<Stores>,
+    dotdeploy_config: DotdeployConfig,
+    context: BTreeMap<String, String>,
+    handlebars: Arc<handlebars::Handlebars<'static>>,
+) -> Result<()> {
+    // A leftover socket can't belong to a running daemon as we hold the run lock
+    if socket.exists() {
+        std::fs::remove_file(socket)
+            .with_context(|| format!("Failed to remove stale socket {:?}", socket))?;
+    }
+    let listener =
+        UnixListener::bind(socket).with_context(|| format!("Failed to bind to {:?}", socket))?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to set permissions of {:?}", socket))?;
+    info!("Listening on {}", socket.display());
+
+    let daemon = Arc::new(Daemon {
+        stores,
+        dotdeploy_config,
+        context,
+        handlebars,
+        started: Instant::now(),
+        deploying: tokio::sync::Mutex::new(()),
+    });
+
+    let mut interval = tokio::time::interval(Duration::from_millis(200));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted.context("Failed to accept connection")?;
+                let daemon = Arc::clone(&daemon);
+                tokio::spawn(async move {
+                    if let Err(e) = daemon.serve(stream).await {
+                        warn!("Connection failed: {:?}", e);
+                    }
+                });
+            }
+            _ = interval.tick() => {
+                if signal::is_cancelled() {
+                    break;
+                }
+            }
+        }
+    }
+
+    info!("Shutting down daemon");
+    std::fs::remove_file(socket)
+        .with_context(|| format!("Failed to remove socket {:?}", socket))?;
+
+    Ok(())
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::tests::store_setup_helper;
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(r#"{"jsonrpc": "2.0", "id": 7, "method": "status"}"#).unwrap();
+        assert_eq!(request.id, json!(7));
+        assert_eq!(request.method, "status");
+        assert_eq!(request.params, Value::Null);
+
+        let (id, e) = parse_request("{").unwrap_err();
+        assert_eq!(id, Value::Null);
+        assert_eq!(e.code, PARSE_ERROR);
+
+        let (id, e) = parse_request(r#"{"id": "a"}"#).unwrap_err();
+        assert_eq!(id, json!("a"));
+        assert_eq!(e.code, INVALID_REQUEST);
+
+        let e = parse_params::<LookupParams>(json!({"foo": 1})).unwrap_err();
+        assert_eq!(e.code, INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn test_status_and_lookup() -> Result<()> {
+        let stores = Stores {
+            user_store: store_setup_helper("link").await?,
+            system_store: None,
+        };
+
+        let result = status(&stores, Instant::now()).await.unwrap();
+        assert_eq!(result["modules"][0]["name"], "test");
+        assert_eq!(result["modules"][0]["file_count"], 5);
+
+        let result = lookup(&stores, json!({"module": "test"})).await.unwrap();
+        assert_eq!(result.as_array().unwrap().len(), 5);
+        let result = lookup(&stores, json!({"module": "foo"})).await.unwrap();
+        assert!(result.as_array().unwrap().is_empty());
+
+        let response = response(json!(1), Ok(result));
+        assert_eq!(response["jsonrpc"], "2.0");
+        assert_eq!(response["id"], 1);
+
+        Ok(())
+    }
+}
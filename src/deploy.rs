@@ -5,6 +5,13 @@ use anyhow::{bail, Context, Result};
 use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
 
+use crate::cli::Component;
+use crate::modules::actions::ModuleAction;
+use crate::modules::conditional::{ConditionalEvaluator, DefaultConditionalEvaluator};
+use crate::modules::FailedModule;
+use crate::timings;
+use crate::utils::progress::Progress;
+use crate::utils::signal;
 use crate::Stores;
 
 /// Executes the deployment process, including setup, deployment, and configuration phases.
@@ -18,7 +25,10 @@ use crate::Stores;
 /// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
 /// * `context` - JSON context for template rendering
 /// * `hb` - Handlebars instance for template rendering
+/// * `levels` - Dependency level of each module. The files of a level are deployed concurrently,
+///   after the files of all lower levels.
 /// * `dotdeploy_config` - Configuration for the deployment process
+/// * `components` - Components of the deployment to execute, others are skipped
 ///
 /// # Returns
 ///
@@ -28,55 +38,128 @@ pub(crate) async fn deploy(
     stores: Arc<(Stores)>,
     context: serde_json::Value,
     hb: Arc<handlebars::Handlebars<'static>>,
+    levels: &BTreeMap<String, usize>,
     dotdeploy_config: &crate::config::DotdeployConfig,
+    components: &[Component],
 ) -> Result<()> {
     let hb = Arc::new(hb);
     let context = Arc::new(context);
 
     // Iterate through predefined phases: setup, deploy, and config
     for phase_name in ["setup", "deploy", "config"].iter() {
+        signal::check_cancelled()?;
         info!("Starting {} phase", phase_name.to_uppercase());
 
         // Remove the current phase from the BTreeMap to take ownership
         if let Some(phase) = phases.remove(*phase_name) {
             // Extract actions for pre, main, and post stages
-            let (pre_actions, main_actions, post_actions) =
+            let (pre_actions, main_actions, post_actions) = if components
+                .contains(&Component::Actions)
+            {
                 phase.actions.map_or((None, None, None), |mut map| {
                     (map.remove("pre"), map.remove("main"), map.remove("post"))
-                });
+                })
+            } else {
+                (None, None, None)
+            };
 
             // Execute pre-stage actions
             if let Some(v) = pre_actions {
                 if !v.is_empty() {
                     info!("Executing pre stage actions");
-                    for a in v.into_iter() {
-                        a.run().await?
-                    }
+                    run_actions(v, dotdeploy_config.max_parallel_actions).await?;
                 }
             }
 
             // Handle file operations
-            if let Some(files) = phase.files {
-                let mut set = tokio::task::JoinSet::new();
+            if let Some(files) = phase.files.filter(|_| components.contains(&Component::Files)) {
+                let progress = dotdeploy_config
+                    .progress
+                    .then(|| Arc::new(Progress::new(format!("{} files", phase_name), files.len())));
 
-                // Spawn concurrent tasks for each file operation
+                // Group the files by the dependency level of their module
+                let mut files_by_level: BTreeMap<usize, Vec<_>> = BTreeMap::new();
                 for file in files {
-                    let stores_clone = Arc::clone(&stores);
-                    let hb_clone = Arc::clone(&hb);
-                    let context_clone = Arc::clone(&context);
-                    set.spawn(async move {
-                        file.perform(&stores_clone, &context_clone, &hb_clone).await
-                    });
+                    files_by_level
+                        .entry(levels.get(&file.module).copied().unwrap_or_default())
+                        .or_default()
+                        .push(file);
                 }
 
-                // Wait for all file operations to complete
-                while let Some(res) = set.join_next().await {
-                    res??;
+                let res: Result<()> = async {
+                    for files in files_by_level.into_values() {
+                        let mut set = tokio::task::JoinSet::new();
+
+                        // Spawn concurrent tasks for each file operation of the level
+                        for file in files {
+                            // Stop spawning new operations once cancelled
+                            if signal::is_cancelled() {
+                                break;
+                            }
+                            let stores_clone = Arc::clone(&stores);
+                            let hb_clone = Arc::clone(&hb);
+                            let context_clone = Arc::clone(&context);
+                            let progress_clone = progress.clone();
+                            set.spawn(async move {
+                                let start = std::time::Instant::now();
+                                let res = timings::scope(
+                                    file.module.clone(),
+                                    file.perform(&stores_clone, &context_clone, &hb_clone),
+                                )
+                                .await
+                                .with_context(|| FailedModule(file.module.clone()));
+                                timings::record(
+                                    timings::Category::Files,
+                                    Some(&file.module),
+                                    start,
+                                );
+                                if let Some(p) = progress_clone {
+                                    p.inc(&file.destination().path().display().to_string());
+                                }
+                                res
+                            });
+                        }
+
+                        // Wait for all file operations of the level to complete
+                        let mut local_changes = vec![];
+                        while let Some(res) = set.join_next().await {
+                            match res.map_err(anyhow::Error::from).and_then(|r| r) {
+                                Ok(changes) => local_changes.extend(changes),
+                                Err(e) => {
+                                    abort_all(&mut set).await;
+                                    return Err(e);
+                                }
+                            }
+                        }
+
+                        // Resolve local modifications one at a time, as they need user input
+                        local_changes.sort_by(|a, b| {
+                            a.file.destination().path().cmp(b.file.destination().path())
+                        });
+                        for changes in local_changes {
+                            let module = changes.file.module.clone();
+                            changes
+                                .resolve(&stores, dotdeploy_config.diff)
+                                .await
+                                .with_context(|| FailedModule(module))?;
+                        }
+                    }
+                    Ok(())
                 }
+                .await;
+                if let Some(p) = progress {
+                    p.finish();
+                }
+                res?;
+                signal::check_cancelled()?;
             }
 
             // Handle package installations
-            if let Some(packages) = phase.packages {
+            if let Some(packages) = phase
+                .packages
+                .filter(|_| components.contains(&Component::Packages))
+            {
+                signal::check_cancelled()?;
                 if dotdeploy_config.skip_pkg_install {
                     warn!("Skipping package installation as requested")
                 } else {
@@ -94,25 +177,17 @@ pub(crate) async fn deploy(
                     }
 
                     // Execute package installation
-                    if let Some(cmd) = install_cmd.pop_front() {
-                        // Add packages to the installation command
-                        for pkg in packages.into_iter() {
-                            install_cmd.push_back(pkg);
-                        }
-
-                        // Spawn the installation process
-                        let mut cmd = tokio::process::Command::new(&cmd)
-                            .args(&install_cmd)
-                            .spawn()
-                            .with_context(|| {
-                                format!("Failed to spawn {:?} with args: {:?}", cmd, install_cmd)
-                            })?;
-
-                        // Check if the installation was successful
-                        if !cmd.wait().await?.success() {
-                            bail!("Failed to execute {:?} with args: {:?}", cmd, install_cmd)
-                        }
+                    if dotdeploy_config.progress {
+                        info!("Installing {} packages: {}", packages.len(), packages.join(", "));
                     }
+                    let start = std::time::Instant::now();
+                    crate::packages::exec_package_cmd(
+                        install_cmd,
+                        packages,
+                        &dotdeploy_config.pkg_lock_retry,
+                    )
+                    .await?;
+                    timings::record(timings::Category::Packages, None, start);
                 }
             }
 
@@ -120,9 +195,7 @@ pub(crate) async fn deploy(
             if let Some(v) = main_actions {
                 if !v.is_empty() {
                     info!("Executing main stage actions");
-                    for a in v.into_iter() {
-                        a.run().await?
-                    }
+                    run_actions(v, dotdeploy_config.max_parallel_actions).await?;
                 }
             }
 
@@ -130,9 +203,7 @@ pub(crate) async fn deploy(
             if let Some(v) = post_actions {
                 if !v.is_empty() {
                     info!("Executing post stage actions");
-                    for a in v.into_iter() {
-                        a.run().await?
-                    }
+                    run_actions(v, dotdeploy_config.max_parallel_actions).await?;
                 }
             }
         }
@@ -140,3 +211,233 @@ pub(crate) async fn deploy(
     }
     Ok(())
 }
+
+/// Runs the actions of a stage.
+///
+/// Actions run in the given order, unless they are marked as `parallel`. A parallel action only
+/// waits for the actions named in its `after` field and runs concurrently with other actions, up
+/// to `max_parallel` at once. Actions which are not parallel wait for all actions listed before
+/// them.
+///
+/// # Arguments
+///
+/// * `actions` - Actions of the stage in declaration order
+/// * `max_parallel` - Maximum number of actions running at once
+///
+/// # Returns
+///
+/// A Result indicating success or failure of the actions
+pub(crate) async fn run_actions(actions: Vec<ModuleAction>, max_parallel: usize) -> Result<()> {
+    // Determine the actions each action has to wait for
+    let mut deps: Vec<Vec<usize>> = Vec::with_capacity(actions.len());
+    for (i, a) in actions.iter().enumerate() {
+        if !a.parallel {
+            deps.push((0..i).collect());
+            continue;
+        }
+        let mut d = vec![];
+        for name in a.after.iter() {
+            let found: Vec<usize> = actions
+                .iter()
+                .enumerate()
+                .filter(|(j, b)| *j != i && b.name.as_ref() == Some(name))
+                .map(|(j, _)| j)
+                .collect();
+            if found.is_empty() {
+                bail!("Action {:?} runs after unknown action {:?}", a.exec, name);
+            }
+            d.extend(found);
+        }
+        deps.push(d);
+    }
+
+    let mut started = vec![false; actions.len()];
+    let mut done = vec![false; actions.len()];
+    let mut set = tokio::task::JoinSet::new();
+    let res = async {
+        loop {
+            // Start all actions whose dependencies have finished
+            for i in 0..actions.len() {
+                if set.len() >= max_parallel.max(1) {
+                    break;
+                }
+                if !started[i] && deps[i].iter().all(|d| done[*d]) {
+                    signal::check_cancelled()?;
+                    started[i] = true;
+                    let a = actions[i].clone();
+                    set.spawn(async move { (i, a.run().await) });
+                }
+            }
+
+            let Some(res) = set.join_next().await else {
+                let waiting: Vec<_> = (0..actions.len())
+                    .filter(|i| !done[*i])
+                    .map(|i| &actions[i].exec)
+                    .collect();
+                if !waiting.is_empty() {
+                    bail!("Cyclic after dependencies between actions {:?}", waiting);
+                }
+                return Ok(());
+            };
+            let (i, res) = res?;
+            res?;
+            done[i] = true;
+        }
+    }
+    .await;
+    if res.is_err() {
+        // Do not leave actions running behind the error, e.g. after a cancellation
+        abort_all(&mut set).await;
+    }
+    res
+}
+
+/// Aborts the remaining tasks of a set and waits for them to stop.
+///
+/// Dropping a [tokio::task::JoinSet] aborts its tasks as well, but does not wait for them.
+async fn abort_all<T: 'static>(set: &mut tokio::task::JoinSet<T>) {
+    set.abort_all();
+    while set.join_next().await.is_some() {}
+}
+
+/// Runs global hooks from the `[hooks]` section of the config.
+///
+/// Hooks whose `eval_when` condition is not met are skipped.
+///
+/// # Arguments
+///
+/// * `name` - Name of the hook, used for logging
+/// * `hooks` - Actions to run
+/// * `context` - JSON context for evaluating conditions
+/// * `hb` - Handlebars instance for evaluating conditions
+///
+/// # Returns
+///
+/// A Result indicating success or failure of the hooks
+pub(crate) async fn run_hooks(
+    name: &str,
+    hooks: &[ModuleAction],
+    context: &serde_json::Value,
+    hb: &handlebars::Handlebars<'static>,
+) -> Result<()> {
+    let hooks = DefaultConditionalEvaluator
+        .eval_conditional_vec(Some(hooks.to_vec()), context, hb)?
+        .unwrap_or_default();
+
+    if !hooks.is_empty() {
+        info!("Executing {} hooks", name);
+        for a in hooks.into_iter() {
+            signal::check_cancelled()?;
+            a.run().await?
+        }
+    }
+    Ok(())
+}
+
+/// Runs the `on_failure` actions of the modules affected by a failed deployment.
+///
+/// If the error is marked with [FailedModule], only the actions of that module are run. Otherwise,
+/// e.g. if a package installation failed, the actions of all modules are run. Failing
+/// `on_failure` actions are only logged, so the original error is preserved.
+///
+/// # Arguments
+///
+/// * `error` - The error the deployment failed with
+/// * `on_failure` - The `on_failure` actions, keyed by module name
+/// * `context` - JSON context for evaluating conditions
+/// * `hb` - Handlebars instance for evaluating conditions
+pub(crate) async fn run_on_failure(
+    error: &anyhow::Error,
+    on_failure: &BTreeMap<String, Vec<ModuleAction>>,
+    context: &serde_json::Value,
+    hb: &handlebars::Handlebars<'static>,
+) {
+    let failed = error.downcast_ref::<FailedModule>().map(|f| &f.0);
+    for (module, actions) in on_failure.iter() {
+        if failed.is_some_and(|f| f != module) {
+            continue;
+        }
+        if let Err(e) = run_hooks(&format!("{} on_failure", module), actions, context, hb).await {
+            error!("on_failure actions of {} failed: {:?}", module, e);
+        }
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::anyhow;
+    use serde::Deserialize;
+
+    use crate::modules::config::ModuleConfig;
+
+    /// Parses the actions of a stage from a TOML array.
+    fn parse_actions(actions: &str) -> Result<Vec<ModuleAction>> {
+        let table: toml::Table = toml::from_str(&format!("actions = {}", actions))?;
+        Ok(Vec::<ModuleAction>::deserialize(table["actions"].clone())?)
+    }
+
+    #[tokio::test]
+    async fn test_run_actions() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let out = temp_dir.path().join("out");
+
+        let actions = parse_actions(&format!(
+            r#"[
+    {{ exec = "sleep 0.3; echo a >> {0}", name = "a", parallel = true }},
+    {{ exec = "echo b >> {0}", parallel = true }},
+    {{ exec = "echo c >> {0}", after = ["a"], parallel = true }},
+    {{ exec = "echo d >> {0}" }},
+]"#,
+            out.display()
+        ))?;
+        run_actions(actions, 4).await?;
+        assert_eq!(std::fs::read_to_string(&out)?, "b\na\nc\nd\n");
+
+        // Unknown and cyclic dependencies are errors
+        let actions = parse_actions(r#"[{ exec = "true", after = ["foo"], parallel = true }]"#)?;
+        assert!(run_actions(actions, 4).await.is_err());
+        let actions = parse_actions(
+            r#"[
+    { exec = "true", name = "x", after = ["y"], parallel = true },
+    { exec = "true", name = "y", after = ["x"], parallel = true },
+]"#,
+        )?;
+        assert!(run_actions(actions, 4).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_on_failure() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut on_failure = BTreeMap::new();
+        for name in ["git", "zsh"] {
+            let config: ModuleConfig = toml::from_str(&format!(
+                r#"on_failure = [{{ exec = "touch {}/{}" }}]"#,
+                temp_dir.path().display(),
+                name
+            ))?;
+            on_failure.insert(name.to_string(), config.on_failure.unwrap());
+        }
+        let context = serde_json::json!({});
+        let hb = handlebars::Handlebars::new();
+
+        // Only the failed module runs its actions
+        let error = anyhow!("Permission denied").context(FailedModule("zsh".to_string()));
+        run_on_failure(&error, &on_failure, &context, &hb).await;
+        assert!(temp_dir.path().join("zsh").exists());
+        assert!(!temp_dir.path().join("git").exists());
+
+        // All modules run their actions if the failure is not attributed
+        let error = anyhow!("Package installation failed");
+        run_on_failure(&error, &on_failure, &context, &hb).await;
+        assert!(temp_dir.path().join("git").exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+//! This module provides the `DOD_*` variables dotdeploy gives to tasks and templates.
+//!
+//! The variables describe the config (e.g. `DOD_ROOT`) and the platform (e.g. `DOD_ARCH`). They
+//! are exported to the environment of actions and added to the template context. `dotdeploy env`
+//! prints them as shell code, so scripts and debugging sessions can reproduce the environment of a
+//! task, e.g. with `eval "$(dotdeploy env)"`.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::cli::{OutputFormat, Shell};
+use crate::config::DotdeployConfig;
+use crate::report::{emit, Report};
+use crate::utils::file_fs::path_to_string;
+
+/// Computes the `DOD_*` variables.
+///
+/// Module specific variables like `DOD_CURRENT_MODULE` are set per module and not included.
+///
+/// # Arguments
+///
+/// * `dotdeploy_config` - Configuration providing the paths, hostname and distribution
+///
+/// # Returns
+///
+/// The variables and their values, or an error if a path is not valid Unicode
+pub(crate) fn vars(dotdeploy_config: &DotdeployConfig) -> Result<BTreeMap<String, String>> {
+    let mut vars = BTreeMap::new();
+    vars.insert(
+        "DOD_ROOT".to_string(),
+        path_to_string(&dotdeploy_config.config_root)?,
+    );
+    vars.insert(
+        "DOD_MODULES_ROOT".to_string(),
+        path_to_string(&dotdeploy_config.modules_root)?,
+    );
+    vars.insert(
+        "DOD_HOSTS_ROOT".to_string(),
+        path_to_string(&dotdeploy_config.hosts_root)?,
+    );
+    vars.insert(
+        "DOD_HOSTNAME".to_string(),
+        dotdeploy_config.hostname.to_string(),
+    );
+    vars.insert(
+        "DOD_DISTRO".to_string(),
+        dotdeploy_config.distribution.to_string(),
+    );
+    if let Some(root) = &dotdeploy_config.target_root {
+        vars.insert("DOD_TARGET_ROOT".to_string(), path_to_string(root)?);
+    }
+    for (name, value) in crate::utils::platform::facts() {
+        vars.insert(name.to_string(), value);
+    }
+    Ok(vars)
+}
+
+/// Quotes a value for the given shell.
+fn quote(value: &str, shell: Shell) -> String {
+    match shell {
+        Shell::Bash | Shell::Zsh => format!("'{}'", value.replace('\'', "'\\''")),
+        Shell::Fish => format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'")),
+    }
+}
+
+/// The `DOD_*` variables, printed as shell code.
+#[derive(Serialize, Debug)]
+struct EnvReport {
+    #[serde(skip)]
+    shell: Shell,
+    #[serde(flatten)]
+    vars: BTreeMap<String, String>,
+}
+
+impl Report for EnvReport {
+    fn print_text(&self) {
+        for (name, value) in self.vars.iter() {
+            match self.shell {
+                Shell::Bash | Shell::Zsh => {
+                    println!("export {}={}", name, quote(value, self.shell))
+                }
+                Shell::Fish => println!("set -gx {} {}", name, quote(value, self.shell)),
+            }
+        }
+    }
+}
+
+/// Prints the `DOD_*` variables.
+///
+/// # Arguments
+///
+/// * `dotdeploy_config` - Configuration the variables are computed from
+/// * `shell` - Syntax of the printed code
+/// * `format` - Output format, JSON prints the variables as an object
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) fn env(
+    dotdeploy_config: &DotdeployConfig,
+    shell: Shell,
+    format: OutputFormat,
+) -> Result<()> {
+    emit(
+        &EnvReport {
+            shell,
+            vars: vars(dotdeploy_config)?,
+        },
+        format,
+    )
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("/home/user", Shell::Bash), "'/home/user'");
+        assert_eq!(quote("it's", Shell::Zsh), "'it'\\''s'");
+        assert_eq!(quote("it's", Shell::Fish), "'it\\'s'");
+        assert_eq!(quote("a\\b", Shell::Fish), "'a\\\\b'");
+    }
+
+    #[test]
+    fn test_report_json() -> Result<()> {
+        let report = EnvReport {
+            shell: Shell::Bash,
+            vars: BTreeMap::from([("DOD_ROOT".to_string(), "/dotfiles".to_string())]),
+        };
+        assert_eq!(
+            serde_json::to_value(&report)?,
+            serde_json::json!({"DOD_ROOT": "/dotfiles"})
+        );
+        Ok(())
+    }
+}
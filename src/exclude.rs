@@ -0,0 +1,97 @@
+//! This module handles the exclusion of single targets from deployment.
+//!
+//! Excluded targets are kept in the user store. During deployment, they are neither deployed nor
+//! checked for local modifications, and they are not removed if already deployed. This allows to
+//! park a problematic file without editing its module.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+use crate::Stores;
+
+/// Turns a user supplied path into the absolute form stored in the database.
+fn normalize_path(path: &str) -> Result<std::path::PathBuf> {
+    let expanded =
+        shellexpand::full(path).with_context(|| format!("Failed to expand path {:?}", path))?;
+    std::path::absolute(expanded.as_ref())
+        .with_context(|| format!("Failed to get absolute path of {:?}", path))
+}
+
+/// Excludes a target from deployment.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `path` - Path of the target
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn add(stores: Arc<Stores>, path: &str) -> Result<()> {
+    let path = normalize_path(path)?;
+    if stores
+        .user_store
+        .add_exclusion(&path)
+        .await
+        .map_err(|e| e.into_anyhow())?
+    {
+        info!("Excluded '{}' from deployment", path.display());
+    } else {
+        info!("'{}' is already excluded", path.display());
+    }
+
+    Ok(())
+}
+
+/// Removes the exclusion of a target.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `path` - Path of the target
+///
+/// # Returns
+///
+/// A Result containing `true` if the target was excluded
+pub(crate) async fn remove(stores: Arc<Stores>, path: &str) -> Result<bool> {
+    let path = normalize_path(path)?;
+    let removed = stores
+        .user_store
+        .remove_exclusion(&path)
+        .await
+        .map_err(|e| e.into_anyhow())?;
+    if removed {
+        info!("'{}' will be deployed again", path.display());
+    } else {
+        warn!("'{}' is not excluded", path.display());
+    }
+
+    Ok(removed)
+}
+
+/// Prints all active exclusions.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn list(stores: Arc<Stores>) -> Result<()> {
+    let exclusions = stores
+        .user_store
+        .get_exclusions()
+        .await
+        .map_err(|e| e.into_anyhow())?;
+
+    if exclusions.is_empty() {
+        info!("No targets excluded");
+    }
+    for (path, date) in exclusions.iter() {
+        println!("{}  {}", date.format("%Y-%m-%d %H:%M"), path);
+    }
+
+    Ok(())
+}
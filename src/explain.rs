@@ -0,0 +1,361 @@
+//! This module explains the conditions of a module.
+//!
+//! `dotdeploy explain <module>` lists the files, generated files, actions, packages and messages of
+//! a module together with their `eval_when` condition, whether it is true in the current context
+//! and the values of the variables it references. The module is evaluated with the same context
+//! and helpers as during a deployment. Nothing is deployed and the stores are not modified.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use handlebars::Handlebars;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::cli::OutputFormat;
+use crate::config::DotdeployConfig;
+use crate::modules::actions::RunExec;
+use crate::modules::conditional::eval_condition;
+use crate::modules::config::ModuleConfig;
+use crate::modules::queue::ModuleQueue;
+use crate::report::{emit, Report};
+
+/// Extracts the variables referenced by a condition.
+///
+/// Helper names, literals and the keys of hash arguments are skipped, e.g. `(eq DOD_DISTRO
+/// "arch")` references `DOD_DISTRO` only.
+fn variables(condition: &str) -> Vec<String> {
+    let mut vars = vec![];
+    let mut chars = condition.chars().peekable();
+    // The first token of a subexpression is the helper name
+    let mut helper_position = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => helper_position = true,
+            ')' => helper_position = false,
+            '"' | '\'' => {
+                while let Some(n) = chars.next() {
+                    if n == '\\' {
+                        chars.next();
+                    } else if n == c {
+                        break;
+                    }
+                }
+                helper_position = false;
+            }
+            c if c.is_whitespace() => (),
+            _ => {
+                let mut token = String::from(c);
+                while let Some(&n) = chars.peek() {
+                    if n.is_whitespace() || n == '(' || n == ')' {
+                        break;
+                    }
+                    chars.next();
+                    if n == '=' {
+                        // Hash argument, only its value may be a variable
+                        token.clear();
+                        if matches!(chars.peek(), Some('"' | '\'')) {
+                            break;
+                        }
+                    } else {
+                        token.push(n);
+                    }
+                }
+                let is_helper = std::mem::take(&mut helper_position);
+                let is_literal = matches!(token.as_str(), "true" | "false" | "null" | "undefined")
+                    || token.parse::<f64>().is_ok();
+                if !is_helper && !is_literal && !token.is_empty() && !vars.contains(&token) {
+                    vars.push(token);
+                }
+            }
+        }
+    }
+
+    vars
+}
+
+/// Looks up a variable like `DOD_FACTS.cpu.cores` in the context.
+fn lookup(context: &Value, variable: &str) -> Option<Value> {
+    let path = variable.strip_prefix("this.").unwrap_or(variable);
+    path.split(['.', '/'])
+        .try_fold(context, |value, segment| match value {
+            Value::Object(map) => map.get(segment),
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => None,
+        })
+        .cloned()
+}
+
+/// An entry of a module and its condition.
+#[derive(Serialize, Debug, PartialEq)]
+struct ExplainEntry {
+    /// Kind of the entry, e.g. `file` or `action`
+    kind: &'static str,
+    /// Target, command or content identifying the entry
+    name: String,
+    eval_when: Option<String>,
+    /// Whether the entry is deployed. Entries without condition always are.
+    result: bool,
+    /// Error evaluating the condition, which counts as false
+    error: Option<String>,
+    /// Values of the referenced variables, null if they are missing
+    variables: BTreeMap<String, Option<Value>>,
+}
+
+/// The entries of a module and their conditions.
+#[derive(Serialize, Debug)]
+struct ExplainReport {
+    module: String,
+    entries: Vec<ExplainEntry>,
+}
+
+impl Report for ExplainReport {
+    fn print_text(&self) {
+        println!("{}", self.module);
+        for entry in self.entries.iter() {
+            let Some(condition) = &entry.eval_when else {
+                println!("  {} {}: always", entry.kind, entry.name);
+                continue;
+            };
+            match &entry.error {
+                Some(_) => println!("  {} {}: error", entry.kind, entry.name),
+                None => println!("  {} {}: {}", entry.kind, entry.name, entry.result),
+            }
+            println!("    eval_when: {}", condition);
+            for (name, value) in entry.variables.iter() {
+                match value {
+                    Some(value) => println!("    {} = {}", name, value),
+                    None => println!("    {} is not set", name),
+                }
+            }
+            if let Some(error) = &entry.error {
+                println!("    {}", error.replace('\n', "\n    "));
+            }
+        }
+    }
+}
+
+/// Shortens a command or message to its first line.
+fn summary(text: &str) -> String {
+    let first = text.trim().lines().next().unwrap_or_default();
+    if first.len() < text.trim().len() {
+        format!("{} ...", first)
+    } else {
+        first.to_string()
+    }
+}
+
+/// Evaluates the conditions of all entries of a module config.
+///
+/// # Arguments
+///
+/// * `config` - The module config
+/// * `context` - The template context
+/// * `hb` - Handlebars registry the conditions are evaluated with
+fn explain_config(
+    config: &ModuleConfig,
+    context: &Value,
+    hb: &Handlebars<'static>,
+) -> Vec<ExplainEntry> {
+    let mut conditions: Vec<(&'static str, String, &Option<String>)> = vec![];
+    for (target, file) in config.files.iter().flatten() {
+        conditions.push(("file", target.display().to_string(), &file.eval_when));
+    }
+    for (target, generate) in config.generate.iter().flatten() {
+        conditions.push((
+            "generate",
+            target.display().to_string(),
+            &generate.eval_when,
+        ));
+    }
+    for (phase, stages) in config.actions.iter().flatten() {
+        for (stage, actions) in stages.iter() {
+            for action in actions.iter() {
+                let exec = match &action.exec {
+                    RunExec::Code(code) => summary(code),
+                    RunExec::File(file) => file.to_string(),
+                };
+                let name = format!(
+                    "{}.{} {}",
+                    phase,
+                    stage,
+                    action.name.as_deref().unwrap_or(&exec)
+                );
+                conditions.push(("action", name, &action.eval_when));
+            }
+        }
+    }
+    for packages in config.packages.iter().flatten() {
+        conditions.push(("packages", packages.install.join(" "), &packages.eval_when));
+    }
+    for message in config.messages.iter().flatten() {
+        conditions.push(("message", summary(&message.message), &message.eval_when));
+    }
+
+    conditions
+        .into_iter()
+        .map(|(kind, name, eval_when)| {
+            let (result, error) = match eval_when {
+                Some(condition) => match eval_condition(condition, context, hb) {
+                    Ok(result) => (result, None),
+                    Err(e) => (false, Some(format!("{:#}", e))),
+                },
+                None => (true, None),
+            };
+            let variables = eval_when
+                .as_deref()
+                .map(variables)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|v| {
+                    let value = lookup(context, &v);
+                    (v, value)
+                })
+                .collect();
+            ExplainEntry {
+                kind,
+                name,
+                eval_when: eval_when.clone(),
+                result,
+                error,
+                variables,
+            }
+        })
+        .collect()
+}
+
+/// Prints the conditions of a module.
+///
+/// # Arguments
+///
+/// * `module_name` - Name of the module
+/// * `context` - Global context values
+/// * `dotdeploy_config` - Configuration used to locate the module
+/// * `hb` - Handlebars registry including the store helpers
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn explain(
+    module_name: &str,
+    context: BTreeMap<String, String>,
+    dotdeploy_config: &DotdeployConfig,
+    hb: &Handlebars<'static>,
+    format: OutputFormat,
+) -> Result<()> {
+    let mut queue = ModuleQueue {
+        modules: std::collections::BTreeSet::new(),
+        deployed: std::collections::BTreeSet::new(),
+        context,
+    };
+    queue
+        .add_modules_offline(&vec![module_name.to_string()], dotdeploy_config)
+        .await?;
+
+    let mut hb = hb.clone();
+    queue.register_templates(&mut hb)?;
+
+    let module = queue
+        .find_queued(module_name, dotdeploy_config)
+        .with_context(|| format!("Module {} is not queued", module_name))?;
+    let context = crate::facts::template_context(&queue.context)?;
+
+    emit(
+        &ExplainReport {
+            module: module.name.to_string(),
+            entries: explain_config(&module.config, &context, &hb),
+        },
+        format,
+    )
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::modules::files::ModuleFile;
+    use serde_json::json;
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(variables("foo"), vec!["foo"]);
+        assert_eq!(
+            variables(r#"(and (eq DOD_DISTRO "arch") (file_exists "/usr/bin/sway"))"#),
+            vec!["DOD_DISTRO"]
+        );
+        assert_eq!(
+            variables(r#"(or (gt DOD_FACTS.cpu.cores 4) (eq name "it\"s") DOD_DISTRO true)"#),
+            vec!["DOD_FACTS.cpu.cores", "name", "DOD_DISTRO"]
+        );
+        assert_eq!(
+            variables(r#"(vault "token" field=key default="x")"#),
+            vec!["key"]
+        );
+        assert_eq!(variables("(not (eq a a))"), vec!["a"]);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let context = json!({"DOD_FACTS": {"cpu": {"cores": 8}}, "list": ["a", "b"]});
+        assert_eq!(lookup(&context, "DOD_FACTS.cpu.cores"), Some(json!(8)));
+        assert_eq!(lookup(&context, "this.list.1"), Some(json!("b")));
+        assert_eq!(lookup(&context, "DOD_FACTS.gpu"), None);
+    }
+
+    #[test]
+    fn test_explain_config() {
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        crate::helpers::register_logic_helpers(&mut hb);
+
+        let config = ModuleConfig {
+            files: Some(BTreeMap::from([
+                ("/home/user/.zshrc".into(), ModuleFile::default()),
+                (
+                    "/home/user/.xinitrc".into(),
+                    ModuleFile {
+                        eval_when: Some(r#"(eq DOD_SESSION_TYPE "x11")"#.to_string()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "/home/user/.vpn".into(),
+                    ModuleFile {
+                        eval_when: Some("vpn_profile".to_string()),
+                        ..Default::default()
+                    },
+                ),
+            ])),
+            ..Default::default()
+        };
+        let context = json!({"DOD_SESSION_TYPE": "tty"});
+
+        let entries = explain_config(&config, &context, &hb);
+        assert_eq!(entries.len(), 3);
+
+        assert_eq!(entries[0].name, "/home/user/.vpn");
+        assert!(!entries[0].result);
+        assert!(entries[0].error.is_none());
+        assert_eq!(
+            entries[0].variables,
+            BTreeMap::from([("vpn_profile".to_string(), None)])
+        );
+
+        assert_eq!(entries[1].name, "/home/user/.xinitrc");
+        assert!(!entries[1].result);
+        assert!(entries[1].error.is_none());
+        assert_eq!(
+            entries[1].variables,
+            BTreeMap::from([("DOD_SESSION_TYPE".to_string(), Some(json!("tty")))])
+        );
+
+        assert_eq!(entries[2].name, "/home/user/.zshrc");
+        assert!(entries[2].result);
+        assert!(entries[2].eval_when.is_none());
+    }
+}
@@ -0,0 +1,503 @@
+//! This module gathers facts about the hardware and the operating system.
+//!
+//! Facts are collected once per run, the first time a template context is built, and are available
+//! to templates and conditions under `DOD_FACTS`, e.g. `{{#if DOD_FACTS.battery}}` or
+//! `{{#if (eq DOD_FACTS.gpu_vendor "nvidia")}}`. This replaces calling external commands from
+//! templates to inspect the system. Facts are read from `/proc` and `/sys`, unknown values are
+//! empty strings or zero.
+//!
+//! Site specific facts are provided by executables in the `facts.d` folder of `config_root`. The
+//! JSON printed by a script becomes the fact named after the script, e.g. `facts.d/vpn.sh` printing
+//! `{"profile": "work"}` is available as `DOD_FACTS.vpn.profile`. The output is cached in the user
+//! store for `facts_ttl` seconds, or until the script changes.
+
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::store::db::Store;
+use crate::utils::file_checksum::calculate_sha256_checksum_bytes;
+
+lazy_static! {
+    /// Facts of the running system, gathered on first use.
+    static ref FACTS: Facts = Facts::gather(Path::new("/"));
+    /// Facts provided by the scripts in `facts.d`, set with `load_custom`.
+    static ref CUSTOM: RwLock<BTreeMap<String, Value>> = RwLock::new(BTreeMap::new());
+}
+
+/// Information about the CPU.
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Cpu {
+    /// Vendor, e.g. `GenuineIntel` or `AuthenticAMD`
+    pub(crate) vendor: String,
+    /// Model name, e.g. `AMD Ryzen 7 5800X 8-Core Processor`
+    pub(crate) model: String,
+    /// Number of logical cores
+    pub(crate) cores: usize,
+}
+
+/// Information about the memory.
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Memory {
+    /// Total memory in MiB
+    pub(crate) total_mb: u64,
+    /// Total swap in MiB
+    pub(crate) swap_mb: u64,
+}
+
+/// Facts about the system.
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Facts {
+    pub(crate) cpu: Cpu,
+    pub(crate) memory: Memory,
+    /// Whether the system has a battery, i.e. is most likely a laptop
+    pub(crate) battery: bool,
+    /// Hypervisor the system runs on, e.g. `kvm` or `vmware`, or `none` on bare metal
+    pub(crate) virtualization: String,
+    /// Vendor of the primary GPU, `nvidia`, `amd`, `intel` or the PCI vendor ID of others
+    pub(crate) gpu_vendor: String,
+    /// Init system, e.g. `systemd`, `openrc` or `runit`
+    pub(crate) init_system: String,
+}
+
+/// Reads a file below a root, returning an empty string on error.
+fn read(root: &Path, path: &str) -> String {
+    std::fs::read_to_string(root.join(path)).unwrap_or_default()
+}
+
+/// Parses `/proc/cpuinfo`.
+fn cpu(cpuinfo: &str) -> Cpu {
+    let field = |name: &str| {
+        cpuinfo
+            .lines()
+            .filter_map(|l| l.split_once(':'))
+            .find(|(k, _)| k.trim() == name)
+            .map(|(_, v)| v.trim().to_string())
+            .unwrap_or_default()
+    };
+    let cores = cpuinfo
+        .lines()
+        .filter(|l| l.split(':').next().is_some_and(|k| k.trim() == "processor"))
+        .count();
+
+    Cpu {
+        vendor: field("vendor_id"),
+        model: field("model name"),
+        cores,
+    }
+}
+
+/// Parses `/proc/meminfo`.
+fn memory(meminfo: &str) -> Memory {
+    let mib = |name: &str| {
+        meminfo
+            .lines()
+            .filter_map(|l| l.split_once(':'))
+            .find(|(k, _)| *k == name)
+            .and_then(|(_, v)| v.split_whitespace().next()?.parse::<u64>().ok())
+            .map(|kib| kib / 1024)
+            .unwrap_or(0)
+    };
+
+    Memory {
+        total_mb: mib("MemTotal"),
+        swap_mb: mib("SwapTotal"),
+    }
+}
+
+/// Checks whether one of the power supplies is a battery.
+fn battery(root: &Path) -> bool {
+    std::fs::read_dir(root.join("sys/class/power_supply"))
+        .map(|entries| {
+            entries.flatten().any(|e| {
+                std::fs::read_to_string(e.path().join("type")).is_ok_and(|t| t.trim() == "Battery")
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Determines the hypervisor from the DMI vendor and product, or the CPU flags.
+fn virtualization(root: &Path, cpuinfo: &str) -> String {
+    let dmi = format!(
+        "{} {}",
+        read(root, "sys/class/dmi/id/sys_vendor").trim(),
+        read(root, "sys/class/dmi/id/product_name").trim()
+    );
+    let known = [
+        ("QEMU", "qemu"),
+        ("KVM", "kvm"),
+        ("VMware", "vmware"),
+        ("VirtualBox", "virtualbox"),
+        ("innotek", "virtualbox"),
+        ("Xen", "xen"),
+        ("Parallels", "parallels"),
+        ("Amazon EC2", "amazon"),
+        ("Google Compute Engine", "google"),
+        ("Microsoft Corporation Virtual Machine", "microsoft"),
+    ];
+    if let Some((_, name)) = known.iter().find(|(k, _)| dmi.contains(k)) {
+        return name.to_string();
+    }
+    if root.join("proc/xen").exists() {
+        return "xen".to_string();
+    }
+
+    let hypervisor = cpuinfo
+        .lines()
+        .filter_map(|l| l.split_once(':'))
+        .any(|(k, v)| k.trim() == "flags" && v.split_whitespace().any(|f| f == "hypervisor"));
+    if hypervisor {
+        "unknown".to_string()
+    } else {
+        "none".to_string()
+    }
+}
+
+/// Determines the vendor of the primary GPU, preferring discrete GPUs.
+fn gpu_vendor(root: &Path) -> String {
+    let mut vendors: Vec<String> = std::fs::read_dir(root.join("sys/class/drm"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|e| {
+                    let name = e.file_name();
+                    let name = name.to_string_lossy();
+                    name.starts_with("card") && !name.contains('-')
+                })
+                .filter_map(|e| std::fs::read_to_string(e.path().join("device/vendor")).ok())
+                .map(|v| match v.trim() {
+                    "0x10de" => "nvidia".to_string(),
+                    "0x1002" => "amd".to_string(),
+                    "0x8086" => "intel".to_string(),
+                    other => other.to_string(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    vendors.sort_by_key(|v| match v.as_str() {
+        "nvidia" => 0,
+        "amd" => 1,
+        "intel" => 2,
+        _ => 3,
+    });
+    vendors.into_iter().next().unwrap_or_default()
+}
+
+/// Determines the init system from PID 1.
+fn init_system(root: &Path) -> String {
+    if root.join("run/systemd/system").is_dir() {
+        return "systemd".to_string();
+    }
+    match read(root, "proc/1/comm").trim() {
+        "init" if root.join("run/openrc").is_dir() => "openrc".to_string(),
+        "init" => "sysvinit".to_string(),
+        "runit" | "runsvdir" => "runit".to_string(),
+        "s6-svscan" => "s6".to_string(),
+        comm => comm.to_string(),
+    }
+}
+
+impl Facts {
+    /// Gathers the facts of a system.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - Root of the file system to inspect, `/` on a real system
+    fn gather(root: &Path) -> Self {
+        let cpuinfo = read(root, "proc/cpuinfo");
+        Facts {
+            cpu: cpu(&cpuinfo),
+            memory: memory(&read(root, "proc/meminfo")),
+            battery: battery(root),
+            virtualization: virtualization(root, &cpuinfo),
+            gpu_vendor: gpu_vendor(root),
+            init_system: init_system(root),
+        }
+    }
+}
+
+/// Time a fact script may run before it is killed.
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs a fact script and parses its output.
+///
+/// The script is killed if it does not finish within [SCRIPT_TIMEOUT].
+async fn run_script(script: &Path) -> Result<Value> {
+    let output = tokio::process::Command::new(script)
+        .current_dir(script.parent().unwrap_or(Path::new("/")))
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(SCRIPT_TIMEOUT, output)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "Fact script {:?} did not finish within {} seconds",
+                script,
+                SCRIPT_TIMEOUT.as_secs()
+            )
+        })?
+        .with_context(|| format!("Failed to run fact script {:?}", script))?;
+    if !output.status.success() {
+        bail!(
+            "Fact script {:?} exited with {}: {}",
+            script,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Fact script {:?} did not print valid JSON", script))
+}
+
+/// Returns the value of a fact script, from the cache if it is recent enough.
+///
+/// If the script fails, the cached value is used instead, even if it is outdated or was printed by
+/// an earlier version of the script.
+async fn custom_fact(script: &Path, name: &str, ttl: u64, store: &Store) -> Result<Value> {
+    let checksum = calculate_sha256_checksum_bytes(
+        &std::fs::read(script).with_context(|| format!("Failed to read {:?}", script))?,
+    );
+    let cached = store.get_fact(name).await.map_err(|e| e.into_anyhow())?;
+
+    if let Some(fact) = cached.as_ref().filter(|f| f.checksum == checksum) {
+        let age = chrono::Local::now().signed_duration_since(fact.date);
+        if age.num_seconds() >= 0 && (age.num_seconds() as u64) < ttl {
+            if let Ok(value) = serde_json::from_str(&fact.value) {
+                return Ok(value);
+            }
+        }
+    }
+
+    match run_script(script).await {
+        Ok(value) => {
+            store
+                .add_fact(name, &checksum, &value.to_string())
+                .await
+                .map_err(|e| e.into_anyhow())?;
+            Ok(value)
+        }
+        Err(e) => match cached.and_then(|f| serde_json::from_str(&f.value).ok()) {
+            Some(value) => {
+                warn!("{:#}, using the cached value of {}", e, name);
+                Ok(value)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// Runs the scripts in a `facts.d` folder and makes their output available to templates.
+///
+/// Files which are not executable are ignored. A failing script is reported and its fact is left
+/// out, unless there is an outdated cached value.
+///
+/// # Arguments
+///
+/// * `dir` - The `facts.d` folder, which may not exist
+/// * `ttl` - Seconds a cached value is reused before the script is run again
+/// * `store` - Store caching the output of the scripts
+///
+/// # Returns
+///
+/// A Result indicating success, or an error if the folder can't be read
+pub(crate) async fn load_custom(dir: &Path, ttl: u64, store: &Store) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    let mut scripts = vec![];
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {:?}", dir))? {
+        let path = entry?.path();
+        let executable = std::fs::metadata(&path)
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+        if executable {
+            scripts.push(path);
+        } else {
+            debug!("Ignoring {:?}, it is not an executable file", path);
+        }
+    }
+    scripts.sort();
+
+    let mut custom = BTreeMap::new();
+    for script in scripts {
+        let Some(name) = script.file_stem().and_then(|s| s.to_str()) else {
+            warn!("Ignoring fact script {:?} with an invalid name", script);
+            continue;
+        };
+        match custom_fact(&script, name, ttl, store).await {
+            Ok(value) => {
+                custom.insert(name.to_string(), value);
+            }
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+    *CUSTOM.write().unwrap() = custom;
+    Ok(())
+}
+
+/// Builds the template context from the context variables and the facts.
+///
+/// # Arguments
+///
+/// * `context` - Context variables, e.g. the `DOD_*` variables and module context
+///
+/// # Returns
+///
+/// The context as JSON object with the facts under `DOD_FACTS`. Custom facts replace built-in
+/// facts of the same name.
+pub(crate) fn template_context(context: &BTreeMap<String, String>) -> Result<Value> {
+    let mut facts = serde_json::to_value(&*FACTS)?;
+    if let Value::Object(map) = &mut facts {
+        for (name, value) in CUSTOM.read().unwrap().iter() {
+            map.insert(name.clone(), value.clone());
+        }
+    }
+
+    let mut value = serde_json::to_value(context)?;
+    if let Value::Object(map) = &mut value {
+        map.insert("DOD_FACTS".to_string(), facts);
+    }
+    Ok(value)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &Path, path: &str, content: &str) -> Result<()> {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_gather() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let root = temp_dir.path();
+        write(
+            root,
+            "proc/cpuinfo",
+            "processor\t: 0\nvendor_id\t: AuthenticAMD\nmodel name\t: AMD Ryzen\nflags\t\t: fpu \
+             hypervisor\n\nprocessor\t: 1\nvendor_id\t: AuthenticAMD\nmodel name\t: AMD Ryzen\n",
+        )?;
+        write(
+            root,
+            "proc/meminfo",
+            "MemTotal:       16384000 kB\nSwapTotal:       2097152 kB\n",
+        )?;
+        write(root, "sys/class/power_supply/AC/type", "Mains\n")?;
+        write(root, "sys/class/power_supply/BAT0/type", "Battery\n")?;
+        write(root, "sys/class/drm/card0/device/vendor", "0x8086\n")?;
+        write(root, "sys/class/drm/card0-eDP-1/status", "connected\n")?;
+        write(root, "sys/class/drm/card1/device/vendor", "0x10de\n")?;
+        write(root, "proc/1/comm", "init\n")?;
+        std::fs::create_dir_all(root.join("run/openrc"))?;
+
+        let facts = Facts::gather(root);
+        assert_eq!(
+            facts.cpu,
+            Cpu {
+                vendor: "AuthenticAMD".to_string(),
+                model: "AMD Ryzen".to_string(),
+                cores: 2,
+            }
+        );
+        assert_eq!(
+            facts.memory,
+            Memory {
+                total_mb: 16000,
+                swap_mb: 2048,
+            }
+        );
+        assert!(facts.battery);
+        assert_eq!(facts.virtualization, "unknown");
+        assert_eq!(facts.gpu_vendor, "nvidia");
+        assert_eq!(facts.init_system, "openrc");
+
+        write(root, "sys/class/dmi/id/sys_vendor", "QEMU\n")?;
+        std::fs::create_dir_all(root.join("run/systemd/system"))?;
+        let facts = Facts::gather(root);
+        assert_eq!(facts.virtualization, "qemu");
+        assert_eq!(facts.init_system, "systemd");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gather_empty_root() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let facts = Facts::gather(temp_dir.path());
+        assert_eq!(facts.cpu, Cpu::default());
+        assert!(!facts.battery);
+        assert_eq!(facts.virtualization, "none");
+        assert_eq!(facts.gpu_vendor, "");
+        assert_eq!(facts.init_system, "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_fact() -> Result<()> {
+        let store = crate::store::tests::store_setup_helper("link").await?;
+        let temp_dir = tempfile::tempdir()?;
+        let script = temp_dir.path().join("vpn.sh");
+        let counter = temp_dir.path().join("runs");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho run >> {:?}\necho '{{\"profile\": \"work\"}}'\n",
+                counter
+            ),
+        )?;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+        let runs = || std::fs::read_to_string(&counter).map_or(0, |c| c.lines().count());
+
+        let value = custom_fact(&script, "vpn", 3600, &store).await?;
+        assert_eq!(value, serde_json::json!({"profile": "work"}));
+        assert_eq!(runs(), 1);
+
+        // Cached
+        custom_fact(&script, "vpn", 3600, &store).await?;
+        assert_eq!(runs(), 1);
+
+        // Expired
+        custom_fact(&script, "vpn", 0, &store).await?;
+        assert_eq!(runs(), 2);
+
+        // A failing script falls back to the cached value
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\necho run >> {:?}\nexit 1\n", counter),
+        )?;
+        let value = custom_fact(&script, "vpn", 3600, &store).await?;
+        assert_eq!(value, serde_json::json!({"profile": "work"}));
+        assert_eq!(runs(), 3);
+
+        // Invalid JSON without a cached value is an error
+        std::fs::write(&script, "#!/bin/sh\necho not json\n")?;
+        assert!(custom_fact(&script, "proxy", 3600, &store).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_template_context() -> Result<()> {
+        let context = BTreeMap::from([("DOD_ROOT".to_string(), "/dotfiles".to_string())]);
+        let value = template_context(&context)?;
+        assert_eq!(value["DOD_ROOT"], "/dotfiles");
+        assert!(value["DOD_FACTS"]["cpu"]["cores"].is_u64());
+        Ok(())
+    }
+}
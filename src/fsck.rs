@@ -0,0 +1,198 @@
+//! This module handles the integrity check of the stores.
+//!
+//! It detects file entries which are no longer associated with a module, e.g. after an interrupted
+//! run, and backups whose content does not match their checksum. Notes, statistics, profiles,
+//! baselines, exclusions and events referring to a module, file or run which no longer exists are
+//! reported as stale. Problems can optionally be fixed by removing the affected rows.
+//!
+//! The store keeps neither task UUIDs nor a message cache, tasks and messages are read from the
+//! module configuration on each run and can not dangle.
+//!
+//! Hard links which were broken, e.g. by an editor replacing the source file, are reported as well.
+//! They are fixed by deploying the module again.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::store::db::Store;
+use crate::utils::file_fs;
+use crate::Stores;
+
+/// Checks a single store and optionally fixes the found problems.
+///
+/// # Returns
+///
+/// The number of problems which remain after the check.
+async fn check_store(store: &Store, name: &str, fix: bool) -> Result<usize> {
+    let mut problems = 0;
+
+    info!("Checking {} store {}", name, store.path.display());
+
+    // File entries without a module
+    let orphans = store
+        .get_orphaned_files()
+        .await
+        .map_err(|e| e.into_anyhow())?;
+    for f in orphans.iter() {
+        warn!("{} store: '{}' is not associated with any module", name, f);
+    }
+    if !orphans.is_empty() {
+        if fix {
+            let removed = store
+                .remove_orphaned_files()
+                .await
+                .map_err(|e| e.into_anyhow())?;
+            info!("{} store: Removed {} orphaned file entries", name, removed);
+        } else {
+            problems += orphans.len();
+        }
+    }
+
+    // Notes, statistics, profiles, baselines and events left behind by removed modules, files or
+    // runs
+    let stale = store.get_stale_rows().await.map_err(|e| e.into_anyhow())?;
+    for (table, key) in stale.iter() {
+        warn!(
+            "{} store: '{}' in {} refers to an entry which no longer exists",
+            name, key, table
+        );
+    }
+    if !stale.is_empty() {
+        if fix {
+            let removed = store
+                .remove_stale_rows()
+                .await
+                .map_err(|e| e.into_anyhow())?;
+            info!("{} store: Removed {} stale rows", name, removed);
+        } else {
+            problems += stale.len();
+        }
+    }
+
+    // Backups which can not be restored correctly
+    let invalid_backups = store
+        .get_invalid_backups()
+        .await
+        .map_err(|e| e.into_anyhow())?;
+    for (path, problem) in invalid_backups.into_iter() {
+        warn!(
+            "{} store: Backup of '{}' is invalid: {}",
+            name, path, problem
+        );
+        if fix {
+            store
+                .remove_backup(&path)
+                .await
+                .map_err(|e| e.into_anyhow())?;
+            info!("{} store: Removed invalid backup of '{}'", name, path);
+        } else {
+            problems += 1;
+        }
+    }
+
+    // Hard links which no longer point to their source
+    let files = store
+        .find_files(None, None)
+        .await
+        .map_err(|e| e.into_anyhow())?;
+    for f in files.iter().filter(|f| f.operation == "hardlink") {
+        let Some(source) = f.source.as_ref() else {
+            continue;
+        };
+        if !file_fs::check_hardlink_exists(&f.destination, source)
+            .await
+            .unwrap_or(false)
+        {
+            warn!(
+                "{} store: '{}' is no longer a hard link to '{}', deploy module {} again to \
+                 restore it",
+                name, f.destination, source, f.module
+            );
+            problems += 1;
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Checks the excluded targets of the user store and optionally removes stale ones.
+///
+/// An exclusion is stale if its target is not managed by any module, e.g. because the module was
+/// removed. Exclusions are kept in the user store, but may refer to files of the system store.
+///
+/// # Returns
+///
+/// The number of problems which remain after the check.
+async fn check_exclusions(stores: &Stores, fix: bool) -> Result<usize> {
+    let mut problems = 0;
+
+    let mut destinations: HashSet<String> = HashSet::new();
+    for store in std::iter::once(&stores.user_store).chain(stores.system_store.as_ref()) {
+        destinations.extend(
+            store
+                .find_files(None, None)
+                .await
+                .map_err(|e| e.into_anyhow())?
+                .into_iter()
+                .map(|f| f.destination),
+        );
+    }
+
+    let exclusions = stores
+        .user_store
+        .get_exclusions()
+        .await
+        .map_err(|e| e.into_anyhow())?;
+    for (path, _) in exclusions
+        .into_iter()
+        .filter(|(path, _)| !destinations.contains(path))
+    {
+        warn!(
+            "User store: Excluded target '{}' is not managed by any module",
+            path
+        );
+        if fix {
+            stores
+                .user_store
+                .remove_exclusion(&path)
+                .await
+                .map_err(|e| e.into_anyhow())?;
+            info!("User store: Removed exclusion of '{}'", path);
+        } else {
+            problems += 1;
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Verifies the integrity of the user and system store.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `fix` - Remove orphaned file entries, stale rows, stale exclusions and invalid backups
+///
+/// # Returns
+///
+/// A Result containing `true` if no unresolved problems were found
+pub(crate) async fn fsck(stores: Arc<Stores>, fix: bool) -> Result<bool> {
+    let mut problems = check_store(&stores.user_store, "User", fix).await?;
+    if let Some(sys_store) = &stores.system_store {
+        problems += check_store(sys_store, "System", fix).await?;
+    }
+    problems += check_exclusions(&stores, fix).await?;
+
+    if problems == 0 {
+        info!("No problems found");
+        Ok(true)
+    } else {
+        warn!(
+            "Found {} problem(s). Run with --fix to repair the stores",
+            problems
+        );
+        Ok(false)
+    }
+}
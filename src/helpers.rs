@@ -0,0 +1,863 @@
+//! This module provides custom handlebars helpers available in templates and conditionals.
+//!
+//! The store helpers are backed by a snapshot of the stores taken before any module is processed.
+//! They are read-only and let templates adapt to what is actually deployed on this machine, e.g.
+//! `{{#if (module_deployed "docker")}}...{{/if}}`.
+//!
+//! The path helpers `file_exists` and `dir_exists` test the local file system, e.g.
+//! `eval_when = "(file_exists \"/sys/class/power_supply/BAT0\")"` for laptop-only files.
+//!
+//! Script helpers are configured in the `[helper]` section of the config. The script receives the
+//! parameters and hash arguments as JSON on stdin, e.g. `{"params": ["foo"], "hash": {}}`, and its
+//! stdout becomes the rendered value, without HTML escaping. Scripts running longer than 30 seconds
+//! are killed. Their names must not clash with the built-in helpers or the helpers of dotdeploy.
+//!
+//! The `file_content` helper inlines a file from the directory of the module a template belongs
+//! to, e.g. `{{file_content "snippets/aliases.sh"}}`.
+//!
+//! The logic helpers extend the built-in `and`, `or`, `not` and `eq` helpers, which are left as
+//! they are, e.g.
+//! `eval_when = "(all (any_eq DOD_DISTRO \"arch\" \"manjaro\") (file_exists \"/usr/bin/sway\"))"`:
+//!
+//! - `(all a b ...)` is true if all parameters are truthy, `(any a b ...)` if any is.
+//! - `(any_eq a b ...)` is true if `a` equals any of the other parameters. Strings equal numbers
+//!   and booleans with the same text, as context values are strings, e.g. `(any_eq "2" 2)`.
+//! - `(match a pattern ...)` is true if `a` matches any of the shell-style wildcard patterns, e.g.
+//!   `(match DOD_HOSTNAME "work-*")`.
+//!
+//! Truthiness follows `#if`: `false`, `null`, `0`, empty strings and empty arrays are false. The
+//! string `"false"` is true, compare it with `any_eq` instead.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context as _, Result};
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, JsonRender, JsonTruthy, Output,
+    RenderContext, RenderError, RenderErrorReason, ScopedJson,
+};
+use serde_json::Value;
+
+use crate::Stores;
+
+/// Snapshot of the store data exposed to templates.
+#[derive(Debug, Default)]
+pub(crate) struct StoreSnapshot {
+    /// Names of all deployed modules
+    pub(crate) modules: BTreeSet<String>,
+    /// Destinations of all managed files
+    pub(crate) files: BTreeSet<String>,
+}
+
+impl StoreSnapshot {
+    /// Takes a snapshot of the user and system store.
+    pub(crate) async fn take(stores: &Stores) -> Result<Self> {
+        let mut snapshot = StoreSnapshot::default();
+
+        let mut all_stores = vec![&stores.user_store];
+        if let Some(sys_store) = &stores.system_store {
+            all_stores.push(sys_store);
+        }
+
+        for store in all_stores {
+            for module in store.get_all_modules().await.map_err(|e| e.into_anyhow())? {
+                for file in store
+                    .get_all_files(&module.name)
+                    .await
+                    .map_err(|e| e.into_anyhow())?
+                {
+                    snapshot.files.insert(file.destination);
+                }
+                snapshot.modules.insert(module.name);
+            }
+        }
+
+        Ok(snapshot)
+    }
+}
+
+/// Helper returning `true` if its single string parameter is part of a set of entries.
+struct ContainsHelper {
+    /// Name of the helper, used in error messages
+    name: &'static str,
+    /// Entries to look up
+    entries: Arc<BTreeSet<String>>,
+    /// Expand the parameter like a file path (`~` and environment variables)
+    expand: bool,
+}
+
+impl HelperDef for ContainsHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let param = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex(self.name, 0))?
+            .value()
+            .as_str()
+            .ok_or(RenderErrorReason::InvalidParamType("string"))?;
+
+        let found = if self.expand {
+            let expanded =
+                shellexpand::full(param).map_err(|e| RenderErrorReason::Other(e.to_string()))?;
+            self.entries.contains(expanded.as_ref())
+        } else {
+            self.entries.contains(param)
+        };
+
+        Ok(ScopedJson::Derived(serde_json::Value::Bool(found)))
+    }
+}
+
+/// Registers the store helpers `module_deployed` and `file_managed`.
+///
+/// # Arguments
+///
+/// * `hb` - Handlebars instance to register the helpers with
+/// * `snapshot` - Store data the helpers are backed by
+pub(crate) fn register_store_helpers(hb: &mut Handlebars<'static>, snapshot: StoreSnapshot) {
+    hb.register_helper(
+        "module_deployed",
+        Box::new(ContainsHelper {
+            name: "module_deployed",
+            entries: Arc::new(snapshot.modules),
+            expand: false,
+        }),
+    );
+    hb.register_helper(
+        "file_managed",
+        Box::new(ContainsHelper {
+            name: "file_managed",
+            entries: Arc::new(snapshot.files),
+            expand: true,
+        }),
+    );
+}
+
+/// Helper testing its single path parameter, which is expanded like a file path.
+struct PathTestHelper {
+    /// Name of the helper, used in error messages
+    name: &'static str,
+    /// The test applied to the expanded path
+    test: fn(&Path) -> bool,
+}
+
+impl HelperDef for PathTestHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let param = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex(self.name, 0))?
+            .value()
+            .as_str()
+            .ok_or(RenderErrorReason::InvalidParamType("string"))?;
+
+        let expanded =
+            shellexpand::full(param).map_err(|e| RenderErrorReason::Other(e.to_string()))?;
+
+        Ok(ScopedJson::Derived(serde_json::Value::Bool((self.test)(
+            Path::new(expanded.as_ref()),
+        ))))
+    }
+}
+
+/// Registers the path helpers `file_exists` and `dir_exists`.
+///
+/// `file_exists` is true for any existing path which is not a directory, e.g. device files or
+/// files in `/sys`. Symlinks are followed.
+///
+/// # Arguments
+///
+/// * `hb` - Handlebars instance to register the helpers with
+pub(crate) fn register_path_helpers(hb: &mut Handlebars<'static>) {
+    hb.register_helper(
+        "file_exists",
+        Box::new(PathTestHelper {
+            name: "file_exists",
+            test: |p| p.exists() && !p.is_dir(),
+        }),
+    );
+    hb.register_helper(
+        "dir_exists",
+        Box::new(PathTestHelper {
+            name: "dir_exists",
+            test: Path::is_dir,
+        }),
+    );
+}
+
+/// Operators of the logic helpers.
+#[derive(Clone, Copy, Debug)]
+enum LogicOp {
+    All,
+    Any,
+    AnyEq,
+    Match,
+}
+
+/// Helper combining or comparing its parameters, see the module documentation.
+struct LogicHelper {
+    /// Name of the helper, used in error messages
+    name: &'static str,
+    /// The operator applied to the parameters
+    op: LogicOp,
+}
+
+/// Compares two values, treating strings as equal to numbers and booleans with the same text.
+fn loose_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::String(s), other @ (Value::Number(_) | Value::Bool(_)))
+        | (other @ (Value::Number(_) | Value::Bool(_)), Value::String(s)) => {
+            s.as_str() == other.render()
+        }
+        _ => a == b,
+    }
+}
+
+impl HelperDef for LogicHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let params: Vec<&Value> = h.params().iter().map(|p| p.value()).collect();
+        let min = match self.op {
+            LogicOp::All | LogicOp::Any => 1,
+            LogicOp::AnyEq | LogicOp::Match => 2,
+        };
+        if params.len() < min {
+            return Err(RenderErrorReason::ParamNotFoundForIndex(self.name, params.len()).into());
+        }
+
+        let result = match self.op {
+            LogicOp::All => params.iter().all(|p| p.is_truthy(false)),
+            LogicOp::Any => params.iter().any(|p| p.is_truthy(false)),
+            LogicOp::AnyEq => params[1..].iter().any(|p| loose_eq(params[0], p)),
+            LogicOp::Match => {
+                let value = params[0].render();
+                let mut matched = false;
+                for pattern in params[1..].iter() {
+                    let pattern = pattern
+                        .as_str()
+                        .ok_or(RenderErrorReason::InvalidParamType("string"))?;
+                    matched |= glob::Pattern::new(pattern)
+                        .map_err(|e| {
+                            RenderErrorReason::Other(format!(
+                                "Invalid pattern {:?}: {}",
+                                pattern, e
+                            ))
+                        })?
+                        .matches(&value);
+                }
+                matched
+            }
+        };
+
+        Ok(ScopedJson::Derived(Value::Bool(result)))
+    }
+}
+
+/// Registers the logic helpers `all`, `any`, `any_eq` and `match`.
+///
+/// Unlike the built-in `and`, `or` and `eq`, they take any number of parameters, and `any_eq`
+/// compares loosely.
+///
+/// # Arguments
+///
+/// * `hb` - Handlebars instance to register the helpers with
+pub(crate) fn register_logic_helpers(hb: &mut Handlebars<'static>) {
+    for (name, op) in [
+        ("all", LogicOp::All),
+        ("any", LogicOp::Any),
+        ("any_eq", LogicOp::AnyEq),
+        ("match", LogicOp::Match),
+    ] {
+        hb.register_helper(name, Box::new(LogicHelper { name, op }));
+    }
+}
+
+/// Helper implemented by an external script.
+struct ScriptHelper {
+    /// Name of the helper, used in error messages
+    name: String,
+    /// Path to the script
+    path: PathBuf,
+}
+
+impl HelperDef for ScriptHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let args = serde_json::json!({
+            "params": h.params().iter().map(|p| p.value()).collect::<Vec<_>>(),
+            "hash": h
+                .hash()
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.value().clone()))
+                .collect::<serde_json::Map<_, _>>(),
+        });
+
+        let output = run_script(&self.path, &args.to_string(), SCRIPT_TIMEOUT).map_err(|e| {
+            RenderErrorReason::Other(format!("Helper {} failed: {:?}", self.name, e))
+        })?;
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(output)))
+    }
+
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        // Script output is not HTML, write it unescaped even in `{{helper ...}}`
+        let output = self.call_inner(h, r, ctx, rc)?;
+        out.write(&output.render())?;
+        Ok(())
+    }
+}
+
+/// Built-in helpers of handlebars, which script helpers must not replace.
+const BUILTIN_HELPERS: [&str; 17] = [
+    "if", "unless", "each", "with", "lookup", "raw", "log", "eq", "ne", "gt", "gte", "lt", "lte",
+    "and", "or", "not", "len",
+];
+
+/// Helpers of dotdeploy, which script helpers must not replace.
+const DOTDEPLOY_HELPERS: [&str; 9] = [
+    "all",
+    "any",
+    "any_eq",
+    "match",
+    "file_exists",
+    "dir_exists",
+    "module_deployed",
+    "file_managed",
+    "file_content",
+];
+
+/// Time a helper script may run before it is killed.
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reads a pipe of a child process to the end on another thread.
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut content = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut content);
+        }
+        content
+    })
+}
+
+/// Runs a helper script with the given input on stdin.
+///
+/// The input is written and the output read on separate threads, so a script printing before it
+/// reads its input can't block. The script is killed if it does not finish within `timeout`.
+///
+/// # Returns
+///
+/// A Result containing the stdout of the script without the trailing newline, or an error if the
+/// script fails.
+fn run_script(path: &Path, input: &str, timeout: Duration) -> Result<String> {
+    let mut child = std::process::Command::new(path)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {:?}", path))?;
+
+    let stdin = child.stdin.take();
+    let input = input.to_string();
+    let writer = std::thread::spawn(move || {
+        // Scripts may exit without reading their input
+        if let Some(mut stdin) = stdin {
+            let _ = stdin.write_all(input.as_bytes());
+        }
+    });
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .with_context(|| format!("Failed to wait for {:?}", path))?
+        {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!(
+                "{:?} did not finish within {} seconds",
+                path,
+                timeout.as_secs()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    };
+    let _ = writer.join();
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+
+    if !status.success() {
+        bail!(
+            "{:?} exited with {}: {}",
+            path,
+            status,
+            String::from_utf8_lossy(&stderr).trim()
+        );
+    }
+
+    let stdout = String::from_utf8(stdout)
+        .with_context(|| format!("Output of {:?} is not valid UTF-8", path))?;
+    Ok(stdout
+        .strip_suffix('\n')
+        .map(str::to_string)
+        .unwrap_or(stdout))
+}
+
+/// Registers the helpers implemented by external scripts.
+///
+/// # Arguments
+///
+/// * `hb` - Handlebars instance to register the helpers with
+/// * `scripts` - Paths to the scripts, keyed by helper name
+///
+/// # Returns
+///
+/// A Result indicating success, or an error if a name is taken by another helper
+pub(crate) fn register_script_helpers(
+    hb: &mut Handlebars<'static>,
+    scripts: &BTreeMap<String, PathBuf>,
+) -> Result<()> {
+    for (name, path) in scripts.iter() {
+        if BUILTIN_HELPERS.contains(&name.as_str()) || DOTDEPLOY_HELPERS.contains(&name.as_str()) {
+            bail!(
+                "Script helper {} clashes with a helper of the same name, rename it in [helper]",
+                name
+            );
+        }
+        hb.register_helper(
+            name,
+            Box::new(ScriptHelper {
+                name: name.to_string(),
+                path: path.to_path_buf(),
+            }),
+        );
+    }
+    Ok(())
+}
+
+/// Maximum size of a file inlined by `file_content`, in bytes.
+const FILE_CONTENT_MAX_SIZE: u64 = 1024 * 1024;
+
+/// Helper inlining a file from the directory of the current module.
+///
+/// The current module is taken from `DOD_MODULE` in the render context. Paths are resolved
+/// relative to the module location and must not leave it.
+struct FileContentHelper {
+    /// Locations of the modules, keyed by module name
+    locations: Arc<BTreeMap<String, PathBuf>>,
+}
+
+impl HelperDef for FileContentHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let param = h
+            .param(0)
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("file_content", 0))?
+            .value()
+            .as_str()
+            .ok_or(RenderErrorReason::InvalidParamType("string"))?;
+
+        let module = ctx
+            .data()
+            .get("DOD_MODULE")
+            .and_then(|m| m.as_str())
+            .ok_or_else(|| {
+                RenderErrorReason::Other(
+                    "file_content can only be used in files of a module".to_string(),
+                )
+            })?;
+        let location = self
+            .locations
+            .get(module)
+            .ok_or_else(|| RenderErrorReason::Other(format!("Unknown module {}", module)))?;
+
+        // Resolve symlinks and `..` before checking that the file is part of the module
+        let root = location
+            .canonicalize()
+            .map_err(|e| RenderErrorReason::Other(format!("{:?}: {}", location, e)))?;
+        let path = root
+            .join(param)
+            .canonicalize()
+            .map_err(|e| RenderErrorReason::Other(format!("{:?}: {}", param, e)))?;
+        if !path.starts_with(&root) {
+            return Err(RenderErrorReason::Other(format!(
+                "{:?} is outside of module {}",
+                param, module
+            ))
+            .into());
+        }
+
+        let size = path
+            .metadata()
+            .map_err(|e| RenderErrorReason::Other(format!("{:?}: {}", path, e)))?
+            .len();
+        if size > FILE_CONTENT_MAX_SIZE {
+            return Err(RenderErrorReason::Other(format!(
+                "{:?} exceeds the size limit of {} bytes",
+                path, FILE_CONTENT_MAX_SIZE
+            ))
+            .into());
+        }
+
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| RenderErrorReason::Other(format!("{:?}: {}", path, e)))?;
+
+        Ok(ScopedJson::Derived(serde_json::Value::String(content)))
+    }
+
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        // Inlined files are not HTML, write them unescaped even in `{{file_content ...}}`
+        let content = self.call_inner(h, r, ctx, rc)?;
+        out.write(&content.render())?;
+        Ok(())
+    }
+}
+
+/// Registers the `file_content` helper.
+///
+/// # Arguments
+///
+/// * `hb` - Handlebars instance to register the helper with
+/// * `locations` - Locations of the deployed modules, keyed by module name
+pub(crate) fn register_file_content_helper(
+    hb: &mut Handlebars<'static>,
+    locations: BTreeMap<String, PathBuf>,
+) {
+    hb.register_helper(
+        "file_content",
+        Box::new(FileContentHelper {
+            locations: Arc::new(locations),
+        }),
+    );
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_store_helpers() -> Result<()> {
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+
+        let mut snapshot = StoreSnapshot::default();
+        snapshot.modules.insert("docker".to_string());
+        snapshot
+            .files
+            .insert(shellexpand::full("~/.config/foo")?.to_string());
+        register_store_helpers(&mut hb, snapshot);
+
+        let context = json!({});
+        assert_eq!(
+            hb.render_template(r#"{{#if (module_deployed "docker")}}yes{{/if}}"#, &context)?,
+            "yes"
+        );
+        assert_eq!(
+            hb.render_template(
+                r#"{{#if (module_deployed "git")}}yes{{else}}no{{/if}}"#,
+                &context
+            )?,
+            "no"
+        );
+        assert_eq!(
+            hb.render_template(r#"{{file_managed "~/.config/foo"}}"#, &context)?,
+            "true"
+        );
+        assert_eq!(
+            hb.render_template(r#"{{file_managed "~/.config/bar"}}"#, &context)?,
+            "false"
+        );
+
+        // Missing parameter
+        assert!(hb.render_template(r#"{{file_managed}}"#, &context).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_logic_helpers() -> Result<()> {
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        register_logic_helpers(&mut hb);
+        register_path_helpers(&mut hb);
+
+        let context = json!({
+            "DOD_DISTRO": "arch",
+            "DOD_HOSTNAME": "work-laptop",
+            "monitors": "2",
+            "DOD_FACTS": {"battery": true, "cpu": {"cores": 8}},
+        });
+        let eval = |condition: &str| {
+            hb.render_template(
+                &format!("{{{{#if {}}}}}true{{{{else}}}}false{{{{/if}}}}", condition),
+                &context,
+            )
+        };
+        assert_eq!(
+            eval(r#"(all (eq DOD_DISTRO "arch") (not (file_exists "/nonexistent")))"#)?,
+            "true"
+        );
+        assert_eq!(
+            eval(r#"(all DOD_FACTS.battery (eq DOD_DISTRO "debian"))"#)?,
+            "false"
+        );
+        assert_eq!(
+            eval("(all DOD_FACTS.battery DOD_FACTS.cpu.cores DOD_DISTRO)")?,
+            "true"
+        );
+        assert_eq!(
+            eval(r#"(any (eq DOD_DISTRO "debian") (eq DOD_DISTRO "ubuntu") DOD_DISTRO)"#)?,
+            "true"
+        );
+        assert_eq!(eval(r#"(any false 0 "")"#)?, "false");
+        assert_eq!(
+            eval(r#"(any_eq DOD_DISTRO "debian" "ubuntu" "arch")"#)?,
+            "true"
+        );
+        assert_eq!(eval("(any_eq monitors 2)")?, "true");
+        assert_eq!(eval("(any_eq DOD_FACTS.cpu.cores \"8\")")?, "true");
+        assert_eq!(eval("(any_eq monitors 3)")?, "false");
+        // The built-in helpers are left alone
+        assert_eq!(eval("(eq monitors 2)")?, "false");
+        assert_eq!(eval(r#"(and (eq DOD_DISTRO "arch") true)"#)?, "true");
+        assert_eq!(eval(r#"(match DOD_HOSTNAME "work-*")"#)?, "true");
+        assert_eq!(
+            eval(r#"(match DOD_HOSTNAME "home-*" "*-desktop")"#)?,
+            "false"
+        );
+        assert_eq!(eval(r#"(match DOD_FACTS.cpu.cores "[4-8]")"#)?, "true");
+
+        // Wrong number of parameters
+        assert!(eval("(all)").is_err());
+        assert!(eval(r#"(any_eq DOD_DISTRO)"#).is_err());
+        assert!(eval(r#"(match DOD_HOSTNAME "[")"#).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_helpers() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let file = temp_dir.path().join("file");
+        std::fs::write(&file, "")?;
+
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        register_path_helpers(&mut hb);
+
+        let context = json!({
+            "file": file,
+            "dir": temp_dir.path(),
+            "missing": temp_dir.path().join("missing"),
+        });
+        let render = |template: &str| hb.render_template(template, &context);
+        assert_eq!(render("{{file_exists file}}")?, "true");
+        assert_eq!(render("{{file_exists dir}}")?, "false");
+        assert_eq!(render("{{file_exists missing}}")?, "false");
+        assert_eq!(render("{{dir_exists dir}}")?, "true");
+        assert_eq!(render("{{dir_exists file}}")?, "false");
+        assert_eq!(render(r#"{{#if (dir_exists "~")}}yes{{/if}}"#)?, "yes");
+
+        // Missing parameter
+        assert!(render("{{dir_exists}}").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_helpers() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir()?;
+        let echo = temp_dir.path().join("echo");
+        std::fs::write(&echo, "#!/bin/sh\ncat\n")?;
+        let fail = temp_dir.path().join("fail");
+        std::fs::write(&fail, "#!/bin/sh\necho oops >&2\nexit 1\n")?;
+        for script in [&echo, &fail] {
+            std::fs::set_permissions(script, std::fs::Permissions::from_mode(0o755))?;
+        }
+
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        register_logic_helpers(&mut hb);
+        register_script_helpers(
+            &mut hb,
+            &BTreeMap::from([
+                ("echo".to_string(), echo.clone()),
+                ("fail".to_string(), fail),
+            ]),
+        )?;
+
+        let context = json!({"name": "foo"});
+        let args: serde_json::Value = serde_json::from_str(
+            &hb.render_template(r#"{{{echo name 1 key="value"}}}"#, &context)?,
+        )?;
+        assert_eq!(
+            args,
+            json!({"params": ["foo", 1], "hash": {"key": "value"}})
+        );
+
+        // Output is written without HTML escaping
+        assert_eq!(
+            hb.render_template(r#"{{echo "<a & 'b'>"}}"#, &context)?,
+            r#"{"hash":{},"params":["<a & 'b'>"]}"#
+        );
+
+        let err = hb.render_template("{{fail}}", &context).unwrap_err();
+        assert!(format!("{:?}", err).contains("oops"));
+
+        // Names of other helpers are rejected
+        for name in ["if", "any_eq", "file_content"] {
+            assert!(register_script_helpers(
+                &mut hb,
+                &BTreeMap::from([(name.to_string(), echo.clone())])
+            )
+            .is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_script() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir()?;
+        // Prints more than a pipe holds before reading its input
+        let chatty = temp_dir.path().join("chatty");
+        std::fs::write(
+            &chatty,
+            "#!/bin/sh\nhead -c 200000 /dev/zero | tr '\\0' a\ncat >/dev/null\n",
+        )?;
+        let hang = temp_dir.path().join("hang");
+        std::fs::write(&hang, "#!/bin/sh\nsleep 10\n")?;
+        for script in [&chatty, &hang] {
+            std::fs::set_permissions(script, std::fs::Permissions::from_mode(0o755))?;
+        }
+
+        let output = run_script(&chatty, &"x".repeat(200000), SCRIPT_TIMEOUT)?;
+        assert_eq!(output, "a".repeat(200000));
+
+        let start = Instant::now();
+        let err = run_script(&hang, "", Duration::from_millis(200)).unwrap_err();
+        assert!(err.to_string().contains("did not finish"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_content_helper() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let location = temp_dir.path().join("zsh");
+        std::fs::create_dir_all(location.join("snippets"))?;
+        std::fs::write(
+            location.join("snippets/aliases.sh"),
+            "alias ll='ls -l' && echo \"<done>\"",
+        )?;
+        std::fs::write(temp_dir.path().join("secret"), "secret")?;
+        std::fs::write(
+            location.join("big"),
+            vec![b'a'; FILE_CONTENT_MAX_SIZE as usize + 1],
+        )?;
+
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        register_file_content_helper(
+            &mut hb,
+            BTreeMap::from([("zsh".to_string(), location.clone())]),
+        );
+
+        let context = json!({"DOD_MODULE": "zsh"});
+        assert_eq!(
+            hb.render_template(r#"{{{file_content "snippets/aliases.sh"}}}"#, &context)?,
+            "alias ll='ls -l' && echo \"<done>\""
+        );
+        // Not HTML escaped without triple braces either
+        assert_eq!(
+            hb.render_template(r#"{{file_content "snippets/aliases.sh"}}"#, &context)?,
+            "alias ll='ls -l' && echo \"<done>\""
+        );
+        // Usable as a subexpression
+        assert_eq!(
+            hb.render_template(
+                r#"{{#if (file_content "snippets/aliases.sh")}}yes{{/if}}"#,
+                &context
+            )?,
+            "yes"
+        );
+
+        // Paths must stay inside the module
+        assert!(hb
+            .render_template(r#"{{file_content "../secret"}}"#, &context)
+            .is_err());
+        assert!(hb
+            .render_template(r#"{{file_content "/etc/hostname"}}"#, &context)
+            .is_err());
+        // Size limit
+        assert!(hb
+            .render_template(r#"{{file_content "big"}}"#, &context)
+            .is_err());
+        // Missing file and module
+        assert!(hb
+            .render_template(r#"{{file_content "missing"}}"#, &context)
+            .is_err());
+        assert!(hb
+            .render_template(r#"{{file_content "snippets/aliases.sh"}}"#, &json!({}))
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+//! This module shows the audit log of the stores.
+//!
+//! Every run changing the deployment is recorded together with the files it changed, see
+//! [crate::store::events]. `dotdeploy history` lists the latest runs, or answers when a file last
+//! changed and by which run.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::cli::OutputFormat;
+use crate::report::{emit, serialize_date, Report};
+use crate::store::events::{StoreEvent, StoreRun};
+use crate::Stores;
+
+/// A recorded run.
+#[derive(Serialize, Debug)]
+struct RunEntry {
+    id: i64,
+    command: String,
+    modules: String,
+    #[serde(serialize_with = "serialize_date")]
+    start: chrono::DateTime<chrono::Local>,
+    /// Duration of the run in milliseconds, unset while the run is open
+    duration_ms: Option<i64>,
+    result: String,
+}
+
+impl From<StoreRun> for RunEntry {
+    fn from(run: StoreRun) -> Self {
+        RunEntry {
+            id: run.id,
+            command: run.command,
+            modules: run.modules,
+            start: run.start,
+            duration_ms: run.end.map(|end| (end - run.start).num_milliseconds()),
+            result: run.result.unwrap_or_else(|| "running".to_string()),
+        }
+    }
+}
+
+/// A recorded change of a file.
+#[derive(Serialize, Debug)]
+struct EventEntry {
+    #[serde(serialize_with = "serialize_date")]
+    date: chrono::DateTime<chrono::Local>,
+    path: String,
+    module: String,
+    action: String,
+    run: RunEntry,
+}
+
+impl From<StoreEvent> for EventEntry {
+    fn from(event: StoreEvent) -> Self {
+        EventEntry {
+            date: event.date,
+            path: event.path,
+            module: event.module,
+            action: event.action,
+            run: event.run.into(),
+        }
+    }
+}
+
+/// The shown runs or file changes.
+#[derive(Serialize, Debug)]
+struct HistoryReport {
+    runs: Vec<RunEntry>,
+    events: Vec<EventEntry>,
+}
+
+impl Report for HistoryReport {
+    fn print_text(&self) {
+        if !self.runs.is_empty() {
+            println!(
+                "{:>5}  {:<19}  {:<8}  {:<8}  {:>10}  MODULES",
+                "RUN", "START", "COMMAND", "RESULT", "TIME (ms)"
+            );
+            for r in self.runs.iter() {
+                println!(
+                    "{:>5}  {:<19}  {:<8}  {:<8}  {:>10}  {}",
+                    r.id,
+                    r.start.format("%Y-%m-%d %H:%M:%S"),
+                    r.command,
+                    r.result,
+                    r.duration_ms.map(|d| d.to_string()).unwrap_or_default(),
+                    r.modules
+                );
+            }
+        }
+
+        for e in self.events.iter() {
+            println!(
+                "{}  {:<6}  {}  ({}, run {} {} {})",
+                e.date.format("%Y-%m-%d %H:%M:%S"),
+                e.action,
+                e.path,
+                e.module,
+                e.run.id,
+                e.run.command,
+                e.run.result
+            );
+        }
+
+        if self.runs.is_empty() && self.events.is_empty() {
+            info!("No history recorded yet");
+        }
+    }
+}
+
+/// Shows the history of runs and file changes.
+///
+/// Without a file or module, the latest runs are listed. Otherwise, the changes of the file or of
+/// the files of the module are shown, newest first. Changes are collected from both stores.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `file` - Only show the changes of this file
+/// * `module` - Only show the changes of files of this module
+/// * `limit` - Maximum number of entries to show
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn history(
+    stores: Arc<Stores>,
+    file: Option<&Path>,
+    module: Option<&str>,
+    limit: usize,
+    format: OutputFormat,
+) -> Result<()> {
+    if file.is_none() && module.is_none() {
+        let runs = stores
+            .user_store
+            .get_runs(limit)
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        return emit(
+            &HistoryReport {
+                runs: runs.into_iter().map(RunEntry::from).collect(),
+                events: vec![],
+            },
+            format,
+        );
+    }
+
+    let path = match file {
+        Some(f) => Some(crate::utils::file_fs::path_to_string(
+            std::path::absolute(f)
+                .with_context(|| format!("Failed to get absolute path of {:?}", f))?,
+        )?),
+        None => None,
+    };
+
+    let mut events = stores
+        .user_store
+        .get_events(path.clone(), module.map(str::to_string))
+        .await
+        .map_err(|e| e.into_anyhow())?;
+    if let Some(sys_store) = &stores.system_store {
+        events.extend(
+            sys_store
+                .get_events(path, module.map(str::to_string))
+                .await
+                .map_err(|e| e.into_anyhow())?,
+        );
+    }
+    events.sort_by_key(|e| std::cmp::Reverse(e.date));
+    events.truncate(limit);
+
+    emit(
+        &HistoryReport {
+            runs: vec![],
+            events: events.into_iter().map(EventEntry::from).collect(),
+        },
+        format,
+    )
+}
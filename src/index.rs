@@ -0,0 +1,238 @@
+//! This module indexes the available modules.
+//!
+//! The metadata of all modules below `modules_root` and `hosts_root` (name, description, version,
+//! author, tags and dependencies) is collected from their configs. `dotdeploy index` writes it to
+//! an index file, which makes large repositories of modules browsable without reading every config,
+//! while `dotdeploy search` looks up modules by their metadata.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::cli::OutputFormat;
+use crate::config::DotdeployConfig;
+use crate::modules::config::ModuleConfig;
+use crate::modules::find_modules;
+use crate::report::{emit, Report};
+
+/// Metadata of an available module.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct ModuleInfo {
+    /// Name of the module as used on the command line, e.g. `hosts/laptop`
+    module: String,
+    /// Human readable name from the module config
+    name: Option<String>,
+    description: Option<String>,
+    version: Option<String>,
+    author: Option<String>,
+    tags: Vec<String>,
+    depends: Vec<String>,
+}
+
+impl ModuleInfo {
+    fn new(module: String, config: ModuleConfig) -> Self {
+        ModuleInfo {
+            module,
+            name: config.name,
+            description: config.description,
+            version: config.version,
+            author: config.author,
+            tags: config.tags.unwrap_or_default(),
+            depends: config.depends.unwrap_or_default(),
+        }
+    }
+
+    /// Checks whether a search term occurs in the module name or its metadata, ignoring case.
+    fn matches(&self, term: &str) -> bool {
+        let term = term.to_lowercase();
+        [
+            Some(&self.module),
+            self.name.as_ref(),
+            self.description.as_ref(),
+            self.author.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.tags.iter())
+        .any(|s| s.to_lowercase().contains(&term))
+    }
+}
+
+/// Collects the metadata of all modules below a directory.
+///
+/// Modules with a broken config are skipped with a warning.
+fn collect_dir(root: &Path, prefix: &str) -> Result<Vec<ModuleInfo>> {
+    if !root.is_dir() {
+        return Ok(vec![]);
+    }
+
+    Ok(find_modules(root)?
+        .into_iter()
+        .filter_map(|name| match ModuleConfig::read_config(root.join(&name)) {
+            Ok(config) => Some(ModuleInfo::new(format!("{}{}", prefix, name), config)),
+            Err(e) => {
+                warn!("Failed to read config of module {}: {:?}", name, e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Collects the metadata of all modules and host modules.
+fn collect(dotdeploy_config: &DotdeployConfig) -> Result<Vec<ModuleInfo>> {
+    let mut modules = collect_dir(&dotdeploy_config.modules_root, "")?;
+    modules.extend(collect_dir(&dotdeploy_config.hosts_root, "hosts/")?);
+    Ok(modules)
+}
+
+/// The index of all available modules.
+#[derive(Serialize, Debug)]
+struct ModuleIndex {
+    #[serde(serialize_with = "crate::report::serialize_date")]
+    generated: chrono::DateTime<chrono::Local>,
+    modules: Vec<ModuleInfo>,
+}
+
+/// Writes the metadata of all available modules to an index file.
+///
+/// # Arguments
+///
+/// * `dotdeploy_config` - Configuration providing `modules_root` and `hosts_root`
+/// * `output` - Path of the index file, defaults to `index.json` in `modules_root`
+///
+/// # Returns
+///
+/// A Result indicating success or failure of writing the index
+pub(crate) fn index(dotdeploy_config: &DotdeployConfig, output: Option<&Path>) -> Result<()> {
+    let output = match output {
+        Some(o) => o.to_path_buf(),
+        None => dotdeploy_config.modules_root.join("index.json"),
+    };
+
+    let index = ModuleIndex {
+        generated: chrono::offset::Local::now(),
+        modules: collect(dotdeploy_config)?,
+    };
+    std::fs::write(&output, serde_json::to_string_pretty(&index)? + "\n")
+        .with_context(|| format!("Failed to write index {:?}", output))?;
+    info!("Indexed {} modules in {:?}", index.modules.len(), output);
+
+    Ok(())
+}
+
+/// Modules matching a search.
+#[derive(Serialize, Debug)]
+struct SearchResult {
+    modules: Vec<ModuleInfo>,
+}
+
+impl Report for SearchResult {
+    fn print_text(&self) {
+        for m in self.modules.iter() {
+            let mut line = m.module.clone();
+            if let Some(version) = &m.version {
+                line = format!("{} {}", line, version);
+            }
+            if let Some(name) = &m.name {
+                line = format!("{} ({})", line, name);
+            }
+            println!("{}", line);
+            if let Some(description) = &m.description {
+                println!("    {}", description);
+            }
+            if let Some(author) = &m.author {
+                println!("    author: {}", author);
+            }
+            if !m.tags.is_empty() {
+                println!("    tags: {}", m.tags.join(", "));
+            }
+        }
+
+        if self.modules.is_empty() {
+            info!("No matching modules found");
+        }
+    }
+}
+
+/// Searches the available modules by name, description, author and tags.
+///
+/// # Arguments
+///
+/// * `dotdeploy_config` - Configuration providing `modules_root` and `hosts_root`
+/// * `term` - The search term, matched case-insensitively. All modules match an empty term.
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result containing `true` if at least one module matched
+pub(crate) fn search(
+    dotdeploy_config: &DotdeployConfig,
+    term: &str,
+    format: OutputFormat,
+) -> Result<bool> {
+    let modules: Vec<ModuleInfo> = collect(dotdeploy_config)?
+        .into_iter()
+        .filter(|m| m.matches(term))
+        .collect();
+
+    let found = !modules.is_empty();
+    emit(&SearchResult { modules }, format)?;
+
+    Ok(found)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_dir() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join("nvim"))?;
+        std::fs::write(
+            root.join("nvim/config.toml"),
+            r#"
+name = "Neovim"
+description = "Editor setup"
+version = "1.2.0"
+author = "Jane Doe"
+tags = ["cli"]
+depends = ["base"]
+"#,
+        )?;
+        std::fs::create_dir_all(root.join("base"))?;
+        std::fs::write(root.join("base/config.toml"), "")?;
+
+        let modules = collect_dir(root, "")?;
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[0].module, "base");
+        assert_eq!(
+            modules[1],
+            ModuleInfo {
+                module: "nvim".to_string(),
+                name: Some("Neovim".to_string()),
+                description: Some("Editor setup".to_string()),
+                version: Some("1.2.0".to_string()),
+                author: Some("Jane Doe".to_string()),
+                tags: vec!["cli".to_string()],
+                depends: vec!["base".to_string()],
+            }
+        );
+
+        assert!(modules[1].matches("EDITOR"));
+        assert!(modules[1].matches("jane"));
+        assert!(modules[1].matches("cli"));
+        assert!(!modules[1].matches("zsh"));
+        assert!(modules[0].matches(""));
+
+        // Missing folders contain no modules
+        assert!(collect_dir(&root.join("missing"), "hosts/")?.is_empty());
+
+        Ok(())
+    }
+}
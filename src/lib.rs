@@ -0,0 +1,1335 @@
+//! The deployment engine of dotdeploy.
+//!
+//! The `dotdeploy` binary is a thin wrapper around [run]. Embedding the engine in another program
+//! works the same way: parse a command line into a [cli::Cli] and pass it to [run].
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use lazy_static::lazy_static;
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[macro_use]
+extern crate log;
+
+mod adopt;
+mod backups;
+mod bootstrap;
+mod check;
+pub mod cli;
+mod completions;
+mod config;
+mod daemon;
+mod deploy;
+mod env;
+mod exclude;
+mod explain;
+mod facts;
+mod fsck;
+mod helpers;
+mod history;
+mod index;
+mod list;
+mod logs;
+mod lookup;
+mod man;
+mod modules;
+mod notes;
+mod notify;
+mod orphans;
+mod packages;
+mod phases;
+mod phases2;
+mod picker;
+mod remotes;
+mod remove;
+mod render;
+mod report;
+mod sandbox;
+mod schedule;
+mod self_update;
+mod stats;
+mod store;
+mod target_root;
+mod target_user;
+mod timings;
+mod utils;
+mod why;
+
+use store::Stores;
+
+lazy_static! {
+    /// Global variable, available to all threads, indicating if the system store can be used.
+    pub(crate) static ref DEPLOY_SYSTEM_FILES: AtomicBool = AtomicBool::new(false);
+    /// Global variable, available to all threads, indicating if sudo can be used.
+    pub(crate) static ref USE_SUDO: AtomicBool = AtomicBool::new(false);
+}
+
+/// Logs an error including its chain of causes.
+pub fn display_error(error: anyhow::Error) {
+    let mut chain = error.chain();
+    let mut error_message = format!("{}\nCaused by:\n", chain.next().unwrap());
+
+    for e in chain {
+        writeln!(error_message, "    {}", e).unwrap();
+    }
+    // Remove last \n
+    error_message.pop();
+
+    error!("{}", error_message);
+}
+
+/// Runs dotdeploy with a parsed command line.
+///
+/// This is the entry point of the `dotdeploy` binary. Other programs and integration tests can use
+/// it to drive deployments, e.g. with `Cli::try_parse_from(["dotdeploy", "deploy", "zsh"])`.
+///
+/// # Arguments
+///
+/// * `cli` - The parsed command line
+///
+/// # Returns
+///
+/// A Result containing `false` if the command completed, but reported a failure, e.g. an unhealthy
+/// store
+///
+/// # Examples
+///
+/// ```no_run
+/// use clap::Parser;
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let cli = dotdeploy::cli::Cli::try_parse_from(["dotdeploy", "config", "check"])?;
+///     if !dotdeploy::run(cli).await? {
+///         eprintln!("The config has problems");
+///     }
+///     Ok(())
+/// }
+/// ```
+pub async fn run(cli: cli::Cli) -> Result<bool> {
+    let level = match cli.verbosity {
+        0 => simplelog::LevelFilter::Info,
+        1 => simplelog::LevelFilter::Debug,
+        2 => simplelog::LevelFilter::Trace,
+        _ => unreachable!(),
+    };
+    report::set_quiet(cli.quiet);
+    // A signal which cancelled a previous run of an embedding program must not cancel this one
+    utils::signal::reset();
+
+    // Completion candidates are read by the shell, so nothing else is printed. Without a logger,
+    // log messages are dropped, and a broken config simply yields no candidates.
+    if let cli::Commands::Complete { kind } = cli.command {
+        if let Ok(dotdeploy_config) = config::DotdeployConfig::init() {
+            completions::complete(kind, &dotdeploy_config);
+        }
+        return Ok(true);
+    }
+
+    // Only the first run of a process sets the logger, later runs of embedding programs keep it
+    let _ = simplelog::CombinedLogger::init(vec![
+        simplelog::TermLogger::new(
+            if cli.quiet {
+                simplelog::LevelFilter::Error
+            } else {
+                level
+            },
+            simplelog::ConfigBuilder::new()
+                .set_time_level(simplelog::LevelFilter::Debug)
+                .set_location_level(simplelog::LevelFilter::Debug)
+                .set_target_level(simplelog::LevelFilter::Debug)
+                .set_thread_level(simplelog::LevelFilter::Debug)
+                .set_level_padding(simplelog::LevelPadding::Left)
+                .add_filter_allow("dotdeploy".to_string())
+                .build(),
+            // Keep stdout clean for machine-readable output
+            match cli.format {
+                cli::OutputFormat::Text => simplelog::TerminalMode::Mixed,
+                cli::OutputFormat::Json => simplelog::TerminalMode::Stderr,
+            },
+            simplelog::ColorChoice::Auto,
+        ),
+        // The log of the run, see utils::task_log
+        simplelog::WriteLogger::new(
+            simplelog::LevelFilter::Debug,
+            simplelog::ConfigBuilder::new()
+                .set_time_level(simplelog::LevelFilter::Error)
+                .set_location_level(simplelog::LevelFilter::Off)
+                .set_target_level(simplelog::LevelFilter::Off)
+                .set_thread_level(simplelog::LevelFilter::Off)
+                .set_level_padding(simplelog::LevelPadding::Off)
+                .add_filter_allow("dotdeploy".to_string())
+                .build(),
+            utils::task_log::RunLogWriter,
+        ),
+        // Silent unless the journal is enabled in the config
+        utils::journal::JournalLogger::new(level),
+    ]);
+
+    // Completion scripts, the man page and updates are handled without touching the config or the
+    // stores
+    match &cli.command {
+        cli::Commands::Completions { shell } => {
+            completions::print_script(*shell);
+            return Ok(true);
+        }
+        cli::Commands::GenMan { out_dir } => {
+            man::generate(out_dir.as_deref())?;
+            return Ok(true);
+        }
+        cli::Commands::SelfUpdate { check } => {
+            self_update::self_update(*check, cli.format)?;
+            return Ok(true);
+        }
+        _ => (),
+    }
+
+    // Handle SIGINT and SIGTERM gracefully
+    utils::signal::install_handler()?;
+
+    // Previewing a module must not touch the real home directory, the stores or the system. Thus,
+    // the environment gets redirected to a throwaway overlay before the config derives the run
+    // state, logs and caches from HOME.
+    let overlay = if let cli::Commands::Try { .. } = &cli.command {
+        Some(sandbox::create_overlay()?)
+    } else {
+        None
+    };
+
+    // Deploying for another user switches HOME and USER to their account before the config
+    // expands the run state and cache directories
+    if let cli::Commands::Deploy {
+        for_user: Some(user),
+        ..
+    } = &cli.command
+    {
+        target_user::switch(user)?;
+    }
+
+    // An invalid config is reported by the check instead of failing to initialize
+    if let cli::Commands::Config {
+        command: cli::ConfigCommands::Check,
+    } = &cli.command
+    {
+        return config::check();
+    }
+
+    // The Dotdeploy config should be on the top level as it contains information like the paths
+    // which are needed often.
+    let mut dotdeploy_config =
+        config::DotdeployConfig::init().context("Failed to initialize Dotdeploy config")?;
+    if cli.skip_pkg_install {
+        dotdeploy_config.skip_pkg_install = cli.skip_pkg_install;
+    }
+    if cli.progress {
+        dotdeploy_config.progress = cli.progress;
+    }
+    if cli.diff {
+        dotdeploy_config.diff = cli.diff;
+    }
+    if let Some(root) = &cli.target_root {
+        dotdeploy_config.target_root = Some(std::path::absolute(root)?);
+    }
+    if overlay.is_some() {
+        dotdeploy_config.deploy_sys_files = false;
+        dotdeploy_config.skip_pkg_install = true;
+    }
+
+    // The config is inspected without touching the stores
+    if let cli::Commands::Config {
+        command: cli::ConfigCommands::Show { effective },
+    } = &cli.command
+    {
+        let mut cli_keys = vec![];
+        if cli.skip_pkg_install {
+            cli_keys.push("skip_pkg_install");
+        }
+        if cli.progress {
+            cli_keys.push("progress");
+        }
+        if cli.diff {
+            cli_keys.push("diff");
+        }
+        if cli.target_root.is_some() {
+            cli_keys.push("target_root");
+        }
+        config::show(&dotdeploy_config, &cli_keys, *effective)?;
+        return Ok(true);
+    }
+
+    // The bootstrap script and the environment of tasks only depend on the config
+    match &cli.command {
+        cli::Commands::Bootstrap { output } => {
+            bootstrap::bootstrap(&dotdeploy_config, output.as_deref())?;
+            return Ok(true);
+        }
+        cli::Commands::Env { shell } => {
+            env::env(&dotdeploy_config, *shell, cli.format)?;
+            return Ok(true);
+        }
+        _ => (),
+    }
+
+    // Automatic deployments only proceed if the schedule conditions are met
+    if cli.auto && matches!(cli.command, cli::Commands::Deploy { .. }) {
+        if let Some(reason) = schedule::skip_reason(&dotdeploy_config.schedule)? {
+            info!("Skipping automatic deployment: {}", reason);
+            return Ok(true);
+        }
+    }
+
+    // Only one instance may work on the stores at a time. The lock is held until run() returns.
+    let _lock = utils::lock::RunLock::acquire(&dotdeploy_config.state_dir, cli.wait)
+        .context("Failed to acquire run lock")?;
+    store::init::migrate_layout(&dotdeploy_config)?;
+
+    // Set global variables according to config
+    DEPLOY_SYSTEM_FILES.store(dotdeploy_config.deploy_sys_files, Ordering::Relaxed);
+    USE_SUDO.store(dotdeploy_config.use_sudo, Ordering::Relaxed);
+    utils::sudo::set_root_cmd(&dotdeploy_config.sudo_cmd)?;
+    utils::sudo::set_askpass(dotdeploy_config.sudo_askpass_cmd.clone());
+    utils::sudo::set_never_sudo_paths(dotdeploy_config.never_sudo_paths.clone());
+
+    // Deploying below another root must not change the packages of the live system
+    if let Some(root) = &dotdeploy_config.target_root {
+        target_root::set(root)?;
+        dotdeploy_config.skip_pkg_install = true;
+    }
+
+    if dotdeploy_config.journal {
+        if let Err(e) = utils::journal::enable() {
+            warn!("Not logging to the journal: {:#}", e);
+        }
+    }
+
+    // Capture the output of actions in the logs directory
+    utils::task_log::init(&dotdeploy_config.logs_dir);
+    if matches!(
+        cli.command,
+        cli::Commands::Deploy { .. }
+            | cli::Commands::Remove { .. }
+            | cli::Commands::Adopt { .. }
+            | cli::Commands::Add { .. }
+    ) {
+        utils::task_log::enable_run_log();
+        if let Err(e) = utils::task_log::prune(
+            &dotdeploy_config.logs_dir,
+            utils::task_log::MAX_RUNS.saturating_sub(1),
+        ) {
+            warn!("Failed to delete old logs: {:#}", e);
+        }
+    }
+
+    // Make config and platform facts available as environment variables
+    let dod_vars = env::vars(&dotdeploy_config)?;
+    unsafe {
+        for (name, value) in dod_vars.iter() {
+            std::env::set_var(name, value);
+        }
+    }
+
+    trace!("Config values: {:#?}", &dotdeploy_config);
+
+    // Handlebars templating. The context starts with the same facts as the environment.
+    let mut context: std::collections::BTreeMap<String, String> = dod_vars;
+    let mut handlebars: handlebars::Handlebars<'static> = handlebars::Handlebars::new();
+    handlebars.set_strict_mode(true);
+    helpers::register_logic_helpers(&mut handlebars);
+    helpers::register_path_helpers(&mut handlebars);
+    helpers::register_script_helpers(&mut handlebars, &dotdeploy_config.helper)?;
+    // Root must not read rendered output from a cache the target user can write to
+    if target_user::get().is_none() {
+        utils::render_cache::init(
+            dotdeploy_config.cache_dir.join("templates"),
+            dotdeploy_config.helper.keys(),
+        );
+    }
+
+    // Run the context commands once instead of calling them from every template
+    context.extend(
+        dotdeploy_config
+            .eval_context_cmds()
+            .context("Failed to evaluate context commands")?,
+    );
+
+    let mut messages: (
+        std::collections::BTreeMap<String, Vec<crate::modules::messages::Message>>,
+        std::collections::BTreeMap<String, Vec<crate::modules::messages::Message>>,
+    ) = (
+        std::collections::BTreeMap::new(),
+        std::collections::BTreeMap::new(),
+    );
+
+    let mut generators: std::collections::BTreeMap<
+        std::path::PathBuf,
+        crate::modules::generate::Generate,
+    > = std::collections::BTreeMap::new();
+
+    // Initialize stores. Dry runs must not change them, thus they work on an in-memory copy.
+    store::db::configure(dotdeploy_config.store.clone());
+    let ephemeral = cli.ephemeral_store
+        || matches!(
+            &cli.command,
+            cli::Commands::Remove { dry_run: true, .. }
+                | cli::Commands::Deploy { check: true, .. }
+                | cli::Commands::Backups {
+                    command: cli::BackupsCommands::Prune { dry_run: true }
+                }
+        );
+    let stores = Arc::new(
+        Stores::init(ephemeral)
+            .await
+            .context("Failed to initialize stores")?,
+    );
+
+    // Run the fact scripts of the dotfiles, their output is cached in the user store. Only commands
+    // evaluating templates or conditions need them, checking a deployment only compares checksums.
+    if matches!(
+        &cli.command,
+        cli::Commands::Deploy { check: false, .. }
+            | cli::Commands::Remove { .. }
+            | cli::Commands::Explain { .. }
+            | cli::Commands::Add { .. }
+            | cli::Commands::Render { .. }
+            | cli::Commands::Daemon { .. }
+    ) {
+        facts::load_custom(
+            &dotdeploy_config.config_root.join("facts.d"),
+            dotdeploy_config.facts_ttl,
+            &stores.user_store,
+        )
+        .await
+        .context("Failed to load custom facts")?;
+    }
+
+    // Make a snapshot of the stores available to templates
+    helpers::register_store_helpers(
+        &mut handlebars,
+        helpers::StoreSnapshot::take(&stores)
+            .await
+            .context("Failed to take snapshot of stores")?,
+    );
+    let handlebars = Arc::new(handlebars);
+
+    match &cli.command {
+        cli::Commands::Deploy {
+            modules,
+            components,
+            interactive,
+            profile,
+            tags,
+            check,
+            for_user,
+            timings,
+        } => {
+            if *timings {
+                crate::timings::enable();
+            }
+
+            let mut module_names = if *interactive {
+                let picked = picker::pick(&dotdeploy_config, &stores).await?;
+                if picked.is_empty() && profile.is_none() && tags.is_empty() {
+                    info!("No modules selected");
+                    // Close pools
+                    stores.close().await?;
+                    return Ok(true);
+                }
+                picked
+            } else {
+                modules.clone().unwrap_or_default()
+            };
+
+            // Profiles compose with explicitly requested modules
+            let mut profile_modules = None;
+            if let Some(profile) = profile {
+                let p = dotdeploy_config.profiles.get(profile).with_context(|| {
+                    format!(
+                        "Profile {} is not defined in the config, available profiles: {}",
+                        profile,
+                        dotdeploy_config
+                            .profiles
+                            .keys()
+                            .cloned()
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                })?;
+                for m in p.modules.iter() {
+                    if !module_names.contains(m) {
+                        module_names.push(m.to_string());
+                    }
+                }
+                profile_modules = Some((profile.as_str(), p.modules.as_slice()));
+            }
+
+            // Tags select modules from modules_root
+            if !tags.is_empty() {
+                let tagged = modules::find_tagged_modules(&dotdeploy_config.modules_root, tags)?;
+                if tagged.is_empty() {
+                    warn!("No modules tagged with {}", tags.join(", "));
+                }
+                for m in tagged.into_iter() {
+                    if !module_names.contains(&m) {
+                        module_names.push(m);
+                    }
+                }
+            }
+
+            if module_names.is_empty() {
+                if !tags.is_empty() {
+                    // Nothing matched the requested tags
+                    // Close pools
+                    stores.close().await?;
+                    return Ok(true);
+                }
+                // Try to add host module
+                module_names.push(["hosts/", &dotdeploy_config.hostname].join("").to_string());
+            } else if dotdeploy_config.auto_host_module {
+                // Add the host module to the requested modules, if it exists
+                let host_module = ["hosts/", &dotdeploy_config.hostname].join("");
+                if !module_names.contains(&host_module)
+                    && modules::module_path(&host_module, &dotdeploy_config)
+                        .join("config.toml")
+                        .is_file()
+                {
+                    module_names.push(host_module);
+                }
+            }
+
+            if *check {
+                let up_to_date =
+                    crate::check::check(Arc::clone(&stores), &module_names, cli.format).await?;
+
+                // Close pools
+                stores.close().await?;
+
+                return Ok(up_to_date);
+            }
+
+            let result = deploy_modules(
+                module_names,
+                context,
+                &dotdeploy_config,
+                stores,
+                handlebars,
+                components
+                    .as_deref()
+                    .unwrap_or(cli::Component::value_variants()),
+                profile_modules,
+                cli.format,
+            )
+            .await;
+
+            // The user store, the run state and the caches were written as root
+            if for_user.is_some() {
+                target_user::restore_ownership(&dotdeploy_config)?;
+            }
+
+            // Notify about the outcome of automatic runs
+            if cli.auto && dotdeploy_config.notify {
+                notify::deploy_finished(&result);
+            }
+            result?;
+
+            Ok(true)
+        }
+        cli::Commands::Remove {
+            modules,
+            keep_packages,
+            keep_files,
+            purge,
+            dry_run,
+        } => match modules {
+            None => {
+                warn!("Not implemented yet");
+                Ok(true)
+            }
+            Some(modules) => {
+                // let mut modules = vec![["hosts/", &dotdeploy_config.hostname.unwrap()].join("")];
+                let mut files: Vec<crate::store::files::StoreFile> = vec![];
+                // Try to add host module
+                // let host_module = ["hosts/", &dotdeploy_config.hostname].join("");
+                let mut module_queue = modules::queue::ModuleQueue {
+                    modules: std::collections::BTreeSet::new(),
+                    deployed: std::collections::BTreeSet::new(),
+                    context,
+                };
+
+                module_queue
+                    .add_modules(modules, &dotdeploy_config, true)
+                    .await?;
+
+                // Only the requested modules are removed, their dependencies stay in place
+                let module_configs: Vec<modules::Module> =
+                    std::mem::take(&mut module_queue.modules)
+                        .into_iter()
+                        .filter(|m| m.reason == "manual")
+                        .collect();
+
+                for module in modules.iter() {
+                    let user_files = stores
+                        .user_store
+                        .get_all_files(&module)
+                        .await
+                        .map_err(|e| e.into_anyhow())
+                        .with_context(|| {
+                            format!(
+                                "Failed to get files for module {:?} from user store",
+                                &module
+                            )
+                        })?;
+                    for f in user_files.into_iter() {
+                        files.push(f);
+                    }
+
+                    if let Some(sys_store) = &stores.system_store {
+                        let sys_files = sys_store
+                            .get_all_files(&module)
+                            .await
+                            .map_err(|e| e.into_anyhow())
+                            .with_context(|| {
+                                format!(
+                                    "Failed to get files for module {:?} from system store",
+                                    &module
+                                )
+                            })?;
+                        for f in sys_files.into_iter() {
+                            files.push(f);
+                        }
+                    };
+                }
+
+                let phases = phases::assign_module_config(
+                    module_configs,
+                    facts::template_context(&module_queue.context)?,
+                    &stores,
+                    &mut messages,
+                    &mut generators,
+                    &handlebars,
+                    &dotdeploy_config,
+                )
+                .await?;
+
+                if *dry_run {
+                    crate::remove::preview(
+                        &phases,
+                        Arc::clone(&stores),
+                        &files,
+                        *keep_packages,
+                        *keep_files,
+                        cli.format,
+                    )
+                    .await?;
+
+                    // Close pools
+                    stores.close().await?;
+
+                    return Ok(true);
+                }
+
+                // Destinations of the files, to purge their backups afterwards
+                let paths: Vec<String> = files.iter().map(|f| f.destination.clone()).collect();
+                stores.start_run("remove", modules).await?;
+
+                if let Err(e) = crate::remove::remove(
+                    phases,
+                    Arc::clone(&stores),
+                    files,
+                    &dotdeploy_config,
+                    *keep_packages,
+                    *keep_files,
+                )
+                .await
+                {
+                    stores.finish_run(false).await?;
+                    // Close pools, also if the removal failed or has been cancelled
+                    stores.close().await?;
+                    return Err(e);
+                }
+
+                // Remove modules from the stores
+                for module in modules.iter() {
+                    if *purge {
+                        stores
+                            .user_store
+                            .purge_module(module, paths.clone())
+                            .await
+                            .map_err(|e| e.into_anyhow())?;
+                    } else {
+                        stores
+                            .user_store
+                            .remove_module(module)
+                            .await
+                            .map_err(|e| e.into_anyhow())?;
+                    }
+                    if let Some(sys_store) = &stores.system_store {
+                        if *purge {
+                            sys_store
+                                .purge_module(module, paths.clone())
+                                .await
+                                .map_err(|e| e.into_anyhow())?;
+                        } else {
+                            sys_store
+                                .remove_module(module)
+                                .await
+                                .map_err(|e| e.into_anyhow())?;
+                        }
+                    }
+                }
+
+                // Generate files
+                crate::modules::generate::generate_files(
+                    Arc::clone(&stores),
+                    generators,
+                    facts::template_context(&module_queue.context)?,
+                    Arc::clone(&handlebars),
+                )
+                .await?;
+
+                crate::deploy::run_hooks(
+                    "post_remove",
+                    &dotdeploy_config.hooks.post_remove,
+                    &facts::template_context(&module_queue.context)?,
+                    &handlebars,
+                )
+                .await?;
+                stores.finish_run(true).await?;
+
+                // Close pools
+                stores.close().await?;
+
+                // Display messages
+                for (module, msgs) in messages.1.iter() {
+                    crate::modules::messages::print(module, msgs);
+                }
+
+                Ok(true)
+            }
+        },
+        cli::Commands::Render {
+            module,
+            file,
+            overrides,
+        } => {
+            crate::render::render(
+                module,
+                file.as_deref(),
+                overrides,
+                context,
+                &dotdeploy_config,
+                &handlebars,
+            )
+            .await?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(true)
+        }
+        cli::Commands::Why { path } => {
+            let managed =
+                crate::why::why(Arc::clone(&stores), path, &dotdeploy_config, cli.format).await?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(managed)
+        }
+        cli::Commands::Explain { module } => {
+            crate::explain::explain(module, context, &dotdeploy_config, &handlebars, cli.format)
+                .await?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(true)
+        }
+        cli::Commands::Daemon { socket } => {
+            let socket = socket
+                .clone()
+                .unwrap_or_else(crate::daemon::default_socket_path);
+            crate::daemon::run(
+                &socket,
+                Arc::clone(&stores),
+                dotdeploy_config,
+                context,
+                handlebars,
+            )
+            .await?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(true)
+        }
+        cli::Commands::Stats { module } => {
+            crate::stats::show(Arc::clone(&stores), module.as_deref(), cli.format).await?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(true)
+        }
+        cli::Commands::Fsck { fix } => {
+            let healthy = crate::fsck::fsck(Arc::clone(&stores), *fix).await?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(healthy)
+        }
+        cli::Commands::Backups { command } => match command {
+            cli::BackupsCommands::Prune { dry_run } => {
+                crate::backups::prune(Arc::clone(&stores), &dotdeploy_config, *dry_run).await?;
+
+                // Close pools
+                stores.close().await?;
+
+                Ok(true)
+            }
+            cli::BackupsCommands::List => {
+                crate::backups::list(Arc::clone(&stores)).await?;
+
+                // Close pools
+                stores.close().await?;
+
+                Ok(true)
+            }
+            cli::BackupsCommands::Show { path } => {
+                crate::backups::show(Arc::clone(&stores), path).await?;
+
+                // Close pools
+                stores.close().await?;
+
+                Ok(true)
+            }
+            cli::BackupsCommands::Restore { path, to } => {
+                let restored =
+                    crate::backups::restore(Arc::clone(&stores), path, to.as_deref()).await?;
+
+                // Close pools
+                stores.close().await?;
+
+                Ok(restored)
+            }
+        },
+        cli::Commands::List { files, packages } => {
+            crate::list::list(Arc::clone(&stores), *files, *packages, cli.format).await?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(true)
+        }
+        cli::Commands::Search { term } => {
+            let found =
+                crate::index::search(&dotdeploy_config, term.as_deref().unwrap_or(""), cli.format)?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(found)
+        }
+        cli::Commands::Index { output } => {
+            crate::index::index(&dotdeploy_config, output.as_deref())?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(true)
+        }
+        cli::Commands::Lookup {
+            target,
+            module,
+            long,
+        } => {
+            let found = crate::lookup::lookup(
+                Arc::clone(&stores),
+                target.as_deref(),
+                module.as_deref(),
+                *long,
+                cli.format,
+            )
+            .await?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(found)
+        }
+        cli::Commands::Logs {
+            list,
+            run,
+            file,
+            level,
+            module,
+            lines,
+        } => {
+            if *list {
+                crate::logs::list(&dotdeploy_config, cli.format)?;
+            } else {
+                crate::logs::show(
+                    Arc::clone(&stores),
+                    &dotdeploy_config,
+                    run.as_deref(),
+                    file.as_deref(),
+                    &crate::logs::LogFilter {
+                        level: *level,
+                        module: module.clone(),
+                        lines: *lines,
+                    },
+                    cli.format,
+                )
+                .await?;
+            }
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(true)
+        }
+        cli::Commands::History {
+            file,
+            module,
+            limit,
+        } => {
+            crate::history::history(
+                Arc::clone(&stores),
+                file.as_deref(),
+                module.as_deref(),
+                *limit,
+                cli.format,
+            )
+            .await?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(true)
+        }
+        cli::Commands::Orphans { remove } => {
+            let clean = crate::orphans::orphans(
+                Arc::clone(&stores),
+                &dotdeploy_config,
+                *remove,
+                cli.format,
+            )
+            .await?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(clean)
+        }
+        cli::Commands::Adopt {
+            target,
+            module,
+            source,
+        } => {
+            stores
+                .start_run("adopt", std::slice::from_ref(module))
+                .await?;
+            let result = crate::adopt::adopt(
+                Arc::clone(&stores),
+                &dotdeploy_config,
+                target,
+                module,
+                source.as_deref(),
+            )
+            .await;
+            stores.finish_run(result.is_ok()).await?;
+            result?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(true)
+        }
+        cli::Commands::Add {
+            module,
+            target,
+            source,
+        } => {
+            stores
+                .start_run("add", std::slice::from_ref(module))
+                .await?;
+            let result = crate::adopt::add(
+                Arc::clone(&stores),
+                &dotdeploy_config,
+                module,
+                target,
+                source.as_deref(),
+                &facts::template_context(&context)?,
+                &handlebars,
+            )
+            .await;
+            stores.finish_run(result.is_ok()).await?;
+            result?;
+
+            // Close pools
+            stores.close().await?;
+
+            Ok(true)
+        }
+        cli::Commands::Exclude { command } => match command {
+            cli::ExcludeCommands::Add { path } => {
+                crate::exclude::add(Arc::clone(&stores), path).await?;
+
+                // Close pools
+                stores.close().await?;
+
+                Ok(true)
+            }
+            cli::ExcludeCommands::List => {
+                crate::exclude::list(Arc::clone(&stores)).await?;
+
+                // Close pools
+                stores.close().await?;
+
+                Ok(true)
+            }
+            cli::ExcludeCommands::Remove { path } => {
+                let removed = crate::exclude::remove(Arc::clone(&stores), path).await?;
+
+                // Close pools
+                stores.close().await?;
+
+                Ok(removed)
+            }
+        },
+        cli::Commands::Note { command } => match command {
+            cli::NoteCommands::Add { target, text } => {
+                crate::notes::add(Arc::clone(&stores), target, text, &dotdeploy_config).await?;
+
+                // Close pools
+                stores.close().await?;
+
+                Ok(true)
+            }
+            cli::NoteCommands::List { target } => {
+                crate::notes::list(Arc::clone(&stores), target.as_deref(), &dotdeploy_config)
+                    .await?;
+
+                // Close pools
+                stores.close().await?;
+
+                Ok(true)
+            }
+            cli::NoteCommands::Remove { id } => {
+                let removed = crate::notes::remove(Arc::clone(&stores), *id).await?;
+
+                // Close pools
+                stores.close().await?;
+
+                Ok(removed)
+            }
+        },
+        cli::Commands::Completions { .. }
+        | cli::Commands::Complete { .. }
+        | cli::Commands::GenMan { .. }
+        | cli::Commands::Config { .. }
+        | cli::Commands::Bootstrap { .. }
+        | cli::Commands::Env { .. }
+        | cli::Commands::SelfUpdate { .. } => {
+            unreachable!("Handled before the stores are opened")
+        }
+        cli::Commands::Try { module } => {
+            let overlay = overlay.context("HOME overlay was not created")?;
+            deploy_modules(
+                vec![module.to_string()],
+                context,
+                &dotdeploy_config,
+                stores,
+                handlebars,
+                cli::Component::value_variants(),
+                None,
+                cli.format,
+            )
+            .await?;
+
+            sandbox::spawn_shell(overlay.path())
+        }
+    }
+}
+
+/// Deploys the given modules and their dependencies.
+///
+/// The modules are added to the stores, their phases get executed and the generated files are
+/// written. Afterwards, the stores are closed and a summary including the deploy messages of the
+/// modules is displayed.
+///
+/// # Arguments
+///
+/// * `module_names` - Names of the modules to deploy
+/// * `context` - Context values for template rendering
+/// * `dotdeploy_config` - Configuration for the deployment process
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `handlebars` - Handlebars instance for template rendering
+/// * `components` - Components of the deployment to execute
+/// * `profile` - Name and modules of the deployed profile, recorded once the deployment succeeded
+/// * `format` - Output format of the summary
+///
+/// # Returns
+///
+/// A Result containing the summary of the deployment
+#[allow(clippy::too_many_arguments)]
+async fn deploy_modules(
+    module_names: Vec<String>,
+    context: std::collections::BTreeMap<String, String>,
+    dotdeploy_config: &config::DotdeployConfig,
+    stores: Arc<Stores>,
+    handlebars: Arc<handlebars::Handlebars<'static>>,
+    components: &[cli::Component],
+    profile: Option<(&str, &[String])>,
+    format: cli::OutputFormat,
+) -> Result<report::DeploySummary> {
+    let mut result = deploy_queue(
+        module_names,
+        context,
+        dotdeploy_config,
+        &stores,
+        handlebars,
+        components,
+    )
+    .await;
+
+    // Record the profile of the modules
+    if let (Ok(_), Some((profile, modules))) = (&result, profile) {
+        if let Err(e) = stores.add_profile_modules(profile, modules).await {
+            result = Err(e);
+        }
+    }
+
+    // Close pools, also if the deployment failed or has been cancelled
+    stores.close().await?;
+
+    // Display summary and messages
+    let summary = result?;
+    report::emit(&summary, format)?;
+
+    Ok(summary)
+}
+
+/// Deploys the given modules and their dependencies, keeping the stores open.
+///
+/// Statistics of the run are recorded, also if the deployment fails.
+///
+/// # Arguments
+///
+/// * `module_names` - Names of the modules to deploy
+/// * `context` - Context values for template rendering
+/// * `dotdeploy_config` - Configuration for the deployment process
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `handlebars` - Handlebars instance for template rendering. A shared registry is copied before
+///   the partials of the modules are registered.
+/// * `components` - Components of the deployment to execute
+///
+/// # Returns
+///
+/// A Result containing the summary of the deployment
+pub(crate) async fn deploy_queue(
+    module_names: Vec<String>,
+    context: std::collections::BTreeMap<String, String>,
+    dotdeploy_config: &config::DotdeployConfig,
+    stores: &Arc<Stores>,
+    mut handlebars: Arc<handlebars::Handlebars<'static>>,
+    components: &[cli::Component],
+) -> Result<report::DeploySummary> {
+    let mut messages: (
+        std::collections::BTreeMap<String, Vec<crate::modules::messages::Message>>,
+        std::collections::BTreeMap<String, Vec<crate::modules::messages::Message>>,
+    ) = (
+        std::collections::BTreeMap::new(),
+        std::collections::BTreeMap::new(),
+    );
+
+    let mut generators: std::collections::BTreeMap<
+        std::path::PathBuf,
+        crate::modules::generate::Generate,
+    > = std::collections::BTreeMap::new();
+
+    let queue_start = std::time::Instant::now();
+
+    // Modules deployed in earlier runs
+    let deployed: Vec<(String, std::path::PathBuf)> = stores
+        .user_store
+        .get_all_modules()
+        .await
+        .map_err(|e| e.into_anyhow())?
+        .into_iter()
+        .map(|m| (m.name, std::path::PathBuf::from(m.location)))
+        .collect();
+
+    let mut module_queue = modules::queue::ModuleQueue {
+        modules: std::collections::BTreeSet::new(),
+        deployed: deployed.iter().map(|(name, _)| name.clone()).collect(),
+        context,
+    };
+    module_queue
+        .add_modules(&module_names, dotdeploy_config, true)
+        .await?;
+
+    // Warn about conflicts with modules deployed in earlier runs
+    for (module, conflict) in module_queue.deployed_conflicts(&deployed) {
+        warn!(
+            "Module {} conflicts with the deployed module {}",
+            module, conflict
+        );
+    }
+
+    trace!("Context values: {:#?}", &module_queue.context);
+
+    // Register partials and helpers of the modules before any template is rendered
+    module_queue.register_templates(Arc::make_mut(&mut handlebars))?;
+
+    // Add modules to stores
+    for module in module_queue.modules.iter() {
+        let m = crate::store::modules::StoreModule {
+            name: module.name.clone(),
+            location: utils::file_fs::path_to_string(&module.location)?,
+            user: Some(std::env::var("USER")?),
+            reason: module.reason.clone(),
+            depends: module.config.depends.clone().map(|deps| deps.join(", ")),
+            date: chrono::offset::Local::now(),
+        };
+        // User store
+        stores
+            .user_store
+            .add_module(m.clone())
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        // System store
+        if let Some(ref sys_store) = stores.system_store {
+            sys_store.add_module(m).await.map_err(|e| e.into_anyhow())?;
+        }
+    }
+
+    // Keep the module names to record statistics once the deployment has finished
+    let module_names: Vec<String> = module_queue
+        .modules
+        .iter()
+        .map(|m| m.name.clone())
+        .collect();
+    stores.start_run("deploy", &module_names).await?;
+    let on_failure: std::collections::BTreeMap<String, Vec<modules::actions::ModuleAction>> =
+        module_queue
+            .modules
+            .iter()
+            .filter_map(|m| Some((m.name.clone(), m.config.on_failure.clone()?)))
+            .collect();
+    let levels = module_queue.levels(dotdeploy_config);
+    timings::record(timings::Category::Queue, None, queue_start);
+    let start = std::time::Instant::now();
+
+    let result: Result<()> = async {
+        let run_hooks = components.contains(&cli::Component::Actions);
+        if run_hooks {
+            crate::deploy::run_hooks(
+                "pre_deploy",
+                &dotdeploy_config.hooks.pre_deploy,
+                &facts::template_context(&module_queue.context)?,
+                &handlebars,
+            )
+            .await?;
+        }
+
+        // Process the modules by level, so dependencies come first
+        let mut ordered: Vec<modules::Module> = module_queue.modules.into_iter().collect();
+        ordered.sort_by_key(|m| levels.get(&m.name).copied().unwrap_or_default());
+
+        let phases_start = std::time::Instant::now();
+        let phases = phases::assign_module_config(
+            ordered,
+            facts::template_context(&module_queue.context)?,
+            stores,
+            &mut messages,
+            &mut generators,
+            &handlebars,
+            dotdeploy_config,
+        )
+        .await?;
+        timings::record(timings::Category::Queue, None, phases_start);
+
+        crate::deploy::deploy(
+            phases,
+            Arc::clone(stores),
+            facts::template_context(&module_queue.context)?,
+            Arc::clone(&handlebars),
+            &levels,
+            dotdeploy_config,
+            components,
+        )
+        .await?;
+
+        // Generate files
+        if components.contains(&cli::Component::Files) {
+            crate::modules::generate::generate_files(
+                Arc::clone(stores),
+                generators,
+                facts::template_context(&module_queue.context)?,
+                Arc::clone(&handlebars),
+            )
+            .await?;
+        }
+
+        if run_hooks {
+            crate::deploy::run_hooks(
+                "post_deploy",
+                &dotdeploy_config.hooks.post_deploy,
+                &facts::template_context(&module_queue.context)?,
+                &handlebars,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+    .await;
+
+    // Run the on_failure actions before the run aborts
+    if let Err(e) = &result {
+        if components.contains(&cli::Component::Actions) && !on_failure.is_empty() {
+            crate::deploy::run_on_failure(
+                e,
+                &on_failure,
+                &facts::template_context(&module_queue.context)?,
+                &handlebars,
+            )
+            .await;
+        }
+    }
+
+    let duration = start.elapsed();
+    let module_times = timings::take_module_times(&module_names);
+    let recorded: Result<()> = async {
+        stores.finish_run(result.is_ok()).await?;
+        crate::stats::record(
+            Arc::clone(stores),
+            &module_names,
+            &module_times,
+            result.is_ok(),
+        )
+        .await
+    }
+    .await;
+
+    // The error of the deployment takes precedence over failing to record it
+    if let Err(e) = recorded {
+        if result.is_ok() {
+            return Err(e);
+        }
+        warn!("Failed to record the failed deployment: {:#}", e);
+    }
+    result?;
+
+    Ok(report::DeploySummary {
+        modules: module_names,
+        duration_ms: duration.as_millis(),
+        messages: messages.0,
+        timings: timings::collect(),
+    })
+}
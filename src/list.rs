@@ -0,0 +1,293 @@
+//! This module lists the deployed modules.
+//!
+//! For each module in the user and system store, the reason it was deployed, the date of the last
+//! deployment and the number of managed files and packages is shown, together with the version and
+//! description of the module. Files and packages can be expanded per module.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::cli::OutputFormat;
+use crate::modules::config::ModuleConfig;
+use crate::report::{emit, serialize_date, Report};
+use crate::store::db::Store;
+use crate::store::modules::StoreModule;
+use crate::Stores;
+
+/// Reads the configuration of a deployed module from the recorded location.
+///
+/// If the configuration can't be read anymore, `None` is returned.
+fn module_config(module: &StoreModule) -> Option<ModuleConfig> {
+    match ModuleConfig::read_config(&module.location) {
+        Ok(c) => Some(c),
+        Err(e) => {
+            debug!("Failed to read config of module {}: {:?}", module.name, e);
+            None
+        }
+    }
+}
+
+/// Collects the packages declared by a module.
+///
+/// Packages are not tracked in the store, thus they are read from the module configuration.
+/// Packages guarded by a condition are marked as such.
+fn module_packages(config: &ModuleConfig) -> Vec<String> {
+    config
+        .packages
+        .iter()
+        .flatten()
+        .flat_map(|p| {
+            let conditional = p.eval_when.is_some();
+            p.install.iter().map(move |name| {
+                if conditional {
+                    format!("{} (conditional)", name)
+                } else {
+                    name.to_string()
+                }
+            })
+        })
+        .collect()
+}
+
+/// A file of a deployed module.
+#[derive(Serialize, Debug)]
+pub(crate) struct ModuleFileEntry {
+    operation: String,
+    destination: String,
+}
+
+/// A deployed module.
+#[derive(Serialize, Debug)]
+pub(crate) struct ModuleEntry {
+    name: String,
+    /// Version from the module config
+    version: Option<String>,
+    /// Description from the module config
+    description: Option<String>,
+    store: &'static str,
+    reason: String,
+    #[serde(serialize_with = "serialize_date")]
+    date: chrono::DateTime<chrono::Local>,
+    file_count: usize,
+    /// Unknown if the module config can't be read anymore
+    package_count: Option<usize>,
+    location: String,
+    /// Profiles the module was deployed with
+    profiles: Vec<String>,
+    /// Operation and destination of each file, only collected if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<ModuleFileEntry>>,
+    /// Packages of the module, only collected if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    packages: Option<Vec<String>>,
+}
+
+/// List of deployed modules.
+#[derive(Serialize, Debug)]
+struct ModuleList {
+    modules: Vec<ModuleEntry>,
+    /// Whether packages were requested, to tell unknown packages apart in text output
+    #[serde(skip)]
+    show_packages: bool,
+}
+
+impl Report for ModuleList {
+    fn print_text(&self) {
+        for module in self.modules.iter() {
+            let name = match &module.version {
+                Some(v) => format!("{} {}", module.name, v),
+                None => module.name.clone(),
+            };
+            println!(
+                "{:<30}  {:<6}  {:<9}  {}  files: {:<4}  packages: {}",
+                name,
+                module.store,
+                module.reason,
+                module.date.format("%Y-%m-%d %H:%M"),
+                module.file_count,
+                module
+                    .package_count
+                    .map(|c| c.to_string())
+                    .unwrap_or_else(|| "?".to_string()),
+            );
+            if let Some(description) = &module.description {
+                println!("    {}", description);
+            }
+            if !module.profiles.is_empty() {
+                println!("    profiles   {}", module.profiles.join(", "));
+            }
+
+            if let Some(files) = &module.files {
+                for f in files.iter() {
+                    println!("    {:<9}  {}", f.operation, f.destination);
+                }
+            }
+            if self.show_packages {
+                match &module.packages {
+                    Some(pkgs) => {
+                        for p in pkgs.iter() {
+                            println!("    package    {}", p);
+                        }
+                    }
+                    None => println!("    module config not found at {}", module.location),
+                }
+            }
+        }
+
+        if self.modules.is_empty() {
+            info!("No modules deployed");
+        }
+    }
+}
+
+/// Collects the modules of a single store.
+async fn collect_modules(
+    store: &Store,
+    label: &'static str,
+    files: bool,
+    packages: bool,
+) -> Result<Vec<ModuleEntry>> {
+    let mut modules = store.get_all_modules().await.map_err(|e| e.into_anyhow())?;
+    modules.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut entries = Vec::with_capacity(modules.len());
+    for module in modules.into_iter() {
+        let module_files = store
+            .get_all_files(&module.name)
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        let config = module_config(&module);
+        let module_pkgs = config.as_ref().map(module_packages);
+        let profiles = store
+            .get_module_profiles(&module.name)
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        entries.push(ModuleEntry {
+            version: config.as_ref().and_then(|c| c.version.clone()),
+            description: config.and_then(|c| c.description),
+            store: label,
+            reason: module.reason,
+            date: module.date,
+            file_count: module_files.len(),
+            package_count: module_pkgs.as_ref().map(Vec::len),
+            location: module.location,
+            profiles,
+            files: files.then(|| {
+                module_files
+                    .into_iter()
+                    .map(|f| ModuleFileEntry {
+                        operation: f.operation,
+                        destination: f.destination,
+                    })
+                    .collect()
+            }),
+            packages: if packages { module_pkgs } else { None },
+            name: module.name,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Collects the deployed modules of the user and system store.
+///
+/// # Arguments
+///
+/// * `stores` - Database stores (user and optional system store)
+/// * `files` - Also collect the files of each module
+/// * `packages` - Also collect the packages of each module
+///
+/// # Returns
+///
+/// A Result containing the modules, sorted by store and name
+pub(crate) async fn collect(
+    stores: &Stores,
+    files: bool,
+    packages: bool,
+) -> Result<Vec<ModuleEntry>> {
+    let mut modules = collect_modules(&stores.user_store, "user", files, packages).await?;
+    if let Some(sys_store) = &stores.system_store {
+        modules.extend(collect_modules(sys_store, "system", files, packages).await?);
+    }
+
+    Ok(modules)
+}
+
+/// Lists deployed modules of the user and system store.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `files` - Also list the files of each module
+/// * `packages` - Also list the packages of each module
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn list(
+    stores: Arc<Stores>,
+    files: bool,
+    packages: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    emit(
+        &ModuleList {
+            modules: collect(&stores, files, packages).await?,
+            show_packages: packages,
+        },
+        format,
+    )
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_packages() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        std::fs::write(
+            temp_dir.path().join("config.toml"),
+            r#"
+[[packages]]
+install = ["git", "zsh"]
+
+[[packages]]
+install = ["docker"]
+eval_when = "false"
+"#,
+        )?;
+
+        let module = StoreModule {
+            name: "test".to_string(),
+            location: crate::utils::file_fs::path_to_string(temp_dir.path())?,
+            user: None,
+            reason: "manual".to_string(),
+            depends: None,
+            date: chrono::offset::Local::now(),
+        };
+        assert_eq!(
+            module_config(&module).map(|c| module_packages(&c)),
+            Some(vec![
+                "git".to_string(),
+                "zsh".to_string(),
+                "docker (conditional)".to_string()
+            ])
+        );
+
+        let module = StoreModule {
+            location: "/nonexistent".to_string(),
+            ..module
+        };
+        assert!(module_config(&module).is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,357 @@
+//! This module shows the logs of earlier runs.
+//!
+//! Every run has its own folder in `logs_dir`, named after its start time. It contains the log of
+//! dotdeploy itself and the output of each action, see [crate::utils::task_log]. Messages of the
+//! log of dotdeploy can be filtered by level, all logs by module.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::cli::OutputFormat;
+use crate::report::{emit, Report};
+use crate::utils::task_log;
+use crate::Stores;
+
+/// Format of the run IDs, which are the names of the run folders.
+const RUN_ID_FORMAT: &str = "%Y-%m-%dT%H-%M-%S";
+
+/// Filters applied to the shown logs.
+#[derive(Debug, Default)]
+pub(crate) struct LogFilter {
+    /// Only show messages of at least this level. Applies to the log of dotdeploy itself.
+    pub(crate) level: Option<log::Level>,
+    /// Only show the logs of actions of this module and messages mentioning it.
+    pub(crate) module: Option<String>,
+    /// Only show the last lines of each log.
+    pub(crate) lines: Option<usize>,
+}
+
+/// A run with logs.
+#[derive(Serialize, Debug)]
+struct RunEntry {
+    id: String,
+    /// Names of the log files of the run
+    logs: Vec<String>,
+}
+
+/// All runs with logs.
+#[derive(Serialize, Debug)]
+struct RunsReport {
+    runs: Vec<RunEntry>,
+}
+
+impl Report for RunsReport {
+    fn print_text(&self) {
+        for r in self.runs.iter() {
+            println!("{}  {} logs", r.id, r.logs.len());
+        }
+
+        if self.runs.is_empty() {
+            info!("No logs found");
+        }
+    }
+}
+
+/// A log file of a run.
+#[derive(Serialize, Debug)]
+struct LogEntry {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+/// The logs of a run.
+#[derive(Serialize, Debug)]
+struct LogsReport {
+    run: String,
+    logs: Vec<LogEntry>,
+}
+
+impl Report for LogsReport {
+    fn print_text(&self) {
+        for (i, l) in self.logs.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            println!("==> {} <==", l.path.display());
+            for line in l.lines.iter() {
+                println!("{}", line);
+            }
+        }
+
+        if self.logs.is_empty() {
+            info!("No matching logs in run {}", self.run);
+        }
+    }
+}
+
+/// Finds the IDs of all runs with logs, oldest first.
+pub(crate) fn runs(logs_dir: &Path) -> Result<Vec<String>> {
+    if !logs_dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut runs = vec![];
+    for entry in std::fs::read_dir(logs_dir)
+        .with_context(|| format!("Failed to read directory {:?}", logs_dir))?
+    {
+        let entry = entry?;
+        let id = entry.file_name().to_string_lossy().to_string();
+        if entry.path().is_dir() && NaiveDateTime::parse_from_str(&id, RUN_ID_FORMAT).is_ok() {
+            runs.push(id);
+        }
+    }
+    runs.sort();
+
+    Ok(runs)
+}
+
+/// Finds the last run started before a point in time.
+fn run_at(runs: &[String], date: NaiveDateTime) -> Option<&String> {
+    runs.iter().rev().find(|id| {
+        NaiveDateTime::parse_from_str(id, RUN_ID_FORMAT).is_ok_and(|start| start <= date)
+    })
+}
+
+/// Extracts the level of a message of the log of dotdeploy, e.g. `12:00:00 [WARN] message`.
+fn line_level(line: &str) -> Option<log::Level> {
+    let start = line.find('[')?;
+    let end = start + line[start..].find(']')?;
+    line[start + 1..end].trim().parse().ok()
+}
+
+/// Filters the messages of the log of dotdeploy.
+///
+/// Lines without a level continue the message before them and are kept along with it.
+fn filter_run_log<'a>(content: &'a str, filter: &LogFilter) -> Vec<&'a str> {
+    let mut keep = true;
+    content
+        .lines()
+        .filter(|line| {
+            if let Some(level) = line_level(line) {
+                keep = filter.level.is_none_or(|min| level <= min)
+                    && filter
+                        .module
+                        .as_ref()
+                        .is_none_or(|m| line.contains(m.as_str()));
+            }
+            keep
+        })
+        .collect()
+}
+
+/// Collects the logs of a run, the log of dotdeploy first.
+fn collect(run_dir: &Path, filter: &LogFilter) -> Result<Vec<LogEntry>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(run_dir)
+        .with_context(|| format!("Failed to read directory {:?}", run_dir))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file())
+        .collect();
+    paths.sort_by_key(|p| (p.file_name() != Some(task_log::RUN_LOG.as_ref()), p.clone()));
+
+    let prefix = filter
+        .module
+        .as_ref()
+        .map(|m| format!("{}-", task_log::sanitize(&m.replace('/', "-"))));
+
+    let mut logs = vec![];
+    for path in paths {
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read log {:?}", path))?;
+        let mut lines: Vec<&str> = if path.file_name() == Some(task_log::RUN_LOG.as_ref()) {
+            filter_run_log(&content, filter)
+        } else if prefix.as_ref().is_none_or(|p| {
+            path.file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with(p.as_str()))
+        }) {
+            content.lines().collect()
+        } else {
+            continue;
+        };
+
+        if let Some(n) = filter.lines {
+            lines = lines.split_off(lines.len().saturating_sub(n));
+        }
+        if !lines.is_empty() {
+            logs.push(LogEntry {
+                path,
+                lines: lines.into_iter().map(str::to_string).collect(),
+            });
+        }
+    }
+
+    Ok(logs)
+}
+
+/// Lists all runs with logs.
+///
+/// # Arguments
+///
+/// * `dotdeploy_config` - Configuration providing the `logs_dir`
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result indicating success or failure of reading the logs
+pub(crate) fn list(
+    dotdeploy_config: &crate::config::DotdeployConfig,
+    format: OutputFormat,
+) -> Result<()> {
+    let mut entries = vec![];
+    for id in runs(&dotdeploy_config.logs_dir)? {
+        let run_dir = dotdeploy_config.logs_dir.join(&id);
+        let mut logs: Vec<String> = std::fs::read_dir(&run_dir)
+            .with_context(|| format!("Failed to read directory {:?}", run_dir))?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect();
+        logs.sort();
+        entries.push(RunEntry { id, logs });
+    }
+
+    emit(&RunsReport { runs: entries }, format)
+}
+
+/// Shows the logs of a run.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `dotdeploy_config` - Configuration providing the `logs_dir`
+/// * `run` - ID of the run to show, defaults to the latest run
+/// * `file` - Show the run which last deployed this file instead
+/// * `filter` - Filters applied to the logs
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result indicating success or failure of reading the logs
+pub(crate) async fn show(
+    stores: Arc<Stores>,
+    dotdeploy_config: &crate::config::DotdeployConfig,
+    run: Option<&str>,
+    file: Option<&Path>,
+    filter: &LogFilter,
+    format: OutputFormat,
+) -> Result<()> {
+    let runs = runs(&dotdeploy_config.logs_dir)?;
+
+    let run = match (run, file) {
+        (Some(run), _) => match runs.iter().find(|r| *r == run) {
+            Some(r) => r,
+            None => bail!("No logs of run {} in {:?}", run, dotdeploy_config.logs_dir),
+        },
+        (None, Some(file)) => {
+            let file = std::path::absolute(file)
+                .with_context(|| format!("Failed to get absolute path of {:?}", file))?;
+            let mut stored = stores.user_store.get_file(&file).await;
+            if let (Err(_), Some(sys_store)) = (&stored, &stores.system_store) {
+                stored = sys_store.get_file(&file).await;
+            }
+            let stored = stored
+                .map_err(|e| e.into_anyhow())
+                .with_context(|| format!("{:?} is not managed by dotdeploy", file))?;
+            match run_at(&runs, stored.date.naive_local()) {
+                Some(r) => r,
+                None => bail!("No logs of the run which deployed {:?}", file),
+            }
+        }
+        (None, None) => match runs.last() {
+            Some(r) => r,
+            None => {
+                info!("No logs found in {:?}", dotdeploy_config.logs_dir);
+                return Ok(());
+            }
+        },
+    };
+
+    let logs = collect(&dotdeploy_config.logs_dir.join(run), filter)?;
+    emit(
+        &LogsReport {
+            run: run.clone(),
+            logs,
+        },
+        format,
+    )
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        for dir in ["2024-05-02T10-00-00", "2024-05-01T09-30-00", "other"] {
+            std::fs::create_dir_all(temp_dir.path().join(dir))?;
+        }
+
+        let runs = runs(temp_dir.path())?;
+        assert_eq!(runs, vec!["2024-05-01T09-30-00", "2024-05-02T10-00-00"]);
+
+        let date = |s| NaiveDateTime::parse_from_str(s, RUN_ID_FORMAT).unwrap();
+        assert_eq!(run_at(&runs, date("2024-05-01T09-30-05")), Some(&runs[0]));
+        assert_eq!(run_at(&runs, date("2024-05-03T00-00-00")), Some(&runs[1]));
+        assert_eq!(run_at(&runs, date("2024-04-30T00-00-00")), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let run_dir = temp_dir.path();
+        std::fs::write(
+            run_dir.join(task_log::RUN_LOG),
+            "10:00:00 [INFO] Starting DEPLOY phase\n\
+             10:00:01 [WARN] zsh: missing plugin\n\
+             details of the warning\n\
+             10:00:02 [DEBUG] nvim: linked init.lua\n\
+             10:00:03 [ERROR] nvim: action failed\n",
+        )?;
+        std::fs::write(run_dir.join("nvim-sh.log"), "one\ntwo\nthree\n")?;
+        std::fs::write(run_dir.join("zsh-sh.log"), "zsh output\n")?;
+
+        let logs = collect(run_dir, &LogFilter::default())?;
+        assert_eq!(logs.len(), 3);
+        assert_eq!(logs[0].path, run_dir.join(task_log::RUN_LOG));
+        assert_eq!(logs[0].lines.len(), 5);
+
+        let logs = collect(
+            run_dir,
+            &LogFilter {
+                level: Some(log::Level::Warn),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(
+            logs[0].lines,
+            vec![
+                "10:00:01 [WARN] zsh: missing plugin",
+                "details of the warning",
+                "10:00:03 [ERROR] nvim: action failed"
+            ]
+        );
+
+        let logs = collect(
+            run_dir,
+            &LogFilter {
+                module: Some("nvim".to_string()),
+                lines: Some(1),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].lines, vec!["10:00:03 [ERROR] nvim: action failed"]);
+        assert_eq!(logs[1].path, run_dir.join("nvim-sh.log"));
+        assert_eq!(logs[1].lines, vec!["three"]);
+
+        Ok(())
+    }
+}
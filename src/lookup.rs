@@ -0,0 +1,169 @@
+//! This module handles queries for deployed files.
+//!
+//! Files can be looked up by their destination, using glob patterns, and by the module owning them.
+//! The result is either a plain list of destinations or a table including the owning module,
+//! operation, source and checksums.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::cli::OutputFormat;
+use crate::report::{emit, serialize_date, Report};
+use crate::store::files::StoreFile;
+use crate::Stores;
+
+/// Turns a user supplied target or pattern into an absolute pattern.
+fn normalize_pattern(pattern: &str) -> Result<String> {
+    let expanded = shellexpand::full(pattern)
+        .with_context(|| format!("Failed to expand path {:?}", pattern))?;
+    let path = std::path::absolute(expanded.as_ref())
+        .with_context(|| format!("Failed to get absolute path of {:?}", pattern))?;
+    crate::utils::file_fs::path_to_string(path)
+}
+
+/// A deployed file found by a lookup.
+#[derive(Serialize, Debug)]
+pub(crate) struct LookupEntry {
+    store: &'static str,
+    module: String,
+    operation: String,
+    destination: String,
+    source: Option<String>,
+    source_checksum: Option<String>,
+    destination_checksum: Option<String>,
+    user: Option<String>,
+    #[serde(serialize_with = "serialize_date")]
+    date: chrono::DateTime<chrono::Local>,
+}
+
+impl LookupEntry {
+    fn new(store: &'static str, f: StoreFile) -> Self {
+        LookupEntry {
+            store,
+            date: f.date,
+            module: f.module,
+            operation: f.operation,
+            destination: f.destination,
+            source: f.source,
+            source_checksum: f.source_checksum,
+            destination_checksum: f.destination_checksum,
+            user: f.user,
+        }
+    }
+}
+
+/// Result of a lookup.
+#[derive(Serialize, Debug)]
+struct LookupReport {
+    /// Show the details of each file in text output
+    #[serde(skip)]
+    long: bool,
+    files: Vec<LookupEntry>,
+}
+
+impl Report for LookupReport {
+    fn print_text(&self) {
+        if self.long {
+            println!(
+                "{:<6}  {:<20}  {:<9}  {:<64}  {:<64}  {:<40}  SOURCE",
+                "STORE",
+                "MODULE",
+                "OPERATION",
+                "SOURCE CHECKSUM",
+                "DESTINATION CHECKSUM",
+                "DESTINATION"
+            );
+            for f in self.files.iter() {
+                println!(
+                    "{:<6}  {:<20}  {:<9}  {:<64}  {:<64}  {:<40}  {}",
+                    f.store,
+                    f.module,
+                    f.operation,
+                    f.source_checksum.as_deref().unwrap_or("-"),
+                    f.destination_checksum.as_deref().unwrap_or("-"),
+                    f.destination,
+                    f.source.as_deref().unwrap_or("-"),
+                );
+            }
+        } else {
+            for f in self.files.iter() {
+                println!("{}", f.destination);
+            }
+        }
+
+        if self.files.is_empty() {
+            info!("No matching files found");
+        }
+    }
+}
+
+/// Finds deployed files in the user and system store.
+///
+/// # Arguments
+///
+/// * `stores` - Database stores (user and optional system store)
+/// * `target` - Optional destination path or glob pattern, e.g. `~/.config/*`
+/// * `module` - Optional name of the module owning the files
+///
+/// # Returns
+///
+/// A Result containing the matching files
+pub(crate) async fn find(
+    stores: &Stores,
+    target: Option<&str>,
+    module: Option<&str>,
+) -> Result<Vec<LookupEntry>> {
+    let pattern = target.map(normalize_pattern).transpose()?;
+    let module = module.map(str::to_string);
+
+    let mut files: Vec<LookupEntry> = stores
+        .user_store
+        .find_files(pattern.clone(), module.clone())
+        .await
+        .map_err(|e| e.into_anyhow())?
+        .into_iter()
+        .map(|f| LookupEntry::new("user", f))
+        .collect();
+    if let Some(sys_store) = &stores.system_store {
+        files.extend(
+            sys_store
+                .find_files(pattern, module)
+                .await
+                .map_err(|e| e.into_anyhow())?
+                .into_iter()
+                .map(|f| LookupEntry::new("system", f)),
+        );
+    }
+
+    Ok(files)
+}
+
+/// Looks up deployed files in the user and system store.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `target` - Optional destination path or glob pattern, e.g. `~/.config/*`
+/// * `module` - Optional name of the module owning the files
+/// * `long` - Show module, operation, source and checksums of each file
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result containing `true` if at least one file was found
+pub(crate) async fn lookup(
+    stores: Arc<Stores>,
+    target: Option<&str>,
+    module: Option<&str>,
+    long: bool,
+    format: OutputFormat,
+) -> Result<bool> {
+    let files = find(&stores, target, module).await?;
+
+    let found = !files.is_empty();
+    emit(&LookupReport { long, files }, format)?;
+
+    Ok(found)
+}
@@ -0,0 +1,154 @@
+//! This module generates the man page of dotdeploy.
+//!
+//! The page is rendered as roff from the CLI definition, so it always documents the current
+//! subcommands and options. The configuration keys are described in an additional section.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::CommandFactory;
+
+use crate::cli::Cli;
+use crate::config::CONFIG_KEYS;
+
+/// Escapes text for roff.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('-', "\\-")
+        .lines()
+        .map(|l| {
+            // Lines starting with a dot or apostrophe would be read as requests
+            if l.starts_with('.') || l.starts_with('\'') {
+                format!("\\&{}", l)
+            } else {
+                l.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Renders the options of a command.
+fn render_options(cmd: &clap::Command, page: &mut String) {
+    for arg in cmd.get_arguments().filter(|a| !a.is_hide_set()) {
+        let mut flags = vec![];
+        if let Some(s) = arg.get_short() {
+            flags.push(format!("\\fB\\-{}\\fR", s));
+        }
+        if let Some(l) = arg.get_long() {
+            flags.push(format!("\\fB\\-\\-{}\\fR", escape(l)));
+        }
+        if flags.is_empty() {
+            flags.push(format!("\\fI{}\\fR", escape(arg.get_id().as_str())));
+        }
+        page.push_str(&format!(".TP\n{}\n", flags.join(", ")));
+        if let Some(help) = arg.get_long_help().or(arg.get_help()) {
+            page.push_str(&format!("{}\n", escape(&help.to_string())));
+        }
+    }
+}
+
+/// Renders a subcommand and its nested subcommands.
+fn render_subcommand(cmd: &clap::Command, prefix: &str, page: &mut String) {
+    let name = format!("{}{}", prefix, cmd.get_name());
+    page.push_str(&format!(".SS {}\n", escape(&name)));
+    if let Some(about) = cmd.get_long_about().or(cmd.get_about()) {
+        page.push_str(&format!("{}\n", escape(&about.to_string())));
+    }
+    render_options(cmd, page);
+
+    for sub in cmd.get_subcommands().filter(|c| !c.is_hide_set()) {
+        render_subcommand(sub, &format!("{} ", name), page);
+    }
+}
+
+/// Renders the man page.
+fn render() -> String {
+    let cmd = Cli::command();
+    let mut page = format!(
+        ".TH DOTDEPLOY 1 \"\" \"dotdeploy {}\" \"User Commands\"\n",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    page.push_str(".SH NAME\n");
+    // The about text already starts with the program name
+    let about = cmd.get_about().map(|a| a.to_string()).unwrap_or_default();
+    page.push_str(&format!(
+        "dotdeploy \\- {}\n",
+        escape(about.trim_start_matches("dotdeploy -- "))
+    ));
+    page.push_str(".SH SYNOPSIS\n\\fBdotdeploy\\fR [\\fIOPTIONS\\fR] \\fICOMMAND\\fR\n");
+
+    page.push_str(".SH OPTIONS\n");
+    render_options(&cmd, &mut page);
+
+    page.push_str(".SH COMMANDS\n");
+    for sub in cmd.get_subcommands().filter(|c| !c.is_hide_set()) {
+        render_subcommand(sub, "", &mut page);
+    }
+
+    page.push_str(".SH CONFIGURATION\n");
+    page.push_str(
+        "The configuration is read from \\fI/etc/dotdeploy/config.toml\\fR and \
+         \\fI~/.config/dotdeploy/config.toml\\fR, the latter taking precedence. \
+         All keys are optional.\n",
+    );
+    for (key, description) in CONFIG_KEYS.iter() {
+        page.push_str(&format!(
+            ".TP\n\\fB{}\\fR\n{}\n",
+            escape(key),
+            escape(description)
+        ));
+    }
+
+    page
+}
+
+/// Prints the man page or writes it to `dotdeploy.1` in a directory.
+///
+/// # Arguments
+///
+/// * `out_dir` - Optional directory to write the man page to
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) fn generate(out_dir: Option<&Path>) -> Result<()> {
+    let page = render();
+    match out_dir {
+        Some(dir) => {
+            let path = dir.join("dotdeploy.1");
+            std::fs::write(&path, page)
+                .with_context(|| format!("Failed to write man page to {:?}", path))?;
+            info!("Wrote man page to {}", path.display());
+        }
+        None => print!("{}", page),
+    }
+
+    Ok(())
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let page = render();
+        assert!(page.starts_with(".TH DOTDEPLOY 1"));
+        assert!(page.contains(".SS deploy\n"));
+        assert!(page.contains(".SS note add\n"));
+        assert!(page.contains("\\fB\\-\\-components\\fR"));
+        assert!(page.contains(".TP\n\\fBuse_sudo\\fR\n"));
+        // Hidden subcommands are not documented
+        assert!(!page.contains("_complete"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a-b\n.c"), "a\\-b\n\\&.c");
+    }
+}
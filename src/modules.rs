@@ -8,9 +8,13 @@ pub(crate) mod generate;
 pub(crate) mod messages;
 pub(crate) mod packages;
 pub(crate) mod queue;
+pub(crate) mod version;
 
 use std::cmp::Ordering;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use handlebars::Handlebars;
 
 use self::config::ModuleConfig;
 
@@ -46,3 +50,230 @@ impl Ord for Module {
         self.name.cmp(&other.name)
     }
 }
+
+impl Module {
+    /// Registers the templates in the `partials/` directory of the module as Handlebars partials.
+    ///
+    /// Partials are namespaced by the module name and named after their path relative to
+    /// `partials/`, without the extension. For example, `partials/colors/dark.hbs` of the module
+    /// `desktop/sway` is included with `{{> desktop/sway/colors/dark}}`.
+    ///
+    /// # Arguments
+    ///
+    /// * `hb` - The Handlebars registry shared by all templates
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success or failure
+    pub(crate) fn register_partials(&self, hb: &mut Handlebars<'static>) -> Result<()> {
+        let root = self.location.join("partials");
+        if !root.is_dir() {
+            return Ok(());
+        }
+
+        let mut dirs = vec![root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let entries = std::fs::read_dir(&dir)
+                .with_context(|| format!("Failed to read directory {:?}", dir))?;
+            for entry in entries {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                let name = format!(
+                    "{}/{}",
+                    self.name,
+                    path.strip_prefix(&root)?
+                        .with_extension("")
+                        .to_string_lossy()
+                );
+                let template = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read partial {:?}", path))?;
+                hb.register_partial(&name, template)
+                    .with_context(|| format!("Failed to register partial {}", name))?;
+                debug!("Registered partial {} from {:?}", name, path);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Error context marking a failure of a single module.
+///
+/// Errors of file operations and actions carry this context, so the `on_failure` actions of the
+/// failed module can be run.
+#[derive(Debug)]
+pub(crate) struct FailedModule(pub(crate) String);
+
+impl std::fmt::Display for FailedModule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Module {} failed", self.0)
+    }
+}
+
+/// Returns the directory of a module.
+///
+/// Host modules, prefixed with `hosts/`, are located in `hosts_root` and remote modules, prefixed
+/// with `remotes/`, in the cache of [crate::remotes]. All other modules are located in
+/// `modules_root`.
+pub(crate) fn module_path(
+    module_name: &str,
+    dotdeploy_config: &crate::config::DotdeployConfig,
+) -> PathBuf {
+    if let Some(name) = module_name.strip_prefix(crate::remotes::PREFIX) {
+        crate::remotes::cache_dir(dotdeploy_config).join(name)
+    } else if module_name.starts_with("hosts") {
+        dotdeploy_config
+            .hosts_root
+            .join(module_name.trim_start_matches("hosts/"))
+    } else {
+        dotdeploy_config.modules_root.join(module_name)
+    }
+}
+
+/// Finds the names of all modules below a directory.
+///
+/// Every directory containing a `config.toml` is a module. Modules may be nested, e.g.
+/// `desktop/sway`. Symbolic links are not followed, so link loops can't hang the search, and hidden
+/// directories like `.git` are skipped.
+///
+/// # Arguments
+///
+/// * `root` - The directory to search, e.g. `modules_root`
+///
+/// # Returns
+///
+/// A Result containing the sorted module names, relative to `root`
+pub(crate) fn find_modules(root: &Path) -> Result<Vec<String>> {
+    let mut modules = vec![];
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read directory {:?}", dir))?;
+        for entry in entries {
+            let entry = entry?;
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if hidden || !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let path = entry.path();
+            if path.join("config.toml").is_file() {
+                modules.push(path.strip_prefix(root)?.to_string_lossy().to_string());
+            }
+            dirs.push(path);
+        }
+    }
+
+    modules.sort();
+    Ok(modules)
+}
+
+/// Finds the names of all modules below a directory which have at least one of the given tags.
+///
+/// # Arguments
+///
+/// * `root` - The directory to search, e.g. `modules_root`
+/// * `tags` - The tags to look for
+///
+/// # Returns
+///
+/// A Result containing the sorted names of the tagged modules, relative to `root`
+pub(crate) fn find_tagged_modules(root: &Path, tags: &[String]) -> Result<Vec<String>> {
+    let mut modules = vec![];
+    for name in find_modules(root)? {
+        let config = ModuleConfig::read_config(root.join(&name))
+            .with_context(|| format!("Failed to read config of module {}", name))?;
+        if config
+            .tags
+            .is_some_and(|t| t.iter().any(|t| tags.contains(t)))
+        {
+            modules.push(name);
+        }
+    }
+
+    Ok(modules)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_modules() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join("desktop/sway"))?;
+        std::fs::write(root.join("desktop/sway/config.toml"), "")?;
+        std::fs::create_dir_all(root.join(".git/zsh"))?;
+        std::fs::write(root.join(".git/zsh/config.toml"), "")?;
+        // Links are not followed, even if they form a loop
+        std::os::unix::fs::symlink(root, root.join("desktop/loop"))?;
+
+        assert_eq!(find_modules(root)?, vec!["desktop/sway"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_tagged_modules() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join("sway"))?;
+        std::fs::write(root.join("sway/config.toml"), "tags = [\"gui\"]")?;
+        std::fs::create_dir_all(root.join("dev/rust"))?;
+        std::fs::write(
+            root.join("dev/rust/config.toml"),
+            "tags = [\"dev\", \"cli\"]",
+        )?;
+        std::fs::create_dir_all(root.join("zsh"))?;
+        std::fs::write(root.join("zsh/config.toml"), "")?;
+
+        assert_eq!(
+            find_tagged_modules(root, &["gui".to_string()])?,
+            vec!["sway"]
+        );
+        assert_eq!(
+            find_tagged_modules(root, &["cli".to_string(), "gui".to_string()])?,
+            vec!["dev/rust", "sway"]
+        );
+        assert!(find_tagged_modules(root, &["foo".to_string()])?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_partials() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let location = temp_dir.path().join("desktop/sway");
+        std::fs::create_dir_all(location.join("partials/colors"))?;
+        std::fs::write(location.join("partials/colors/dark.hbs"), "bg={{bg}}")?;
+        std::fs::write(location.join("partials/header"), "# managed")?;
+
+        let module = Module {
+            name: "desktop/sway".to_string(),
+            location,
+            reason: "manual".to_string(),
+            config: ModuleConfig::default(),
+        };
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        module.register_partials(&mut hb)?;
+
+        assert_eq!(
+            hb.render_template(
+                "{{> desktop/sway/header}}, {{> desktop/sway/colors/dark}}",
+                &serde_json::json!({"bg": "#000000"})
+            )?,
+            "# managed, bg=#000000"
+        );
+
+        Ok(())
+    }
+}
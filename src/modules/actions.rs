@@ -10,6 +10,10 @@ use serde::de::{self, Error, MapAccess, Visitor};
 use serde::{Deserialize, Deserializer};
 
 use crate::modules::conditional::Conditional;
+use crate::modules::FailedModule;
+use crate::utils::signal;
+use crate::utils::sudo;
+use crate::utils::task_log;
 
 /// Represents an individual action within a deployment process.
 ///
@@ -21,10 +25,25 @@ pub(crate) struct ModuleAction {
     pub(crate) exec: RunExec,
     /// Indicates if the command should be run with sudo privileges.
     sudo: bool,
+    /// Run the command as this user, using `sudo -u`.
+    user: Option<String>,
     /// Additional arguments to be passed to the command.
     args: Option<Vec<String>>,
     /// A conditional expression that determines if the action should be executed.
     pub(crate) eval_when: Option<String>,
+    /// Name of the action, referenced by the `after` field of other actions.
+    pub(crate) name: Option<String>,
+    /// Names of the actions of the same stage which have to finish before this action starts.
+    pub(crate) after: Vec<String>,
+    /// Run the action concurrently with other actions of the same stage. Actions which are not
+    /// parallel wait for all actions listed before them.
+    pub(crate) parallel: bool,
+    /// Number of retries if the action fails.
+    retries: u32,
+    /// Seconds to wait before the first retry. The delay doubles with every further retry.
+    retry_delay: u64,
+    /// The module the action belongs to. Set once the action is assigned to a phase.
+    pub(crate) module: Option<String>,
 }
 
 // Custom deserialization implementation for ModuleAction
@@ -39,11 +58,17 @@ impl<'de> Deserialize<'de> for ModuleAction {
         // interpret the `exec` field.
         #[derive(Deserialize)]
         struct Helper {
-            exec: String,              // Raw command or filepath as a string
-            exec_file: Option<bool>,   // Indicator if exec is a file
-            sudo: Option<bool>,        // Indicator if sudo should be used
-            args: Option<Vec<String>>, // Indicator if additional args should be used
-            eval_when: Option<String>, // Optional condition for execution
+            exec: String,               // Raw command or filepath as a string
+            exec_file: Option<bool>,    // Indicator if exec is a file
+            sudo: Option<bool>,         // Indicator if sudo should be used
+            user: Option<String>,       // User to run the command as
+            args: Option<Vec<String>>,  // Indicator if additional args should be used
+            eval_when: Option<String>,  // Optional condition for execution
+            name: Option<String>,       // Optional name referenced by other actions
+            after: Option<Vec<String>>, // Names of actions to wait for
+            parallel: Option<bool>,     // Indicator if the action may run concurrently
+            retries: Option<u32>,       // Number of retries if the action fails
+            retry_delay: Option<u64>,   // Seconds to wait before the first retry
         }
 
         // Visitor struct for custom processing of the deserialized data.
@@ -87,7 +112,14 @@ impl<'de> Deserialize<'de> for ModuleAction {
                     exec,
                     eval_when: helper.eval_when,
                     sudo: helper.sudo.unwrap_or(false),
+                    user: helper.user,
                     args: helper.args,
+                    name: helper.name,
+                    after: helper.after.unwrap_or_default(),
+                    parallel: helper.parallel.unwrap_or(false),
+                    retries: helper.retries.unwrap_or(0),
+                    retry_delay: helper.retry_delay.unwrap_or(1),
+                    module: None,
                 })
             }
         }
@@ -117,67 +149,175 @@ pub(crate) enum RunExec {
 impl ModuleAction {
     /// Executes the action.
     ///
-    /// This method runs the action based on its configuration, handling both direct code execution
-    /// and file execution, with or without sudo.
+    /// A failed action is retried up to `retries` times with exponential backoff. Errors of actions
+    /// belonging to a module are marked with [FailedModule]. Actions are skipped when deploying
+    /// below a target root, as they would run on the live system.
     pub(crate) async fn run(&self) -> Result<()> {
+        if crate::target_root::is_set() {
+            warn!(
+                "Skipping action {:?}, actions are not run below a target root",
+                self.exec
+            );
+            return Ok(());
+        }
+        let start = std::time::Instant::now();
+        let mut result = self.execute().await;
+        let mut delay = self.retry_delay;
+        for attempt in 1..=self.retries {
+            let Err(e) = &result else {
+                break;
+            };
+            warn!(
+                "Action {:?} failed, retrying in {}s ({}/{}): {:#}",
+                self.exec, delay, attempt, self.retries, e
+            );
+            tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+            signal::check_cancelled()?;
+            delay = delay.saturating_mul(2);
+            result = self.execute().await;
+        }
+        crate::timings::record(crate::timings::Category::Tasks, self.module.as_deref(), start);
+
+        match &self.module {
+            Some(module) => result.with_context(|| FailedModule(module.clone())),
+            None => result,
+        }
+    }
+
+    /// Returns the name of the action used for its log file, i.e. the name of the command or
+    /// script.
+    fn task_name(&self) -> String {
+        let cmd = match &self.exec {
+            RunExec::Code(code) => code.split_whitespace().next().unwrap_or("sh"),
+            RunExec::File(file) => file.as_str(),
+        };
+        std::path::Path::new(cmd)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "action".to_string())
+    }
+
+    /// Executes the command of the action.
+    ///
+    /// This method runs the action based on its configuration, handling both direct code execution
+    /// and file execution, with or without sudo. The output is captured in the task log.
+    async fn execute(&self) -> Result<()> {
+        let module = self.module.as_deref();
         match &self.exec {
             RunExec::Code(code) => {
-                // Execute the code directly using sh
-                let mut cmd = std::process::Command::new("sh")
-                    .arg("-c")
-                    .arg(code)
-                    .spawn()
-                    .with_context(|| format!("Failed to run {:?}", &self.exec))?;
+                // Execute the code directly using sh, as another user if requested
+                let cmd = match &self.user {
+                    Some(user) => sudo::run_as(user, "sh", &["-c", code]).await?,
+                    None => {
+                        let mut cmd = std::process::Command::new("sh");
+                        cmd.arg("-c").arg(code);
+                        cmd
+                    }
+                };
 
-                let status = cmd.wait()?;
+                let (status, log) = task_log::run(cmd, module, &self.task_name())
+                    .await
+                    .with_context(|| format!("Failed to run {:?}", &self.exec))?;
                 if status.success() {
                     Ok(())
                 } else {
-                    bail!("Failed to execute {:?}", code);
+                    bail!("Failed to execute {:?}{}", code, task_log::hint(&log));
                 }
             }
             RunExec::File(file) => {
                 let args = self.args.as_deref().unwrap_or(&[]);
-                if self.sudo {
+                if let Some(user) = &self.user {
+                    // Execute the file as another user
+                    let cmd = sudo::run_as(user, file, args).await?;
+
+                    let (status, log) = task_log::run(cmd, module, &self.task_name())
+                        .await
+                        .with_context(|| format!("Failed to run {:?} as {}", file, user))?;
+                    if status.success() {
+                        Ok(())
+                    } else {
+                        bail!(
+                            "Failed to execute {:?} with args {:?} as {}{}",
+                            file,
+                            args,
+                            user,
+                            task_log::hint(&log)
+                        );
+                    }
+                } else if self.sudo {
                     // Use sudo to execute the file
-                    crate::utils::sudo::spawn_sudo_maybe(format!(
-                        "Running {:?} with args: {:?}",
-                        file, args
-                    ))
-                    .await
-                    .context("Failed to spawn sudo")?;
+                    sudo::spawn_sudo_maybe(format!("Running {:?} with args: {:?}", file, args))
+                        .await
+                        .context("Failed to spawn sudo")?;
 
                     let mut fcmd = vec![file];
                     fcmd.extend(args);
 
-                    let mut cmd = std::process::Command::new("sudo")
-                        .args(&fcmd)
-                        .spawn()
-                        .with_context(|| format!("Failed to run {:?}", fcmd))?;
+                    let mut cmd = std::process::Command::new(sudo::root_cmd());
+                    cmd.args(&fcmd);
 
-                    let status = cmd.wait()?;
+                    let (status, log) = task_log::run(cmd, module, &self.task_name())
+                        .await
+                        .with_context(|| format!("Failed to run {:?}", fcmd))?;
                     if status.success() {
                         Ok(())
                     } else {
-                        bail!("Failed to execute {:?} with args {:?}", file, args);
+                        bail!(
+                            "Failed to execute {:?} with args {:?}{}",
+                            file,
+                            args,
+                            task_log::hint(&log)
+                        );
                     }
                 } else {
                     // Execute the file directly
-                    let mut cmd = std::process::Command::new(file)
-                        .args(args)
-                        .spawn()
+                    let mut cmd = std::process::Command::new(file);
+                    cmd.args(args);
+
+                    let (status, log) = task_log::run(cmd, module, &self.task_name())
+                        .await
                         .with_context(|| {
                             format!("Failed to run {:?} with args {:?}", file, args)
                         })?;
-
-                    let status = cmd.wait()?;
                     if status.success() {
                         Ok(())
                     } else {
-                        bail!("Failed to execute {:?} with args {:?}", file, args);
+                        bail!(
+                            "Failed to execute {:?} with args {:?}{}",
+                            file,
+                            args,
+                            task_log::hint(&log)
+                        );
                     }
                 }
             }
         }
     }
 }
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_retries() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let marker = temp_dir.path().join("marker");
+        // Fails on the first run only
+        let exec = format!("test -e {0} || {{ touch {0}; false; }}", marker.display());
+
+        let action: ModuleAction = toml::from_str(&format!("exec = {:?}", exec))?;
+        assert!(action.run().await.is_err());
+
+        std::fs::remove_file(&marker)?;
+        let action: ModuleAction =
+            toml::from_str(&format!("exec = {:?}\nretries = 2\nretry_delay = 0", exec))?;
+        assert_eq!(action.retries, 2);
+        action.run().await?;
+
+        Ok(())
+    }
+}
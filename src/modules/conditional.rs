@@ -132,8 +132,12 @@ impl ConditionalEvaluator for DefaultConditionalEvaluator {
 ///
 /// This function constructs a Handlebars template that will return "true" or "false" based on the
 /// evaluation of the condition. It then renders this template with the provided context and
-/// interprets the result.
-fn eval_condition(condition: &str, context: &Value, hb: &Handlebars<'static>) -> Result<bool> {
+/// interprets the result. Conditions are combined with the logic helpers, see [crate::helpers].
+pub(crate) fn eval_condition(
+    condition: &str,
+    context: &Value,
+    hb: &Handlebars<'static>,
+) -> Result<bool> {
     // Construct a Handlebars template that will evaluate to "true" or "false"
     let eval_template = format!(
         "{{{{#if {condition}}}}}true{{{{else}}}}false{{{{/if}}}}",
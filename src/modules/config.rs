@@ -22,14 +22,31 @@ use crate::utils::file_fs;
 /// This configuration includes optional dependencies, files, hooks, and packages.
 #[derive(Deserialize, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) struct ModuleConfig {
+    /// A human readable name of the module. Defaults to its path below `modules_root`.
+    pub(crate) name: Option<String>,
+    /// A short description of the module.
+    pub(crate) description: Option<String>,
+    /// The version of the module, e.g. `1.2.0`.
+    pub(crate) version: Option<String>,
+    /// The author of the module.
+    pub(crate) author: Option<String>,
+    /// Tags for selecting the module, e.g. `gui` or `dev`.
+    pub(crate) tags: Option<Vec<String>>,
     /// A list of module dependencies. Each dependency is identified by its name.
     pub(crate) depends: Option<Vec<String>>,
+    /// Modules which must not be deployed together with this module.
+    pub(crate) conflicts: Option<Vec<String>>,
+    /// Capabilities provided by this module, which other modules can depend on instead of a concrete
+    /// module, e.g. `editor`.
+    pub(crate) provides: Option<Vec<String>>,
     /// A mapping from file destinations to their configurations.
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_files")]
     pub(crate) files: Option<BTreeMap<PathBuf, ModuleFile>>,
     /// Defines actions to be executed at different phases of the deployment process.
     pub(crate) actions: Option<BTreeMap<String, BTreeMap<String, Vec<ModuleAction>>>>,
+    /// Actions executed if deploying this module fails, before the run aborts.
+    pub(crate) on_failure: Option<Vec<ModuleAction>>,
     /// Specifies packages to be installed as part of the module setup.
     pub(crate) packages: Option<Vec<ModulePackages>>,
     /// Key-value pairs used for handlebars templating.
@@ -40,6 +57,9 @@ pub(crate) struct ModuleConfig {
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_files")]
     pub(crate) generate: Option<BTreeMap<PathBuf, Generate>>,
+    /// Render the templates of this module in strict mode, failing on missing context keys.
+    /// Defaults to true.
+    pub(crate) template_strict: Option<bool>,
 }
 
 /// Custom deserializer for file paths in the configuration.
@@ -122,23 +142,21 @@ impl ModuleConfig {
         Ok(config)
     }
 
-    /// Expands directory wildcards in the file configurations.
+    /// Expands directory wildcards and glob patterns in the file configurations.
     ///
-    /// This method processes any file paths ending with '*' and expands them to include all files
-    /// in the specified directory.
+    /// This method processes any file entries with a glob pattern as source and a destination
+    /// ending with '*', and expands them to include all matching files. See
+    /// [expand_single_directory](Self::expand_single_directory) for how matches map onto the
+    /// destination. The destination marks the source as pattern, other sources are file names,
+    /// even if they contain `*`, `?` or `[`.
     fn expand_directory_wildcards(&mut self) -> Result<()> {
         if let Some(files) = &mut self.files {
             // Collect all expansions that need to be made
             let expansions: Result<Vec<(PathBuf, Vec<(PathBuf, ModuleFile)>)>> = files
                 .iter()
                 .filter(|(dest, conf)| {
-                    // Filter for paths ending with '*'
-                    dest.to_str().map(|s| s.ends_with('*')).unwrap_or(false)
-                        && conf
-                            .source
-                            .as_ref()
-                            .map(|s| s.to_str().map(|t| t.ends_with('*')).unwrap_or(false))
-                            .unwrap_or(false)
+                    dest.to_str().is_some_and(|s| s.ends_with('*'))
+                        && conf.source.as_deref().is_some_and(is_glob)
                 })
                 .map(|(dest, conf)| {
                     // Expand each wildcard directory
@@ -149,6 +167,36 @@ impl ModuleConfig {
 
             let expansions = expansions?;
 
+            // Stripping template extensions must not map several files onto one destination
+            let mut destinations: BTreeMap<&Path, Vec<(Option<&Path>, bool)>> = BTreeMap::new();
+            for (dest, conf) in files
+                .iter()
+                .filter(|(d, _)| !expansions.iter().any(|(k, _)| k == *d))
+            {
+                destinations
+                    .entry(dest.as_path())
+                    .or_default()
+                    .push((conf.source.as_deref(), false));
+            }
+            for (dest, conf) in expansions.iter().flat_map(|(_, e)| e.iter()) {
+                let source = conf.source.as_deref();
+                let stripped = dest.file_name() != source.and_then(Path::file_name);
+                destinations
+                    .entry(dest.as_path())
+                    .or_default()
+                    .push((source, stripped));
+            }
+            for (dest, entries) in destinations.iter() {
+                if entries.len() > 1 && entries.iter().any(|(_, stripped)| *stripped) {
+                    return Err(anyhow!(
+                        "Destination {:?} is used by several files after stripping template \
+                         extensions: {:?}",
+                        dest,
+                        entries.iter().filter_map(|(s, _)| *s).collect::<Vec<_>>()
+                    ));
+                }
+            }
+
             // Apply the expansions to the files map
             for (key, expanded_files) in expansions {
                 files.remove(&key);
@@ -158,38 +206,96 @@ impl ModuleConfig {
         Ok(())
     }
 
-    /// Expands a single directory wildcard.
+    /// Expands a single directory wildcard or glob pattern.
     ///
     /// This method takes a destination path and file configuration with wildcards and expands them
-    /// to include all files in the specified directory.
+    /// to include all matching files. A source ending with a plain `dir/*` includes all files below
+    /// `dir` recursively, other patterns like `config/**/*.conf` or `themes/*/colors.toml` only the
+    /// files they match.
+    ///
+    /// Matches are mapped onto the directory containing the destination, keeping their path
+    /// relative to the base of the pattern, which are the leading components without wildcards.
+    /// E.g. with the source `themes/*/colors.toml` and the destination `~/.config/app/*`, the
+    /// match `themes/dark/colors.toml` is deployed to `~/.config/app/dark/colors.toml`.
+    ///
+    /// Files matching one of the `exclude` patterns, or located below a directory matching one,
+    /// are skipped. If `template_extensions` is set, only files with one of these extensions are
+    /// templates, and the extension is stripped from their destination.
     fn expand_single_directory(
         dest: &PathBuf,
         conf: &ModuleFile,
     ) -> Result<Vec<(PathBuf, ModuleFile)>> {
-        // Get the parent directories of the source and destination
-        let source_parent = conf
+        let source = conf
             .source
             .as_ref()
-            .and_then(|s| s.parent())
-            .ok_or_else(|| anyhow!("Source {:?} has no parent directory", conf.source.as_ref()))?;
+            .ok_or_else(|| anyhow!("Destination {:?} has no source", dest))?;
+        // Get the base directory of the source and the parent of the destination
+        let source_parent: PathBuf = source.components().take_while(|c| !is_glob(c)).collect();
         let dest_parent = dest
             .parent()
             .ok_or_else(|| anyhow!("Destination {:?} has no parent directory", dest))?;
 
-        // Read all files in the source directory
-        let new_sources = file_fs::read_directory(conf.source.as_ref().unwrap().parent().unwrap())
-            .with_context(|| {
-                format!(
-                    "Failed to read directory {:?}",
-                    conf.source.as_ref().unwrap().parent().unwrap()
-                )
+        let new_sources = if source.parent() == Some(source_parent.as_path())
+            && source.file_name().is_some_and(|n| n == "*")
+        {
+            // Read all files in the source directory
+            file_fs::read_directory(&source_parent)
+                .with_context(|| format!("Failed to read directory {:?}", source_parent))?
+        } else {
+            let pattern = source.to_str().ok_or_else(|| {
+                anyhow!("Source {:?} contains invalid Unicode characters", source)
             })?;
+            let mut matches = Vec::new();
+            for entry in
+                glob::glob(pattern).with_context(|| format!("Invalid glob pattern {:?}", source))?
+            {
+                let entry = entry.with_context(|| format!("Failed to expand {:?}", source))?;
+                if entry.is_file() {
+                    matches.push(entry);
+                }
+            }
+            matches
+        };
+
+        let exclude = conf
+            .exclude
+            .iter()
+            .flatten()
+            .map(|p| {
+                glob::Pattern::new(p).with_context(|| format!("Invalid exclude pattern {:?}", p))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if conf.template_extensions.is_some() && conf.action.as_deref() != Some("copy") {
+            return Err(anyhow!(
+                "'template_extensions' of {:?} requires the 'copy' action",
+                dest
+            ));
+        }
 
         // Create new ModuleFile for each file found
         let mut expanded_files = Vec::new();
         for s in new_sources {
-            let relative_path = s.strip_prefix(source_parent)?.to_owned();
-            let new_dest = dest_parent.join(relative_path);
+            let relative_path = s.strip_prefix(&source_parent)?.to_owned();
+            if relative_path
+                .ancestors()
+                .any(|a| exclude.iter().any(|p| p.matches_path(a)))
+            {
+                debug!("Excluding {:?} from expansion of {:?}", s, dest);
+                continue;
+            }
+            let mut new_dest = dest_parent.join(relative_path);
+            let mut template = conf.template;
+            if let Some(extensions) = conf.template_extensions.as_ref() {
+                let name = new_dest.to_string_lossy().to_string();
+                let extension = extensions
+                    .iter()
+                    .find(|e| name.len() > e.len() && name.ends_with(e.as_str()));
+                template = Some(extension.is_some());
+                if let Some(extension) = extension {
+                    new_dest = PathBuf::from(&name[..name.len() - extension.len()]);
+                }
+            }
             expanded_files.push((
                 new_dest,
                 ModuleFile {
@@ -197,13 +303,18 @@ impl ModuleConfig {
                     content: conf.content.clone(),
                     phase: conf.phase.clone(),
                     action: conf.action.clone(),
+                    copy: conf.copy,
                     eval_when: conf.eval_when.clone(),
                     permissions: conf.permissions.as_ref().map(|p| FilePermissions {
                         owner: p.owner.clone(),
                         group: p.group.clone(),
                         permissions: p.permissions.clone(),
                     }),
-                    template: conf.template,
+                    template,
+                    template_strict: conf.template_strict,
+                    exclude: None,
+                    template_extensions: None,
+                    filter: conf.filter.clone(),
                 },
             ));
         }
@@ -211,6 +322,13 @@ impl ModuleConfig {
     }
 }
 
+/// Checks if a path contains glob wildcards.
+fn is_glob<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .to_str()
+        .is_some_and(|s| s.contains(['*', '?', '[']))
+}
+
 //
 // Tests
 
@@ -361,4 +479,206 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_expand_directory_wildcards_exclude() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let source_dir = temp_dir.path().join("source");
+        let dest_dir = temp_dir.path().join("dest");
+
+        fs::create_dir_all(source_dir.join(".git/objects"))?;
+        fs::create_dir_all(source_dir.join("themes"))?;
+        fs::write(source_dir.join(".git/objects/abc"), "")?;
+        fs::write(source_dir.join("README.md"), "")?;
+        fs::write(source_dir.join("themes/README.md"), "")?;
+        fs::write(source_dir.join("themes/dark.toml"), "")?;
+        fs::write(source_dir.join("config.toml~"), "")?;
+
+        let mut config = ModuleConfig {
+            files: Some(BTreeMap::from([(
+                dest_dir.join("*"),
+                ModuleFile {
+                    source: Some(source_dir.join("*")),
+                    exclude: Some(vec![
+                        "*.md".to_string(),
+                        ".git/**".to_string(),
+                        "*~".to_string(),
+                    ]),
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+
+        config.expand_directory_wildcards()?;
+
+        let files = config.files.as_ref().unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(files.contains_key(&dest_dir.join("themes/dark.toml")));
+
+        // Invalid patterns are rejected
+        let mut config = ModuleConfig {
+            files: Some(BTreeMap::from([(
+                dest_dir.join("*"),
+                ModuleFile {
+                    source: Some(source_dir.join("*")),
+                    exclude: Some(vec!["[".to_string()]),
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+        assert!(config.expand_directory_wildcards().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_glob_sources() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let source_dir = temp_dir.path().join("source");
+        let dest_dir = temp_dir.path().join("dest");
+
+        fs::create_dir_all(source_dir.join("config/a/b"))?;
+        fs::create_dir_all(source_dir.join("themes/dark"))?;
+        fs::create_dir_all(source_dir.join("themes/light"))?;
+        fs::write(source_dir.join("config/top.conf"), "")?;
+        fs::write(source_dir.join("config/a/b/nested.conf"), "")?;
+        fs::write(source_dir.join("config/a/other.txt"), "")?;
+        fs::write(source_dir.join("themes/dark/colors.toml"), "")?;
+        fs::write(source_dir.join("themes/dark/fonts.toml"), "")?;
+        fs::write(source_dir.join("themes/light/colors.toml"), "")?;
+
+        let glob = |dest: &str, source: &str| {
+            (
+                dest_dir.join(dest),
+                ModuleFile {
+                    source: Some(source_dir.join(source)),
+                    ..Default::default()
+                },
+            )
+        };
+        let mut config = ModuleConfig {
+            files: Some(BTreeMap::from([
+                glob("conf/*", "config/**/*.conf"),
+                glob("themes/*", "themes/*/colors.toml"),
+            ])),
+            ..Default::default()
+        };
+
+        config.expand_directory_wildcards()?;
+
+        let files: Vec<_> = config.files.unwrap().into_keys().collect();
+        assert_eq!(
+            files,
+            vec![
+                dest_dir.join("conf/a/b/nested.conf"),
+                dest_dir.join("conf/top.conf"),
+                dest_dir.join("themes/dark/colors.toml"),
+                dest_dir.join("themes/light/colors.toml"),
+            ]
+        );
+
+        // Without a wildcard destination, the source is a file name
+        let mut config = ModuleConfig {
+            files: Some(BTreeMap::from([glob("colors[1].toml", "colors[1].toml")])),
+            ..Default::default()
+        };
+        config.expand_directory_wildcards()?;
+        let files = config.files.unwrap();
+        assert_eq!(
+            files[&dest_dir.join("colors[1].toml")].source,
+            Some(source_dir.join("colors[1].toml"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_template_extensions() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let source_dir = temp_dir.path().join("source");
+        let dest_dir = temp_dir.path().join("dest");
+
+        fs::create_dir(&source_dir)?;
+        fs::write(source_dir.join("config.toml.tmpl"), "")?;
+        fs::write(source_dir.join("logo.png"), "")?;
+
+        let mut config = ModuleConfig {
+            files: Some(BTreeMap::from([(
+                dest_dir.join("*"),
+                ModuleFile {
+                    source: Some(source_dir.join("*")),
+                    action: Some("copy".to_string()),
+                    template: Some(true),
+                    template_extensions: Some(vec![".tmpl".to_string()]),
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+
+        config.expand_directory_wildcards()?;
+
+        let files = config.files.as_ref().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[&dest_dir.join("config.toml")].template, Some(true));
+        assert_eq!(files[&dest_dir.join("logo.png")].template, Some(false));
+
+        // Stripped destinations must not collide with other files
+        fs::write(source_dir.join("config.toml"), "")?;
+        let mut config = ModuleConfig {
+            files: Some(BTreeMap::from([(
+                dest_dir.join("*"),
+                ModuleFile {
+                    source: Some(source_dir.join("*")),
+                    action: Some("copy".to_string()),
+                    template_extensions: Some(vec![".tmpl".to_string()]),
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+        assert!(config.expand_directory_wildcards().is_err());
+        fs::remove_file(source_dir.join("config.toml"))?;
+        let mut config = ModuleConfig {
+            files: Some(BTreeMap::from([
+                (
+                    dest_dir.join("*"),
+                    ModuleFile {
+                        source: Some(source_dir.join("*")),
+                        action: Some("copy".to_string()),
+                        template_extensions: Some(vec![".tmpl".to_string()]),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    dest_dir.join("config.toml"),
+                    ModuleFile {
+                        content: Some("foo".to_string()),
+                        ..Default::default()
+                    },
+                ),
+            ])),
+            ..Default::default()
+        };
+        assert!(config.expand_directory_wildcards().is_err());
+
+        // Only copied files can be templates
+        let mut config = ModuleConfig {
+            files: Some(BTreeMap::from([(
+                dest_dir.join("*"),
+                ModuleFile {
+                    source: Some(source_dir.join("*")),
+                    action: Some("link".to_string()),
+                    template_extensions: Some(vec![".tmpl".to_string()]),
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+        assert!(config.expand_directory_wildcards().is_err());
+
+        Ok(())
+    }
 }
@@ -21,24 +21,37 @@ pub(crate) struct ModuleFile {
     /// Specifies the deployment phase for the file. Defaults to "deploy".
     #[serde(default = "default_phase")]
     pub(crate) phase: Option<String>,
-    /// The action to be taken with this file ("link", "copy" or "create"). Defaults to "link".
+    /// The action to be taken with this file ("link", "hardlink", "copy", "create" or "directory").
+    /// Defaults to "link".
     #[serde(default = "default_action")]
     pub(crate) action: Option<String>,
+    /// Only used by the "directory" action: copy the directory tree instead of linking it. Defaults
+    /// to false.
+    pub(crate) copy: Option<bool>,
     /// A conditional expression evaluated to decide if the file should be deployed.
     /// Deployment occurs only if this condition evaluates to true.
     /// This field is optional.
     pub(crate) eval_when: Option<String>,
     /// File permissions and ownership.
     pub(crate) permissions: Option<FilePermissions>,
-    /// If file is a template
-    #[serde(default = "default_template")]
+    /// If file is a template. If unset, the `template_default` value of the Dotdeploy config is
+    /// used.
     pub(crate) template: Option<bool>,
+    /// If the template is rendered in strict mode, failing on missing context keys. If unset, the
+    /// `template_strict` value of the module is used.
+    pub(crate) template_strict: Option<bool>,
+    /// Glob patterns of files to skip when expanding a wildcard source, e.g. `["*.md", ".git/**"]`.
+    /// Patterns are matched against the path relative to the expanded directory.
+    pub(crate) exclude: Option<Vec<String>>,
+    /// Extensions of templates when expanding a wildcard source with the "copy" action, e.g.
+    /// `[".tmpl"]`. Only matching files are rendered and the extension is stripped from their
+    /// destination, all other files are copied verbatim. Takes precedence over `template`.
+    pub(crate) template_extensions: Option<Vec<String>>,
+    /// Shell commands the content of copied and created files is piped through before it is
+    /// written, e.g. `["sops -d", "envsubst"]`. Templates are rendered after filtering.
+    pub(crate) filter: Option<Vec<String>>,
 }
 
-/// Provides default value for template.
-fn default_template() -> Option<bool> {
-    Some(false)
-}
 // Default values for ModuleFile
 /// Provides default values for the deployment phase of a file.
 fn default_phase() -> Option<String> {
@@ -83,6 +96,8 @@ where
 pub(crate) struct FilePermissions {
     pub(crate) owner: Option<String>,
     pub(crate) group: Option<String>,
+    /// Octal ("755") or symbolic ("u+x,go-w") mode. Symbolic modes are applied to the mode of the
+    /// source for copied files.
     pub(crate) permissions: Option<String>,
 }
 
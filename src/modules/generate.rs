@@ -50,6 +50,24 @@ pub(crate) struct Generate {
     /// condition evaluates to true. If not provided, the file will always be generated (subject to
     /// other deployment rules).
     pub(crate) eval_when: Option<String>,
+
+    /// Optional priority of the snippet of the declaring module.
+    ///
+    /// Snippets are concatenated in ascending order of their priority, ties are broken by the
+    /// module name. Modules which provide a snippet without declaring the generator have the
+    /// priority 0.
+    pub(crate) priority: Option<i64>,
+
+    /// Render the snippets as well as `prepend` and `append` as Handlebars templates.
+    ///
+    /// Snippets are rendered with the same context as regular template files, including the
+    /// `DOD_MODULE` of the module providing them. Defaults to true, if false, everything is
+    /// inserted verbatim.
+    pub(crate) template: Option<bool>,
+
+    /// Priorities of the snippets of all modules declaring the generator, keyed by module name.
+    #[serde(skip)]
+    pub(crate) priorities: BTreeMap<String, i64>,
 }
 
 /// Implementation of the `Conditional` trait for `Generate`.
@@ -64,6 +82,22 @@ impl Conditional for Generate {
     }
 }
 
+/// Looks up the priority a module declares for the snippet of a generated file.
+///
+/// The config is read from the location of the module, modules without a readable config or
+/// without the generator have the priority 0.
+fn stored_priority(module: &StoreModule, target: &Path) -> i64 {
+    crate::modules::config::ModuleConfig::read_config(&module.location)
+        .ok()
+        .and_then(|c| c.generate)
+        .and_then(|g| {
+            g.into_iter()
+                .find(|(k, _)| crate::target_root::rebase(k) == target)
+        })
+        .and_then(|(_, v)| v.priority)
+        .unwrap_or(0)
+}
+
 /// Generates a single file based on the provided configuration and context.
 ///
 /// This function collects content from multiple modules, applies templates, and writes the result
@@ -88,32 +122,53 @@ async fn generate_file<P: AsRef<Path>>(
     hb: &Handlebars<'static>,
 ) -> Result<()> {
     // Retrieve all modules from the store
-    let modules = stores
+    let mut modules = stores
         .user_store
         .get_all_modules()
         .await
         .map_err(|e| e.into_anyhow())?;
 
     let mut content = String::new();
+    let template = generator.template.unwrap_or(true);
+    let render = |text: &str, context: &Value| -> Result<String> {
+        match template {
+            true => Ok(hb.render_template(text, context)?),
+            false => Ok(text.to_string()),
+        }
+    };
 
     // Handle prepend content if present
     if let Some(prepend) = &generator.prepend {
-        let rendered = hb
-            .render_template(prepend, &context)
+        let rendered = render(prepend, context)
             .with_context(|| format!("Failed to render template {:?}", &prepend))?;
 
         content.push_str(&rendered);
     }
 
-    // Iterate through all modules and collect relevant content
+    // Iterate through all modules and collect relevant content, ordered by priority and name.
+    // Modules deployed in earlier runs keep the priority declared in their config.
+    modules.retain(|m| Path::new(&m.location).join(&generator.source).exists());
+    modules.sort_by_cached_key(|m| {
+        (
+            generator
+                .priorities
+                .get(&m.name)
+                .copied()
+                .unwrap_or_else(|| stored_priority(m, target.as_ref())),
+            m.name.clone(),
+        )
+    });
     for module in modules.iter() {
         let location: PathBuf = [&module.location, &generator.source].iter().collect();
         if location.exists() {
             // Read and render the content from each module
             let found_content = fs::read_to_string(&location).await?;
-            let rendered = hb
-                .render_template(&found_content, &context)
-                .with_context(|| format!("Failed to render template {:?}", &found_content))?;
+            let mut module_context = context.clone();
+            if let Some(map) = module_context.as_object_mut() {
+                map.insert("DOD_MODULE".to_string(), module.name.clone().into());
+            }
+            let rendered = render(&found_content, &module_context)
+                .with_context(|| format!("Failed to render template {:?}", &location))?;
 
             content.push_str(&rendered);
         }
@@ -121,8 +176,7 @@ async fn generate_file<P: AsRef<Path>>(
 
     // Handle append content if present
     if let Some(append) = &generator.append {
-        let rendered = hb
-            .render_template(append, &context)
+        let rendered = render(append, context)
             .with_context(|| format!("Failed to render template {:?}", &append))?;
 
         content.push_str(&rendered);
@@ -130,7 +184,7 @@ async fn generate_file<P: AsRef<Path>>(
 
     // Write the generated content to the target file if not empty
     if !content.is_empty() {
-        fs::write(&target, content).await?;
+        file_fs::write_file(&target, content).await?;
 
         // Add a special module entry for generated content
         stores
@@ -187,6 +241,9 @@ pub(crate) async fn generate_files(
     context: Value,
     hb: Arc<Handlebars<'static>>,
 ) -> Result<()> {
+    // Do not remove previously generated files if they can not be regenerated
+    crate::utils::signal::check_cancelled()?;
+
     let mut set = tokio::task::JoinSet::new();
     let context = Arc::new(context);
 
@@ -227,3 +284,47 @@ pub(crate) async fn generate_files(
 
     Ok(())
 }
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stored_priority() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        std::fs::write(
+            temp_dir.path().join("config.toml"),
+            "[generate.\"/tmp/profile\"]\nsource = \"profile\"\npriority = 10\n",
+        )?;
+        let module = |location: &Path| StoreModule {
+            name: "test".to_string(),
+            location: location.display().to_string(),
+            user: None,
+            reason: "manual".to_string(),
+            depends: None,
+            date: chrono::offset::Local::now(),
+        };
+
+        assert_eq!(
+            stored_priority(&module(temp_dir.path()), Path::new("/tmp/profile")),
+            10
+        );
+        // Other generators and missing configs fall back to 0
+        assert_eq!(
+            stored_priority(&module(temp_dir.path()), Path::new("/tmp/bashrc")),
+            0
+        );
+        assert_eq!(
+            stored_priority(
+                &module(Path::new("/nonexistent")),
+                Path::new("/tmp/profile")
+            ),
+            0
+        );
+
+        Ok(())
+    }
+}
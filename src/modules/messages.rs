@@ -3,11 +3,27 @@
 //! This module defines the structure and behavior of messages that can be displayed during the
 //! deployment or removal process. It allows for conditional display of messages based on the
 //! deployment stage and custom conditions.
+//!
+//! Messages have a severity level. Critical messages are always shown, the others can be silenced
+//! with the `show_messages` and `message_level` settings of the config.
+
+use std::io::IsTerminal;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
 
+use crate::config::DotdeployConfig;
 use crate::modules::conditional::Conditional;
 
+/// Severity of a message.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MessageLevel {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
 /// Configuration for messages within a module.
 ///
 /// This struct represents a message that can be displayed during the deployment or removal process.
@@ -23,6 +39,10 @@ pub(crate) struct ModuleMessages {
     #[serde(default = "default_message")]
     pub(crate) display_when: String,
 
+    /// The severity of the message: "info", "warning" or "critical". Defaults to "info".
+    #[serde(default)]
+    pub(crate) level: MessageLevel,
+
     /// An optional conditional expression for displaying the message.
     ///
     /// If provided, this expression is evaluated at runtime. The message is only displayed if the
@@ -50,3 +70,94 @@ impl Conditional for ModuleMessages {
         &self.eval_when
     }
 }
+
+/// A rendered message, ready to be displayed.
+///
+/// Messages are serialized as plain strings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Message {
+    /// The severity of the message
+    pub(crate) level: MessageLevel,
+    /// The rendered content of the message
+    pub(crate) text: String,
+}
+
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.text)
+    }
+}
+
+impl Message {
+    /// Formats the message for the terminal, marking warnings and critical messages.
+    fn format(&self, color: bool) -> String {
+        match (self.level, color) {
+            (MessageLevel::Info, _) => self.text.clone(),
+            (MessageLevel::Warning, false) => format!("Warning: {}", self.text),
+            (MessageLevel::Warning, true) => format!("\x1b[33mWarning:\x1b[0m {}", self.text),
+            (MessageLevel::Critical, false) => format!("CRITICAL: {}", self.text),
+            (MessageLevel::Critical, true) => format!("\x1b[1;31mCRITICAL: {}\x1b[0m", self.text),
+        }
+    }
+}
+
+/// Checks if messages of the given level are shown.
+///
+/// Critical messages are always shown. Other messages are shown if `show_messages` is set and
+/// their level is at least `message_level`.
+pub(crate) fn is_shown(level: MessageLevel, dotdeploy_config: &DotdeployConfig) -> bool {
+    level == MessageLevel::Critical
+        || (dotdeploy_config.show_messages && level >= dotdeploy_config.message_level)
+}
+
+/// Prints the messages of a module.
+///
+/// In quiet mode, only critical messages are printed.
+///
+/// # Arguments
+///
+/// * `module` - Name of the module
+/// * `msgs` - The messages to print
+pub(crate) fn print(module: &str, msgs: &[Message]) {
+    info!("Message for {}", module);
+    let color = std::io::stdout().is_terminal();
+    let quiet = crate::report::is_quiet();
+    for m in msgs
+        .iter()
+        .filter(|m| !quiet || m.level == MessageLevel::Critical)
+    {
+        println!("{}", m.format(color))
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_level() {
+        let m: ModuleMessages =
+            toml::from_str("message = \"Reboot\"\nlevel = \"critical\"").unwrap();
+        assert_eq!(m.level, MessageLevel::Critical);
+        let m: ModuleMessages = toml::from_str("message = \"Hello\"").unwrap();
+        assert_eq!(m.level, MessageLevel::Info);
+        assert!(toml::from_str::<ModuleMessages>("message = \"a\"\nlevel = \"loud\"").is_err());
+
+        let message = |level| Message {
+            level,
+            text: "Hello".to_string(),
+        };
+        assert_eq!(message(MessageLevel::Info).format(false), "Hello");
+        assert_eq!(
+            message(MessageLevel::Critical).format(false),
+            "CRITICAL: Hello"
+        );
+        assert_eq!(
+            serde_json::to_value(message(MessageLevel::Warning)).unwrap(),
+            "Hello"
+        );
+    }
+}
@@ -3,19 +3,25 @@
 //! The queue holds all modules and their configurations to be deployed. It provides methods to add
 //! modules to the queue and process them, handling dependencies and context variables.
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use handlebars::Handlebars;
 use std::collections::{BTreeMap, BTreeSet};
 use std::path::PathBuf;
 
 use crate::config::DotdeployConfig;
+use crate::helpers;
 use crate::modules::Module;
 use crate::modules::config::ModuleConfig;
+use crate::modules::version;
+use crate::utils::common::{ask_boolean, ask_index, levenshtein};
 
 /// Represents a queue of modules to be processed for deployment.
 #[derive(Debug)]
 pub(crate) struct ModuleQueue {
     /// A set of modules, ordered by their natural ordering.
     pub(crate) modules: BTreeSet<Module>,
+    /// Names of the modules deployed in earlier runs, preferred when resolving capabilities.
+    pub(crate) deployed: BTreeSet<String>,
     /// A map of context variables shared across modules.
     pub(crate) context: BTreeMap<String, String>,
 }
@@ -23,28 +29,119 @@ pub(crate) struct ModuleQueue {
 impl ModuleQueue {
     /// Adds modules to the queue, processing their dependencies recursively.
     ///
+    /// Remote modules are fetched first. A dependency on a `git+` URL runs code the user did not
+    /// ask for, so it is only fetched after confirmation, unless the URL was added manually.
+    ///
+    /// # Arguments
+    ///
+    /// * `module_names` - A vector of module names to be added.
+    /// * `dotdeploy_config` - The global configuration for dotdeploy.
+    /// * `manual` - A flag indicating whether the modules are being added manually or
+    ///   automatically.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success or containing an error if module processing fails.
+    pub(crate) async fn add_modules(
+        &mut self,
+        module_names: &Vec<String>,
+        dotdeploy_config: &DotdeployConfig,
+        manual: bool,
+    ) -> Result<()> {
+        self.add(module_names, dotdeploy_config, manual, true).await
+    }
+
+    /// Adds modules to the queue like [ModuleQueue::add_modules], without network access or
+    /// prompts.
+    ///
+    /// Remote modules must have been fetched before, and a capability provided by several modules
+    /// is an error unless one of them is queued or deployed. Used to inspect modules.
+    ///
+    /// # Arguments
+    ///
+    /// * `module_names` - A vector of module names to be added.
+    /// * `dotdeploy_config` - The global configuration for dotdeploy.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success or containing an error if module processing fails.
+    pub(crate) async fn add_modules_offline(
+        &mut self,
+        module_names: &Vec<String>,
+        dotdeploy_config: &DotdeployConfig,
+    ) -> Result<()> {
+        self.add(module_names, dotdeploy_config, true, false).await
+    }
+
+    /// Adds modules to the queue, see [ModuleQueue::add_modules].
+    ///
     /// # Arguments
     ///
     /// * `module_names` - A vector of module names to be added.
     /// * `dotdeploy_config` - The global configuration for dotdeploy.
     /// * `manual` - A flag indicating whether the modules are being added manually or
     ///   automatically.
+    /// * `online` - Whether remote modules are fetched and the user may be asked
     ///
     /// # Returns
     ///
     /// A Result indicating success or containing an error if module processing fails.
-    pub(crate) fn add_modules(
+    async fn add(
         &mut self,
         module_names: &Vec<String>,
         dotdeploy_config: &DotdeployConfig,
         manual: bool,
+        online: bool,
     ) -> Result<()> {
         // Iterate over each module name provided
         for module_name in module_names {
+            // Version constraints are checked once all modules are known
+            let (requested, _) = version::split_constraint(module_name);
+
+            // Fetch remote modules before locating them
+            let (mut module_name, url) =
+                crate::remotes::resolve(requested, &dotdeploy_config.remotes);
+            if let Some(url) = url.filter(|_| online) {
+                if !manual
+                    && requested.starts_with("git+")
+                    && !crate::remotes::is_fetched(&module_name, &url)
+                    && !ask_boolean(&format!(
+                        "A requested requires the remote module {} from {}. Fetch it and run its \
+                         actions? [y/N]",
+                        module_name, url
+                    ))
+                {
+                    bail!(
+                        "Remote module {} was not fetched, add {} to the modules to deploy to \
+                         trust it",
+                        module_name,
+                        requested
+                    );
+                }
+                crate::remotes::fetch(&module_name, &url, dotdeploy_config).await?;
+            }
+
             // Determine the filesystem location of the module
-            let path = self
+            let mut path = self
                 .locate_module(&module_name, dotdeploy_config)
                 .with_context(|| format!("Failed to locate module {}", module_name))?;
+            if !path.join("config.toml").is_file() {
+                if !online && module_name.starts_with(crate::remotes::PREFIX) {
+                    bail!(
+                        "Remote module {} has not been fetched yet, deploy it first",
+                        module_name
+                    );
+                }
+                // The name may refer to a capability provided by other modules
+                match self.find_provider(&module_name, dotdeploy_config, online)? {
+                    Some(provider) => {
+                        debug!("Using module {} to provide {}", provider, module_name);
+                        module_name = provider;
+                        path = self.locate_module(&module_name, dotdeploy_config)?;
+                    }
+                    None => return Err(module_not_found(&module_name, dotdeploy_config)),
+                }
+            }
 
             // Set an environment variable with the current module's path
             // This is used by other parts of the application that need to know the current context
@@ -102,12 +199,367 @@ impl ModuleQueue {
 
             // If the module has dependencies, process them recursively and add them to the queue.
             if let Some(dependencies) = &dependencies {
-                self.add_modules(dependencies, dotdeploy_config, false)?;
+                Box::pin(self.add(dependencies, dotdeploy_config, false, online)).await?;
             }
         }
+
+        // All dependencies have been resolved once the manually added modules are processed
+        if manual {
+            self.check_cycles(dotdeploy_config)?;
+            self.check_constraints(dotdeploy_config)?;
+            self.check_conflicts()?;
+        }
         Ok(())
     }
 
+    /// Finds the queued modules a module depends on.
+    ///
+    /// Dependencies on capabilities are resolved to the queued module providing them.
+    fn queued_dependencies<'a>(
+        &'a self,
+        module: &Module,
+        dotdeploy_config: &DotdeployConfig,
+    ) -> Vec<&'a Module> {
+        module
+            .config
+            .depends
+            .iter()
+            .flatten()
+            .filter_map(|dependency| self.find_queued(dependency, dotdeploy_config))
+            .collect()
+    }
+
+    /// Finds the queued module a module name or dependency refers to.
+    ///
+    /// The name may carry a version constraint, refer to a remote module or to a capability of a
+    /// queued module.
+    ///
+    /// # Arguments
+    ///
+    /// * `module_name` - A module name as passed to [ModuleQueue::add_modules].
+    /// * `dotdeploy_config` - The global configuration for dotdeploy.
+    ///
+    /// # Returns
+    ///
+    /// The queued module, or `None` if the name is not queued.
+    pub(crate) fn find_queued(
+        &self,
+        module_name: &str,
+        dotdeploy_config: &DotdeployConfig,
+    ) -> Option<&Module> {
+        let name = version::split_constraint(module_name).0;
+        let name = crate::remotes::resolve(name, &dotdeploy_config.remotes).0;
+        self.modules.iter().find(|m| m.name == name).or_else(|| {
+            self.modules
+                .iter()
+                .find(|m| m.config.provides.iter().flatten().any(|p| p == &name))
+        })
+    }
+
+    /// Computes the dependency level of each module in the queue.
+    ///
+    /// Modules without dependencies in the queue are on level 0, all other modules are one level
+    /// above their highest dependency. Modules on the same level don't depend on each other. The
+    /// queue must be free of cycles, see [ModuleQueue::check_cycles].
+    ///
+    /// # Arguments
+    ///
+    /// * `dotdeploy_config` - The global configuration for dotdeploy.
+    ///
+    /// # Returns
+    ///
+    /// The level of each module, keyed by module name.
+    pub(crate) fn levels(&self, dotdeploy_config: &DotdeployConfig) -> BTreeMap<String, usize> {
+        let mut levels = BTreeMap::new();
+        for module in self.modules.iter() {
+            self.level(module, &mut levels, dotdeploy_config);
+        }
+        levels
+    }
+
+    /// Computes the dependency level of a module, see [ModuleQueue::levels].
+    fn level(
+        &self,
+        module: &Module,
+        levels: &mut BTreeMap<String, usize>,
+        dotdeploy_config: &DotdeployConfig,
+    ) -> usize {
+        if let Some(level) = levels.get(&module.name) {
+            return *level;
+        }
+        let level = self
+            .queued_dependencies(module, dotdeploy_config)
+            .into_iter()
+            .map(|dependency| self.level(dependency, levels, dotdeploy_config) + 1)
+            .max()
+            .unwrap_or(0);
+        levels.insert(module.name.clone(), level);
+        level
+    }
+
+    /// Searches the dependencies of a module for a cycle, depth first.
+    ///
+    /// # Arguments
+    ///
+    /// * `module` - The module to start from.
+    /// * `stack` - The chain of modules leading to `module`.
+    /// * `done` - Names of the modules known to be free of cycles.
+    /// * `dotdeploy_config` - The global configuration for dotdeploy.
+    ///
+    /// # Returns
+    ///
+    /// The modules forming the cycle, starting and ending with the same module.
+    fn find_cycle<'a>(
+        &'a self,
+        module: &'a Module,
+        stack: &mut Vec<&'a Module>,
+        done: &mut BTreeSet<&'a str>,
+        dotdeploy_config: &DotdeployConfig,
+    ) -> Option<Vec<&'a Module>> {
+        if done.contains(module.name.as_str()) {
+            return None;
+        }
+        if let Some(start) = stack.iter().position(|m| m.name == module.name) {
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(module);
+            return Some(cycle);
+        }
+
+        stack.push(module);
+        for dependency in self.queued_dependencies(module, dotdeploy_config) {
+            if let Some(cycle) = self.find_cycle(dependency, stack, done, dotdeploy_config) {
+                return Some(cycle);
+            }
+        }
+        stack.pop();
+        done.insert(&module.name);
+
+        None
+    }
+
+    /// Checks that the dependencies of the queued modules contain no cycles.
+    ///
+    /// The error shows the complete chain of the first cycle found, e.g. `a -> b -> c -> a`, and the
+    /// configs declaring each dependency.
+    ///
+    /// # Arguments
+    ///
+    /// * `dotdeploy_config` - The global configuration for dotdeploy.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating whether the dependencies are free of cycles.
+    fn check_cycles(&self, dotdeploy_config: &DotdeployConfig) -> Result<()> {
+        let mut done = BTreeSet::new();
+        for module in self.modules.iter() {
+            let Some(cycle) = self.find_cycle(module, &mut vec![], &mut done, dotdeploy_config)
+            else {
+                continue;
+            };
+
+            let chain: Vec<&str> = cycle.iter().map(|m| m.name.as_str()).collect();
+            let locations: Vec<String> = cycle
+                .windows(2)
+                .map(|pair| {
+                    format!(
+                        "{} depends on {} in {:?}",
+                        pair[0].name,
+                        pair[1].name,
+                        pair[0].location.join("config.toml")
+                    )
+                })
+                .collect();
+            bail!(
+                "Circular module dependency: {}\n  {}",
+                chain.join(" -> "),
+                locations.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks the version constraints of all dependencies in the queue.
+    ///
+    /// All unsatisfied constraints are collected, so a single error reports them for all modules.
+    ///
+    /// # Arguments
+    ///
+    /// * `dotdeploy_config` - The global configuration for dotdeploy.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating whether all constraints are satisfied.
+    fn check_constraints(&self, dotdeploy_config: &DotdeployConfig) -> Result<()> {
+        let mut problems = vec![];
+        for module in self.modules.iter() {
+            for dependency in module.config.depends.iter().flatten() {
+                let (name, Some(constraint)) = version::split_constraint(dependency) else {
+                    continue;
+                };
+                let name = crate::remotes::resolve(name, &dotdeploy_config.remotes).0;
+                let Some(dep) = self.modules.iter().find(|m| m.name == name) else {
+                    continue;
+                };
+
+                match dep.config.version.as_deref() {
+                    Some(v) => match version::satisfies(v, constraint) {
+                        Ok(true) => (),
+                        Ok(false) => problems.push(format!(
+                            "{} requires {} {}, but version {} is available",
+                            module.name, name, constraint, v
+                        )),
+                        Err(e) => problems.push(format!("{}: {}", module.name, e)),
+                    },
+                    None => problems.push(format!(
+                        "{} requires {} {}, but {} has no version",
+                        module.name, name, constraint, name
+                    )),
+                }
+            }
+        }
+
+        if !problems.is_empty() {
+            bail!("Unsatisfied module dependencies:\n  {}", problems.join("\n  "));
+        }
+        Ok(())
+    }
+
+    /// Checks that no two modules in the queue conflict with each other.
+    ///
+    /// A conflict declared by either of two modules is enough. All conflicts are reported at once.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating whether the queue is free of conflicts.
+    fn check_conflicts(&self) -> Result<()> {
+        let problems: Vec<String> = self
+            .modules
+            .iter()
+            .flat_map(|module| {
+                module
+                    .config
+                    .conflicts
+                    .iter()
+                    .flatten()
+                    .filter(|c| self.modules.iter().any(|m| &m.name == *c))
+                    .map(move |c| format!("{} conflicts with {}", module.name, c))
+            })
+            .collect();
+
+        if !problems.is_empty() {
+            bail!("Conflicting modules requested:\n  {}", problems.join("\n  "));
+        }
+        Ok(())
+    }
+
+    /// Finds deployed modules which conflict with a module in the queue.
+    ///
+    /// Conflicts declared by modules in the queue as well as by the deployed modules are found.
+    /// Modules in the queue are not compared to themselves, as they are redeployed.
+    ///
+    /// # Arguments
+    ///
+    /// * `deployed` - Name and location of the deployed modules.
+    ///
+    /// # Returns
+    ///
+    /// Pairs of the queued and the conflicting deployed module.
+    pub(crate) fn deployed_conflicts(
+        &self,
+        deployed: &[(String, PathBuf)],
+    ) -> Vec<(String, String)> {
+        let mut conflicts = vec![];
+        for (name, location) in deployed.iter() {
+            if self.modules.iter().any(|m| &m.name == name) {
+                continue;
+            }
+            // A broken config of a deployed module only prevents its own declarations
+            let declared: Vec<String> = std::fs::read_to_string(location.join("config.toml"))
+                .ok()
+                .and_then(|c| toml::from_str::<toml::Table>(&c).ok())
+                .and_then(|t| t.get("conflicts").cloned())
+                .and_then(|c| c.try_into().ok())
+                .unwrap_or_default();
+
+            for module in self.modules.iter() {
+                if module.config.conflicts.iter().flatten().any(|c| c == name)
+                    || declared.contains(&module.name)
+                {
+                    conflicts.push((module.name.clone(), name.clone()));
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Finds a module providing a capability.
+    ///
+    /// A provider already in the queue is preferred, followed by a provider deployed in an earlier
+    /// run. If several other modules provide the capability, the user is asked to choose one.
+    ///
+    /// # Arguments
+    ///
+    /// * `capability` - The name of the capability, e.g. `editor`.
+    /// * `dotdeploy_config` - The global configuration for dotdeploy.
+    /// * `ask` - Whether the user may be asked, otherwise several providers are an error.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the name of the providing module, or `None` if no module provides the
+    /// capability.
+    fn find_provider(
+        &self,
+        capability: &str,
+        dotdeploy_config: &DotdeployConfig,
+        ask: bool,
+    ) -> Result<Option<String>> {
+        let root = &dotdeploy_config.modules_root;
+        if !root.is_dir() {
+            return Ok(None);
+        }
+
+        // Modules with a broken config can't provide anything
+        let providers: Vec<String> = crate::modules::find_modules(root)?
+            .into_iter()
+            .filter(|name| {
+                ModuleConfig::read_config(root.join(name))
+                    .is_ok_and(|c| c.provides.iter().flatten().any(|p| p == capability))
+            })
+            .collect();
+
+        let provider = if let Some(p) = providers
+            .iter()
+            .find(|p| self.modules.iter().any(|m| &&m.name == p))
+            .or_else(|| providers.iter().find(|p| self.deployed.contains(*p)))
+        {
+            Some(p.clone())
+        } else if providers.len() > 1 {
+            if !ask {
+                bail!(
+                    "Several modules provide {}, use one of them: {}",
+                    capability,
+                    providers.join(", ")
+                );
+            }
+            let choice = ask_index(
+                &format!("Several modules provide {}, choose one:", capability),
+                &providers,
+            )
+            .with_context(|| {
+                format!(
+                    "Several modules provide {}, add one of them to the deployment: {}",
+                    capability,
+                    providers.join(", ")
+                )
+            })?;
+            Some(providers[choice].clone())
+        } else {
+            providers.into_iter().next()
+        };
+
+        Ok(provider)
+    }
+
     /// Determines the filesystem location of a module based on its name.
     ///
     /// # Arguments
@@ -124,26 +576,120 @@ impl ModuleQueue {
         module_name: &str,
         dotdeploy_config: &DotdeployConfig,
     ) -> Result<PathBuf> {
-        // Determine the path to the module's configuration file
-        let path: PathBuf = if module_name.starts_with("hosts") {
-            // For host-specific modules, use the hosts_root directory
-            dotdeploy_config
-                .hosts_root
-                .join(module_name.trim_start_matches("hosts/"))
-        } else {
-            // For regular modules, use the modules_root directory
-            dotdeploy_config.modules_root.join(module_name)
-        };
+        Ok(crate::modules::module_path(module_name, dotdeploy_config))
+    }
 
-        Ok(path)
+    /// Prepares a Handlebars registry for rendering the templates of the queued modules.
+    ///
+    /// Registers the partials of all modules and the `file_content` helper.
+    ///
+    /// # Arguments
+    ///
+    /// * `hb` - The Handlebars registry shared by all templates
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success or failure
+    pub(crate) fn register_templates(&self, hb: &mut Handlebars<'static>) -> Result<()> {
+        for module in self.modules.iter() {
+            module.register_partials(hb).with_context(|| {
+                format!("Failed to register partials of module {}", module.name)
+            })?;
+        }
+        helpers::register_file_content_helper(
+            hb,
+            self.modules
+                .iter()
+                .map(|m| (m.name.clone(), m.location.clone()))
+                .collect(),
+        );
+
+        Ok(())
     }
 }
 
+/// Creates the error for a module which does not exist, suggesting similar module names.
+///
+/// # Arguments
+///
+/// * `module_name` - The name of the missing module.
+/// * `dotdeploy_config` - The global configuration for dotdeploy.
+pub(crate) fn module_not_found(module_name: &str, dotdeploy_config: &DotdeployConfig) -> anyhow::Error {
+    let (root, prefix) = if module_name.starts_with("hosts") {
+        (&dotdeploy_config.hosts_root, "hosts/")
+    } else {
+        (&dotdeploy_config.modules_root, "")
+    };
+    // Failing to search for suggestions should not hide the actual error
+    let candidates: Vec<String> = crate::modules::find_modules(root)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|m| format!("{}{}", prefix, m))
+        .collect();
+
+    let suggestions = suggest_modules(module_name, &candidates);
+    if suggestions.is_empty() {
+        anyhow!("Module {} not found in {:?}", module_name, root)
+    } else {
+        anyhow!(
+            "Module {} not found in {:?}, did you mean {}?",
+            module_name,
+            root,
+            suggestions
+                .iter()
+                .map(|s| format!("`{}`", s))
+                .collect::<Vec<_>>()
+                .join(" or ")
+        )
+    }
+}
+
+/// Finds module names close to a requested name.
+///
+/// A candidate is suggested if its edit distance to the name is at most a third of the name's
+/// length (but at least 2), or if one contains the other.
+///
+/// # Arguments
+///
+/// * `name` - The requested module name.
+/// * `candidates` - Names of the existing modules.
+///
+/// # Returns
+///
+/// Up to three suggestions, closest first.
+fn suggest_modules(name: &str, candidates: &[String]) -> Vec<String> {
+    let max_distance = (name.chars().count() / 3).max(2);
+    let name_lower = name.to_lowercase();
+
+    let mut matches: Vec<(usize, &String)> = candidates
+        .iter()
+        .filter_map(|c| {
+            let c_lower = c.to_lowercase();
+            let distance = levenshtein(&name_lower, &c_lower);
+            if distance <= max_distance
+                || c_lower.contains(&name_lower)
+                || name_lower.contains(&c_lower)
+            {
+                Some((distance, c))
+            } else {
+                None
+            }
+        })
+        .collect();
+    matches.sort();
+
+    matches
+        .into_iter()
+        .take(3)
+        .map(|(_, c)| c.clone())
+        .collect()
+}
+
 //
 // Tests
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use serde::Serialize;
     use std::fs;
@@ -173,23 +719,53 @@ mod tests {
     }
 
     /// Creates a DotdeployConfig for testing purposes.
-    fn create_test_config(temp_dir: &tempfile::TempDir) -> DotdeployConfig {
+    pub(crate) fn create_test_config(temp_dir: &tempfile::TempDir) -> DotdeployConfig {
         DotdeployConfig {
             config_root: temp_dir.path().to_path_buf(),
             hosts_root: temp_dir.path().to_path_buf(),
             modules_root: temp_dir.path().to_path_buf(),
+            state_dir: temp_dir.path().join("state"),
+            cache_dir: temp_dir.path().join("cache"),
+            logs_dir: temp_dir.path().join("logs"),
             distribution: "None".to_string(),
             hostname: "None".to_string(),
+            auto_host_module: false,
             use_sudo: true,
+            sudo_cmd: "sudo".to_string(),
+            sudo_askpass_cmd: None,
+            never_sudo_paths: vec![],
             deploy_sys_files: true,
+            target_root: None,
             skip_pkg_install: false,
             intall_pkg_cmd: None,
             remove_pkg_cmd: None,
+            backup_retention: Default::default(),
+            template_default: false,
+            require_template: false,
+            file_defaults: Default::default(),
+            pkg_lock_retry: Default::default(),
+            schedule: Default::default(),
+            bootstrap: Default::default(),
+            progress: false,
+            diff: false,
+            notify: false,
+            journal: false,
+            store: Default::default(),
+            show_messages: true,
+            message_level: Default::default(),
+            max_parallel_actions: 1,
+            orphan_paths: vec![],
+            hooks: Default::default(),
+            profiles: Default::default(),
+            context_cmds: Default::default(),
+            facts_ttl: 0,
+            helper: Default::default(),
+            remotes: Default::default(),
         }
     }
 
-    #[test]
-    fn test_add_modules_with_dependencies() -> Result<()> {
+    #[tokio::test]
+    async fn test_add_modules_with_dependencies() -> Result<()> {
         let temp_dir = tempdir().expect("Failed to create temp dir");
         let dotdeploy_config = create_test_config(&temp_dir);
 
@@ -200,11 +776,14 @@ mod tests {
 
         let mut queue = ModuleQueue {
             modules: BTreeSet::new(),
+            deployed: BTreeSet::new(),
             context: BTreeMap::new(),
         };
 
         // Add the root module, which should recursively add its dependencies
-        queue.add_modules(&vec!["module1".to_string()], &dotdeploy_config, true)?;
+        queue
+            .add_modules(&vec!["module1".to_string()], &dotdeploy_config, true)
+            .await?;
 
         // Check that all modules are present in the set
         assert!(queue.modules.iter().any(|m| m.name == "module1"));
@@ -212,14 +791,16 @@ mod tests {
         assert!(queue.modules.iter().any(|m| m.name == "module3"));
 
         // Test that adding modules does not cause duplicates
-        queue.add_modules(&vec!["module1".to_string()], &dotdeploy_config, true)?;
+        queue
+            .add_modules(&vec!["module1".to_string()], &dotdeploy_config, true)
+            .await?;
         assert_eq!(queue.modules.len(), 3);
 
         Ok(())
     }
 
-    #[test]
-    fn test_circular_dependencies() -> Result<()> {
+    #[tokio::test]
+    async fn test_circular_dependencies() -> Result<()> {
         let temp_dir = tempdir().context("Failed to create temp dir")?;
         let dotdeploy_config = create_test_config(&temp_dir);
 
@@ -227,22 +808,55 @@ mod tests {
         create_temp_module_config(&temp_dir, "module1", Some(vec!["module2"]));
         create_temp_module_config(&temp_dir, "module2", Some(vec!["module3"]));
         create_temp_module_config(&temp_dir, "module3", Some(vec!["module1"]));
-        create_temp_module_config(&temp_dir, "foo", Some(vec!["module1", "module2", "module3"]));
+        create_temp_module_config(
+            &temp_dir,
+            "foo",
+            Some(vec!["module1", "module2", "module3"]),
+        );
 
         let mut queue = ModuleQueue {
             modules: BTreeSet::new(),
+            deployed: BTreeSet::new(),
             context: BTreeMap::new(),
         };
 
-        // Add the root module, which should handle the circular dependency
-        queue.add_modules(&vec!["module1".to_string(), "foo".to_string()], &dotdeploy_config, true)?;
+        // The circular dependency is reported with the complete chain
+        let err = queue
+            .add_modules(
+                &vec!["module1".to_string(), "foo".to_string()],
+                &dotdeploy_config,
+                true,
+            )
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Circular module dependency: module1 -> module2 -> module3 -> module1\n"));
+        assert!(err.to_string().contains(&format!(
+            "module3 depends on module1 in {:?}",
+            temp_dir.path().join("module3/config.toml")
+        )));
+
+        // Without the cycle, modules are added once with their reason
+        create_temp_module_config(&temp_dir, "module3", None);
+        let mut queue = ModuleQueue {
+            modules: BTreeSet::new(),
+            deployed: BTreeSet::new(),
+            context: BTreeMap::new(),
+        };
+        queue
+            .add_modules(
+                &vec!["module1".to_string(), "foo".to_string()],
+                &dotdeploy_config,
+                true,
+            )
+            .await?;
 
         // Check that all modules are present in the set
         assert!(queue.modules.iter().any(|m| m.name == "module1"));
         assert!(queue.modules.iter().any(|m| m.name == "module2"));
         assert!(queue.modules.iter().any(|m| m.name == "module3"));
 
-        // Ensure that the circular dependency didn't cause infinite recursion
         assert_eq!(queue.modules.len(), 4);
 
         // Verify that module1 is marked as manual
@@ -260,6 +874,290 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_levels() -> Result<()> {
+        let temp_dir = tempdir().context("Failed to create temp dir")?;
+        let dotdeploy_config = create_test_config(&temp_dir);
+
+        create_temp_module_config(&temp_dir, "app", Some(vec!["lib", "base"]));
+        create_temp_module_config(&temp_dir, "lib", Some(vec!["base"]));
+        create_temp_module_config(&temp_dir, "base", None);
+        create_temp_module_config(&temp_dir, "tool", None);
+
+        let mut queue = ModuleQueue {
+            modules: BTreeSet::new(),
+            deployed: BTreeSet::new(),
+            context: BTreeMap::new(),
+        };
+        queue
+            .add_modules(
+                &vec!["app".to_string(), "tool".to_string()],
+                &dotdeploy_config,
+                true,
+            )
+            .await?;
+
+        assert_eq!(
+            queue.levels(&dotdeploy_config),
+            BTreeMap::from([
+                ("app".to_string(), 2),
+                ("base".to_string(), 0),
+                ("lib".to_string(), 1),
+                ("tool".to_string(), 0),
+            ])
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version_constraints() -> Result<()> {
+        let temp_dir = tempdir().context("Failed to create temp dir")?;
+        let dotdeploy_config = create_test_config(&temp_dir);
+
+        for (name, config) in [
+            ("base", "version = \"1.4\""),
+            ("nvim", "depends = [\"base>=1,<2\"]"),
+            ("zsh", "depends = [\"base>=2\", \"plugins>1\"]"),
+            ("plugins", ""),
+        ] {
+            fs::create_dir_all(temp_dir.path().join(name))?;
+            fs::write(temp_dir.path().join(name).join("config.toml"), config)?;
+        }
+
+        let mut queue = ModuleQueue {
+            modules: BTreeSet::new(),
+            deployed: BTreeSet::new(),
+            context: BTreeMap::new(),
+        };
+        queue
+            .add_modules(&vec!["nvim".to_string()], &dotdeploy_config, true)
+            .await?;
+        assert!(queue.modules.iter().any(|m| m.name == "base"));
+
+        // All unsatisfied constraints are reported
+        let err = queue
+            .add_modules(&vec!["zsh".to_string()], &dotdeploy_config, true)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("zsh requires base >=2, but version 1.4 is available"));
+        assert!(err.contains("zsh requires plugins >1, but plugins has no version"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_conflicts() -> Result<()> {
+        let temp_dir = tempdir().context("Failed to create temp dir")?;
+        let dotdeploy_config = create_test_config(&temp_dir);
+
+        for (name, config) in [
+            ("pipewire", "conflicts = [\"pulseaudio\"]"),
+            ("pulseaudio", ""),
+            ("desktop", "depends = [\"pulseaudio\"]"),
+        ] {
+            fs::create_dir_all(temp_dir.path().join(name))?;
+            fs::write(temp_dir.path().join(name).join("config.toml"), config)?;
+        }
+
+        let mut queue = ModuleQueue {
+            modules: BTreeSet::new(),
+            deployed: BTreeSet::new(),
+            context: BTreeMap::new(),
+        };
+        let err = queue
+            .add_modules(
+                &vec!["pipewire".to_string(), "desktop".to_string()],
+                &dotdeploy_config,
+                true,
+            )
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("pipewire conflicts with pulseaudio"));
+
+        // Conflicts with deployed modules are found in both directions
+        let mut queue = ModuleQueue {
+            modules: BTreeSet::new(),
+            deployed: BTreeSet::new(),
+            context: BTreeMap::new(),
+        };
+        queue
+            .add_modules(&vec!["desktop".to_string()], &dotdeploy_config, true)
+            .await?;
+        let deployed = vec![
+            ("pipewire".to_string(), temp_dir.path().join("pipewire")),
+            ("pulseaudio".to_string(), temp_dir.path().join("pulseaudio")),
+        ];
+        assert_eq!(
+            queue.deployed_conflicts(&deployed),
+            vec![("pulseaudio".to_string(), "pipewire".to_string())]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_provides() -> Result<()> {
+        let temp_dir = tempdir().context("Failed to create temp dir")?;
+        let dotdeploy_config = create_test_config(&temp_dir);
+
+        for (name, config) in [
+            ("nvim", "provides = [\"editor\"]"),
+            ("emacs", "provides = [\"editor\"]"),
+            ("git", "depends = [\"editor\"]"),
+            ("tmux", "depends = [\"pager\"]"),
+            ("less", "provides = [\"pager\"]"),
+        ] {
+            fs::create_dir_all(temp_dir.path().join(name))?;
+            fs::write(temp_dir.path().join(name).join("config.toml"), config)?;
+        }
+
+        // A single provider is used right away
+        let mut queue = ModuleQueue {
+            modules: BTreeSet::new(),
+            deployed: BTreeSet::new(),
+            context: BTreeMap::new(),
+        };
+        queue
+            .add_modules(&vec!["tmux".to_string()], &dotdeploy_config, true)
+            .await?;
+        assert!(queue.modules.iter().any(|m| m.name == "less"));
+
+        // Deployed providers are preferred
+        let mut queue = ModuleQueue {
+            modules: BTreeSet::new(),
+            deployed: BTreeSet::from(["emacs".to_string()]),
+            context: BTreeMap::new(),
+        };
+        queue
+            .add_modules(&vec!["git".to_string()], &dotdeploy_config, true)
+            .await?;
+        let names: Vec<&str> = queue.modules.iter().map(|m| m.name.as_str()).collect();
+        assert!(names.contains(&"emacs") && !names.contains(&"nvim"));
+
+        // Providers in the queue are preferred
+        let mut queue = ModuleQueue {
+            modules: BTreeSet::new(),
+            deployed: BTreeSet::from(["emacs".to_string()]),
+            context: BTreeMap::new(),
+        };
+        queue
+            .add_modules(
+                &vec!["nvim".to_string(), "git".to_string()],
+                &dotdeploy_config,
+                true,
+            )
+            .await?;
+        let names: Vec<&str> = queue.modules.iter().map(|m| m.name.as_str()).collect();
+        assert!(names.contains(&"nvim") && !names.contains(&"emacs"));
+
+        // Capabilities without providers are missing modules
+        assert!(queue
+            .find_provider("browser", &dotdeploy_config, true)?
+            .is_none());
+
+        // Queued modules are found by their capability and with version constraints
+        assert_eq!(
+            queue
+                .find_queued("editor", &dotdeploy_config)
+                .map(|m| m.name.as_str()),
+            Some("nvim")
+        );
+        assert_eq!(
+            queue
+                .find_queued("git >=1.0", &dotdeploy_config)
+                .map(|m| m.name.as_str()),
+            Some("git")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_modules_offline() -> Result<()> {
+        let temp_dir = tempdir().context("Failed to create temp dir")?;
+        let dotdeploy_config = create_test_config(&temp_dir);
+
+        for (name, config) in [
+            ("nvim", "provides = [\"editor\"]"),
+            ("emacs", "provides = [\"editor\"]"),
+            ("git", "depends = [\"editor\"]"),
+        ] {
+            fs::create_dir_all(temp_dir.path().join(name))?;
+            fs::write(temp_dir.path().join(name).join("config.toml"), config)?;
+        }
+        let queue = || ModuleQueue {
+            modules: BTreeSet::new(),
+            deployed: BTreeSet::new(),
+            context: BTreeMap::new(),
+        };
+
+        // Several providers are an error instead of a prompt
+        let err = queue()
+            .add_modules_offline(&vec!["git".to_string()], &dotdeploy_config)
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Several modules provide editor"));
+        let mut q = queue();
+        q.add_modules_offline(
+            &vec!["emacs".to_string(), "git".to_string()],
+            &dotdeploy_config,
+        )
+        .await?;
+        assert_eq!(q.modules.len(), 2);
+
+        // Remote modules are never fetched
+        let err = queue()
+            .add_modules_offline(
+                &vec!["git+https://example.com/dotfiles.git".to_string()],
+                &dotdeploy_config,
+            )
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("has not been fetched yet"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_module_suggestions() -> Result<()> {
+        let temp_dir = tempdir().context("Failed to create temp dir")?;
+        let dotdeploy_config = create_test_config(&temp_dir);
+
+        create_temp_module_config(&temp_dir, "neovim", None);
+        create_temp_module_config(&temp_dir, "zsh", None);
+        create_temp_module_config(&temp_dir, "desktop/sway", None);
+
+        let mut queue = ModuleQueue {
+            modules: BTreeSet::new(),
+            deployed: BTreeSet::new(),
+            context: BTreeMap::new(),
+        };
+
+        let err = queue
+            .add_modules(&vec!["neovm".to_string()], &dotdeploy_config, true)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("did you mean `neovim`?"));
+
+        let candidates = vec![
+            "desktop/sway".to_string(),
+            "neovim".to_string(),
+            "zsh".to_string(),
+        ];
+        assert_eq!(suggest_modules("sway", &candidates), vec!["desktop/sway"]);
+        assert_eq!(
+            suggest_modules("firefox", &candidates),
+            Vec::<String>::new()
+        );
+        assert_eq!(suggest_modules("Zsh", &candidates), vec!["zsh"]);
+
+        Ok(())
+    }
+
     #[test]
     fn test_locate_module() -> Result<()> {
         let temp_dir = tempdir().context("Failed to create temp dir")?;
@@ -267,6 +1165,7 @@ mod tests {
 
         let queue = ModuleQueue {
             modules: BTreeSet::new(),
+            deployed: BTreeSet::new(),
             context: BTreeMap::new(),
         };
 
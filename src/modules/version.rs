@@ -0,0 +1,118 @@
+//! This module handles versions of modules and version constraints of dependencies.
+//!
+//! A dependency may carry constraints after the module name, e.g. `base>=2` or `base>=2,<3`.
+//! Supported operators are `=`, `==`, `!=`, `<`, `<=`, `>` and `>=`. Versions are compared by their
+//! dot separated components, numerically where possible. Missing components count as zero, so
+//! `2` equals `2.0.0`.
+
+use std::cmp::Ordering;
+
+use anyhow::{bail, Result};
+
+/// Splits a dependency into the module name and its version constraint, if any.
+///
+/// Remote dependencies (`git+...`) never carry a constraint.
+pub(crate) fn split_constraint(dependency: &str) -> (&str, Option<&str>) {
+    if dependency.starts_with("git+") {
+        return (dependency, None);
+    }
+    match dependency.find(['<', '>', '=', '!']) {
+        Some(i) => (dependency[..i].trim(), Some(dependency[i..].trim())),
+        None => (dependency, None),
+    }
+}
+
+/// Compares two versions component-wise.
+fn compare(a: &str, b: &str) -> Ordering {
+    let a: Vec<&str> = a.trim().split('.').collect();
+    let b: Vec<&str> = b.trim().split('.').collect();
+    for i in 0..a.len().max(b.len()) {
+        let (x, y) = (
+            a.get(i).copied().unwrap_or("0"),
+            b.get(i).copied().unwrap_or("0"),
+        );
+        let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            _ => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Checks whether a version satisfies a constraint.
+///
+/// # Arguments
+///
+/// * `version` - The version of a module, e.g. `2.1`
+/// * `constraint` - Comma separated requirements, e.g. `>=2,<3`
+///
+/// # Returns
+///
+/// A Result containing whether all requirements are met, or an error if the constraint is invalid.
+pub(crate) fn satisfies(version: &str, constraint: &str) -> Result<bool> {
+    for requirement in constraint.split(',').map(str::trim) {
+        let (op, required) = match ["==", "!=", ">=", "<=", "=", ">", "<"]
+            .iter()
+            .find_map(|op| requirement.strip_prefix(op).map(|v| (*op, v.trim())))
+        {
+            Some((_, "")) | None => bail!("Invalid version constraint {:?}", requirement),
+            Some(r) => r,
+        };
+
+        let ordering = compare(version, required);
+        let met = match op {
+            "=" | "==" => ordering == Ordering::Equal,
+            "!=" => ordering != Ordering::Equal,
+            ">=" => ordering != Ordering::Less,
+            "<=" => ordering != Ordering::Greater,
+            ">" => ordering == Ordering::Greater,
+            _ => ordering == Ordering::Less,
+        };
+        if !met {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_constraint() {
+        assert_eq!(split_constraint("base"), ("base", None));
+        assert_eq!(split_constraint("base>=2"), ("base", Some(">=2")));
+        assert_eq!(
+            split_constraint("base >= 2, <3"),
+            ("base", Some(">= 2, <3"))
+        );
+        assert_eq!(
+            split_constraint("git+https://example.com/nvim?a=b"),
+            ("git+https://example.com/nvim?a=b", None)
+        );
+    }
+
+    #[test]
+    fn test_satisfies() -> Result<()> {
+        assert!(satisfies("2", ">=2")?);
+        assert!(satisfies("2.0.0", "=2")?);
+        assert!(satisfies("2.10", ">2.9")?);
+        assert!(satisfies("2.1", ">=2,<3")?);
+        assert!(!satisfies("3.0", ">=2,<3")?);
+        assert!(!satisfies("1.9", ">=2")?);
+        assert!(satisfies("1.0", "!=2")?);
+        assert!(satisfies("1.0-beta", "<=1.0-rc")?);
+
+        assert!(satisfies("1.0", "~1").is_err());
+        assert!(satisfies("1.0", ">=").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+//! This module handles notes attached to modules or deployed files.
+//!
+//! Notes are free-form annotations kept in the user store, e.g. to remember why a file was
+//! modified by hand. They are shown next to the deployment statistics of a module.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+use crate::config::DotdeployConfig;
+use crate::store::notes::StoreNote;
+use crate::Stores;
+
+/// Turns a user supplied target into the form stored in the database.
+///
+/// Names of existing modules, including nested and host modules like `desktop/sway`, are kept
+/// as they are. Other targets containing a path separator or starting with `~` or `.` are treated
+/// as file paths and made absolute. Everything else is considered a module name.
+///
+/// # Arguments
+///
+/// * `target` - Module name or file path
+/// * `dotdeploy_config` - Configuration used to locate modules
+fn normalize_target(target: &str, dotdeploy_config: &DotdeployConfig) -> Result<String> {
+    let is_module = !target.starts_with('/')
+        && crate::modules::module_path(target, dotdeploy_config)
+            .join("config.toml")
+            .is_file();
+    if !is_module && (target.contains('/') || target.starts_with('~') || target.starts_with('.')) {
+        let expanded = shellexpand::full(target)
+            .with_context(|| format!("Failed to expand path {:?}", target))?;
+        let path = std::path::absolute(expanded.as_ref())
+            .with_context(|| format!("Failed to get absolute path of {:?}", target))?;
+        crate::utils::file_fs::path_to_string(path)
+    } else {
+        Ok(target.to_string())
+    }
+}
+
+/// Prints a list of notes.
+pub(crate) fn print_notes(notes: &[StoreNote]) {
+    for n in notes.iter() {
+        println!(
+            "[{}] {}  {}: {}",
+            n.id,
+            n.date.format("%Y-%m-%d %H:%M"),
+            n.target,
+            n.text
+        );
+    }
+}
+
+/// Adds a note to a module or file.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `target` - Module name or file path
+/// * `text` - The note text
+/// * `dotdeploy_config` - Configuration used to locate modules
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn add(
+    stores: Arc<Stores>,
+    target: &str,
+    text: &str,
+    dotdeploy_config: &DotdeployConfig,
+) -> Result<()> {
+    let target = normalize_target(target, dotdeploy_config)?;
+    let id = stores
+        .user_store
+        .add_note(target.as_str(), text)
+        .await
+        .map_err(|e| e.into_anyhow())?;
+    info!("Added note {} to '{}'", id, target);
+
+    Ok(())
+}
+
+/// Prints all notes, or the notes of a single module or file.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `target` - Optional module name or file path
+/// * `dotdeploy_config` - Configuration used to locate modules
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn list(
+    stores: Arc<Stores>,
+    target: Option<&str>,
+    dotdeploy_config: &DotdeployConfig,
+) -> Result<()> {
+    let target = target
+        .map(|t| normalize_target(t, dotdeploy_config))
+        .transpose()?;
+    let notes = stores
+        .user_store
+        .get_notes(target)
+        .await
+        .map_err(|e| e.into_anyhow())?;
+
+    if notes.is_empty() {
+        info!("No notes found");
+    }
+    print_notes(&notes);
+
+    Ok(())
+}
+
+/// Removes a note.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `id` - ID of the note
+///
+/// # Returns
+///
+/// A Result containing `true` if the note existed
+pub(crate) async fn remove(stores: Arc<Stores>, id: i64) -> Result<bool> {
+    let removed = stores
+        .user_store
+        .remove_note(id)
+        .await
+        .map_err(|e| e.into_anyhow())?;
+    if removed {
+        info!("Removed note {}", id);
+    } else {
+        warn!("Note {} does not exist", id);
+    }
+
+    Ok(removed)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_target() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let config = crate::modules::queue::tests::create_test_config(&temp_dir);
+        std::fs::create_dir_all(temp_dir.path().join("desktop/sway"))?;
+        std::fs::write(temp_dir.path().join("desktop/sway/config.toml"), "")?;
+
+        assert_eq!(normalize_target("firewall", &config)?, "firewall");
+        assert_eq!(normalize_target("/etc/hosts", &config)?, "/etc/hosts");
+        assert!(normalize_target("./foo", &config)?.starts_with('/'));
+        // Nested modules are no paths
+        assert_eq!(normalize_target("desktop/sway", &config)?, "desktop/sway");
+        assert!(normalize_target("desktop/foot", &config)?.starts_with('/'));
+
+        Ok(())
+    }
+}
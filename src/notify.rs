@@ -0,0 +1,123 @@
+//! This module sends desktop notifications about automatic runs.
+//!
+//! Notifications are sent with `notify-send` from libnotify, so they reach the notification daemon
+//! of the running graphical session. If `notify-send` is missing or fails, the notification is
+//! dropped and a warning is logged; the outcome of the run is not affected.
+
+use std::process::Command;
+
+use crate::report::DeploySummary;
+
+/// Urgency level of a notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Urgency {
+    Normal,
+    Critical,
+}
+
+impl Urgency {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Urgency::Normal => "normal",
+            Urgency::Critical => "critical",
+        }
+    }
+}
+
+/// Builds the summary, body and urgency of the notification about a finished deployment.
+fn deploy_notification(result: &anyhow::Result<DeploySummary>) -> (String, String, Urgency) {
+    match result {
+        Ok(summary) => {
+            let mut body = format!(
+                "Deployed {} in {:.1}s",
+                summary.modules.join(", "),
+                summary.duration_ms as f64 / 1000.0
+            );
+            let messages: usize = summary.messages.values().map(|m| m.len()).sum();
+            if messages > 0 {
+                body.push_str(&format!(
+                    "\n{} message(s), see the log for details",
+                    messages
+                ));
+            }
+            (
+                "dotdeploy: Deployment finished".to_string(),
+                body,
+                Urgency::Normal,
+            )
+        }
+        Err(e) => (
+            "dotdeploy: Deployment failed".to_string(),
+            format!("{:#}", e),
+            Urgency::Critical,
+        ),
+    }
+}
+
+/// Sends a notification with `notify-send`.
+fn send(summary: &str, body: &str, urgency: Urgency) {
+    let status = Command::new("notify-send")
+        .args([
+            "--app-name=dotdeploy",
+            "--urgency",
+            urgency.as_str(),
+            summary,
+            body,
+        ])
+        .status();
+
+    match status {
+        Ok(s) if s.success() => (),
+        Ok(s) => warn!("notify-send exited with {}", s),
+        Err(e) => warn!("Failed to run notify-send: {}", e),
+    }
+}
+
+/// Notifies about the result of a deployment.
+///
+/// # Arguments
+///
+/// * `result` - Summary of the finished deployment or the error it failed with
+pub(crate) fn deploy_finished(result: &anyhow::Result<DeploySummary>) {
+    let (summary, body, urgency) = deploy_notification(result);
+    send(&summary, &body, urgency);
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_deploy_notification() {
+        let summary = DeploySummary {
+            modules: vec!["git".to_string(), "zsh".to_string()],
+            duration_ms: 1340,
+            messages: std::collections::BTreeMap::from([(
+                "zsh".to_string(),
+                vec![crate::modules::messages::Message {
+                    level: crate::modules::messages::MessageLevel::Info,
+                    text: "Restart your shell".to_string(),
+                }],
+            )]),
+            timings: None,
+        };
+        let (title, body, urgency) = deploy_notification(&Ok(summary));
+        assert_eq!(title, "dotdeploy: Deployment finished");
+        assert_eq!(
+            body,
+            "Deployed git, zsh in 1.3s\n1 message(s), see the log for details"
+        );
+        assert_eq!(urgency, Urgency::Normal);
+
+        let error = Err(anyhow!("Permission denied").context("Failed to copy ~/.zshrc"));
+        let (title, body, urgency) = deploy_notification(&error);
+        assert_eq!(title, "dotdeploy: Deployment failed");
+        assert_eq!(body, "Failed to copy ~/.zshrc: Permission denied");
+        assert_eq!(urgency, Urgency::Critical);
+    }
+}
@@ -0,0 +1,245 @@
+//! This module finds orphaned links.
+//!
+//! An orphaned link points into the dotfiles (`config_root`), but is not tracked by a store, e.g. a
+//! leftover of a setup managed by GNU Stow before switching to dotdeploy. The folders listed in
+//! `orphan_paths` are searched recursively, without following linked directories.
+
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::cli::OutputFormat;
+use crate::report::{emit, Report};
+use crate::utils::common::ask_boolean;
+use crate::utils::file_fs;
+use crate::Stores;
+
+/// A link into the dotfiles which is not tracked by a store.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Orphan {
+    /// Path of the link
+    path: PathBuf,
+    /// Target of the link, as stored in the link
+    target: PathBuf,
+}
+
+/// Result of an orphan search.
+#[derive(Serialize, Debug)]
+struct OrphansReport {
+    orphans: Vec<Orphan>,
+}
+
+impl Report for OrphansReport {
+    fn print_text(&self) {
+        for o in self.orphans.iter() {
+            println!("{} -> {}", o.path.display(), o.target.display());
+        }
+
+        if self.orphans.is_empty() {
+            info!("No orphaned links found");
+        }
+    }
+}
+
+/// Normalizes a path lexically, resolving `.` and `..` without accessing the file system.
+///
+/// Link targets of orphans are often broken, so they can't be canonicalized.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// Recursively collects links below `dir` pointing into `root`.
+///
+/// Folders which can't be read are skipped.
+fn find_links(dir: &Path, root: &Path, links: &mut Vec<Orphan>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Skipping {:?}: {}", dir, e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_symlink() {
+            let Ok(target) = std::fs::read_link(&path) else {
+                continue;
+            };
+            // Relative targets, as created by stow, are relative to the folder of the link
+            let resolved = normalize(&dir.join(&target));
+            if resolved.starts_with(root) {
+                links.push(Orphan { path, target });
+            }
+        } else if file_type.is_dir() {
+            find_links(&path, root, links);
+        }
+    }
+}
+
+/// Finds links into the dotfiles which are not tracked by a store.
+///
+/// # Arguments
+///
+/// * `stores` - Database stores (user and optional system store)
+/// * `dotdeploy_config` - Configuration providing `config_root` and `orphan_paths`
+///
+/// # Returns
+///
+/// A Result containing the orphaned links, sorted by path
+async fn find(
+    stores: &Stores,
+    dotdeploy_config: &crate::config::DotdeployConfig,
+) -> Result<Vec<Orphan>> {
+    let mut tracked: HashSet<PathBuf> = stores
+        .user_store
+        .find_files(None, None)
+        .await
+        .map_err(|e| e.into_anyhow())?
+        .into_iter()
+        .map(|f| PathBuf::from(f.destination))
+        .collect();
+    if let Some(sys_store) = &stores.system_store {
+        tracked.extend(
+            sys_store
+                .find_files(None, None)
+                .await
+                .map_err(|e| e.into_anyhow())?
+                .into_iter()
+                .map(|f| PathBuf::from(f.destination)),
+        );
+    }
+
+    // Links may point to the dotfiles through a linked config_root
+    let mut roots = vec![normalize(&dotdeploy_config.config_root)];
+    if let Ok(root) = dotdeploy_config.config_root.canonicalize() {
+        if !roots.contains(&root) {
+            roots.push(root);
+        }
+    }
+
+    let mut links = vec![];
+    for dir in dotdeploy_config.orphan_paths.iter() {
+        for root in roots.iter() {
+            find_links(dir, root, &mut links);
+        }
+    }
+
+    let mut orphans: Vec<Orphan> = links
+        .into_iter()
+        .filter(|l| !tracked.contains(&l.path))
+        .collect();
+    orphans.sort_by(|a, b| a.path.cmp(&b.path));
+    orphans.dedup();
+
+    Ok(orphans)
+}
+
+/// Lists links into the dotfiles which are not tracked by a store.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `dotdeploy_config` - Configuration providing `config_root` and `orphan_paths`
+/// * `remove` - Ask to remove each orphaned link
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result containing `true` if no orphaned links are left
+pub(crate) async fn orphans(
+    stores: Arc<Stores>,
+    dotdeploy_config: &crate::config::DotdeployConfig,
+    remove: bool,
+    format: OutputFormat,
+) -> Result<bool> {
+    let mut orphans = find(&stores, dotdeploy_config).await?;
+
+    if remove {
+        let mut kept = vec![];
+        for o in orphans.into_iter() {
+            if ask_boolean(&format!(
+                "Remove {} -> {}? [y/N] ",
+                o.path.display(),
+                o.target.display()
+            )) {
+                file_fs::delete_path(&o.path).await?;
+                info!("Removed {:?}", o.path);
+            } else {
+                kept.push(o);
+            }
+        }
+        orphans = kept;
+    }
+
+    let clean = orphans.is_empty();
+    emit(&OrphansReport { orphans }, format)?;
+
+    Ok(clean)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_links() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let root = temp_dir.path().join("dotfiles");
+        let home = temp_dir.path().join("home");
+        std::fs::create_dir_all(root.join("vim"))?;
+        std::fs::create_dir_all(home.join(".config/nested"))?;
+        std::fs::write(root.join("vim/vimrc"), "")?;
+
+        // Absolute link
+        std::os::unix::fs::symlink(root.join("vim/vimrc"), home.join(".vimrc"))?;
+        // Relative, broken link as created by stow
+        std::os::unix::fs::symlink(
+            "../../../dotfiles/git/config",
+            home.join(".config/nested/gitconfig"),
+        )?;
+        // Link pointing elsewhere
+        std::os::unix::fs::symlink("/etc/hosts", home.join("hosts"))?;
+        // Linked folder, not followed
+        std::os::unix::fs::symlink(&home, home.join(".config/home"))?;
+
+        let mut links = vec![];
+        find_links(&home, &root, &mut links);
+        links.sort_by(|a, b| a.path.cmp(&b.path));
+
+        assert_eq!(
+            links,
+            vec![
+                Orphan {
+                    path: home.join(".config/nested/gitconfig"),
+                    target: PathBuf::from("../../../dotfiles/git/config"),
+                },
+                Orphan {
+                    path: home.join(".vimrc"),
+                    target: root.join("vim/vimrc"),
+                },
+            ]
+        );
+
+        Ok(())
+    }
+}
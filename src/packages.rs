@@ -1,7 +1,14 @@
-//! This module provides default package management commands for different Linux distributions.
+//! This module provides default package management commands for different Linux distributions
+//! and executes them, diagnosing common failures from their output.
 
 use std::collections::{HashMap, VecDeque};
-use anyhow::Result;
+use std::fmt::Write;
+use std::process::Stdio;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+use crate::config::PkgLockRetry;
 
 /// Returns default package installation and uninstallation commands for supported distributions.
 ///
@@ -33,7 +40,7 @@ pub(crate) fn default_cmds() -> Result<(
     install_cmds.insert(
         "gentoo".to_string(),
         vec![
-            "sudo".to_string(),
+            crate::utils::sudo::root_cmd(),
             "emerge".to_string(),
             "--verbose".to_string(),
             "--changed-use".to_string(),
@@ -46,7 +53,9 @@ pub(crate) fn default_cmds() -> Result<(
     install_cmds.insert(
         "ubuntu".to_string(),
         vec![
-            "sudo".to_string(),
+            crate::utils::sudo::root_cmd(),
+            // pkexec doesn't accept variable assignments like sudo does
+            "env".to_string(),
             "DEBIAN_FRONTEND=noninteractive".to_string(),
             "apt-get".to_string(),
             "install".to_string(),
@@ -63,7 +72,7 @@ pub(crate) fn default_cmds() -> Result<(
     uninstall_cmds.insert(
         "gentoo".to_string(),
         vec![
-            "sudo".to_string(),
+            crate::utils::sudo::root_cmd(),
             "emerge".to_string(),
             "--deselect".to_string(),
         ]
@@ -74,13 +83,330 @@ pub(crate) fn default_cmds() -> Result<(
     uninstall_cmds.insert(
         "ubuntu".to_string(),
         vec![
-            "sudo".to_string(),
+            crate::utils::sudo::root_cmd(),
             "apt-get".to_string(),
             "autoremove".to_string(),
             "--purge".to_string(),
+            "-y".to_string(),
         ]
         .into(),
     );
 
     Ok((install_cmds, uninstall_cmds))
 }
+
+/// Common causes of package manager failures, detected from the command output.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PackageFailure {
+    /// The package database is locked by another process.
+    LockHeld,
+    /// The package manager does not know the listed packages.
+    UnknownPackages(Vec<String>),
+    /// A network resource could not be reached.
+    Network,
+}
+
+impl PackageFailure {
+    /// Returns a suggestion on how to resolve the failure.
+    fn suggestion(&self) -> String {
+        match self {
+            PackageFailure::LockHeld => "The package database is locked by another process. Wait \
+                for other package managers or automatic updates to finish and try again."
+                .to_string(),
+            PackageFailure::UnknownPackages(pkgs) => format!(
+                "Unknown package(s): {}. Check the spelling and whether they are available for \
+                this distribution, or update the package database.",
+                pkgs.join(", ")
+            ),
+            PackageFailure::Network => "A network resource could not be reached. Check your \
+                connection and the configured mirrors."
+                .to_string(),
+        }
+    }
+}
+
+/// Extracts the package name following `marker` in `line`, stripping quotes and punctuation.
+fn package_after(line: &str, marker: &str) -> Option<String> {
+    line.find(marker)
+        .map(|idx| &line[idx + marker.len()..])
+        .and_then(|rest| rest.split_whitespace().next())
+        .map(|pkg| {
+            pkg.trim_matches(|c: char| c == '"' || c == '\'' || c == '.' || c == ',')
+                .to_string()
+        })
+        .filter(|pkg| !pkg.is_empty())
+}
+
+/// Detects common failure signatures in the output of a package manager.
+///
+/// Supported are the messages of apt, emerge, pacman and dnf.
+///
+/// # Arguments
+///
+/// * `output` - Combined stdout and stderr of the package manager
+///
+/// # Returns
+///
+/// The detected failures, empty if the cause is unknown.
+pub(crate) fn diagnose_failure(output: &str) -> Vec<PackageFailure> {
+    const LOCK_SIGNATURES: [&str; 5] = [
+        "Could not get lock",
+        "Unable to acquire the dpkg frontend lock",
+        "unable to lock database",
+        "another instance of emerge",
+        "Waiting for process with pid",
+    ];
+    const NETWORK_SIGNATURES: [&str; 6] = [
+        "Temporary failure resolving",
+        "Could not resolve",
+        "Failed to fetch",
+        "Connection timed out",
+        "Network is unreachable",
+        "failed retrieving file",
+    ];
+    const UNKNOWN_PACKAGE_MARKERS: [&str; 4] = [
+        "Unable to locate package ",
+        "there are no ebuilds to satisfy ",
+        "target not found: ",
+        "No match for argument: ",
+    ];
+
+    let mut failures = vec![];
+    let mut unknown = vec![];
+    let mut lock_held = false;
+    let mut network = false;
+
+    for line in output.lines() {
+        lock_held |= LOCK_SIGNATURES.iter().any(|sig| line.contains(sig));
+        network |= NETWORK_SIGNATURES.iter().any(|sig| line.contains(sig));
+        for marker in UNKNOWN_PACKAGE_MARKERS.iter() {
+            if let Some(pkg) = package_after(line, marker) {
+                if !unknown.contains(&pkg) {
+                    unknown.push(pkg);
+                }
+            }
+        }
+    }
+
+    if lock_held {
+        failures.push(PackageFailure::LockHeld);
+    }
+    if !unknown.is_empty() {
+        failures.push(PackageFailure::UnknownPackages(unknown));
+    }
+    if network {
+        failures.push(PackageFailure::Network);
+    }
+    failures
+}
+
+/// Forwards the lines of a stream to stderr while collecting them.
+///
+/// With `--quiet`, the lines are only collected.
+async fn tee_lines<R: AsyncRead + Unpin>(reader: R) -> String {
+    let quiet = crate::report::is_quiet();
+    let mut collected = String::new();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if !quiet {
+            eprintln!("{}", line);
+        }
+        collected.push_str(&line);
+        collected.push('\n');
+    }
+    collected
+}
+
+/// Runs a package manager command once, returning its exit status and captured error output.
+///
+/// Only stderr is captured, which is where package managers report errors. Stdout is redirected to
+/// stderr, which keeps prompts and progress bars working while stdout stays reserved for reports,
+/// e.g. with `--format json`. With `--quiet`, stdout is discarded.
+async fn run_package_cmd(program: &str, args: &VecDeque<String>) -> Result<(bool, String)> {
+    // Spawn the package manager process
+    let mut child = tokio::process::Command::new(program)
+        .args(args)
+        .stdout(if crate::report::is_quiet() {
+            Stdio::null()
+        } else {
+            std::io::stderr().into()
+        })
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to spawn {:?} with args: {:?}", program, args))?;
+
+    let stderr = tokio::spawn(tee_lines(
+        child.stderr.take().context("Failed to capture stderr")?,
+    ));
+
+    let status = child.wait().await?;
+    let output = stderr.await?;
+
+    Ok((status.success(), output))
+}
+
+/// Executes a package manager command for the given packages.
+///
+/// The output of the command is shown as usual, but its error output is also captured. If the
+/// package database is locked by another process, the command is retried according to `retry`. If
+/// the command fails otherwise, the error output is checked for common failure causes and the error
+/// contains targeted suggestions.
+///
+/// # Arguments
+///
+/// * `cmd` - The package manager command, the first element being the program
+/// * `packages` - Packages appended to the command
+/// * `retry` - Wait and retry policy for a locked package database
+///
+/// # Returns
+///
+/// A Result indicating success or failure of the command
+pub(crate) async fn exec_package_cmd(
+    mut cmd: VecDeque<String>,
+    packages: Vec<String>,
+    retry: &PkgLockRetry,
+) -> Result<()> {
+    let program = match cmd.pop_front() {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    cmd.extend(packages);
+
+    let start = std::time::Instant::now();
+    let timeout = std::time::Duration::from_secs(retry.timeout);
+    // An interval of 0 would hammer the package manager
+    let interval = std::time::Duration::from_secs(retry.interval.max(1));
+
+    loop {
+        let (success, output) = run_package_cmd(&program, &cmd).await?;
+        if success {
+            return Ok(());
+        }
+
+        let failures = diagnose_failure(&output);
+
+        // Wait for the lock to be released and try again
+        if failures.contains(&PackageFailure::LockHeld) && start.elapsed() + interval <= timeout {
+            info!(
+                "Package database is locked, retrying in {}s ({}s until timeout)",
+                interval.as_secs(),
+                (timeout - start.elapsed()).as_secs()
+            );
+            crate::utils::signal::check_cancelled()?;
+            tokio::time::sleep(interval).await;
+            continue;
+        }
+
+        let mut msg = format!("Failed to execute {:?} with args: {:?}", program, cmd);
+        if failures.is_empty() {
+            write!(msg, "\nThe cause could not be determined, see the output above.")?;
+        }
+        for f in failures.iter() {
+            write!(msg, "\nSuggestion: {}", f.suggestion())?;
+        }
+        bail!(msg)
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnose_failure() {
+        // apt
+        let output = "E: Could not get lock /var/lib/dpkg/lock-frontend. It is held by process 1234
+E: Unable to locate package fooo
+E: Unable to locate package barr";
+        assert_eq!(
+            diagnose_failure(output),
+            vec![
+                PackageFailure::LockHeld,
+                PackageFailure::UnknownPackages(vec!["fooo".to_string(), "barr".to_string()])
+            ]
+        );
+
+        // emerge
+        let output = r#"emerge: there are no ebuilds to satisfy "app-misc/fooo"."#;
+        assert_eq!(
+            diagnose_failure(output),
+            vec![PackageFailure::UnknownPackages(vec![
+                "app-misc/fooo".to_string()
+            ])]
+        );
+
+        // Network
+        let output = "Err:1 http://archive.ubuntu.com/ubuntu noble InRelease
+  Temporary failure resolving 'archive.ubuntu.com'";
+        assert_eq!(diagnose_failure(output), vec![PackageFailure::Network]);
+
+        // Unknown cause
+        assert!(diagnose_failure("Segmentation fault").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_exec_package_cmd() -> Result<()> {
+        let retry = PkgLockRetry::default();
+
+        // Successful command
+        exec_package_cmd(
+            vec!["echo".to_string(), "install".to_string()].into(),
+            vec!["foo".to_string()],
+            &retry,
+        )
+        .await?;
+
+        // Failing command with diagnosis
+        let err = exec_package_cmd(
+            vec!["sh".to_string(), "-c".to_string()].into(),
+            vec!["echo 'E: Unable to locate package foo' >&2; exit 100".to_string()],
+            &retry,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("Unknown package(s): foo"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exec_package_cmd_lock_retry() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let marker = temp_dir.path().join("locked");
+        let retry = PkgLockRetry {
+            timeout: 5,
+            interval: 1,
+        };
+
+        // Locked on the first attempt, succeeds on the second one
+        let script = format!(
+            "if [ -e {0} ]; then exit 0; fi; touch {0}; echo 'E: Could not get lock' >&2; exit 100",
+            marker.display()
+        );
+        exec_package_cmd(
+            vec!["sh".to_string(), "-c".to_string()].into(),
+            vec![script],
+            &retry,
+        )
+        .await?;
+
+        // Gives up once the timeout is reached
+        let retry = PkgLockRetry {
+            timeout: 0,
+            interval: 1,
+        };
+        let err = exec_package_cmd(
+            vec!["sh".to_string(), "-c".to_string()].into(),
+            vec!["echo 'E: Could not get lock' >&2; exit 100".to_string()],
+            &retry,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("locked by another process"));
+
+        Ok(())
+    }
+}
@@ -1,13 +1,13 @@
 use anyhow::{anyhow, bail, Context, Result};
 
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::path::PathBuf;
 use std::sync::atomic::Ordering;
 
 use crate::store::Stores;
 use crate::phases::destination::Destination;
 use crate::phases::file_operations::{FileOperation, ManagedFile};
-use crate::utils::file_fs;
+use crate::utils::file_permissions;
 
 pub(crate) mod destination;
 pub(crate) mod file_operations;
@@ -33,22 +33,25 @@ pub(crate) struct Phase {
 /// allowing for conditional deployment based on the context.
 ///
 /// # Arguments
-/// * `modules` - A set of modules whose configurations are to be processed and assigned.
+/// * `modules` - The modules whose configurations are to be processed and assigned. Modules should
+///   follow their dependencies, so their actions run after the actions of the dependencies.
 /// * `context` - A context used for evaluating conditional configurations within each module.
+/// * `dotdeploy_config` - Configuration providing defaults for unset file options.
 ///
 /// # Errors
 /// Returns an error if conditional evaluation fails for any module configuration, or if there's an
 /// attempt to use an undefined phase or action.
 pub(crate) async fn assign_module_config(
-    modules: std::collections::BTreeSet<crate::modules::Module>,
+    modules: Vec<crate::modules::Module>,
     context: serde_json::Value,
     stores: &Stores,
     messages: &mut (
-        std::collections::BTreeMap<String, Vec<String>>,
-        std::collections::BTreeMap<String, Vec<String>>,
+        std::collections::BTreeMap<String, Vec<crate::modules::messages::Message>>,
+        std::collections::BTreeMap<String, Vec<crate::modules::messages::Message>>,
     ),
     generators: &mut std::collections::BTreeMap<std::path::PathBuf, crate::modules::generate::Generate>,
     hb: &handlebars::Handlebars<'static>,
+    dotdeploy_config: &crate::config::DotdeployConfig,
 ) -> Result<BTreeMap<String, Phase>> {
     let mut phases: BTreeMap<String, Phase> = BTreeMap::new();
     let stage_names = ["pre", "main", "post"];
@@ -74,8 +77,17 @@ pub(crate) async fn assign_module_config(
         );
     }
 
+    // Targets excluded by the user are skipped for all modules
+    let exclusions: HashSet<String> = stores
+        .user_store
+        .get_exclusions()
+        .await
+        .map_err(|e| e.into_anyhow())?
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+
     // Iterate through each module to assign its configurations to the appropriate phase and stage.
-    // BTreeSet does not have drain so we do it manually. Should be fast enough.
     for mut module in modules.into_iter() {
         // Evaluate module configurations against the provided context
         module
@@ -93,82 +105,80 @@ pub(crate) async fn assign_module_config(
         // Add messages
         if let Some(mod_messages) = module.config.messages {
             for m in mod_messages.iter() {
-                match m.display_when.as_str() {
-                    "deploy" => {
-                        if messages.0.get(&module_name).is_none() {
-                            messages.0.insert(module_name.clone(), vec![]);
-                        }
-                        let value = messages.0.get_mut(&module_name).unwrap();
-                        let rendered =
-                            hb.render_template(&m.message, &context).with_context(|| {
-                                format!("Failed to render template {:?}", &m.message)
-                            })?;
-
-                        value.push(rendered);
-                        // messages.0.insert(module_name.clone(), value.to_vec());
-                    }
-                    "remove" => {
-                        if messages.1.get(&module_name).is_none() {
-                            messages.1.insert(module_name.clone(), vec![]);
-                        }
-                        let value = messages.1.get_mut(&module_name).unwrap();
-                        value.push(m.message.clone());
-                        let rendered =
-                            hb.render_template(&m.message, &context).with_context(|| {
-                                format!("Failed to render template {:?}", &m.message)
-                            })?;
-
-                        value.push(rendered);
-                        // messages.0.insert(module_name.clone(), value.to_vec());
-                    }
-                    _ => unreachable!(),
+                if !crate::modules::messages::is_shown(m.level, dotdeploy_config) {
+                    continue;
                 }
+                let value = match m.display_when.as_str() {
+                    "deploy" => messages.0.entry(module_name.clone()).or_default(),
+                    "remove" => messages.1.entry(module_name.clone()).or_default(),
+                    _ => unreachable!(),
+                };
+                let rendered = hb
+                    .render_template(&m.message, &context)
+                    .with_context(|| format!("Failed to render template {:?}", &m.message))?;
+
+                value.push(crate::modules::messages::Message {
+                    level: m.level,
+                    text: rendered,
+                });
             }
         }
 
         // Add generators
         if let Some(mod_generators) = module.config.generate {
-            for (k, v) in mod_generators.into_iter() {
+            for (k, mut v) in mod_generators.into_iter() {
+                let k = crate::target_root::rebase(&k);
+                // Keep the priorities of snippets declared by other modules
+                if let Some(prev) = generators.remove(&k) {
+                    v.priorities = prev.priorities;
+                }
+                v.priorities.insert(module_name.clone(), v.priority.unwrap_or(0));
                 generators.insert(k, v);
             }
         }
 
         // Retrieve deployed files in order to check if their status is still valid. Thus, if a source
         // does not exist or it is not part of the config anymore, remove the destination.
-        let mut user_files: HashMap<String, (Option<String>, String)> = stores
+        let mut user_files: HashMap<String, (Option<String>, String, Option<String>)> = stores
             .user_store
             .get_all_files(&module_name)
             .await
             .map_err(|e| e.into_anyhow())?
             .into_iter()
-            .map(|f| (f.destination, (f.source, f.operation)))
+            .map(|f| (f.destination, (f.source, f.operation, f.destination_checksum)))
             .collect();
-        let mut sys_files: HashMap<String, (Option<String>, String)> =
+        let mut sys_files: HashMap<String, (Option<String>, String, Option<String>)> =
             if let Some(ref sys_store) = stores.system_store {
                 sys_store
                     .get_all_files(&module_name)
                     .await
                     .map_err(|e| e.into_anyhow())?
                     .into_iter()
-                    .map(|f| (f.destination, (f.source, f.operation)))
+                    .map(|f| (f.destination, (f.source, f.operation, f.destination_checksum)))
                     .collect()
             } else {
                 HashMap::new()
             };
 
         // Assign files to phases based on their specified deployment phase.
-        if let Some(files) = module.config.files {
+        if let Some(mut files) = module.config.files {
+            // Files inherit the strict mode of their module
+            for conf in files.values_mut() {
+                conf.template_strict = conf.template_strict.or(module.config.template_strict);
+            }
             assign_files_to_phases(
                 module_name.clone(),
                 files,
                 &mut phases,
                 &mut user_files,
                 &mut sys_files,
+                &exclusions,
+                dotdeploy_config,
             )?;
         }
         // Assign actions to their respective phases and stages.
         if let Some(actions) = module.config.actions {
-            assign_actions_to_phases(actions, &mut phases)?;
+            assign_actions_to_phases(&module_name, actions, &mut phases)?;
         }
         // Append packages to the deploy phase, if any.
         if let Some(packages) = module.config.packages {
@@ -176,7 +186,7 @@ pub(crate) async fn assign_module_config(
         }
 
         // Remove files with missing source files and files which are dynamically created.
-        for (k, _) in user_files {
+        for (k, (_, operation, checksum)) in user_files {
             info!(
                 "{}: '{}' is not part of the config anymore, its action has changed or source file has been removed. Removing.",
                 module_name, k
@@ -187,9 +197,12 @@ pub(crate) async fn assign_module_config(
                 .await
                 .map_err(|e| e.into_anyhow())?;
 
-            file_fs::delete_file(&k)
+            if !crate::remove::delete_deployed(&k, &operation, checksum.as_deref())
                 .await
-                .with_context(|| format!("Failed to remove file {:?}", &k))?;
+                .with_context(|| format!("Failed to remove file {:?}", &k))?
+            {
+                continue;
+            }
 
             // Restore backup
             if stores
@@ -214,7 +227,7 @@ pub(crate) async fn assign_module_config(
                 info!("Restored {:?} from backup", &k);
             }
         }
-        for (k, _) in sys_files {
+        for (k, (_, operation, checksum)) in sys_files {
             info!(
                 "{}: '{}' is not part of the config anymore, its action has changed or source file has been removed. Removing.",
                 module_name, k
@@ -228,9 +241,12 @@ pub(crate) async fn assign_module_config(
                 .await
                 .map_err(|e| e.into_anyhow())?;
 
-            file_fs::delete_file(&k)
+            if !crate::remove::delete_deployed(&k, &operation, checksum.as_deref())
                 .await
-                .with_context(|| format!("Failed to remove file {:?}", &k))?;
+                .with_context(|| format!("Failed to remove file {:?}", &k))?
+            {
+                continue;
+            }
 
             // Restore backup
             if stores
@@ -285,16 +301,40 @@ fn assign_files_to_phases(
     module_name: String,
     files: BTreeMap<PathBuf, crate::modules::files::ModuleFile>,
     phases: &mut BTreeMap<String, Phase>,
-    user_files: &mut HashMap<String, (Option<String>, String)>,
-    sys_files: &mut HashMap<String, (Option<String>, String)>,
+    user_files: &mut HashMap<String, (Option<String>, String, Option<String>)>,
+    sys_files: &mut HashMap<String, (Option<String>, String, Option<String>)>,
+    exclusions: &HashSet<String>,
+    dotdeploy_config: &crate::config::DotdeployConfig,
 ) -> Result<()> {
+    let home = shellexpand::full("$HOME")
+        .context("Failed to expand $HOME")?
+        .to_string();
     for (dest, conf) in files.into_iter() {
-        // Check if source is defined for copy and link as well as if the file exists. If one
-        // check fails, return early.
+        // Targets in HOME stay files of the user when deployed below a target root
+        let in_home = dest.starts_with(&home);
+        let dest = crate::target_root::rebase(&dest);
+
+        // Skip excluded targets. Already deployed files are kept as they are.
+        let dest_str = dest
+            .to_str()
+            .ok_or_else(|| anyhow!("Filename contains invalid Unicode characters"))?
+            .replace("##dot##", ".");
+        if exclusions.contains(&dest_str) {
+            info!("{}: '{}' is excluded, skipping", module_name, dest_str);
+            user_files.remove(&dest_str);
+            sys_files.remove(&dest_str);
+            continue;
+        }
+
+        // Check if source is defined for copy, link, hardlink and directory as well as if the file
+        // exists. If one check fails, return early.
         match conf.action.as_deref() {
-            Some("copy") | Some("link") => {
+            Some("copy") | Some("link") | Some("hardlink") | Some("directory") => {
                 let source = conf.source.clone().ok_or_else(|| {
-                    anyhow!("'source' is required for 'link' or 'copy' operations")
+                    anyhow!(
+                        "'source' is required for 'link', 'hardlink', 'copy' or 'directory' \
+                         operations"
+                    )
                 })?;
                 match source.try_exists() {
                     Ok(true) => (),
@@ -303,15 +343,17 @@ fn assign_files_to_phases(
                         source.display()
                     ),
                 }
+                if conf.action.as_deref() == Some("directory") && !source.is_dir() {
+                    bail!("Source {} is not a directory", source.display());
+                }
+                if conf.action.as_deref() == Some("hardlink") && !source.is_file() {
+                    bail!("Source {} is not a regular file", source.display());
+                }
             }
             _ => (),
         }
 
-        let destination = if dest.starts_with(
-            &shellexpand::full("$HOME")
-                .context("Failed to expand $HOME")?
-                .to_string(),
-        ) {
+        let destination = if in_home {
             Destination::Home(PathBuf::from(
                 &dest
                     .to_str()
@@ -335,14 +377,64 @@ fn assign_files_to_phases(
                     };
 
         // Directly extract the inner fields if permissions is Some, otherwise set them to None
-        let (owner, group, perms) = conf.permissions.map_or((None, None, None), |perms| {
-            (perms.owner, perms.group, perms.permissions)
-        });
+        let (mut owner, mut group, mut perms) =
+            conf.permissions.map_or((None, None, None), |perms| {
+                (perms.owner, perms.group, perms.permissions)
+            });
+
+        // Fall back to the configured defaults. Hard links share their metadata with the source and
+        // only copied and created files have permissions of their own.
+        let defaults = &dotdeploy_config.file_defaults;
+        if matches!(conf.action.as_deref(), Some("copy") | Some("create")) {
+            perms = perms.or_else(|| defaults.permissions.clone());
+        }
+        if matches!(destination, Destination::Root(_)) && conf.action.as_deref() != Some("hardlink")
+        {
+            owner = owner.or_else(|| defaults.system_owner.clone());
+            group = group.or_else(|| defaults.system_group.clone());
+        }
+        // Files deployed for another user belong to that user
+        if let Some(user) = crate::target_user::get() {
+            if matches!(destination, Destination::Home(_))
+                && conf.action.as_deref() != Some("hardlink")
+            {
+                owner = owner.or_else(|| Some(user.name.clone()));
+                group = group.or_else(|| Some(user.gid.to_string()));
+            }
+        }
+
+        // Fail before any file is written if the owner or group does not exist or the permissions
+        // are invalid
+        if let Some(owner) = owner.as_ref() {
+            file_permissions::user_to_uid(owner)
+                .with_context(|| format!("{}: Invalid owner of {}", module_name, dest_str))?;
+        }
+        if let Some(group) = group.as_ref() {
+            file_permissions::group_to_gid(group)
+                .with_context(|| format!("{}: Invalid group of {}", module_name, dest_str))?;
+        }
+        if let Some(perms) = perms.as_ref() {
+            file_permissions::apply_mode(perms, 0).with_context(|| {
+                format!("{}: Invalid permissions of {}", module_name, dest_str)
+            })?;
+        }
+
+        if conf.filter.is_some() && !matches!(conf.action.as_deref(), Some("copy") | Some("create"))
+        {
+            bail!(
+                "{}: 'filter' of {} requires the 'copy' or 'create' action",
+                module_name,
+                dest_str
+            );
+        }
 
         let operation = match conf.action.as_deref() {
-            Some("copy") | Some("link") => {
+            Some("copy") | Some("link") | Some("hardlink") | Some("directory") => {
                 let source = conf.source.ok_or_else(|| {
-                    anyhow!("'source' is required for 'link' or 'copy' operations")
+                    anyhow!(
+                        "'source' is required for 'link', 'hardlink', 'copy' or 'directory' \
+                         operations"
+                    )
                 })?;
 
                 // Remove files with changed source, which means here: keep them in the hashmap.
@@ -372,7 +464,13 @@ fn assign_files_to_phases(
                         owner: owner.map(String::from),
                         group: group.map(String::from),
                         permissions: perms.map(String::from),
-                        template: conf.template.map(bool::from),
+                        template: Some(resolve_template(
+                            conf.template,
+                            &dest,
+                            dotdeploy_config,
+                        )?),
+                        template_strict: conf.template_strict.unwrap_or(true),
+                        filter: conf.filter.unwrap_or_default(),
                     },
                     Some("link") => FileOperation::Symlink {
                         source,
@@ -380,7 +478,36 @@ fn assign_files_to_phases(
                         owner: owner.map(String::from),
                         group: group.map(String::from),
                     },
-                    // We've already filtered for "copy" or "link"
+                    Some("directory") => {
+                        if perms.is_some() {
+                            bail!(
+                                "'permissions' are not supported for 'directory' operations, \
+                                 only 'owner' and 'group'"
+                            );
+                        }
+                        FileOperation::Directory {
+                            source,
+                            destination,
+                            copy: conf.copy.unwrap_or(false),
+                            owner,
+                            group,
+                        }
+                    }
+                    Some("hardlink") => {
+                        // The hard link shares its metadata with the source, setting it would
+                        // change the source as well
+                        if owner.is_some() || group.is_some() || perms.is_some() {
+                            bail!(
+                                "'permissions' are not supported for 'hardlink' operations, the \
+                                 source would be changed as well"
+                            );
+                        }
+                        FileOperation::Hardlink {
+                            source,
+                            destination,
+                        }
+                    }
+                    // We've already filtered for "copy", "link", "hardlink" or "directory"
                     _ => unreachable!(),
                 }
             }
@@ -395,7 +522,9 @@ fn assign_files_to_phases(
                     owner: owner.map(String::from),
                     group: group.map(String::from),
                     permissions: perms.map(String::from),
-                    template: conf.template,
+                    template: Some(resolve_template(conf.template, &dest, dotdeploy_config)?),
+                    template_strict: conf.template_strict.unwrap_or(true),
+                    filter: conf.filter.unwrap_or_default(),
                 }
             }
             _ => return Err(anyhow!("Unsupported file action for '{}'", dest.display())),
@@ -421,8 +550,27 @@ fn assign_files_to_phases(
     Ok(())
 }
 
+/// Determines if a copied or created file is a template, falling back to the configured default if
+/// the module does not specify it. Fails if the config requires the `template` field to be set
+/// explicitly.
+fn resolve_template(
+    template: Option<bool>,
+    dest: &std::path::Path,
+    dotdeploy_config: &crate::config::DotdeployConfig,
+) -> Result<bool> {
+    match template {
+        Some(t) => Ok(t),
+        None if dotdeploy_config.require_template => Err(anyhow!(
+            "'template' is required for file '{}'",
+            dest.display()
+        )),
+        None => Ok(dotdeploy_config.template_default),
+    }
+}
+
 /// Assigns actions from a module to their corresponding phases and stages.
 fn assign_actions_to_phases(
+    module_name: &str,
     actions: BTreeMap<String, BTreeMap<String, Vec<crate::modules::actions::ModuleAction>>>,
     phases: &mut BTreeMap<String, Phase>,
 ) -> Result<()> {
@@ -437,7 +585,10 @@ fn assign_actions_to_phases(
                     phase.actions.as_mut().and_then(|a| a.get_mut(stage_name))
                 {
                     // If the stage is found, extend its list of actions with those from the module
-                    phase_stage_actions.extend(action_vec.iter().cloned());
+                    phase_stage_actions.extend(action_vec.iter().cloned().map(|mut a| {
+                        a.module = Some(module_name.to_string());
+                        a
+                    }));
                 } else {
                     // Return an error if the specified stage does not exist within the phase
                     return Err(anyhow!(
@@ -507,3 +658,264 @@ fn append_packages(
 //     // code/programm executions
 //     actions: ...,
 // }
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use crate::modules::queue::tests::create_test_config;
+
+    /// Returns a destination in HOME, so no owner is set.
+    fn test_dest() -> Result<PathBuf> {
+        Ok(PathBuf::from(
+            shellexpand::full("$HOME/.dotdeploy-test")?.as_ref(),
+        ))
+    }
+
+    /// Creates phases holding only an empty deploy phase.
+    fn test_phases() -> BTreeMap<String, Phase> {
+        BTreeMap::from([(
+            "deploy".to_string(),
+            Phase {
+                files: Some(VecDeque::new()),
+                actions: None,
+                packages: None,
+            },
+        )])
+    }
+
+    /// Assigns a single file of the module "test" to the phases.
+    fn assign_file(
+        phases: &mut BTreeMap<String, Phase>,
+        dest: &Path,
+        conf: crate::modules::files::ModuleFile,
+        config: &crate::config::DotdeployConfig,
+    ) -> Result<()> {
+        assign_files_to_phases(
+            "test".to_string(),
+            BTreeMap::from([(dest.to_path_buf(), conf)]),
+            phases,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+            &HashSet::new(),
+            config,
+        )
+    }
+
+    #[test]
+    fn test_resolve_template() -> Result<()> {
+        let dest = Path::new("/tmp/foo.txt");
+        let temp_dir = tempfile::tempdir()?;
+        let test_config = |template_default, require_template| crate::config::DotdeployConfig {
+            template_default,
+            require_template,
+            ..create_test_config(&temp_dir)
+        };
+
+        // Explicit values always win
+        assert!(resolve_template(Some(true), dest, &test_config(false, true))?);
+        assert!(!resolve_template(Some(false), dest, &test_config(true, false))?);
+
+        // Missing values fall back to the default
+        assert!(!resolve_template(None, dest, &test_config(false, false))?);
+        assert!(resolve_template(None, dest, &test_config(true, false))?);
+
+        // Unless the field is required
+        assert!(resolve_template(None, dest, &test_config(false, true)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_owner() -> Result<()> {
+        let dest = test_dest()?;
+        let file = |owner: &str, group: &str| crate::modules::files::ModuleFile {
+            action: Some("create".to_string()),
+            content: Some("foo".to_string()),
+            phase: Some("deploy".to_string()),
+            permissions: Some(crate::modules::files::FilePermissions {
+                owner: Some(owner.to_string()),
+                group: Some(group.to_string()),
+                permissions: Some("u+x,go-w".to_string()),
+            }),
+            ..Default::default()
+        };
+        let temp_dir = tempfile::tempdir()?;
+        let mut phases = test_phases();
+        let config = create_test_config(&temp_dir);
+        let mut assign = |conf| assign_file(&mut phases, &dest, conf, &config);
+
+        let e = assign(file("dotdeploy-missing-user", "root")).unwrap_err();
+        assert!(format!("{:#}", e).contains("Invalid owner"));
+        let e = assign(file("root", "dotdeploy-missing-group")).unwrap_err();
+        assert!(format!("{:#}", e).contains("Invalid group"));
+        let mut invalid = file("root", "root");
+        invalid.permissions.as_mut().unwrap().permissions = Some("u+y".to_string());
+        let e = assign(invalid).unwrap_err();
+        assert!(format!("{:#}", e).contains("Invalid permissions"));
+        // Names and IDs are both accepted
+        assign(file("root", "0"))?;
+        assert_eq!(phases["deploy"].files.as_ref().unwrap().len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_action() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        std::fs::write(temp_dir.path().join("file"), "")?;
+        let dest = test_dest()?;
+        let dir = |source: PathBuf, permissions: Option<&str>| crate::modules::files::ModuleFile {
+            source: Some(source),
+            action: Some("directory".to_string()),
+            copy: Some(true),
+            phase: Some("deploy".to_string()),
+            permissions: permissions.map(|p| crate::modules::files::FilePermissions {
+                owner: None,
+                group: None,
+                permissions: Some(p.to_string()),
+            }),
+            ..Default::default()
+        };
+        let mut phases = test_phases();
+        let config = create_test_config(&temp_dir);
+        let mut assign = |conf| assign_file(&mut phases, &dest, conf, &config);
+
+        assert!(assign(dir(temp_dir.path().join("file"), None)).is_err());
+        assert!(assign(dir(temp_dir.path().to_path_buf(), Some("755"))).is_err());
+        assign(dir(temp_dir.path().to_path_buf(), None))?;
+
+        let files = phases["deploy"].files.as_ref().unwrap();
+        assert!(matches!(
+            files[0].operation,
+            FileOperation::Directory { copy: true, .. }
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hardlink_action() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        std::fs::write(temp_dir.path().join("file"), "")?;
+        let dest = test_dest()?;
+        let hardlink = |source: PathBuf, permissions: Option<&str>| {
+            crate::modules::files::ModuleFile {
+                source: Some(source),
+                action: Some("hardlink".to_string()),
+                phase: Some("deploy".to_string()),
+                permissions: permissions.map(|p| crate::modules::files::FilePermissions {
+                    owner: None,
+                    group: None,
+                    permissions: Some(p.to_string()),
+                }),
+                ..Default::default()
+            }
+        };
+        let mut phases = test_phases();
+        let config = create_test_config(&temp_dir);
+        let mut assign = |conf| assign_file(&mut phases, &dest, conf, &config);
+
+        assert!(assign(hardlink(temp_dir.path().to_path_buf(), None)).is_err());
+        assert!(assign(hardlink(temp_dir.path().join("file"), Some("644"))).is_err());
+        assign(hardlink(temp_dir.path().join("file"), None))?;
+
+        let files = phases["deploy"].files.as_ref().unwrap();
+        assert!(matches!(files[0].operation, FileOperation::Hardlink { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_defaults() -> Result<()> {
+        let dest = test_dest()?;
+        let file = |permissions: Option<&str>| crate::modules::files::ModuleFile {
+            action: Some("create".to_string()),
+            content: Some("foo".to_string()),
+            phase: Some("deploy".to_string()),
+            permissions: permissions.map(|p| crate::modules::files::FilePermissions {
+                owner: None,
+                group: None,
+                permissions: Some(p.to_string()),
+            }),
+            ..Default::default()
+        };
+        let temp_dir = tempfile::tempdir()?;
+        let mut config = create_test_config(&temp_dir);
+        config.file_defaults.permissions = Some("600".to_string());
+        config.file_defaults.system_owner = Some("root".to_string());
+        let mut phases = test_phases();
+
+        for conf in [file(None), file(Some("644"))] {
+            assign_file(&mut phases, &dest, conf, &config)?;
+        }
+
+        let files = phases["deploy"].files.as_ref().unwrap();
+        let modes: Vec<_> = files
+            .iter()
+            .map(|f| match &f.operation {
+                FileOperation::Create {
+                    owner, permissions, ..
+                } => (owner.clone(), permissions.clone()),
+                _ => unreachable!(),
+            })
+            .collect();
+        // Files in HOME keep their owner
+        assert_eq!(
+            modes,
+            vec![
+                (None, Some("600".to_string())),
+                (None, Some("644".to_string()))
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_module_packages() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let stores = Stores {
+            user_store: crate::store::tests::store_setup_helper("copy").await?,
+            system_store: None,
+        };
+        let module = crate::modules::Module {
+            name: "test".to_string(),
+            location: PathBuf::from("/tmp"),
+            reason: "manual".to_string(),
+            config: crate::modules::config::ModuleConfig {
+                packages: Some(vec![crate::modules::packages::ModulePackages {
+                    install: vec!["foo".to_string(), "bar".to_string()],
+                    eval_when: None,
+                }]),
+                ..Default::default()
+            },
+        };
+
+        let phases = assign_module_config(
+            vec![module],
+            serde_json::json!({}),
+            &stores,
+            &mut Default::default(),
+            &mut BTreeMap::new(),
+            &handlebars::Handlebars::new(),
+            &create_test_config(&temp_dir),
+        )
+        .await?;
+
+        // The packages of a module are installed on deploy and removed together with the module
+        for phase in ["deploy", "remove"] {
+            assert_eq!(
+                phases[phase].packages.as_deref(),
+                Some(&["foo".to_string(), "bar".to_string()][..])
+            );
+        }
+
+        Ok(())
+    }
+}
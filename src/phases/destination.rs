@@ -13,12 +13,25 @@ use tokio::fs;
 use crate::utils::file_fs;
 use crate::utils::sudo;
 
+/// Renders a template using the render cache, recording the time spent for `--timings`.
+pub(crate) fn render(
+    hb: &Handlebars<'static>,
+    template: &str,
+    context: &Value,
+) -> Result<String, handlebars::RenderError> {
+    let start = std::time::Instant::now();
+    let rendered = crate::utils::render_cache::render(hb, template, context);
+    crate::timings::record(crate::timings::Category::Render, None, start);
+    rendered
+}
+
 /// Represents the destination of the file operation, including whether sudo is required.
 #[derive(Debug, Clone)]
 pub(crate) enum Destination {
     /// Represents a destination in the user's home directory. Should not require sudo.
     Home(PathBuf),
-    /// Represents a destination in a root-owned directory. Should require sudo.
+    /// Represents a destination outside of the user's home directory. Requires sudo if the user
+    /// can't write to it.
     Root(PathBuf),
 }
 
@@ -57,8 +70,15 @@ impl Destination {
                     .await
             }
             Destination::Root(dest) => {
-                self.copy_fn(source, dest, template, context, hb, true)
-                    .await
+                self.copy_fn(
+                    source,
+                    dest,
+                    template,
+                    context,
+                    hb,
+                    file_fs::needs_sudo(dest),
+                )
+                .await
             }
         }
     }
@@ -91,7 +111,7 @@ impl Destination {
                     let temp_file = tempfile::NamedTempFile::new()?;
                     let file_content = fs::read_to_string(&source).await?;
                     let rendered =
-                        hb.render_template(&file_content, context)
+                        render(hb, &file_content, context)
                             .with_context(|| {
                                 format!("Failed to render template {:?}", &source.as_ref())
                             })?;
@@ -112,11 +132,11 @@ impl Destination {
                     // If it's a template, render it before writing
                     let file_content = fs::read_to_string(&source).await?;
                     let rendered =
-                        hb.render_template(&file_content, context)
+                        render(hb, &file_content, context)
                             .with_context(|| {
                                 format!("Failed to render template {:?}", &source.as_ref())
                             })?;
-                    fs::write(dest, rendered).await?;
+                    file_fs::write_file(dest, rendered).await?;
                 }
             }
         } else {
@@ -126,6 +146,7 @@ impl Destination {
                     sudo::sudo_exec(
                         "cp",
                         &[
+                            "--reflink=auto",
                             &file_fs::path_to_string(&source)?,
                             &file_fs::path_to_string(dest)?,
                         ],
@@ -135,9 +156,7 @@ impl Destination {
                 }
                 false => {
                     // If it's not a template, perform a simple copy
-                    fs::copy(&source, dest).await.with_context(|| {
-                        format!("Failed to copy {:?} to {:?}", source.as_ref(), dest)
-                    })?;
+                    file_fs::copy_file(source.as_ref(), dest).await?;
                 }
             }
         }
@@ -156,7 +175,7 @@ impl Destination {
     pub(crate) async fn link<P: AsRef<Path>>(&self, source: P) -> Result<()> {
         match self {
             Destination::Home(dest) => self.link_fn(source, dest, false).await,
-            Destination::Root(dest) => self.link_fn(source, dest, true).await,
+            Destination::Root(dest) => self.link_fn(source, dest, file_fs::needs_sudo(dest)).await,
         }
     }
 
@@ -198,6 +217,86 @@ impl Destination {
         Ok(())
     }
 
+    /// Creates a hard link at the destination pointing to the source.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The file the hard link should point to.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success or failure of the hard link creation.
+    pub(crate) async fn hardlink<P: AsRef<Path>>(&self, source: P) -> Result<()> {
+        let dest = self.path();
+
+        // Ensure the parent directory exists
+        let parent = dest
+            .parent()
+            .ok_or_else(|| anyhow!("Could not get parent of {:?}", dest))?;
+        file_fs::ensure_dir_exists(parent).await?;
+
+        // Remove existing file or symlink if it exists
+        if file_fs::check_file_exists(dest).await? {
+            file_fs::delete_file(dest).await?
+        }
+
+        file_fs::hardlink_file(source.as_ref(), dest).await
+    }
+
+    /// Copies a directory tree to the destination, replacing an existing file or tree.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The directory to copy.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success or failure of the copy operation.
+    pub(crate) async fn copy_dir<P: AsRef<Path>>(&self, source: P) -> Result<()> {
+        let dest = self.path();
+        let sudo = match self {
+            Destination::Home(_) => false,
+            Destination::Root(dest) => file_fs::needs_sudo(dest),
+        };
+
+        // Ensure the parent directory exists
+        let parent = dest
+            .parent()
+            .ok_or_else(|| anyhow!("Could not get parent of {:?}", dest))?;
+        file_fs::ensure_dir_exists(parent).await?;
+
+        // Remove the existing file, link or tree
+        if file_fs::check_file_exists(dest).await?
+            || file_fs::check_link_exists(dest.as_path(), None).await?
+        {
+            file_fs::delete_path(dest).await?
+        }
+
+        match sudo {
+            true => {
+                // Copy the tree using sudo
+                sudo::sudo_exec(
+                    "cp",
+                    &[
+                        "-R",
+                        "--reflink=auto",
+                        &file_fs::path_to_string(&source)?,
+                        &file_fs::path_to_string(dest)?,
+                    ],
+                    Some(&format!("Copy {:?} to {:?}", source.as_ref(), dest)),
+                )
+                .await?;
+            }
+            false => {
+                // Copy the tree
+                let (source, dest) = (source.as_ref().to_path_buf(), dest.clone());
+                tokio::task::spawn_blocking(move || file_fs::copy_tree(&source, &dest)).await??;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Creates a file at the destination with the given content, with optional templating.
     ///
     /// # Arguments
@@ -223,8 +322,15 @@ impl Destination {
                     .await
             }
             Destination::Root(dest) => {
-                self.create_fn(content, dest, template, context, hb, true)
-                    .await
+                self.create_fn(
+                    content,
+                    dest,
+                    template,
+                    context,
+                    hb,
+                    file_fs::needs_sudo(dest),
+                )
+                .await
             }
         }
     }
@@ -239,68 +345,64 @@ impl Destination {
         hb: &Handlebars<'static>,
         sudo: bool,
     ) -> Result<()> {
+        if template.is_some_and(|t| t == true) {
+            // If it's a template, render it before writing
+            let rendered = render(hb, content.as_ref(), context)
+                .with_context(|| format!("Failed to render template for {:?}", dest))?;
+            self.write_fn(rendered.as_bytes(), dest, sudo).await
+        } else {
+            // If it's not a template, write the content directly
+            self.write_fn(content.as_ref().as_bytes(), dest, sudo).await
+        }
+    }
+
+    /// Writes raw content to the destination, e.g. a binary file.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content to write to the file.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success or failure of writing the file.
+    pub(crate) async fn write(&self, content: &[u8]) -> Result<()> {
+        match self {
+            Destination::Home(dest) => self.write_fn(content, dest, false).await,
+            Destination::Root(dest) => {
+                self.write_fn(content, dest, file_fs::needs_sudo(dest))
+                    .await
+            }
+        }
+    }
+
+    /// Writes a file in the directory given by `dest`.
+    async fn write_fn(&self, content: &[u8], dest: &Path, sudo: bool) -> Result<()> {
         // Ensure the parent directory exists
         let parent = dest
             .parent()
             .ok_or_else(|| anyhow!("Could not get parent of {:?}", dest))?;
         file_fs::ensure_dir_exists(parent).await?;
 
-        let temp_file = tempfile::NamedTempFile::new()?;
-
-        if template.is_some_and(|t| t == true) {
-            match sudo {
-                true => {
-                    // If it's a template, render it before writing to the temporary file
-                    fs::write(
-                        &temp_file,
-                        hb.render_template(content.as_ref(), context)
-                            .with_context(|| {
-                                format!("Failed to render template for {:?}", temp_file)
-                            })?,
-                    )
-                    .await
-                    .with_context(|| format!("Failed to create {:?}", temp_file))?;
-                }
-                false => {
-                    // If it's a template, render it before writing
-                    fs::write(
-                        dest,
-                        hb.render_template(content.as_ref(), context)
-                            .with_context(|| format!("Failed to render template for {:?}", dest))?,
-                    )
-                    .await
-                    .with_context(|| format!("Failed to create {:?}", dest))?;
-                }
-            }
-        } else {
-            match sudo {
-                true => {
-                    // If it's not a template, write the content directly to the temporary file
-                    fs::write(&temp_file, content.as_ref())
-                        .await
-                        .with_context(|| format!("Failed to create {:?}", temp_file))?;
-                }
-                false => {
-                    // If it's not a template, write the content directly
-                    fs::write(dest, content.as_ref())
-                        .await
-                        .with_context(|| format!("Failed to create {:?}", dest))?;
-                }
-            }
+        if !sudo {
+            return file_fs::write_file(dest, content)
+                .await
+                .with_context(|| format!("Failed to create {:?}", dest));
         }
 
-        if sudo {
-            // Copy the temporary file to the destination using sudo
-            sudo::sudo_exec(
-                "cp",
-                &[
-                    &file_fs::path_to_string(&temp_file)?,
-                    &file_fs::path_to_string(dest)?,
-                ],
-                None,
-            )
-            .await?;
-        }
+        // Write to a temporary file and copy it to the destination using sudo
+        let temp_file = tempfile::NamedTempFile::new()?;
+        fs::write(&temp_file, content)
+            .await
+            .with_context(|| format!("Failed to create {:?}", temp_file))?;
+        sudo::sudo_exec(
+            "cp",
+            &[
+                &file_fs::path_to_string(&temp_file)?,
+                &file_fs::path_to_string(dest)?,
+            ],
+            None,
+        )
+        .await?;
 
         Ok(())
     }
@@ -503,4 +605,52 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_copy_dir() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let source = temp_dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).await?;
+        fs::write(source.join("sub/file.txt"), "foo").await?;
+
+        // Existing files and trees are replaced
+        let dest_path = temp_dir.path().join("nested/dest");
+        fs::create_dir_all(dest_path.join("old")).await?;
+        let home_dest = Destination::Home(dest_path.clone());
+        home_dest.copy_dir(&source).await?;
+        assert_eq!(
+            fs::read_to_string(dest_path.join("sub/file.txt")).await?,
+            "foo"
+        );
+        assert!(!dest_path.join("old").exists());
+
+        // A linked tree is replaced by a copy
+        file_fs::delete_path(&dest_path).await?;
+        home_dest.link(&source).await?;
+        home_dest.copy_dir(&source).await?;
+        assert!(!dest_path.is_symlink());
+        assert!(dest_path.join("sub/file.txt").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hardlink() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let source_path = temp_dir.path().join("source.txt");
+        let dest_path = temp_dir.path().join("sub/dest.txt");
+        fs::write(&source_path, "Hello, World!").await?;
+
+        let home_dest = Destination::Home(dest_path.clone());
+        home_dest.hardlink(&source_path).await?;
+        assert!(file_fs::check_hardlink_exists(&dest_path, &source_path).await?);
+
+        // Existing files are replaced
+        file_fs::delete_file(&dest_path).await?;
+        fs::write(&dest_path, "old").await?;
+        home_dest.hardlink(&source_path).await?;
+        assert_eq!(fs::read_to_string(&dest_path).await?, "Hello, World!");
+
+        Ok(())
+    }
 }
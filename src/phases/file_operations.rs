@@ -3,16 +3,22 @@
 //! This module contains structures and functions for performing various file operations during the
 //! deployment process, such as copying, symlinking, and creating files.
 
+use std::borrow::Cow;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use handlebars::Handlebars;
 use serde_json::Value;
 
-use crate::phases::destination::Destination;
+use crate::phases::destination::{self, Destination};
+use crate::store::db::Store;
 use crate::Stores;
+use crate::utils::common;
 use crate::utils::file_checksum;
 use crate::utils::file_fs;
+use crate::utils::file_filter;
+use crate::utils::file_merge;
 use crate::utils::file_metadata;
 use crate::utils::file_permissions;
 
@@ -27,6 +33,10 @@ pub(crate) enum FileOperation {
         group: Option<String>,
         permissions: Option<String>,
         template: Option<bool>,
+        /// Fail on missing context keys while rendering the template
+        template_strict: bool,
+        /// Commands the content is piped through before it is written
+        filter: Vec<String>,
     },
     /// Link file from source to destination.
     Symlink {
@@ -43,10 +53,52 @@ pub(crate) enum FileOperation {
         group: Option<String>,
         permissions: Option<String>,
         template: Option<bool>,
+        /// Fail on missing context keys while rendering the template
+        template_strict: bool,
+        /// Commands the content is piped through before it is written
+        filter: Vec<String>,
+    },
+    /// Link or copy a whole directory tree from source to destination.
+    Directory {
+        source: PathBuf,
+        destination: Destination,
+        /// Copy the tree instead of linking it
+        copy: bool,
+        owner: Option<String>,
+        group: Option<String>,
+    },
+    /// Hard link file from source to destination.
+    ///
+    /// Source and destination share their metadata, so ownership and permissions can't be set.
+    Hardlink {
+        source: PathBuf,
+        destination: Destination,
     },
 }
 
 impl FileOperation {
+    /// Returns the Handlebars registry to render the file with.
+    ///
+    /// The shared registry renders in strict mode. Templates which opt out of it are rendered with
+    /// a relaxed copy.
+    fn registry<'a>(&self, hb: &'a Handlebars<'static>) -> Cow<'a, Handlebars<'static>> {
+        match self {
+            FileOperation::Copy {
+                template_strict: false,
+                ..
+            }
+            | FileOperation::Create {
+                template_strict: false,
+                ..
+            } => {
+                let mut relaxed = hb.clone();
+                relaxed.set_strict_mode(false);
+                Cow::Owned(relaxed)
+            }
+            _ => Cow::Borrowed(hb),
+        }
+    }
+
     /// Run the file operation appropriate for the variant.
     ///
     /// This method executes the specific file operation based on the enum variant, handling
@@ -61,6 +113,7 @@ impl FileOperation {
     ///
     /// A Result indicating success or failure of the operation.
     async fn run(&self, context: &Value, hb: &Handlebars<'static>) -> Result<()> {
+        let hb = &*self.registry(hb);
         match self {
             FileOperation::Copy {
                 source,
@@ -69,9 +122,11 @@ impl FileOperation {
                 group,
                 permissions,
                 template,
+                filter,
+                ..
             } => {
-                // Copy the file, potentially rendering it as a template
-                destination.copy(source, *template, context, hb).await?;
+                // Copy the file, potentially filtering it and rendering it as a template
+                copy_filtered(destination, source, *template, filter, context, hb).await?;
                 // Set permissions on the copied file
                 self.set_permissions(destination.path(), owner, group, permissions)
                     .await?;
@@ -95,13 +150,40 @@ impl FileOperation {
                 group,
                 permissions,
                 template,
+                filter,
+                ..
             } => {
-                // Create a new file with the given content, potentially rendering it as a template
+                // Create a new file with the given content, potentially filtering it and rendering
+                // it as a template
+                let content = filter_content(content, filter).await?;
                 destination.create(content, *template, context, hb).await?;
                 // Set permissions on the newly created file
                 self.set_permissions(destination.path(), owner, group, permissions)
                     .await?;
             }
+            FileOperation::Directory {
+                source,
+                destination,
+                copy,
+                owner,
+                group,
+            } => {
+                // Link or copy the directory tree
+                match copy {
+                    true => destination.copy_dir(source).await?,
+                    false => destination.link(source).await?,
+                }
+                // Set ownership of the directory (permissions are not supported)
+                self.set_permissions(destination.path(), owner, group, &None)
+                    .await?;
+            }
+            FileOperation::Hardlink {
+                source,
+                destination,
+            } => {
+                // Create a hard link
+                destination.hardlink(source).await?;
+            }
         }
         Ok(())
     }
@@ -115,7 +197,7 @@ impl FileOperation {
     /// * `path` - The path to the file or link.
     /// * `owner` - Optional owner (username) to set.
     /// * `group` - Optional group to set.
-    /// * `permissions` - Optional permissions to set (as a string, e.g., "644").
+    /// * `permissions` - Optional permissions to set (as a string, e.g., "644" or "u+x").
     ///
     /// # Returns
     ///
@@ -138,10 +220,7 @@ impl FileOperation {
                     .map(|g| file_permissions::group_to_gid(g))
                     .transpose()?,
                 // Convert permission string to numeric mode if specified
-                permissions: permissions
-                    .as_ref()
-                    .map(|p| file_permissions::perms_str_to_int(p))
-                    .transpose()?,
+                permissions: resolve_mode(permissions, path).await?,
                 is_symlink: false,
                 symlink_source: None,
                 checksum: None,
@@ -153,6 +232,371 @@ impl FileOperation {
     }
 }
 
+/// Copies a file to its destination, piping its content through the filters first.
+///
+/// Without filters, this is the same as [Destination::copy].
+async fn copy_filtered(
+    destination: &Destination,
+    source: &Path,
+    template: Option<bool>,
+    filter: &[String],
+    context: &Value,
+    hb: &Handlebars<'static>,
+) -> Result<()> {
+    if filter.is_empty() {
+        return destination.copy(source, template, context, hb).await;
+    }
+    let content = tokio::fs::read(source)
+        .await
+        .with_context(|| format!("Failed to read {:?}", source))?;
+    let content = file_filter::apply_filters(content, filter)
+        .await
+        .with_context(|| format!("Failed to filter {:?}", source))?;
+    destination.create(content, template, context, hb).await
+}
+
+/// Returns the content a copied file gets at its destination, without writing it.
+async fn copied_content(
+    source: &Path,
+    template: Option<bool>,
+    context: &Value,
+    hb: &Handlebars<'static>,
+) -> Result<Vec<u8>> {
+    let content = tokio::fs::read(source)
+        .await
+        .with_context(|| format!("Failed to read {:?}", source))?;
+    if !template.unwrap_or(false) {
+        return Ok(content);
+    }
+    let content = String::from_utf8(content)
+        .with_context(|| format!("Failed to read template {:?}", source))?;
+    Ok(destination::render(hb, &content, context)
+        .with_context(|| format!("Failed to render template {:?}", source))?
+        .into_bytes())
+}
+
+/// Pipes the content of a created file through its filters.
+async fn filter_content<'a>(content: &'a str, filter: &[String]) -> Result<Cow<'a, str>> {
+    if filter.is_empty() {
+        return Ok(Cow::Borrowed(content));
+    }
+    file_filter::apply_filters(content.as_bytes().to_vec(), filter)
+        .await
+        .map(Cow::Owned)
+}
+
+/// Converts the permissions of a file entry to a numeric mode.
+///
+/// Symbolic modes like "u+x" are applied to the current mode of `base`, which is the source of
+/// copied files and the destination itself otherwise.
+///
+/// # Arguments
+///
+/// * `permissions` - Optional permissions as a string, e.g. "644" or "u+x".
+/// * `base` - The file whose mode symbolic modes are applied to.
+///
+/// # Returns
+///
+/// The numeric mode, or `None` if no permissions are given.
+async fn resolve_mode(permissions: &Option<String>, base: &Path) -> Result<Option<u32>> {
+    let Some(permissions) = permissions else {
+        return Ok(None);
+    };
+    let base_mode = file_metadata::get_file_metadata(base)
+        .await?
+        .permissions
+        .unwrap_or(0);
+    file_permissions::apply_mode(permissions, base_mode).map(Some)
+}
+
+/// Checks if a deployed file was modified locally since dotdeploy last wrote it.
+///
+/// # Arguments
+///
+/// * `store` - The store holding the baseline of the file.
+/// * `path` - The path of the deployed file.
+/// * `filter` - The filters of the file. Filtered files have no baseline and never drift.
+///
+/// # Returns
+///
+/// The [Drift] of the file if it was modified locally, `None` otherwise.
+async fn detect_drift(store: &Store, path: &Path, filter: &[String]) -> Result<Option<Drift>> {
+    if !filter.is_empty() {
+        return Ok(None);
+    }
+    let baseline = match store
+        .get_baseline(path)
+        .await
+        .map_err(|e| e.into_anyhow())?
+    {
+        Some(baseline) => baseline,
+        None => return Ok(None),
+    };
+    if !file_fs::check_file_exists(path).await? || file_fs::check_link_exists(path, None).await? {
+        return Ok(None);
+    }
+
+    match tokio::fs::read(path).await {
+        Ok(local) if local != baseline => Ok(Some(Drift { baseline, local })),
+        Ok(_) => Ok(None),
+        Err(e) => {
+            debug!("Could not read {:?} to detect local changes: {}", path, e);
+            Ok(None)
+        }
+    }
+}
+
+/// Largest file whose content is kept as baseline, in bytes.
+const MAX_BASELINE_SIZE: usize = 1024 * 1024;
+
+/// Records the freshly deployed content of a file as its new baseline.
+///
+/// Filtered files are skipped, as the output of filters (e.g. decrypted secrets) must not be
+/// stored in plain text or shown in diffs.
+///
+/// # Arguments
+///
+/// * `store` - The store holding the baseline of the file.
+/// * `path` - The path of the deployed file.
+/// * `filter` - The filters of the file.
+async fn record_baseline(store: &Store, path: &Path, filter: &[String]) -> Result<()> {
+    let content = if filter.is_empty() {
+        tokio::fs::read(path)
+            .await
+            .inspect_err(|e| debug!("Could not read {:?} to record its baseline: {}", path, e))
+            .ok()
+    } else {
+        None
+    };
+    set_baseline(store, path, content).await
+}
+
+/// Stores the baseline of a deployed file.
+///
+/// Only text files up to [MAX_BASELINE_SIZE] get a baseline, as merging is line based. The stale
+/// baseline of any other file is removed.
+///
+/// # Arguments
+///
+/// * `store` - The store holding the baseline of the file.
+/// * `path` - The path of the deployed file.
+/// * `content` - The deployed content, `None` to remove the baseline.
+async fn set_baseline(store: &Store, path: &Path, content: Option<Vec<u8>>) -> Result<()> {
+    match content.filter(|c| c.len() <= MAX_BASELINE_SIZE && std::str::from_utf8(c).is_ok()) {
+        Some(content) => store.add_baseline(path, content).await,
+        None => store.remove_baseline(path).await,
+    }
+    .map_err(|e| e.into_anyhow())
+}
+
+/// Content of a deployed file which was modified locally since dotdeploy last wrote it.
+struct Drift {
+    /// The content dotdeploy wrote during the previous deployment
+    baseline: Vec<u8>,
+    /// The content of the file before it was overwritten
+    local: Vec<u8>,
+}
+
+/// Local modifications of a deployed file, which the user has to resolve.
+///
+/// Files are deployed concurrently, so the local changes are collected and resolved one at a time
+/// once all files of a dependency level have been processed. The file is not touched until the
+/// user made a decision.
+pub(crate) struct LocalChanges {
+    /// The copied or created file
+    pub(crate) file: ManagedFile,
+    /// The module source of the file, if it can be updated
+    source: Option<PathBuf>,
+    /// The baseline and local content of the file
+    drift: Drift,
+    /// The new content of the file
+    new: Vec<u8>,
+}
+
+impl LocalChanges {
+    /// Lets the user decide how to deal with the local changes, see [resolve_local_changes], and
+    /// finishes the deployment of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `stores` - The stores holding the baseline of the file.
+    /// * `show_diff` - Whether to show the full diff instead of a summary.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success or failure of applying the decision.
+    pub(crate) async fn resolve(self, stores: &Stores, show_diff: bool) -> Result<()> {
+        let destination = self.file.destination();
+        let store = match destination {
+            Destination::Home(_) => &stores.user_store,
+            Destination::Root(_) => stores
+                .system_store
+                .as_ref()
+                .expect("System store should not be empty"),
+        };
+        let (content, baseline) =
+            resolve_local_changes(
+                destination,
+                self.source.as_deref(),
+                self.drift,
+                self.new,
+                show_diff,
+            )
+            .await?;
+
+        destination
+            .write(&content)
+            .await
+            .with_context(|| format!("Failed to write {:?}", destination.path()))?;
+        set_baseline(store, destination.path(), Some(baseline)).await?;
+        self.file.finish(store).await
+    }
+}
+
+/// Prints a unified diff between two versions of a file to stderr, colorized if stderr is a
+/// terminal.
+fn print_diff(old: &str, new: &str) {
+    let diff = file_merge::diff(old, new, 3);
+    if std::io::stderr().is_terminal() {
+        eprint!("{}", file_merge::colorize_diff(&diff));
+    } else {
+        eprint!("{}", diff);
+    }
+}
+
+/// Lets the user decide how to deal with local modifications of a deployed file.
+///
+/// The user can choose to
+/// - overwrite the local changes (default),
+/// - keep the local version,
+/// - merge local changes into the new version, using the baseline as common ancestor,
+/// - show a diff between the local and the new version or
+/// - adopt the local version back into the module source (only for non-template copies).
+///
+/// # Arguments
+///
+/// * `destination` - The destination of the file.
+/// * `source` - The module source of the file, if it can be updated.
+/// * `drift` - The baseline and local content of the file.
+/// * `new` - The new content of the file.
+/// * `show_diff` - Whether to show the full diff up front instead of a summary.
+///
+/// # Returns
+///
+/// The content to write to the destination and the new baseline of the file.
+async fn resolve_local_changes(
+    destination: &Destination,
+    source: Option<&Path>,
+    drift: Drift,
+    new: Vec<u8>,
+    show_diff: bool,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let path = destination.path().display();
+
+    // Merging and diffing is only possible for text files
+    let text = match (
+        std::str::from_utf8(&drift.baseline),
+        std::str::from_utf8(&drift.local),
+        std::str::from_utf8(&new),
+    ) {
+        (Ok(baseline), Ok(local), Ok(new)) => Some((baseline, local, new)),
+        _ => None,
+    };
+
+    // Show what would be lost by overwriting the file
+    match text {
+        Some((_, local, new)) if show_diff => {
+            info!("'{}' differs from the new version (- local, + new):", path);
+            print_diff(local, new);
+        }
+        Some((_, local, new)) => {
+            let changed = file_merge::diff(local, new, 0)
+                .lines()
+                .filter(|l| l.starts_with(['-', '+']))
+                .count();
+            info!(
+                "'{}' differs from the new version in {} line(s)",
+                path, changed
+            );
+        }
+        None => (),
+    }
+
+    let mut choices = String::from("ok");
+    let mut prompt = format!(
+        "'{}' was modified locally. [o]verwrite local changes, [k]eep local version",
+        path
+    );
+    if text.is_some() {
+        choices.push_str("md");
+        prompt.push_str(", [m]erge, show [d]iff");
+    }
+    if source.is_some() {
+        choices.push('a');
+        prompt.push_str(", [a]dopt local version into module source");
+    }
+    prompt.push_str(&format!(
+        "? [{}]",
+        choices
+            .chars()
+            .map(String::from)
+            .collect::<Vec<_>>()
+            .join("/")
+    ));
+
+    loop {
+        match common::ask_choice(&prompt, &choices) {
+            'k' => {
+                info!("Kept local version of '{}'", path);
+                return Ok((drift.local, new));
+            }
+            'm' => {
+                let (baseline, local, new_text) = text.expect("merge requires text content");
+                let merged = file_merge::merge3(baseline, local, new_text);
+                if merged.conflicts > 0 {
+                    info!("Merged version of '{}' (- local, + merged):", path);
+                    print_diff(local, &merged.content);
+                    if !common::ask_boolean(&format!(
+                        "{} conflict(s) while merging local changes into '{}'. Write the merged \
+                         version with conflict markers? (y/N)",
+                        merged.conflicts, path
+                    )) {
+                        continue;
+                    }
+                    warn!(
+                        "'{}': {} conflict(s) while merging local changes, resolve the marked regions manually",
+                        path, merged.conflicts
+                    );
+                } else {
+                    info!("Merged local changes into '{}'", path);
+                }
+                return Ok((merged.content.into_bytes(), new));
+            }
+            'd' => {
+                let (_, local, new) = text.expect("diff requires text content");
+                print_diff(local, new);
+            }
+            'a' => {
+                let source = source.expect("adopt requires a source");
+                tokio::fs::write(source, &drift.local)
+                    .await
+                    .with_context(|| format!("Failed to write local changes to {:?}", source))?;
+                info!(
+                    "Adopted local version of '{}' into '{}'",
+                    path,
+                    source.display()
+                );
+                // The source now matches the deployed file
+                return Ok((drift.local.clone(), drift.local));
+            }
+            _ => {
+                warn!("Local changes of '{}' have been overwritten", path);
+                return Ok((new.clone(), new));
+            }
+        }
+    }
+}
+
 /// A structure to manage file configurations, including the operation, source and destination.
 #[derive(Debug, Clone)]
 pub(crate) struct ManagedFile {
@@ -163,20 +607,107 @@ pub(crate) struct ManagedFile {
 }
 
 impl ManagedFile {
+    /// Returns the destination of the file.
+    pub(crate) fn destination(&self) -> &Destination {
+        match &self.operation {
+            FileOperation::Copy { destination, .. }
+            | FileOperation::Symlink { destination, .. }
+            | FileOperation::Create { destination, .. }
+            | FileOperation::Directory { destination, .. }
+            | FileOperation::Hardlink { destination, .. } => destination,
+        }
+    }
+
+    /// Sets the metadata of a copied or created file and records it in the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The store to record the file in.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success or failure of the operation.
+    async fn finish(&self, store: &Store) -> Result<()> {
+        let (source, destination, owner, group, permissions, operation) = match &self.operation {
+            FileOperation::Copy {
+                source,
+                destination,
+                owner,
+                group,
+                permissions,
+                ..
+            } => (Some(source), destination, owner, group, permissions, "copy"),
+            FileOperation::Create {
+                destination,
+                owner,
+                group,
+                permissions,
+                ..
+            } => (None, destination, owner, group, permissions, "create"),
+            _ => bail!("Only copied and created files can be finished"),
+        };
+
+        // Set permissions, symbolic modes of copies are applied to the mode of their source
+        file_metadata::set_file_metadata(
+            destination.path(),
+            file_metadata::FileMetadata {
+                uid: owner.as_ref().map(file_permissions::user_to_uid).transpose()?,
+                gid: group.as_ref().map(file_permissions::group_to_gid).transpose()?,
+                permissions: resolve_mode(
+                    permissions,
+                    source.map_or(destination.path().as_path(), |s| s.as_path()),
+                )
+                .await?,
+                is_symlink: false,
+                symlink_source: None,
+                checksum: None,
+            },
+        )
+        .await?;
+
+        // Record file in store
+        let source_checksum = match source {
+            Some(source) => Some(file_checksum::calculate_sha256_checksum(source).await?),
+            None => None,
+        };
+        store
+            .add_file(crate::store::files::StoreFile {
+                module: self.module.clone(),
+                source: source.map(|s| s.display().to_string()),
+                source_checksum,
+                destination: destination.path().display().to_string(),
+                destination_checksum: Some(
+                    file_checksum::calculate_sha256_checksum(destination.path()).await?,
+                ),
+                operation: operation.to_string(),
+                user: Some(std::env::var("USER")?),
+                date: chrono::offset::Local::now(),
+            })
+            .await
+            .map_err(|e| e.into_anyhow())
+    }
+
     pub(crate) async fn perform(
         &self,
         stores: &Stores,
         context: &serde_json::Value,
         hb: &handlebars::Handlebars<'static>,
-    ) -> Result<()> {
+    ) -> Result<Option<LocalChanges>> {
+        // Let helpers like `file_content` know which module the file belongs to
+        let mut context = context.clone();
+        if let Some(map) = context.as_object_mut() {
+            map.insert("DOD_MODULE".to_string(), self.module.clone().into());
+        }
+        let context = &context;
+        let hb = &*self.operation.registry(hb);
+
         match &self.operation {
             FileOperation::Copy {
                 source,
                 destination,
-                owner,
-                group,
-                permissions,
                 template,
+                filter,
+                ..
             } => {
                 let store = match destination {
                     Destination::Home(_) => &stores.user_store,
@@ -193,8 +724,8 @@ impl ManagedFile {
 
                 let mut do_copy = false;
 
-                if template.expect("template should always be Some()") {
-                    // Always copy the file if it is a template, no further checks
+                if template.expect("template should always be Some()") || !filter.is_empty() {
+                    // Always copy the file if it is a template or filtered, no further checks
                     do_copy = true;
                 } else {
                     // Check if source has changed
@@ -231,6 +762,8 @@ impl ManagedFile {
                 }
 
                 if do_copy {
+                    let drift = detect_drift(store, destination.path(), filter).await?;
+
                     // Create backup if no backup is already stored and if the destination file
                     // already exists
                     if !store
@@ -244,48 +777,29 @@ impl ManagedFile {
                             .await
                             .map_err(|e| e.into_anyhow())?;
                     }
+                    if let Some(drift) = drift {
+                        // Let the user decide before overwriting local changes
+                        let new = copied_content(source, *template, context, hb).await?;
+                        // Templates can not be updated from their output
+                        let source = (!template.unwrap_or(false)).then(|| source.clone());
+                        return Ok(Some(LocalChanges {
+                            file: self.clone(),
+                            source,
+                            drift,
+                            new,
+                        }));
+                    }
+
                     debug!("Trying to copy {:?} to {:?}", source, destination.path());
 
-                    destination
-                        .copy(source, *template, context, hb)
+                    copy_filtered(destination, source, *template, filter, context, hb)
                         .await
                         .with_context(|| {
                             format!("Failed to copy {:?} to {:?}", source, destination.path())
                         })?;
 
-                    // Set permissions
-                    file_metadata::set_file_metadata(
-                        destination.path(),
-                        file_metadata::FileMetadata {
-                            uid: owner.as_ref().map(file_permissions::user_to_uid).transpose()?,
-                            gid: group.as_ref().map(file_permissions::group_to_gid).transpose()?,
-                            permissions: permissions
-                                .as_ref()
-                                .map(file_permissions::perms_str_to_int)
-                                .transpose()?,
-                            is_symlink: false,
-                            symlink_source: None,
-                            checksum: None,
-                        },
-                    )
-                    .await?;
-
-                    // Record file in store
-                    store
-                        .add_file(crate::store::files::StoreFile {
-                            module: self.module.clone(),
-                            source: Some(source.display().to_string()),
-                            source_checksum: Some(file_checksum::calculate_sha256_checksum(source).await?),
-                            destination: destination.path().display().to_string(),
-                            destination_checksum: Some(
-                                file_checksum::calculate_sha256_checksum(destination.path()).await?,
-                            ),
-                            operation: "copy".to_string(),
-                            user: Some(std::env::var("USER")?),
-                            date: chrono::offset::Local::now(),
-                        })
-                        .await
-                        .map_err(|e| e.into_anyhow())?;
+                    record_baseline(store, destination.path(), filter).await?;
+                    self.finish(store).await?;
 
                     info!(
                         "Copy: '{}' -> '{}'",
@@ -378,10 +892,9 @@ impl ManagedFile {
             FileOperation::Create {
                 content,
                 destination,
-                owner,
-                group,
-                permissions,
                 template,
+                filter,
+                ..
             } => {
                 let store = match destination {
                     Destination::Home(_) => &stores.user_store,
@@ -395,6 +908,8 @@ impl ManagedFile {
                     destination.path()
                 );
 
+                let drift = detect_drift(store, destination.path(), filter).await?;
+
                 if !store
                     .check_backup_exists(destination.path())
                     .await
@@ -407,45 +922,255 @@ impl ManagedFile {
                         .map_err(|e| e.into_anyhow())?;
                 }
 
+                let content = filter_content(content, filter).await?;
+                if let Some(drift) = drift {
+                    // Let the user decide before overwriting local changes
+                    let new = if template.unwrap_or(false) {
+                        destination::render(hb, &content, context)
+                            .with_context(|| {
+                                format!("Failed to render template for {:?}", destination.path())
+                            })?
+                            .into_bytes()
+                    } else {
+                        content.as_bytes().to_vec()
+                    };
+                    return Ok(Some(LocalChanges {
+                        file: self.clone(),
+                        source: None,
+                        drift,
+                        new,
+                    }));
+                }
+
                 destination
                     .create(content, *template, context, hb)
                     .await?;
 
-                file_metadata::set_file_metadata(
-                    destination.path(),
-                    file_metadata::FileMetadata {
-                        uid: owner.as_ref().map(file_permissions::user_to_uid).transpose()?,
-                        gid: group.as_ref().map(file_permissions::group_to_gid).transpose()?,
-                        permissions: permissions
-                            .as_ref()
-                            .map(file_permissions::perms_str_to_int)
-                            .transpose()?,
-                        is_symlink: false,
-                        symlink_source: None,
-                        checksum: None,
-                    },
-                )
-                .await?;
+                record_baseline(store, destination.path(), filter).await?;
+                self.finish(store).await?;
 
-                store
-                    .add_file(crate::store::files::StoreFile {
-                        module: self.module.clone(),
-                        source: None,
-                        source_checksum: None,
-                        destination: destination.path().display().to_string(),
-                        destination_checksum: Some(
-                            file_checksum::calculate_sha256_checksum(destination.path()).await?,
-                        ),
-                        operation: "create".to_string(),
-                        user: Some(std::env::var("USER")?),
-                        date: chrono::offset::Local::now(),
-                    })
+                info!("Create: '{}'", destination.path().display());
+            }
+            FileOperation::Directory {
+                source,
+                destination,
+                copy,
+                owner,
+                group,
+            } => {
+                let store = match destination {
+                    Destination::Home(_) => &stores.user_store,
+                    Destination::Root(_) => {
+                        stores.system_store.as_ref().expect("System store should not be empty")
+                    }
+                };
+                let path = destination.path();
+
+                let source_checksum = file_checksum::calculate_dir_checksum(source)
+                    .await
+                    .with_context(|| format!("Failed to get checksum of {:?}", source))?;
+                let deployed = store
+                    .get_source_checksum(path)
                     .await
                     .map_err(|e| e.into_anyhow())?;
 
-                info!("Create: '{}'", destination.path().display());
+                // A linked tree is up to date as long as the link exists, files added to the source
+                // show up automatically. A copied tree has to match the source.
+                let up_to_date = match &deployed {
+                    None => false,
+                    Some(_) if !copy => file_fs::check_link_exists(path, Some(source)).await?,
+                    Some((_, checksum)) => {
+                        checksum == &source_checksum
+                            && file_fs::check_file_exists(path).await?
+                            && !file_fs::check_link_exists(path, None).await?
+                    }
+                };
+
+                if up_to_date {
+                    info!("'{}' deployed and up to date", path.display());
+                } else {
+                    if deployed.is_none() {
+                        // Directories can not be backed up, so they are never replaced
+                        if std::fs::symlink_metadata(path).is_ok_and(|m| m.is_dir()) {
+                            bail!(
+                                "'{}' is an existing directory, move it out of the way to deploy '{}'",
+                                path.display(),
+                                source.display()
+                            );
+                        }
+                        if !store
+                            .check_backup_exists(path)
+                            .await
+                            .map_err(|e| e.into_anyhow())?
+                            & file_fs::check_file_exists(path).await?
+                        {
+                            store.add_backup(path).await.map_err(|e| e.into_anyhow())?;
+                        }
+                    }
+
+                    debug!("Trying to deploy directory {:?} to {:?}", source, path);
+                    let result = match copy {
+                        true => destination.copy_dir(source).await,
+                        false => destination.link(source).await,
+                    };
+                    result.with_context(|| {
+                        format!("Failed to deploy directory {:?} to {:?}", source, path)
+                    })?;
+
+                    // Set ownership of the whole tree
+                    let mut entries = vec![path.clone()];
+                    if *copy {
+                        entries.extend(file_fs::read_tree(path)?);
+                    }
+                    for entry in entries.iter() {
+                        file_metadata::set_file_metadata(
+                            entry,
+                            file_metadata::FileMetadata {
+                                uid: owner
+                                    .as_ref()
+                                    .map(file_permissions::user_to_uid)
+                                    .transpose()?,
+                                gid: group
+                                    .as_ref()
+                                    .map(file_permissions::group_to_gid)
+                                    .transpose()?,
+                                permissions: None,
+                                is_symlink: entry.is_symlink(),
+                                symlink_source: None,
+                                checksum: None,
+                            },
+                        )
+                        .await?;
+                    }
+
+                    // Removing a copied tree checks it against this checksum for local changes
+                    let destination_checksum = match copy {
+                        true => Some(file_checksum::calculate_dir_checksum(path).await?),
+                        false => None,
+                    };
+
+                    store
+                        .add_file(crate::store::files::StoreFile {
+                            module: self.module.clone(),
+                            source: Some(source.display().to_string()),
+                            source_checksum: Some(source_checksum),
+                            destination: path.display().to_string(),
+                            destination_checksum,
+                            operation: "directory".to_string(),
+                            user: Some(std::env::var("USER")?),
+                            date: chrono::offset::Local::now(),
+                        })
+                        .await
+                        .map_err(|e| e.into_anyhow())?;
+
+                    info!(
+                        "Directory ({}): '{}' -> '{}'",
+                        if *copy { "copy" } else { "link" },
+                        source.display(),
+                        path.display()
+                    );
+                }
+            }
+            FileOperation::Hardlink {
+                source,
+                destination,
+            } => {
+                let store = match destination {
+                    Destination::Home(_) => &stores.user_store,
+                    Destination::Root(_) => {
+                        stores.system_store.as_ref().expect("System store should not be empty")
+                    }
+                };
+                let path = destination.path();
+
+                // Perform hard link operation
+                if store
+                    .check_file_exists(path)
+                    .await
+                    .map_err(|e| e.into_anyhow())?
+                    && file_fs::check_hardlink_exists(path, source).await?
+                {
+                    info!("'{}' deployed and up to date", path.display());
+                } else {
+                    if !store
+                        .check_backup_exists(path)
+                        .await
+                        .map_err(|e| e.into_anyhow())?
+                        & file_fs::check_file_exists(path).await?
+                    {
+                        store.add_backup(path).await.map_err(|e| e.into_anyhow())?;
+                    }
+
+                    debug!("Trying to hard link {:?} to {:?}", source, path);
+
+                    destination.hardlink(source).await.with_context(|| {
+                        format!("Failed to hard link {:?} to {:?}", source, path)
+                    })?;
+
+                    // Source and destination are the same file
+                    let checksum = file_checksum::calculate_sha256_checksum(source).await?;
+                    store
+                        .add_file(crate::store::files::StoreFile {
+                            module: self.module.clone(),
+                            source: Some(source.display().to_string()),
+                            source_checksum: Some(checksum.clone()),
+                            destination: path.display().to_string(),
+                            destination_checksum: Some(checksum),
+                            operation: "hardlink".to_string(),
+                            user: Some(std::env::var("USER")?),
+                            date: chrono::offset::Local::now(),
+                        })
+                        .await
+                        .map_err(|e| e.into_anyhow())?;
+
+                    info!("Hardlink: '{}' -> '{}'", source.display(), path.display());
+                }
             }
         };
+        Ok(None)
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() -> Result<()> {
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+
+        let create = |template_strict| FileOperation::Create {
+            content: String::new(),
+            destination: Destination::Home(PathBuf::from("/tmp/foo")),
+            owner: None,
+            group: None,
+            permissions: None,
+            template: Some(true),
+            template_strict,
+            filter: vec![],
+        };
+        let context = serde_json::json!({"foo": "bar"});
+
+        let strict = create(true);
+        assert!(strict
+            .registry(&hb)
+            .render_template("{{missing}}", &context)
+            .is_err());
+
+        let relaxed = create(false);
+        assert_eq!(
+            relaxed
+                .registry(&hb)
+                .render_template("{{foo}}{{missing}}", &context)?,
+            "bar"
+        );
+        // The shared registry stays strict
+        assert!(hb.strict_mode());
+
         Ok(())
     }
 }
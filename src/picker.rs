@@ -0,0 +1,197 @@
+//! This module provides an interactive picker for modules to deploy.
+//!
+//! All modules found below `modules_root` are listed with their description and whether they are
+//! deployed already. The user selects modules by their number, e.g. `1 3 5-7`, or `all`.
+
+use std::collections::BTreeSet;
+use std::io::{stderr, stdin, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::DotdeployConfig;
+use crate::modules::config::ModuleConfig;
+use crate::modules::find_modules;
+use crate::Stores;
+
+/// A module available for deployment.
+#[derive(Debug, PartialEq, Eq)]
+struct AvailableModule {
+    /// Name of the module, relative to `modules_root`
+    name: String,
+    /// Description from the module configuration
+    description: Option<String>,
+}
+
+/// Finds all modules below a directory, including their description.
+fn available_modules(modules_root: &Path) -> Result<Vec<AvailableModule>> {
+    Ok(find_modules(modules_root)?
+        .into_iter()
+        .map(|name| {
+            // A broken config should not prevent picking other modules
+            let description = match ModuleConfig::read_config(modules_root.join(&name)) {
+                Ok(config) => config.description,
+                Err(e) => {
+                    warn!("Failed to read config of module {}: {:?}", name, e);
+                    None
+                }
+            };
+            AvailableModule { name, description }
+        })
+        .collect())
+}
+
+/// Parses a selection like `1 3 5-7` or `all` into zero based indices.
+///
+/// Numbers may be separated by spaces or commas. Duplicates are removed.
+fn parse_selection(input: &str, count: usize) -> Result<Vec<usize>> {
+    if input.trim().eq_ignore_ascii_case("all") {
+        return Ok((0..count).collect());
+    }
+
+    let parse = |n: &str| -> Result<usize> {
+        let n: usize = n
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid number {:?}", n))?;
+        if n == 0 || n > count {
+            bail!("{} is not between 1 and {}", n, count)
+        }
+        Ok(n - 1)
+    };
+
+    let mut selection = BTreeSet::new();
+    for part in input
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|p| !p.is_empty())
+    {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    bail!("Invalid range {:?}", part)
+                }
+                selection.extend(start..=end);
+            }
+            None => {
+                selection.insert(parse(part)?);
+            }
+        }
+    }
+
+    Ok(selection.into_iter().collect())
+}
+
+/// Lets the user pick modules to deploy.
+///
+/// # Arguments
+///
+/// * `dotdeploy_config` - Configuration providing the `modules_root`
+/// * `stores` - Database stores, used to mark deployed modules
+///
+/// # Returns
+///
+/// A Result containing the names of the selected modules, which may be empty
+pub(crate) async fn pick(
+    dotdeploy_config: &DotdeployConfig,
+    stores: &Stores,
+) -> Result<Vec<String>> {
+    let modules = available_modules(&dotdeploy_config.modules_root)?;
+    if modules.is_empty() {
+        warn!(
+            "No modules found in {}",
+            dotdeploy_config.modules_root.display()
+        );
+        return Ok(vec![]);
+    }
+
+    let deployed: BTreeSet<String> = stores
+        .user_store
+        .get_all_modules()
+        .await
+        .map_err(|e| e.into_anyhow())?
+        .into_iter()
+        .map(|m| m.name)
+        .collect();
+
+    // The list is part of the prompt, keep it off stdout
+    for (i, m) in modules.iter().enumerate() {
+        eprintln!(
+            "{:>3}) [{}] {:<30}  {}",
+            i + 1,
+            if deployed.contains(&m.name) { "x" } else { " " },
+            m.name,
+            m.description.as_deref().unwrap_or("")
+        );
+    }
+
+    // Repeat the prompt until the selection is valid
+    loop {
+        eprintln!("Modules to deploy (e.g. 1 3 5-7, all), empty to abort:");
+        stderr().flush().expect("Failed to flush stderr");
+
+        let mut buf = String::new();
+        stdin()
+            .read_line(&mut buf)
+            .expect("Failed to read line from stdin");
+
+        match parse_selection(&buf, modules.len()) {
+            Ok(selection) => {
+                return Ok(selection
+                    .into_iter()
+                    .map(|i| modules[i].name.clone())
+                    .collect())
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_selection() -> Result<()> {
+        assert_eq!(parse_selection("1 3,5-7", 8)?, vec![0, 2, 4, 5, 6]);
+        assert_eq!(parse_selection("2 2 1-2", 3)?, vec![0, 1]);
+        assert_eq!(parse_selection("all", 3)?, vec![0, 1, 2]);
+        assert!(parse_selection("\n", 3)?.is_empty());
+        assert!(parse_selection("0", 3).is_err());
+        assert!(parse_selection("4", 3).is_err());
+        assert!(parse_selection("3-1", 3).is_err());
+        assert!(parse_selection("foo", 3).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_available_modules() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join("zsh"))?;
+        std::fs::write(root.join("zsh/config.toml"), "description = \"Z shell\"")?;
+        std::fs::create_dir_all(root.join("desktop/sway"))?;
+        std::fs::write(root.join("desktop/sway/config.toml"), "")?;
+        std::fs::create_dir_all(root.join("empty"))?;
+
+        assert_eq!(
+            available_modules(root)?,
+            vec![
+                AvailableModule {
+                    name: "desktop/sway".to_string(),
+                    description: None
+                },
+                AvailableModule {
+                    name: "zsh".to_string(),
+                    description: Some("Z shell".to_string())
+                },
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+//! This module fetches modules from git repositories.
+//!
+//! Remote modules are named `remotes/<name>`. They are declared in the `[remotes]` table of the
+//! config, or directly as a dependency like `git+https://github.com/user/dotdeploy-nvim`, which
+//! becomes the module `remotes/dotdeploy-nvim`. A branch or tag can be selected by appending it
+//! after `#`. The repositories are cloned into the `remotes` folder of `cache_dir`, named after a
+//! hash of their URL, and updated once per run. `remotes/<name>` links to the clone, so afterwards
+//! remote modules are treated like any other module.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use lazy_static::lazy_static;
+
+use crate::utils::file_fs;
+
+/// Prefix of module names referring to remote modules.
+pub(crate) const PREFIX: &str = "remotes/";
+
+/// Time a git command may run before it is killed.
+const GIT_TIMEOUT: Duration = Duration::from_secs(300);
+
+lazy_static! {
+    /// Repositories of the remote modules already fetched during this run, keyed by module name.
+    static ref FETCHED: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Returns the folder remote modules are cloned into.
+///
+/// Modules deployed for another user are cloned into the cache of the administrator, as root must
+/// not run actions of clones the user could change.
+pub(crate) fn cache_dir(dotdeploy_config: &crate::config::DotdeployConfig) -> PathBuf {
+    match crate::target_user::get() {
+        Some(user) => user.admin_home.join(".cache/dotdeploy/remotes"),
+        None => dotdeploy_config.cache_dir.join("remotes"),
+    }
+}
+
+/// Resolves a module name or dependency to a remote module.
+///
+/// # Arguments
+///
+/// * `module_name` - A module name or a `git+` URL
+/// * `remotes` - The `[remotes]` table of the config, URLs keyed by name
+///
+/// # Returns
+///
+/// The module name and, for remote modules, the URL of the repository
+pub(crate) fn resolve(
+    module_name: &str,
+    remotes: &BTreeMap<String, String>,
+) -> (String, Option<String>) {
+    if let Some(url) = module_name.strip_prefix("git+") {
+        let repository = split_url(url).0.trim_end_matches('/');
+        let name = repository.rsplit('/').next().unwrap_or(repository);
+        let name = name.strip_suffix(".git").unwrap_or(name);
+        return (format!("{}{}", PREFIX, name), Some(url.to_string()));
+    }
+
+    let url = module_name
+        .strip_prefix(PREFIX)
+        .and_then(|name| remotes.get(name))
+        .cloned();
+    (module_name.to_string(), url)
+}
+
+/// Splits a URL into the repository and the branch or tag following `#`.
+fn split_url(url: &str) -> (&str, Option<&str>) {
+    match url.split_once('#') {
+        Some((repository, reference)) => (repository, Some(reference)),
+        None => (url, None),
+    }
+}
+
+/// Returns the folder a repository is cloned into.
+///
+/// Clones are named after a hash of the repository URL, so repositories with the same name never
+/// share a clone.
+fn clone_dir(cache: &Path, repository: &str) -> PathBuf {
+    let hash = crate::utils::file_checksum::calculate_sha256_checksum_bytes(repository.as_bytes());
+    cache.join(".clones").join(&hash[..16])
+}
+
+/// Runs git, failing with its error output.
+///
+/// Git never prompts for credentials and is killed if it does not finish within [GIT_TIMEOUT].
+///
+/// # Returns
+///
+/// The output of git
+async fn git(args: &[&str]) -> Result<String> {
+    let output = tokio::process::Command::new("git")
+        .args(args)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(GIT_TIMEOUT, output)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "git {} did not finish within {} seconds",
+                args.join(" "),
+                GIT_TIMEOUT.as_secs()
+            )
+        })?
+        .with_context(|| format!("Failed to run git {}", args.join(" ")))?;
+    if !output.status.success() {
+        bail!(
+            "git {} exited with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Clones a repository into a folder, or updates an existing clone.
+///
+/// Existing clones of another repository are replaced. Failing to update a clone only logs a
+/// warning, so remote modules can be deployed while offline.
+///
+/// # Arguments
+///
+/// * `url` - The URL of the repository, optionally followed by `#<branch or tag>`
+/// * `dir` - The folder of the clone
+///
+/// # Returns
+///
+/// A Result indicating success or failure of cloning the repository
+async fn fetch_into(url: &str, dir: &Path) -> Result<()> {
+    let (repository, reference) = split_url(url);
+    // Remote modules may come from third parties, git must not read their URLs as options
+    if repository.starts_with('-') || reference.is_some_and(|r| r.starts_with('-')) {
+        bail!(
+            "Invalid remote module URL {:?}, the repository and reference must not start with '-'",
+            url
+        );
+    }
+    let dir_str = file_fs::path_to_string(dir)?;
+
+    if dir.join(".git").is_dir() {
+        let origin = git(&["-C", &dir_str, "remote", "get-url", "origin"])
+            .await
+            .unwrap_or_default();
+        if origin != repository {
+            warn!(
+                "{:?} is not a clone of {}, cloning it again",
+                dir, repository
+            );
+            tokio::fs::remove_dir_all(dir)
+                .await
+                .with_context(|| format!("Failed to remove {:?}", dir))?;
+        }
+    }
+
+    if dir.join(".git").is_dir() {
+        debug!("Updating {:?}", dir);
+        // Pulling fails on the detached HEAD of a tag, so the reference is fetched and checked out
+        let update = async {
+            git(&[
+                "-C",
+                &dir_str,
+                "fetch",
+                "--quiet",
+                "--depth",
+                "1",
+                "origin",
+                reference.unwrap_or("HEAD"),
+            ])
+            .await?;
+            git(&[
+                "-C",
+                &dir_str,
+                "checkout",
+                "--quiet",
+                "--force",
+                "--detach",
+                "FETCH_HEAD",
+            ])
+            .await
+        };
+        if let Err(e) = update.await {
+            warn!("Failed to update {:?}, using the cached copy: {:#}", dir, e);
+        }
+        return Ok(());
+    }
+
+    if let Some(parent) = dir.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create directory {:?}", parent))?;
+    }
+    info!("Cloning {} into {:?}", repository, dir);
+    let mut args = vec!["clone", "--quiet", "--depth", "1"];
+    if let Some(reference) = reference {
+        args.extend(["--branch", reference]);
+    }
+    args.extend(["--", repository, &dir_str]);
+    git(&args).await?;
+    Ok(())
+}
+
+/// Points the folder of a remote module to its clone.
+async fn link_module(link: &Path, dir: &Path) -> Result<()> {
+    if tokio::fs::read_link(link)
+        .await
+        .is_ok_and(|target| target == dir)
+    {
+        return Ok(());
+    }
+    // Replace links to other clones as well as clones of earlier versions
+    if tokio::fs::symlink_metadata(link).await.is_ok() {
+        file_fs::delete_path(link).await?;
+    }
+    tokio::fs::symlink(dir, link)
+        .await
+        .with_context(|| format!("Failed to link {:?} to {:?}", link, dir))
+}
+
+/// Checks whether a remote module has been fetched from a repository during this run.
+///
+/// # Arguments
+///
+/// * `module_name` - The name of the remote module, see [resolve]
+/// * `url` - The URL of the repository
+pub(crate) fn is_fetched(module_name: &str, url: &str) -> bool {
+    FETCHED
+        .lock()
+        .expect("Lock should not be poisoned")
+        .get(module_name)
+        .is_some_and(|repository| repository == split_url(url).0)
+}
+
+/// Makes sure a remote module is available, cloning or updating it once per run.
+///
+/// # Arguments
+///
+/// * `module_name` - The name of the remote module, see [resolve]
+/// * `url` - The URL of the repository
+/// * `dotdeploy_config` - Configuration providing the `cache_dir`
+///
+/// # Returns
+///
+/// A Result indicating success or failure of fetching the module
+pub(crate) async fn fetch(
+    module_name: &str,
+    url: &str,
+    dotdeploy_config: &crate::config::DotdeployConfig,
+) -> Result<()> {
+    let repository = split_url(url).0;
+    if let Some(fetched) = FETCHED
+        .lock()
+        .expect("Lock should not be poisoned")
+        .get(module_name)
+    {
+        if fetched == repository {
+            return Ok(());
+        }
+        bail!(
+            "Remote module {} refers to both {} and {}",
+            module_name,
+            fetched,
+            repository
+        );
+    }
+
+    let cache = cache_dir(dotdeploy_config);
+    let dir = clone_dir(&cache, repository);
+    async {
+        fetch_into(url, &dir).await?;
+        link_module(&cache.join(module_name.trim_start_matches(PREFIX)), &dir).await
+    }
+    .await
+    .with_context(|| format!("Failed to fetch remote module {}", module_name))?;
+    FETCHED
+        .lock()
+        .expect("Lock should not be poisoned")
+        .insert(module_name.to_string(), repository.to_string());
+
+    Ok(())
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let remotes = BTreeMap::from([(
+            "nvim".to_string(),
+            "https://example.com/nvim.git".to_string(),
+        )]);
+
+        assert_eq!(
+            resolve("git+https://github.com/user/dotdeploy-nvim", &remotes),
+            (
+                "remotes/dotdeploy-nvim".to_string(),
+                Some("https://github.com/user/dotdeploy-nvim".to_string())
+            )
+        );
+        assert_eq!(
+            resolve("git+https://example.com/user/zsh.git#v1", &remotes).0,
+            "remotes/zsh"
+        );
+        assert_eq!(
+            resolve("remotes/nvim", &remotes),
+            (
+                "remotes/nvim".to_string(),
+                Some("https://example.com/nvim.git".to_string())
+            )
+        );
+        assert_eq!(resolve("zsh", &remotes), ("zsh".to_string(), None));
+    }
+
+    /// Commits a new file to a repository.
+    async fn commit(repository: &Path, file: &str) -> Result<()> {
+        let repository = repository.display().to_string();
+        std::fs::write(Path::new(&repository).join(file), "")?;
+        git(&["-C", &repository, "add", file]).await?;
+        git(&[
+            "-C",
+            &repository,
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "--quiet",
+            "-m",
+            file,
+        ])
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_into() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let repository = temp_dir.path().join("repository");
+        let repository_str = repository.display().to_string();
+        std::fs::create_dir_all(&repository)?;
+        git(&["-C", &repository_str, "init", "--quiet"]).await?;
+        commit(&repository, "config.toml").await?;
+        git(&["-C", &repository_str, "tag", "v1"]).await?;
+
+        let clone = temp_dir.path().join("cache/remote");
+        let url = format!("file://{}", repository_str);
+        fetch_into(&url, &clone).await?;
+        assert!(clone.join("config.toml").is_file());
+
+        // Existing clones are updated
+        commit(&repository, "README").await?;
+        fetch_into(&url, &clone).await?;
+        assert!(clone.join("README").is_file());
+
+        // Tags are checked out
+        fetch_into(&format!("{}#v1", url), &clone).await?;
+        assert!(!clone.join("README").exists());
+        fetch_into(&url, &clone).await?;
+        assert!(clone.join("README").is_file());
+
+        // Clones of other repositories are replaced
+        let other = temp_dir.path().join("other");
+        std::fs::create_dir_all(&other)?;
+        git(&["-C", &other.display().to_string(), "init", "--quiet"]).await?;
+        commit(&other, "other.toml").await?;
+        fetch_into(&format!("file://{}", other.display()), &clone).await?;
+        assert!(clone.join("other.toml").is_file() && !clone.join("README").exists());
+
+        // URLs and references are never passed as options
+        let marker = temp_dir.path().join("marker");
+        let option = format!("--upload-pack=touch {}", marker.display());
+        let injected = temp_dir.path().join("cache/injected");
+        assert!(fetch_into(&option, &injected).await.is_err());
+        assert!(fetch_into(&format!("{}#{}", url, option), &clone)
+            .await
+            .is_err());
+        assert!(!marker.exists() && !injected.exists());
+        assert!(clone.join("other.toml").is_file());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_dir() {
+        let cache = Path::new("/cache");
+        let dir = clone_dir(cache, "https://example.com/a/nvim");
+        assert_eq!(dir, clone_dir(cache, "https://example.com/a/nvim"));
+        assert_ne!(dir, clone_dir(cache, "https://example.com/b/nvim"));
+    }
+}
@@ -1,12 +1,55 @@
 //! This module handles the removal process for files and packages, including backup restoration and
 //! cleanup operations.
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, Result};
+use serde::Serialize;
 use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
 
+use crate::cli::OutputFormat;
+use crate::report::{emit, Report};
 use crate::Stores;
+use crate::utils::file_checksum;
 use crate::utils::file_fs;
+use crate::utils::signal;
+
+/// Deletes a deployed file, or the whole tree deployed by a `directory` operation.
+///
+/// Copied trees are only deleted if they still match the deployed copy, so local changes are never
+/// lost.
+///
+/// # Arguments
+///
+/// * `path` - The deployed path
+/// * `operation` - The operation which deployed the path
+/// * `checksum` - The checksum of a copied tree at deploy time
+///
+/// # Returns
+///
+/// `true` if the path was deleted, `false` if a modified tree was kept
+pub(crate) async fn delete_deployed(
+    path: &str,
+    operation: &str,
+    checksum: Option<&str>,
+) -> Result<bool> {
+    if operation != "directory" {
+        file_fs::delete_file(path).await?;
+        return Ok(true);
+    }
+
+    // Linked trees are a single symbolic link
+    if !file_fs::check_link_exists(path, None).await?
+        && checksum != Some(file_checksum::calculate_dir_checksum(path).await?.as_str())
+    {
+        warn!(
+            "'{}' has been modified since it was deployed, remove it manually",
+            path
+        );
+        return Ok(false);
+    }
+    file_fs::delete_path(path).await?;
+    Ok(true)
+}
 
 /// Removes a file and restores its backup if available.
 ///
@@ -15,67 +58,184 @@ use crate::utils::file_fs;
 ///
 /// # Arguments
 ///
-/// * `file` - The path of the file to remove
+/// * `file` - The file to remove
 /// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
 ///
 /// # Returns
 ///
 /// A Result indicating success or failure of the file removal and backup restoration process
-async fn remove_file<S: AsRef<str>>(
-    file: S,
-    stores: Arc<Stores>,
-) -> Result<()> {
-    if file_fs::check_file_exists(file.as_ref()).await? {
+async fn remove_file(file: &crate::store::files::StoreFile, stores: Arc<Stores>) -> Result<()> {
+    let path = file.destination.as_str();
+    if file_fs::check_file_exists(path).await? {
         // Delete the file
-        file_fs::delete_file(file.as_ref()).await?;
-        debug!("Removed {:?}", file.as_ref());
+        if !delete_deployed(path, &file.operation, file.destination_checksum.as_deref()).await? {
+            return Ok(());
+        }
+        debug!("Removed {:?}", path);
 
         // Check for and restore backup from the user store
         if stores
             .user_store
-            .check_backup_exists(file.as_ref())
+            .check_backup_exists(path)
             .await
             .map_err(|e| e.into_anyhow())?
         {
             stores
                 .user_store
-                .restore_backup(file.as_ref(), file.as_ref())
+                .restore_backup(path, path)
                 .await
                 .map_err(|e| e.into_anyhow())?;
             // TODO: Implement backup validation
             stores
                 .user_store
-                .remove_backup(file.as_ref())
+                .remove_backup(path)
                 .await
                 .map_err(|e| e.into_anyhow())?;
 
-            info!("Restored {:?} from user store backup", file.as_ref());
+            info!("Restored {:?} from user store backup", path);
         }
 
         // Check for and restore backup from the system store (if it exists)
         if let Some(sys_store) = &stores.system_store {
             if sys_store
-                .check_backup_exists(file.as_ref())
+                .check_backup_exists(path)
                 .await
                 .map_err(|e| e.into_anyhow())?
             {
                 sys_store
-                    .restore_backup(file.as_ref(), file.as_ref())
+                    .restore_backup(path, path)
                     .await
                     .map_err(|e| e.into_anyhow())?;
                 // TODO: Implement backup validation
                 sys_store
-                    .remove_backup(file.as_ref())
+                    .remove_backup(path)
                     .await
                     .map_err(|e| e.into_anyhow())?;
 
-                info!("Restored {:?} from system store backup", file.as_ref());
+                info!("Restored {:?} from system store backup", path);
             }
         }
     }
     Ok(())
 }
 
+/// Changes a removal would make.
+#[derive(Serialize, Debug)]
+struct RemovalPreview {
+    /// Files which would be deleted
+    files: Vec<String>,
+    /// Files which would be restored from a backup
+    backups: Vec<String>,
+    /// Packages which would be removed
+    packages: Vec<String>,
+}
+
+impl Report for RemovalPreview {
+    fn print_text(&self) {
+        for (title, entries) in [
+            ("Files to delete", &self.files),
+            ("Backups to restore", &self.backups),
+            ("Packages to remove", &self.packages),
+        ] {
+            if !entries.is_empty() {
+                println!("{}:", title);
+                for e in entries.iter() {
+                    println!("  {}", e);
+                }
+            }
+        }
+
+        if self.files.is_empty() && self.packages.is_empty() {
+            info!("Nothing to remove");
+        }
+    }
+}
+
+/// Checks whether a backup of a file exists in the user or system store.
+async fn backup_exists(path: &str, stores: &Stores) -> Result<bool> {
+    if stores
+        .user_store
+        .check_backup_exists(path)
+        .await
+        .map_err(|e| e.into_anyhow())?
+    {
+        return Ok(true);
+    }
+    if let Some(sys_store) = &stores.system_store {
+        return sys_store
+            .check_backup_exists(path)
+            .await
+            .map_err(|e| e.into_anyhow());
+    }
+    Ok(false)
+}
+
+/// Reports what the removal process would do, without changing anything.
+///
+/// # Arguments
+///
+/// * `phases` - A BTreeMap of phase names to their corresponding Phase structs
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `files` - A vector of StoreFile objects representing files to be removed
+/// * `keep_packages` - Skip the removal of packages
+/// * `keep_files` - Skip the removal of files
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result indicating success or failure of collecting the changes
+pub(crate) async fn preview(
+    phases: &BTreeMap<String, crate::phases::Phase>,
+    stores: Arc<Stores>,
+    files: &[crate::store::files::StoreFile],
+    keep_packages: bool,
+    keep_files: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let report = collect_preview(phases, &stores, files, keep_packages, keep_files).await?;
+
+    // The text preview is terminal output like the log, JSON is a result for scripts
+    if crate::report::is_quiet() && format == OutputFormat::Text {
+        return Ok(());
+    }
+    emit(&report, format)
+}
+
+/// Collects the files, backups and packages a removal would touch.
+async fn collect_preview(
+    phases: &BTreeMap<String, crate::phases::Phase>,
+    stores: &Stores,
+    files: &[crate::store::files::StoreFile],
+    keep_packages: bool,
+    keep_files: bool,
+) -> Result<RemovalPreview> {
+    let mut report = RemovalPreview {
+        files: vec![],
+        backups: vec![],
+        packages: vec![],
+    };
+
+    if !keep_files {
+        for file in files.iter() {
+            // Only existing files are deleted and have their backup restored
+            if file_fs::check_file_exists(&file.destination).await? {
+                report.files.push(file.destination.clone());
+                if backup_exists(&file.destination, stores).await? {
+                    report.backups.push(file.destination.clone());
+                }
+            }
+        }
+    }
+
+    if !keep_packages {
+        if let Some(packages) = phases.get("remove").and_then(|p| p.packages.as_ref()) {
+            report.packages.extend(packages.iter().cloned());
+        }
+    }
+
+    Ok(report)
+}
+
 /// Executes the removal process for files and packages.
 ///
 /// This function handles the "remove" phase, including pre-actions, package removal, file removal,
@@ -87,6 +247,9 @@ async fn remove_file<S: AsRef<str>>(
 /// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
 /// * `files` - A vector of StoreFile objects representing files to be removed
 /// * `dotdeploy_config` - Configuration for the deployment process
+/// * `keep_packages` - Skip the removal of packages
+/// * `keep_files` - Skip the removal of files, they stay in place without their backups being
+///   restored
 ///
 /// # Returns
 ///
@@ -96,6 +259,8 @@ pub(crate) async fn remove(
     stores: Arc<Stores>,
     files: Vec<crate::store::files::StoreFile>,
     dotdeploy_config: &crate::config::DotdeployConfig,
+    keep_packages: bool,
+    keep_files: bool,
 ) -> Result<()> {
     let phase_name = "remove";
     info!("Starting {} phase", phase_name.to_uppercase());
@@ -112,14 +277,15 @@ pub(crate) async fn remove(
         if let Some(v) = pre_actions {
             if !v.is_empty() {
                 info!("Executing pre stage actions");
-                for a in v.into_iter() {
-                    a.run().await?
-                }
+                crate::deploy::run_actions(v, dotdeploy_config.max_parallel_actions).await?;
             }
         }
 
         // Handle package removal
-        if let Some(packages) = phase.packages {
+        if keep_packages {
+            info!("Keeping packages installed");
+        } else if let Some(packages) = phase.packages {
+            signal::check_cancelled()?;
             // Prepare package removal command
             let default_cmds = crate::packages::default_cmds()?.1;
             let mut remove_cmd: VecDeque<String> = VecDeque::new();
@@ -134,35 +300,34 @@ pub(crate) async fn remove(
             }
 
             // Execute package removal
-            if let Some(cmd) = remove_cmd.pop_front() {
-                // Add packages to the removal command
-                for pkg in packages.into_iter() {
-                    remove_cmd.push_back(pkg);
-                }
-
-                // Spawn the removal process
-                let mut cmd = tokio::process::Command::new(&cmd)
-                    .args(&remove_cmd)
-                    .spawn()
-                    .with_context(|| {
-                        format!("Failed to spawn {:?} with args: {:?}", cmd, remove_cmd)
-                    })?;
-
-                // Check if the removal was successful
-                if !cmd.wait().await?.success() {
-                    bail!("Failed to execute {:?} with args: {:?}", cmd, remove_cmd)
-                }
-            }
+            crate::packages::exec_package_cmd(
+                remove_cmd,
+                packages,
+                &dotdeploy_config.pkg_lock_retry,
+            )
+            .await?;
         }
 
         warn!("This shit better works...");  // TODO: Consider removing or rephrasing this debug message
         let mut set = tokio::task::JoinSet::new();
 
+        // Files which are kept are simply left in place
+        let files = if keep_files {
+            info!("Keeping deployed files");
+            vec![]
+        } else {
+            files
+        };
+
         // Remove files asynchronously
         for file in files.clone() {
+            // Stop spawning new operations once cancelled
+            if signal::is_cancelled() {
+                break;
+            }
             let stores_clone = Arc::clone(&stores);
             set.spawn(async move {
-                match remove_file(&file.destination, stores_clone).await {
+                match remove_file(&file, stores_clone).await {
                     Ok(()) => Ok(()),
                     Err(e) => bail!("Failed to remove {:?}\n {:?}", &file.destination, e),
                 }
@@ -173,6 +338,7 @@ pub(crate) async fn remove(
         while let Some(res) = set.join_next().await {
             res??;
         }
+        signal::check_cancelled()?;
 
         // Remove parent directories synchronously
         for file in files {
@@ -183,9 +349,7 @@ pub(crate) async fn remove(
         if let Some(v) = main_actions {
             if !v.is_empty() {
                 info!("Executing main stage actions");
-                for a in v.into_iter() {
-                    a.run().await?
-                }
+                crate::deploy::run_actions(v, dotdeploy_config.max_parallel_actions).await?;
             }
         }
 
@@ -193,12 +357,72 @@ pub(crate) async fn remove(
         if let Some(v) = post_actions {
             if !v.is_empty() {
                 info!("Executing post stage actions");
-                for a in v.into_iter() {
-                    a.run().await?
-                }
+                crate::deploy::run_actions(v, dotdeploy_config.max_parallel_actions).await?;
             }
         }
     }
     info!("Finished {} phase", phase_name.to_uppercase());
     Ok(())
 }
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::tests::store_setup_helper;
+
+    #[tokio::test]
+    async fn test_collect_preview() -> Result<()> {
+        let stores = Stores {
+            user_store: store_setup_helper("copy").await?,
+            system_store: None,
+        };
+        let phases = BTreeMap::from([(
+            "remove".to_string(),
+            crate::phases::Phase {
+                files: Some(VecDeque::new()),
+                actions: None,
+                packages: Some(vec!["foo".to_string(), "bar".to_string()]),
+            },
+        )]);
+
+        // Packages of the removed modules are listed
+        let report = collect_preview(&phases, &stores, &[], false, false).await?;
+        assert_eq!(report.packages, vec!["foo", "bar"]);
+        assert!(report.files.is_empty());
+
+        // Unless they are kept
+        let report = collect_preview(&phases, &stores, &[], true, false).await?;
+        assert!(report.packages.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_deployed() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let tree = temp_dir.path().join("tree");
+        std::fs::create_dir_all(tree.join("sub"))?;
+        std::fs::write(tree.join("sub/file.txt"), "foo")?;
+        let path = tree.display().to_string();
+        let checksum = file_checksum::calculate_dir_checksum(&tree).await?;
+
+        // Only directory operations delete whole trees
+        assert!(delete_deployed(&path, "copy", None).await.is_err());
+        assert!(tree.exists());
+
+        // Modified copies are kept
+        std::fs::write(tree.join("new.txt"), "")?;
+        assert!(!delete_deployed(&path, "directory", Some(&checksum)).await?);
+        assert!(tree.exists());
+
+        std::fs::remove_file(tree.join("new.txt"))?;
+        assert!(delete_deployed(&path, "directory", Some(&checksum)).await?);
+        assert!(!tree.exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! This module renders single templates for debugging.
+//!
+//! A template is rendered with the same context as during a deployment: the global context
+//! values, the context variables of the module and its dependencies as well as the partials and
+//! helpers of the modules. Nothing is deployed and the stores are not modified.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use handlebars::Handlebars;
+
+use crate::config::DotdeployConfig;
+use crate::modules::queue::ModuleQueue;
+
+/// Determines if a template of a module is rendered in strict mode.
+///
+/// The `template_strict` value of the file entry with the given source takes precedence over the
+/// value of the module. Templates are strict by default.
+fn is_strict(queue: &ModuleQueue, module_name: &str, source: Option<&Path>) -> bool {
+    let Some(module) = queue.modules.iter().find(|m| m.name == module_name) else {
+        return true;
+    };
+
+    let file_strict = source.and_then(|source| {
+        module
+            .config
+            .files
+            .as_ref()?
+            .values()
+            .find(|f| {
+                f.source
+                    .as_ref()
+                    .and_then(|s| s.canonicalize().ok())
+                    .as_deref()
+                    == Some(source)
+            })?
+            .template_strict
+    });
+
+    file_strict
+        .or(module.config.template_strict)
+        .unwrap_or(true)
+}
+
+/// Renders a template with the context of a queued module.
+///
+/// # Arguments
+///
+/// * `queue` - Queue containing the module and its dependencies
+/// * `module_name` - Name of the module the template belongs to
+/// * `template` - The template to render
+/// * `source` - Canonical path of the template file, used to look up its `template_strict` value
+/// * `overrides` - Context values replacing the values of the deployment context
+/// * `hb` - Handlebars registry, prepared with [ModuleQueue::register_templates]
+///
+/// # Returns
+///
+/// A Result containing the rendered template
+fn render_template(
+    queue: &ModuleQueue,
+    module_name: &str,
+    template: &str,
+    source: Option<&Path>,
+    overrides: &[(String, String)],
+    hb: &mut Handlebars<'static>,
+) -> Result<String> {
+    let mut context = queue.context.clone();
+    context.insert("DOD_MODULE".to_string(), module_name.to_string());
+    context.extend(overrides.iter().cloned());
+
+    hb.set_strict_mode(is_strict(queue, module_name, source));
+    hb.render_template(template, &crate::facts::template_context(&context)?)
+        .context("Failed to render template")
+}
+
+/// Renders a file of a module, or stdin, and prints the result.
+///
+/// # Arguments
+///
+/// * `module_name` - Name of the module providing the context
+/// * `file` - Template file, relative to the module location. Reads stdin if `None` or `-`.
+/// * `overrides` - Context values replacing the values of the deployment context
+/// * `context` - Global context values
+/// * `dotdeploy_config` - Configuration used to locate the module
+/// * `hb` - Handlebars registry including the store helpers
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn render(
+    module_name: &str,
+    file: Option<&Path>,
+    overrides: &[(String, String)],
+    context: BTreeMap<String, String>,
+    dotdeploy_config: &DotdeployConfig,
+    hb: &Handlebars<'static>,
+) -> Result<()> {
+    let mut queue = ModuleQueue {
+        modules: std::collections::BTreeSet::new(),
+        deployed: std::collections::BTreeSet::new(),
+        context,
+    };
+    queue.add_modules(&vec![module_name.to_string()], dotdeploy_config, true).await?;
+
+    let mut hb = hb.clone();
+    queue.register_templates(&mut hb)?;
+
+    let (template, source) = match file.filter(|f| f != &Path::new("-")) {
+        Some(file) => {
+            let location = &queue
+                .modules
+                .iter()
+                .find(|m| m.name == module_name)
+                .with_context(|| format!("Module {} is not queued", module_name))?
+                .location;
+            let path = location
+                .join(file)
+                .canonicalize()
+                .with_context(|| format!("Failed to find {:?} in module {}", file, module_name))?;
+            let template = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read template {:?}", path))?;
+            (template, Some(path))
+        }
+        None => {
+            let mut template = String::new();
+            std::io::stdin()
+                .read_to_string(&mut template)
+                .context("Failed to read template from stdin")?;
+            (template, None)
+        }
+    };
+
+    print!(
+        "{}",
+        render_template(
+            &queue,
+            module_name,
+            &template,
+            source.as_deref(),
+            overrides,
+            &mut hb
+        )?
+    );
+
+    Ok(())
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::modules::config::ModuleConfig;
+    use crate::modules::files::ModuleFile;
+    use crate::modules::Module;
+
+    #[test]
+    fn test_render_template() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let location = temp_dir.path().join("zsh");
+        std::fs::create_dir_all(location.join("partials"))?;
+        std::fs::write(location.join("partials/header"), "# {{DOD_MODULE}}")?;
+        std::fs::write(location.join("zshrc"), "")?;
+        let source = location.join("zshrc").canonicalize()?;
+
+        let mut queue = ModuleQueue {
+            modules: std::collections::BTreeSet::new(),
+            deployed: std::collections::BTreeSet::new(),
+            context: BTreeMap::from([("editor".to_string(), "vim".to_string())]),
+        };
+        queue.modules.insert(Module {
+            name: "zsh".to_string(),
+            location,
+            reason: "manual".to_string(),
+            config: ModuleConfig {
+                files: Some(BTreeMap::from([(
+                    "~/.zshrc".into(),
+                    ModuleFile {
+                        source: Some(source.clone()),
+                        template_strict: Some(false),
+                        ..Default::default()
+                    },
+                )])),
+                ..Default::default()
+            },
+        });
+
+        let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        queue.register_templates(&mut hb)?;
+
+        let template = "{{> zsh/header}}, {{editor}}{{missing}}";
+        assert_eq!(
+            render_template(&queue, "zsh", template, Some(&source), &[], &mut hb)?,
+            "# zsh, vim"
+        );
+        // Overrides replace context values
+        assert_eq!(
+            render_template(
+                &queue,
+                "zsh",
+                template,
+                Some(&source),
+                &[("editor".to_string(), "nano".to_string())],
+                &mut hb
+            )?,
+            "# zsh, nano"
+        );
+        // Templates without a file entry are strict
+        assert!(render_template(&queue, "zsh", template, None, &[], &mut hb).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! This module provides the reporting layer for command results.
+//!
+//! Commands collect their results into a report which is printed either as human readable text or
+//! as JSON, depending on the global `--format` flag. This keeps the output of a command stable for
+//! scripts and dashboards, independent of log messages.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::cli::OutputFormat;
+
+/// Whether terminal output is suppressed, set with `--quiet`.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Suppresses log messages below the error level and non-critical module messages on the terminal.
+///
+/// Reports are still printed, as they are the result a command was run for.
+pub(crate) fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Checks whether terminal output is suppressed.
+pub(crate) fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// A command result which can be printed in every output format.
+pub(crate) trait Report: Serialize {
+    /// Prints the report as human readable text.
+    fn print_text(&self);
+}
+
+/// Prints a report in the requested format.
+///
+/// # Arguments
+///
+/// * `report` - The report to print
+/// * `format` - The output format
+///
+/// # Returns
+///
+/// A Result indicating success or failure of serializing the report
+pub(crate) fn emit<R: Report>(report: &R, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => report.print_text(),
+        OutputFormat::Json => println!("{}", to_json(report)?),
+    }
+
+    Ok(())
+}
+
+/// Serializes a report into pretty printed JSON.
+fn to_json<R: Report>(report: &R) -> Result<String> {
+    Ok(serde_json::to_string_pretty(report)?)
+}
+
+/// Serializes a date for reports, using RFC 3339.
+pub(crate) fn serialize_date<S: serde::Serializer>(
+    date: &chrono::DateTime<chrono::Local>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&date.to_rfc3339())
+}
+
+/// Summary of a deployment run.
+#[derive(Serialize, Debug)]
+pub(crate) struct DeploySummary {
+    /// Names of the deployed modules
+    pub(crate) modules: Vec<String>,
+    /// Duration of the run in milliseconds
+    pub(crate) duration_ms: u128,
+    /// Messages of the deployed modules, keyed by module name
+    pub(crate) messages: std::collections::BTreeMap<String, Vec<crate::modules::messages::Message>>,
+    /// Time spent per phase and module, if requested with `--timings`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) timings: Option<Vec<crate::timings::TimingEntry>>,
+}
+
+impl Report for DeploySummary {
+    fn print_text(&self) {
+        for (module, msgs) in self.messages.iter() {
+            crate::modules::messages::print(module, msgs);
+        }
+        if let Some(timings) = &self.timings {
+            crate::timings::print(timings, self.duration_ms);
+        }
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deploy_summary_json() -> Result<()> {
+        let summary = DeploySummary {
+            modules: vec!["hosts/foo".to_string()],
+            duration_ms: 42,
+            messages: std::collections::BTreeMap::from([(
+                "hosts/foo".to_string(),
+                vec![crate::modules::messages::Message {
+                    level: crate::modules::messages::MessageLevel::Info,
+                    text: "Hello".to_string(),
+                }],
+            )]),
+            timings: None,
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&to_json(&summary)?)?;
+        assert_eq!(json["modules"][0], "hosts/foo");
+        assert_eq!(json["duration_ms"], 42);
+        assert_eq!(json["messages"]["hosts/foo"][0], "Hello");
+        assert!(json.get("timings").is_none());
+
+        Ok(())
+    }
+}
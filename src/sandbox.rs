@@ -0,0 +1,136 @@
+//! This module provides a throwaway HOME overlay used to preview modules.
+//!
+//! The overlay is a temporary directory which replaces HOME and the XDG base directories for the
+//! current process. Modules deployed while the overlay is active never touch the real home
+//! directory or the real user store. The config file and the dotfiles are still read from the real
+//! home directory, see [origin]. After deployment, the user is dropped into a subshell inside the
+//! overlay to evaluate the result. The overlay is deleted once the subshell exits.
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+lazy_static! {
+    /// The environment replaced by the overlay, set once the overlay is created.
+    static ref ORIGIN: RwLock<Option<Origin>> = RwLock::new(None);
+}
+
+/// The home directory and config folder of the user before the overlay replaced them.
+#[derive(Clone, Debug)]
+pub(crate) struct Origin {
+    /// Real home directory, used to expand the paths of the dotfiles
+    pub(crate) home: PathBuf,
+    /// Real folder of the config file
+    pub(crate) config_dir: PathBuf,
+}
+
+/// Returns the environment replaced by the overlay, if an overlay is active.
+pub(crate) fn origin() -> Option<Origin> {
+    ORIGIN.read().unwrap().clone()
+}
+
+/// Returns the environment variables pointing into the overlay rooted at `root`.
+///
+/// # Arguments
+///
+/// * `root` - The root directory of the overlay, which serves as the new HOME
+///
+/// # Returns
+///
+/// A vector of variable names and their paths inside the overlay.
+fn overlay_env(root: &Path) -> Vec<(&'static str, PathBuf)> {
+    vec![
+        ("HOME", root.to_path_buf()),
+        ("XDG_CONFIG_HOME", root.join(".config")),
+        ("XDG_DATA_HOME", root.join(".local/share")),
+        ("XDG_STATE_HOME", root.join(".local/state")),
+        ("XDG_CACHE_HOME", root.join(".cache")),
+    ]
+}
+
+/// Creates a new HOME overlay and points the environment of the current process at it.
+///
+/// This must be called before the config is initialized, so the run state, logs, caches and
+/// stores derived from HOME and the XDG base directories all point into the overlay. The config
+/// file and the dotfiles keep being read from the real home directory, see [origin].
+///
+/// # Returns
+///
+/// The temporary directory holding the overlay. It is deleted when the returned value is dropped.
+pub(crate) fn create_overlay() -> Result<tempfile::TempDir> {
+    let home = PathBuf::from(std::env::var("HOME").context("HOME is not set")?);
+    let config_dir = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => home.join(".config"),
+    };
+
+    let overlay = tempfile::Builder::new()
+        .prefix("dotdeploy-try-")
+        .tempdir()
+        .context("Failed to create temporary directory for HOME overlay")?;
+
+    for (var, path) in overlay_env(overlay.path()) {
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create overlay directory {:?}", &path))?;
+        unsafe {
+            std::env::set_var(var, &path);
+        }
+    }
+
+    *ORIGIN.write().unwrap() = Some(Origin { home, config_dir });
+    debug!("Created HOME overlay at {}", overlay.path().display());
+
+    Ok(overlay)
+}
+
+/// Spawns an interactive shell inside the overlay and waits for it to exit.
+///
+/// The shell is taken from the `SHELL` environment variable and defaults to `/bin/sh`.
+///
+/// # Arguments
+///
+/// * `overlay` - The root directory of the overlay
+///
+/// # Returns
+///
+/// A Result containing `true` if the shell exited successfully.
+pub(crate) fn spawn_shell(overlay: &Path) -> Result<bool> {
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+
+    info!(
+        "Entering HOME overlay at {}. Exit the shell to discard it.",
+        overlay.display()
+    );
+
+    let status = std::process::Command::new(&shell)
+        .current_dir(overlay)
+        .env("DOD_TRY", "1")
+        .status()
+        .with_context(|| format!("Failed to spawn shell {:?}", &shell))?;
+
+    info!("Discarding HOME overlay at {}", overlay.display());
+
+    Ok(status.success())
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlay_env() {
+        let root = PathBuf::from("/tmp/overlay");
+        let env = overlay_env(&root);
+
+        assert_eq!(env[0], ("HOME", PathBuf::from("/tmp/overlay")));
+        // All variables must point into the overlay
+        assert!(env.iter().all(|(_, path)| path.starts_with(&root)));
+        assert!(env
+            .iter()
+            .any(|(var, path)| *var == "XDG_DATA_HOME" && path == &root.join(".local/share")));
+    }
+}
@@ -0,0 +1,195 @@
+//! This module decides whether an automatic run should proceed.
+//!
+//! Automatic runs, e.g. started by a systemd timer, are skipped if the machine runs on battery with
+//! a low charge, the network connection is metered or the current time lies outside the configured
+//! window. The battery state is read from sysfs, the metered state is queried from NetworkManager.
+//! Manual runs are never affected.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveTime;
+
+use crate::config::Schedule;
+
+/// Location of the power supply information in sysfs.
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+/// Reads the battery state from the power supply directory in sysfs.
+///
+/// # Arguments
+///
+/// * `root` - The power supply directory, usually `/sys/class/power_supply`
+///
+/// # Returns
+///
+/// The lowest charge of all batteries in percent if the machine is running on battery, `None` if
+/// it is connected to AC or has no battery.
+fn battery_level(root: &Path) -> Option<u8> {
+    let read = |path: &Path, file: &str| {
+        std::fs::read_to_string(path.join(file))
+            .ok()
+            .map(|s| s.trim().to_string())
+    };
+
+    let mut level: Option<u8> = None;
+    for entry in std::fs::read_dir(root).ok()?.flatten() {
+        let path = entry.path();
+        match read(&path, "type").as_deref() {
+            Some("Mains") if read(&path, "online").as_deref() == Some("1") => return None,
+            Some("Battery") => {
+                if let Some(capacity) = read(&path, "capacity").and_then(|c| c.parse().ok()) {
+                    level = Some(level.map_or(capacity, |l: u8| l.min(capacity)));
+                }
+            }
+            _ => (),
+        }
+    }
+    level
+}
+
+/// Queries NetworkManager whether the primary connection is metered.
+///
+/// Returns `false` if NetworkManager is not available.
+fn is_metered() -> bool {
+    let output = std::process::Command::new("busctl")
+        .args([
+            "get-property",
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.NetworkManager",
+            "Metered",
+        ])
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => {
+            parse_metered(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => {
+            debug!("Could not query metered state from NetworkManager");
+            false
+        }
+    }
+}
+
+/// Parses the output of the NetworkManager `Metered` property, e.g. "u 1".
+///
+/// The values 1 (yes) and 3 (guess yes) are considered metered.
+fn parse_metered(output: &str) -> bool {
+    matches!(output.split_whitespace().nth(1), Some("1") | Some("3"))
+}
+
+/// Checks whether `now` lies within a window like "22:00-06:00". Windows may wrap around midnight.
+pub(crate) fn in_window(window: &str, now: NaiveTime) -> Result<bool> {
+    let (start, end) = window
+        .split_once('-')
+        .ok_or_else(|| anyhow!("Invalid time window {:?}, expected HH:MM-HH:MM", window))?;
+    let parse = |t: &str| {
+        NaiveTime::parse_from_str(t.trim(), "%H:%M")
+            .with_context(|| format!("Invalid time {:?} in window {:?}", t, window))
+    };
+    let (start, end) = (parse(start)?, parse(end)?);
+
+    Ok(if start <= end {
+        start <= now && now < end
+    } else {
+        now >= start || now < end
+    })
+}
+
+/// Checks the schedule conditions for an automatic run.
+///
+/// # Arguments
+///
+/// * `schedule` - The schedule configuration
+///
+/// # Returns
+///
+/// The reason why the run should be skipped, or `None` if it can proceed.
+pub(crate) fn skip_reason(schedule: &Schedule) -> Result<Option<String>> {
+    if let Some(min) = schedule.min_battery {
+        if let Some(level) = battery_level(Path::new(POWER_SUPPLY_PATH)) {
+            if level < min {
+                return Ok(Some(format!(
+                    "running on battery at {}% (minimum {}%)",
+                    level, min
+                )));
+            }
+        }
+    }
+
+    if schedule.skip_metered && is_metered() {
+        return Ok(Some("network connection is metered".to_string()));
+    }
+
+    if let Some(window) = &schedule.window {
+        if !in_window(window, chrono::Local::now().time())? {
+            return Ok(Some(format!("outside of time window {}", window)));
+        }
+    }
+
+    Ok(None)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supply(root: &Path, name: &str, files: &[(&str, &str)]) -> Result<()> {
+        let dir = root.join(name);
+        std::fs::create_dir_all(&dir)?;
+        for (file, content) in files {
+            std::fs::write(dir.join(file), format!("{}\n", content))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_battery_level() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let root = temp_dir.path();
+
+        // No battery
+        assert_eq!(battery_level(root), None);
+
+        supply(root, "BAT0", &[("type", "Battery"), ("capacity", "40")])?;
+        supply(root, "BAT1", &[("type", "Battery"), ("capacity", "25")])?;
+        supply(root, "AC", &[("type", "Mains"), ("online", "0")])?;
+        assert_eq!(battery_level(root), Some(25));
+
+        // Connected to AC
+        supply(root, "AC", &[("type", "Mains"), ("online", "1")])?;
+        assert_eq!(battery_level(root), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_metered() {
+        assert!(parse_metered("u 1\n"));
+        assert!(parse_metered("u 3\n"));
+        assert!(!parse_metered("u 2\n"));
+        assert!(!parse_metered(""));
+    }
+
+    #[test]
+    fn test_in_window() -> Result<()> {
+        let t = |s| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+
+        assert!(in_window("08:00-18:00", t("12:00"))?);
+        assert!(!in_window("08:00-18:00", t("18:00"))?);
+        // Wrapping around midnight
+        assert!(in_window("22:00-06:00", t("23:30"))?);
+        assert!(in_window("22:00-06:00", t("05:59"))?);
+        assert!(!in_window("22:00-06:00", t("12:00"))?);
+
+        assert!(in_window("foo", t("12:00")).is_err());
+        assert!(in_window("25:00-06:00", t("12:00")).is_err());
+
+        Ok(())
+    }
+}
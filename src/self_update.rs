@@ -0,0 +1,268 @@
+//! This module updates a prebuilt dotdeploy binary to the latest release.
+//!
+//! `dotdeploy self-update` looks up the latest GitHub release, downloads the binary matching the
+//! architecture of the machine, e.g. `dotdeploy-linux-x64-musl`, and verifies it against the
+//! SHA-256 checksum published with the release, either as `<binary>.sha256` or in `SHA256SUMS`.
+//! The running binary is then replaced atomically. Releases are fetched with curl. Installations
+//! managed by a package manager or cargo should be updated with those instead.
+//!
+//! The checksum is downloaded from the same release as the binary. It detects corrupted or
+//! truncated downloads but is no signature: whoever can replace the binary of a release can
+//! replace its checksum as well.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::OutputFormat;
+use crate::report::{emit, Report};
+use crate::utils::file_checksum::calculate_sha256_checksum_bytes;
+
+/// API endpoint of the latest release.
+const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/FrauH0lle/dotdeploy-rs/releases/latest";
+
+/// Name of the checksum file covering all assets of a release.
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+
+/// A release on GitHub.
+#[derive(Deserialize, Debug)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+/// A file attached to a release.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// Result of checking for or installing an update.
+#[derive(Serialize, Debug)]
+struct UpdateReport {
+    current: String,
+    latest: String,
+    update_available: bool,
+    updated: bool,
+}
+
+impl Report for UpdateReport {
+    fn print_text(&self) {
+        if self.updated {
+            println!("Updated dotdeploy from {} to {}", self.current, self.latest);
+        } else if self.update_available {
+            println!(
+                "dotdeploy {} is available, {} is installed",
+                self.latest, self.current
+            );
+        } else {
+            println!("dotdeploy {} is up to date", self.current);
+        }
+    }
+}
+
+/// Downloads a URL with curl, failing with its error output.
+fn fetch(url: &str) -> Result<Vec<u8>> {
+    let output = Command::new("curl")
+        .args(["-fsSL", url])
+        .output()
+        .with_context(|| format!("Failed to run curl {}", url))?;
+    if !output.status.success() {
+        bail!(
+            "curl {} exited with {}: {}",
+            url,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Returns the name of the release asset holding the binary for an architecture.
+///
+/// Names follow the `build_name` of the release workflow, e.g. `dotdeploy-linux-x64-musl`.
+/// Architectures without a build name return `None`.
+fn asset_name(arch: &str) -> Option<String> {
+    let build_arch = match arch {
+        "x86_64" => "x64",
+        "aarch64" => "arm64",
+        _ => return None,
+    };
+    Some(format!("dotdeploy-linux-{}-musl", build_arch))
+}
+
+/// Selects the binary for an architecture among the assets of a release.
+fn select_asset<'a>(assets: &'a [Asset], arch: &str) -> Option<&'a Asset> {
+    let name = asset_name(arch)?;
+    assets.iter().find(|a| a.name == name)
+}
+
+/// Reads the checksum of a file from the content of a checksum file.
+///
+/// Lines have the format of `sha256sum`, i.e. `<checksum>  <name>`. A file holding only a checksum
+/// belongs to `name`.
+fn parse_checksum(content: &str, name: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let checksum = fields.next()?;
+        match fields.next() {
+            Some(file) if file.trim_start_matches('*') != name => None,
+            _ => Some(checksum.to_lowercase()),
+        }
+    })
+}
+
+/// Downloads the published checksum of a release asset.
+fn expected_checksum(release: &Release, asset: &Asset) -> Result<String> {
+    let sums = release
+        .assets
+        .iter()
+        .find(|a| a.name == format!("{}.sha256", asset.name))
+        .or_else(|| release.assets.iter().find(|a| a.name == CHECKSUMS_ASSET))
+        .ok_or_else(|| {
+            anyhow!(
+                "Release {} publishes no checksum of {}",
+                release.tag_name,
+                asset.name
+            )
+        })?;
+    let content = String::from_utf8(fetch(&sums.browser_download_url)?)
+        .with_context(|| format!("Checksum file {} is not valid UTF-8", sums.name))?;
+    parse_checksum(&content, &asset.name)
+        .ok_or_else(|| anyhow!("{} contains no checksum of {}", sums.name, asset.name))
+}
+
+/// Replaces a binary atomically with new content.
+fn replace_binary(exe: &Path, content: &[u8]) -> Result<()> {
+    let dir = exe
+        .parent()
+        .ok_or_else(|| anyhow!("Binary {:?} has no parent directory", exe))?;
+    let mut tmp = tempfile::NamedTempFile::new_in(dir).with_context(|| {
+        format!(
+            "Failed to create temporary file in {:?}, run self-update with sufficient permissions",
+            dir
+        )
+    })?;
+    tmp.write_all(content)
+        .context("Failed to write the new binary")?;
+    std::fs::set_permissions(
+        tmp.path(),
+        std::os::unix::fs::PermissionsExt::from_mode(0o755),
+    )
+    .context("Failed to make the new binary executable")?;
+    tmp.persist(exe)
+        .with_context(|| format!("Failed to replace {:?}", exe))?;
+    Ok(())
+}
+
+/// Checks for a newer release and installs it.
+///
+/// # Arguments
+///
+/// * `check` - Only report whether an update is available
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) fn self_update(check: bool, format: OutputFormat) -> Result<()> {
+    let current = env!("CARGO_PKG_VERSION").to_string();
+    let release: Release = serde_json::from_slice(&fetch(LATEST_RELEASE_URL)?)
+        .context("Failed to parse the latest release")?;
+    let latest = release.tag_name.trim_start_matches('v').to_string();
+    let update_available = crate::modules::version::satisfies(&latest, &format!(">{}", current))?;
+
+    let mut report = UpdateReport {
+        current,
+        latest,
+        update_available,
+        updated: false,
+    };
+    if check || !update_available {
+        return emit(&report, format);
+    }
+
+    let asset = select_asset(&release.assets, std::env::consts::ARCH).ok_or_else(|| {
+        anyhow!(
+            "Release {} has no binary for {}",
+            release.tag_name,
+            std::env::consts::ARCH
+        )
+    })?;
+    let expected = expected_checksum(&release, asset)?;
+    info!("Downloading {}", asset.name);
+    let binary = fetch(&asset.browser_download_url)?;
+    let actual = calculate_sha256_checksum_bytes(&binary);
+    if actual != expected {
+        bail!(
+            "Checksum of {} does not match: expected {}, got {}",
+            asset.name,
+            expected,
+            actual
+        );
+    }
+
+    let exe = std::env::current_exe().context("Failed to locate the running binary")?;
+    replace_binary(&exe, &binary)?;
+    report.updated = true;
+    emit(&report, format)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asset(name: &str) -> Asset {
+        Asset {
+            name: name.to_string(),
+            browser_download_url: format!("https://example.com/{}", name),
+        }
+    }
+
+    #[test]
+    fn test_select_asset() {
+        let assets = vec![
+            asset("SHA256SUMS"),
+            asset("dotdeploy-linux-x64-musl.sha256"),
+            asset("dotdeploy-linux-x64-musl"),
+            asset("dotdeploy-linux-arm64-musl"),
+            asset("dotdeploy-linux-x64-gnu"),
+        ];
+        assert_eq!(
+            select_asset(&assets, "x86_64").map(|a| a.name.as_str()),
+            Some("dotdeploy-linux-x64-musl")
+        );
+        assert_eq!(
+            select_asset(&assets, "aarch64").map(|a| a.name.as_str()),
+            Some("dotdeploy-linux-arm64-musl")
+        );
+        assert!(select_asset(&assets, "riscv64").is_none());
+        assert!(select_asset(&assets[..2], "x86_64").is_none());
+    }
+
+    #[test]
+    fn test_parse_checksum() {
+        let sums = "AAAA  dotdeploy-linux-arm64-musl\nbbbb *dotdeploy-linux-x64-musl\n";
+        assert_eq!(
+            parse_checksum(sums, "dotdeploy-linux-arm64-musl"),
+            Some("aaaa".to_string())
+        );
+        assert_eq!(
+            parse_checksum(sums, "dotdeploy-linux-x64-musl"),
+            Some("bbbb".to_string())
+        );
+        assert_eq!(parse_checksum(sums, "dotdeploy-riscv64"), None);
+        assert_eq!(
+            parse_checksum("cccc\n", "dotdeploy-riscv64"),
+            Some("cccc".to_string())
+        );
+    }
+}
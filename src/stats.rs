@@ -0,0 +1,204 @@
+//! This module handles the collection and display of deployment statistics.
+//!
+//! After each deployment, the number and total size of the files managed by every deployed module
+//! are recorded together with the duration and status of the run.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::cli::OutputFormat;
+use crate::report::{emit, serialize_date, Report};
+use crate::store::stats::StoreModuleStats;
+use crate::Stores;
+
+/// Records the statistics of a deployment run for the given modules.
+///
+/// The statistics are always written to the user store, but include the files of the system store.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `modules` - Names of the deployed modules
+/// * `durations` - Time spent on the operations of each module, see
+///   [crate::timings::take_module_times]
+/// * `success` - Whether the deployment run was successful
+///
+/// # Returns
+///
+/// A Result indicating success or failure of recording the statistics
+pub(crate) async fn record(
+    stores: Arc<Stores>,
+    modules: &[String],
+    durations: &BTreeMap<String, Duration>,
+    success: bool,
+) -> Result<()> {
+    let date = chrono::offset::Local::now();
+
+    for module in modules.iter() {
+        let mut files = stores
+            .user_store
+            .get_all_files(module)
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        if let Some(sys_store) = &stores.system_store {
+            files.extend(
+                sys_store
+                    .get_all_files(module)
+                    .await
+                    .map_err(|e| e.into_anyhow())?,
+            );
+        }
+
+        // Files which can not be accessed do not count towards the total size
+        let mut bytes: u64 = 0;
+        for f in files.iter() {
+            if let Ok(m) = tokio::fs::metadata(&f.destination).await {
+                bytes += m.len();
+            }
+        }
+
+        stores
+            .user_store
+            .add_module_stats(StoreModuleStats {
+                module: module.to_string(),
+                files: files.len() as i64,
+                bytes: bytes as i64,
+                duration_ms: durations
+                    .get(module)
+                    .copied()
+                    .unwrap_or_default()
+                    .as_millis() as i64,
+                status: if success { "success" } else { "failed" }.to_string(),
+                date,
+            })
+            .await
+            .map_err(|e| e.into_anyhow())?;
+    }
+
+    Ok(())
+}
+
+/// Statistics of a single deployment run of a module.
+#[derive(Serialize, Debug)]
+struct StatsEntry {
+    module: String,
+    files: i64,
+    bytes: i64,
+    duration_ms: i64,
+    status: String,
+    #[serde(serialize_with = "serialize_date")]
+    date: chrono::DateTime<chrono::Local>,
+}
+
+/// A note attached to a module.
+#[derive(Serialize, Debug)]
+struct NoteEntry {
+    id: i64,
+    target: String,
+    text: String,
+    #[serde(serialize_with = "serialize_date")]
+    date: chrono::DateTime<chrono::Local>,
+}
+
+/// Deployment statistics and notes of the shown modules.
+#[derive(Serialize, Debug)]
+struct StatsReport {
+    stats: Vec<StatsEntry>,
+    notes: Vec<NoteEntry>,
+}
+
+impl Report for StatsReport {
+    fn print_text(&self) {
+        if self.stats.is_empty() {
+            info!("No statistics recorded yet");
+            return;
+        }
+
+        println!(
+            "{:<19}  {:<30}  {:>6}  {:>12}  {:>10}  STATUS",
+            "DATE", "MODULE", "FILES", "BYTES", "TIME (ms)"
+        );
+        for s in self.stats.iter() {
+            println!(
+                "{:<19}  {:<30}  {:>6}  {:>12}  {:>10}  {}",
+                s.date.format("%Y-%m-%d %H:%M:%S"),
+                s.module,
+                s.files,
+                s.bytes,
+                s.duration_ms,
+                s.status
+            );
+        }
+
+        if !self.notes.is_empty() {
+            println!("\nNOTES");
+            for n in self.notes.iter() {
+                println!(
+                    "[{}] {}  {}: {}",
+                    n.id,
+                    n.date.format("%Y-%m-%d %H:%M"),
+                    n.target,
+                    n.text
+                );
+            }
+        }
+    }
+}
+
+/// Prints the deployment statistics.
+///
+/// Without a module, the latest statistics of every module are shown. With a module, its full
+/// history is shown. Notes attached to the shown modules are listed afterwards.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `module` - Optional name of a module
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result indicating success or failure
+pub(crate) async fn show(
+    stores: Arc<Stores>,
+    module: Option<&str>,
+    format: OutputFormat,
+) -> Result<()> {
+    let stats: Vec<StatsEntry> = match module {
+        Some(m) => stores.user_store.get_module_stats_history(m).await,
+        None => stores.user_store.get_latest_module_stats().await,
+    }
+    .map_err(|e| e.into_anyhow())?
+    .into_iter()
+    .map(|s| StatsEntry {
+        module: s.module,
+        files: s.files,
+        bytes: s.bytes,
+        duration_ms: s.duration_ms,
+        status: s.status,
+        date: s.date,
+    })
+    .collect();
+
+    // Show notes attached to the listed modules
+    let notes: Vec<NoteEntry> = stores
+        .user_store
+        .get_notes(module.map(str::to_string))
+        .await
+        .map_err(|e| e.into_anyhow())?
+        .into_iter()
+        .filter(|n| stats.iter().any(|s| s.module == n.target))
+        .map(|n| NoteEntry {
+            id: n.id,
+            target: n.target,
+            text: n.text,
+            date: n.date,
+        })
+        .collect();
+
+    emit(&StatsReport { stats, notes }, format)
+}
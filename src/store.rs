@@ -4,6 +4,7 @@
 //! their source and target as well as their associated module.
 
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
 
@@ -12,12 +13,20 @@ use self::db::Store;
 use self::init::{init_user_store, init_system_store};
 
 pub(crate) mod backups;
+pub(crate) mod baselines;
 pub(crate) mod checksums;
 pub(crate) mod db;
 pub(crate) mod errors;
+pub(crate) mod events;
+pub(crate) mod exclusions;
+pub(crate) mod facts;
 pub(crate) mod files;
 pub(crate) mod init;
+pub(crate) mod integrity;
 pub(crate) mod modules;
+pub(crate) mod notes;
+pub(crate) mod profiles;
+pub(crate) mod stats;
 
 #[cfg(test)]
 pub(crate) mod tests;
@@ -28,15 +37,20 @@ pub(crate) struct Stores {
 }
 
 impl Stores {
-    pub(crate) async fn init() -> Result<Self> {
+    /// Initializes the user store and, if system files are deployed, the system store.
+    ///
+    /// # Arguments
+    ///
+    /// * `ephemeral` - Use in-memory copies of the stores, see [db::Store::init]
+    pub(crate) async fn init(ephemeral: bool) -> Result<Self> {
         Ok(Self {
-            user_store: init_user_store(None)
+            user_store: init_user_store(None, ephemeral)
                 .await
                 .map_err(|e| e.into_anyhow())
                 .context("Failed to initialize user store")?,
             system_store: if DEPLOY_SYSTEM_FILES.load(Ordering::Relaxed) {
                 Some(
-                    init_system_store()
+                    init_system_store(ephemeral)
                         .await
                         .map_err(|e| e.into_anyhow())
                         .context("Failed to initialize system store")?,
@@ -46,4 +60,101 @@ impl Stores {
             },
         })
     }
+
+    /// Opens a new run in all stores, see [events].
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command of the run, e.g. "deploy"
+    /// * `modules` - The modules of the run
+    pub(crate) async fn start_run(&self, command: &str, modules: &[String]) -> Result<()> {
+        self.user_store
+            .start_run(command, modules)
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        if let Some(sys_store) = &self.system_store {
+            sys_store
+                .start_run(command, modules)
+                .await
+                .map_err(|e| e.into_anyhow())?;
+        }
+        Ok(())
+    }
+
+    /// Records that modules were deployed as part of a profile in all stores.
+    ///
+    /// # Arguments
+    ///
+    /// * `profile` - The name of the profile
+    /// * `modules` - The modules of the profile
+    pub(crate) async fn add_profile_modules(
+        &self,
+        profile: &str,
+        modules: &[String],
+    ) -> Result<()> {
+        for m in modules.iter() {
+            self.user_store
+                .add_profile_module(profile, m)
+                .await
+                .map_err(|e| e.into_anyhow())?;
+            if let Some(sys_store) = &self.system_store {
+                sys_store
+                    .add_profile_module(profile, m)
+                    .await
+                    .map_err(|e| e.into_anyhow())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the open run in all stores.
+    ///
+    /// # Arguments
+    ///
+    /// * `success` - Whether the run was successful
+    pub(crate) async fn finish_run(&self, success: bool) -> Result<()> {
+        self.user_store
+            .finish_run(success)
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        if let Some(sys_store) = &self.system_store {
+            sys_store
+                .finish_run(success)
+                .await
+                .map_err(|e| e.into_anyhow())?;
+        }
+        Ok(())
+    }
+
+    /// Closes the connection pools of all stores and waits until SQLite has cleaned up the WAL and
+    /// SHM files.
+    pub(crate) async fn close(self: Arc<Self>) -> Result<()> {
+        // Close pools and save the location of stores on disk
+        let mut user_store_path = std::path::PathBuf::new();
+        let mut sys_store_path = std::path::PathBuf::new();
+
+        if !self.user_store.ephemeral {
+            user_store_path.push(self.user_store.path.clone());
+        }
+        self.user_store.close().await.map_err(|e| e.into_anyhow())?;
+        if let Some(sys_store) = &self.system_store {
+            if !sys_store.ephemeral {
+                sys_store_path.push(sys_store.path.clone());
+            }
+            sys_store.close().await.map_err(|e| e.into_anyhow())?;
+        }
+
+        // Drop seems to be the way to make sure the connections get closed
+        drop(self);
+
+        // Wait until SQLite cleans up the WAL and SHM files
+        if !user_store_path.as_os_str().is_empty() {
+            db::close_connection(&user_store_path)?;
+        }
+        if !sys_store_path.as_os_str().is_empty() {
+            db::close_connection(&sys_store_path)?;
+        }
+
+        Ok(())
+    }
 }
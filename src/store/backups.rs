@@ -13,6 +13,7 @@ use crate::store::db;
 use crate::store::errors::SQLiteError;
 use crate::utils::file_fs;
 use crate::utils::file_metadata;
+use crate::utils::file_permissions;
 use crate::utils::sudo;
 
 /// Representation of a store backup entry (row) in the database.
@@ -76,7 +77,7 @@ impl db::Store {
             file_type: "link".to_string(),
             content: None,
             link_source: Some(link_source),
-            owner: format!("{}:{}", user_id, group_id),
+            owner: file_permissions::format_owner(user_id, group_id),
             permissions: None,
             checksum: None,
             date: chrono::offset::Local::now(),
@@ -105,7 +106,7 @@ impl db::Store {
             file_type: "regular".to_string(),
             content: Some(content),
             link_source: None,
-            owner: format!("{}:{}", user_id, group_id),
+            owner: file_permissions::format_owner(user_id, group_id),
             permissions: Some(permissions),
             checksum: Some(checksum),
             date: chrono::offset::Local::now(),
@@ -134,7 +135,7 @@ impl db::Store {
     ) -> Result<Vec<u8>, SQLiteError> {
         match fs::read(&file_path).await {
             Ok(c) => Ok(c),
-            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(e) if file_fs::retry_with_sudo(&e, &file_path) => {
                 self.read_file_with_elevated_permissions(file_path).await
             }
             Err(e) => Err(e).with_context(|| format!("Failed to read {:?}", file_path.as_ref()))?,
@@ -289,6 +290,108 @@ impl db::Store {
         Ok(result)
     }
 
+    /// Retrieves all backups whose path is no longer tracked in the files table.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<(String, chrono::DateTime<chrono::Local>)>)` containing path and date of each
+    ///   stale backup, ordered from newest to oldest.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_stale_backups(
+        &self,
+    ) -> Result<Vec<(String, chrono::DateTime<chrono::Local>)>, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(
+            move |conn| -> Result<Vec<(String, chrono::DateTime<chrono::Local>)>, SQLiteError> {
+                db::prepare_connection(conn)?;
+                let mut stmt = conn.prepare(
+                    "SELECT path, date FROM backups
+                     WHERE path NOT IN (SELECT destination FROM files)
+                     ORDER BY date DESC",
+                )?;
+
+                let rows: Vec<
+                    Result<(String, chrono::DateTime<chrono::Local>), deadpool_sqlite::rusqlite::Error>,
+                > = stmt
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect();
+
+                // Process the query results, handling any errors
+                let mut backups = Vec::with_capacity(rows.len());
+                for row in rows {
+                    match row {
+                        Ok(backup) => backups.push(backup),
+                        Err(e) => eprintln!("Error processing backup row: {:?}", e),
+                    }
+                }
+                Ok(backups)
+            },
+        )
+        .await?
+    }
+
+    /// Retrieves a single backup from the store database.
+    ///
+    /// # Arguments
+    /// * `file_path` - The original path of the backed-up file.
+    ///
+    /// # Returns
+    /// * `Ok(StoreBackup)` containing the backup entry.
+    /// * `Err(SQLiteError)` if the backup does not exist or there's an error during the database
+    ///   operation.
+    pub(crate) async fn get_backup<P: AsRef<Path>>(
+        &self,
+        file_path: P,
+    ) -> Result<StoreBackup, SQLiteError> {
+        let file_path_str = file_fs::path_to_string(&file_path)?;
+
+        let conn = &self.get_con().await?;
+
+        self.fetch_backup_from_db(file_path_str, conn).await
+    }
+
+    /// Retrieves all backups from the store database.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<StoreBackup>)` containing all backup entries, ordered by path.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_all_backups(&self) -> Result<Vec<StoreBackup>, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<Vec<StoreBackup>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let mut stmt = conn.prepare(
+                "SELECT path, file_type, content, link_source, owner, permissions, checksum, date FROM backups ORDER BY path"
+            )?;
+
+            let rows: Vec<Result<StoreBackup, deadpool_sqlite::rusqlite::Error>> = stmt
+                .query_map([], |row| {
+                    Ok(StoreBackup {
+                        path: row.get(0)?,
+                        file_type: row.get(1)?,
+                        content: row.get(2)?,
+                        link_source: row.get(3)?,
+                        owner: row.get(4)?,
+                        permissions: row.get(5)?,
+                        checksum: row.get(6)?,
+                        date: row.get(7)?,
+                    })
+                })?
+                .collect();
+
+            // Process the query results, handling any errors
+            let mut backups = Vec::with_capacity(rows.len());
+            for row in rows {
+                match row {
+                    Ok(backup) => backups.push(backup),
+                    Err(e) => eprintln!("Error processing backup row: {:?}", e),
+                }
+            }
+            Ok(backups)
+        })
+        .await?
+    }
+
     /// Restores a backup from the store database to a specified location.
     ///
     /// # Arguments
@@ -355,7 +458,7 @@ impl db::Store {
     ) -> Result<(), SQLiteError> {
         match fs::symlink(backup.link_source.as_ref().unwrap(), &to).await {
             Ok(_) => (),
-            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(e) if file_fs::retry_with_sudo(&e, &to) => {
                 sudo::sudo_exec(
                     "ln",
                     &[
@@ -373,10 +476,9 @@ impl db::Store {
             }
         }
 
-        let owner: Vec<&str> = backup.owner.split(':').collect();
         let permissions: Option<u32> = backup.permissions;
 
-        self.set_file_metadata(to.as_ref(), owner, permissions, true).await?;
+        self.set_file_metadata(to.as_ref(), &backup.owner, permissions, true).await?;
         Ok(())
     }
 
@@ -391,10 +493,9 @@ impl db::Store {
         self.write_backup_content(file, &backup.content.clone().unwrap(), &write_dest)
             .await?;
 
-        let owner: Vec<&str> = backup.owner.split(':').collect();
         let permissions: Option<u32> = backup.permissions;
 
-        self.set_file_metadata(&write_dest, owner, permissions, false)
+        self.set_file_metadata(&write_dest, &backup.owner, permissions, false)
             .await?;
 
         if write_dest != to.as_ref() {
@@ -411,9 +512,16 @@ impl db::Store {
     ) -> Result<(PathBuf, fs::File), SQLiteError> {
         let temp_file = tempfile::NamedTempFile::new().map_err(|e| SQLiteError::Other(e.into()))?;
 
-        match fs::File::create(&to).await {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(crate::target_user::open_flags())
+            .open(&to)
+            .await
+        {
             Ok(f) => Ok((to.as_ref().to_path_buf(), f)),
-            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(e) if file_fs::retry_with_sudo(&e, &to) => {
                 let temp_path = temp_file.path().to_path_buf();
                 let file = fs::File::create(&temp_path)
                     .await
@@ -449,23 +557,16 @@ impl db::Store {
     async fn set_file_metadata(
         &self,
         path: &Path,
-        owner: Vec<&str>,
+        owner: &str,
         permissions: Option<u32>,
         is_symlink: bool,
     ) -> Result<(), SQLiteError> {
+        let (uid, gid) = file_permissions::parse_owner(owner)?;
         file_metadata::set_file_metadata(
             path,
             file_metadata::FileMetadata {
-                uid: Some(
-                    owner[0]
-                        .parse::<u32>()
-                        .map_err(|e| SQLiteError::Other(e.into()))?,
-                ),
-                gid: Some(
-                    owner[1]
-                        .parse::<u32>()
-                        .map_err(|e| SQLiteError::Other(e.into()))?,
-                ),
+                uid: Some(uid),
+                gid: Some(gid),
                 permissions,
                 is_symlink,
                 symlink_source: None,
@@ -544,4 +645,87 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_get_stale_backups() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+
+        let temp_path = tempdir()?;
+        fs::write(temp_path.path().join("foo.txt"), b"Hello World!").await?;
+        store
+            .add_backup(&temp_path.path().join("foo.txt"))
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        // Backup of an untracked file
+        let result = store
+            .get_stale_backups()
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(
+            result[0].0,
+            temp_path.path().join("foo.txt").display().to_string()
+        );
+
+        // Backups of tracked files are not stale
+        store
+            .add_file(crate::store::files::StoreFile {
+                module: "test".to_string(),
+                source: None,
+                source_checksum: None,
+                destination: temp_path.path().join("foo.txt").display().to_string(),
+                destination_checksum: None,
+                operation: "create".to_string(),
+                user: None,
+                date: chrono::offset::Local::now(),
+            })
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        let result = store
+            .get_stale_backups()
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert!(result.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_backups() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+
+        let temp_path = tempdir()?;
+        fs::write(temp_path.path().join("foo.txt"), b"Hello World!").await?;
+        fs::write(temp_path.path().join("bar.txt"), b"Hello").await?;
+        for f in ["foo.txt", "bar.txt"] {
+            store
+                .add_backup(&temp_path.path().join(f))
+                .await
+                .map_err(|e| e.into_anyhow())?;
+        }
+
+        let result = store.get_all_backups().await.map_err(|e| e.into_anyhow())?;
+        assert_eq!(result.len(), 2);
+        // Ordered by path
+        assert_eq!(
+            result[0].path,
+            temp_path.path().join("bar.txt").display().to_string()
+        );
+
+        let result = store
+            .get_backup(&temp_path.path().join("foo.txt"))
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(result.file_type, "regular");
+        assert_eq!(result.content, Some(b"Hello World!".to_vec()));
+
+        // Missing backup
+        assert!(store
+            .get_backup(&temp_path.path().join("baz.txt"))
+            .await
+            .is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,162 @@
+//! This module provides functionality for managing deployment baselines in the dotdeploy store
+//! database.
+//!
+//! A baseline is the content dotdeploy last wrote to a copied or created file. It serves as the
+//! common ancestor when merging local modifications of a deployed file with its new content.
+
+use std::path::Path;
+
+use deadpool_sqlite::rusqlite::params;
+
+use crate::store::db;
+use crate::store::errors::SQLiteError;
+use crate::utils::file_fs;
+
+impl db::Store {
+    /// Adds or updates the baseline of a deployed file.
+    ///
+    /// # Arguments
+    /// * `path` - The destination path of the deployed file.
+    /// * `content` - The content dotdeploy wrote to the file.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the operation is successful.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn add_baseline<P: AsRef<Path>>(
+        &self,
+        path: P,
+        content: Vec<u8>,
+    ) -> Result<(), SQLiteError> {
+        let path = file_fs::path_to_string(path)?;
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<(), SQLiteError> {
+            db::prepare_connection(conn)?;
+            conn.execute(
+                "INSERT INTO baselines (path, content, date)
+                 VALUES ($1, $2, $3)
+                 ON CONFLICT(path)
+                 DO UPDATE SET
+                   content = excluded.content,
+                   date = excluded.date",
+                params![path, content, chrono::offset::Local::now()],
+            )?;
+            Ok(())
+        })
+        .await??;
+        Ok(())
+    }
+
+    /// Retrieves the baseline of a deployed file.
+    ///
+    /// # Arguments
+    /// * `path` - The destination path of the deployed file.
+    ///
+    /// # Returns
+    /// * `Ok(Some(Vec<u8>))` containing the baseline content if found.
+    /// * `Ok(None)` if no baseline is stored for the file.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_baseline<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Option<Vec<u8>>, SQLiteError> {
+        let path = file_fs::path_to_string(path)?;
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<Option<Vec<u8>>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            match conn.query_row(
+                "SELECT content FROM baselines WHERE path = $1",
+                params![path],
+                |row| row.get(0),
+            ) {
+                Ok(content) => Ok(Some(content)),
+                Err(deadpool_sqlite::rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await?
+    }
+
+    /// Removes the baseline of a deployed file, if any.
+    ///
+    /// # Arguments
+    /// * `path` - The destination path of the deployed file.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the operation is successful.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn remove_baseline<P: AsRef<Path>>(&self, path: P) -> Result<(), SQLiteError> {
+        let path = file_fs::path_to_string(path)?;
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<(), SQLiteError> {
+            db::prepare_connection(conn)?;
+            conn.execute("DELETE FROM baselines WHERE path = $1", params![path])?;
+            Ok(())
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::store::tests::store_setup_helper;
+
+    #[tokio::test]
+    async fn test_baselines() -> Result<()> {
+        let store = store_setup_helper("copy").await?;
+
+        assert!(store
+            .get_baseline("/home/foo0.txt")
+            .await
+            .map_err(|e| e.into_anyhow())?
+            .is_none());
+
+        store
+            .add_baseline("/home/foo0.txt", b"first".to_vec())
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_baseline("/home/foo0.txt", b"second".to_vec())
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(
+            store
+                .get_baseline("/home/foo0.txt")
+                .await
+                .map_err(|e| e.into_anyhow())?,
+            Some(b"second".to_vec())
+        );
+
+        store
+            .remove_baseline("/home/foo0.txt")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert!(store
+            .get_baseline("/home/foo0.txt")
+            .await
+            .map_err(|e| e.into_anyhow())?
+            .is_none());
+        store
+            .add_baseline("/home/foo0.txt", b"second".to_vec())
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        // Removing the file entry also removes its baseline
+        store
+            .remove_file("/home/foo0.txt")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert!(store
+            .get_baseline("/home/foo0.txt")
+            .await
+            .map_err(|e| e.into_anyhow())?
+            .is_none());
+
+        Ok(())
+    }
+}
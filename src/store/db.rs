@@ -2,10 +2,15 @@
 //! the dotdeploy application's store.
 
 use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicI64;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use anyhow::{anyhow, bail, Context, Result};
-use deadpool_sqlite::{Config, Runtime};
+use deadpool_sqlite::{Config, PoolConfig, Runtime};
+use lazy_static::lazy_static;
 
+use crate::config::StoreSettings;
 use crate::store::errors::SQLiteError;
 use crate::utils::file_fs;
 use crate::utils::sudo;
@@ -19,13 +24,28 @@ pub(crate) struct Store {
     pub(crate) path: PathBuf,
     /// Indicates whether this is a system-wide store (true) or user-specific store (false)
     system: bool,
+    /// Indicates whether the store is an in-memory copy, which is discarded once closed
+    pub(crate) ephemeral: bool,
+    /// ID of the run opened in this store, 0 while no run is open
+    pub(crate) run: Arc<AtomicI64>,
+}
+
+lazy_static! {
+    /// Connection and maintenance settings of the stores, set with `configure`.
+    static ref SETTINGS: RwLock<StoreSettings> = RwLock::new(StoreSettings::default());
+}
+
+/// Sets the connection and maintenance settings of stores initialized afterwards.
+pub(crate) fn configure(settings: StoreSettings) {
+    *SETTINGS.write().unwrap() = settings;
 }
 
 /// Runs maintenance and closes the connection gracefully, cleaning up temporary WAL and SHM files.
 ///
 /// This function attempts to clean up the Write-Ahead Logging (WAL) files that SQLite creates
 /// during normal operation. It runs in a loop, checking for and cleaning up these files until they
-/// no longer exist.
+/// no longer exist. The database is vacuumed unless disabled in the settings, otherwise only the
+/// WAL is checkpointed.
 ///
 /// # Arguments
 /// * `path` - A reference to the path of the SQLite database file.
@@ -62,9 +82,14 @@ pub(crate) fn close_connection<P: AsRef<Path>>(path: P) -> Result<()> {
             )
             .context("Failed to run PRAGMA synchronous=NORMAL")?;
 
-            // Run VACUUM to optimize the database
-            conn.execute_batch("VACUUM;;")
-                .context("Failed to run VACUUM;")?;
+            if SETTINGS.read().unwrap().vacuum {
+                // Run VACUUM to optimize the database
+                conn.execute_batch("VACUUM;;")
+                    .context("Failed to run VACUUM;")?;
+            } else {
+                conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")
+                    .context("Failed to run PRAGMA wal_checkpoint(TRUNCATE)")?;
+            }
 
             // Close the connection and wait a bit before checking again
             drop(conn);
@@ -77,7 +102,8 @@ pub(crate) fn close_connection<P: AsRef<Path>>(path: P) -> Result<()> {
 /// Prepares a SQLite connection with optimal settings.
 ///
 /// This function sets the journal mode to WAL (Write-Ahead Logging) and the synchronous mode to
-/// NORMAL, which can improve performance in many scenarios.
+/// NORMAL, which can improve performance in many scenarios. The busy timeout and the WAL
+/// autocheckpoint interval are taken from the settings, see `configure`.
 ///
 /// # Arguments
 /// * `connection` - A mutable reference to the SQLite connection to be prepared.
@@ -102,6 +128,21 @@ pub(crate) fn prepare_connection(
             "NORMAL",
         )
         .context("Failed to run PRAGMA synchronous=NORMAL")?;
+
+    let (busy_timeout, wal_autocheckpoint) = {
+        let settings = SETTINGS.read().unwrap();
+        (settings.busy_timeout, settings.wal_autocheckpoint)
+    };
+    connection
+        .busy_timeout(Duration::from_millis(busy_timeout))
+        .context("Failed to set busy timeout")?;
+    connection
+        .pragma_update(
+            Some(deadpool_sqlite::rusqlite::DatabaseName::Main),
+            "wal_autocheckpoint",
+            wal_autocheckpoint,
+        )
+        .context("Failed to run PRAGMA wal_autocheckpoint")?;
     Ok(())
 }
 
@@ -112,14 +153,17 @@ impl Store {
     /// * `path` - The path where the store database will be created.
     /// * `system` - A boolean indicating whether this is a system-wide store (true) or
     ///   user-specific store (false).
+    /// * `ephemeral` - A boolean indicating whether to use an in-memory copy of the store.
     ///
     /// # Returns
-    /// A new `Store` instance with the specified path and system flag.
-    pub(crate) fn new(path: PathBuf, system: bool) -> Self {
+    /// A new `Store` instance with the specified path and flags.
+    pub(crate) fn new(path: PathBuf, system: bool, ephemeral: bool) -> Self {
         Store {
             pool: None,
             path,
             system,
+            ephemeral,
+            run: Arc::new(AtomicI64::new(0)),
         }
     }
 
@@ -153,16 +197,24 @@ impl Store {
                     .await
                     .with_context(|| format!("Failed to create directory {:?}", &self.path))?;
 
-                // Set permissions to allow all users to write to the directory
-                sudo::sudo_exec(
-                    "chmod",
-                    &["777", file_fs::path_to_string(&self.path)?.as_str()],
-                    Some("Adjusting permissions of system store DB directory"),
+                // Set permissions to allow all users to write to the directory. Directories below
+                // a target root may belong to the user already.
+                if std::fs::set_permissions(
+                    &self.path,
+                    std::os::unix::fs::PermissionsExt::from_mode(0o777),
                 )
-                .await
-                .with_context(|| {
-                    format!("Failed to change permissions of directory {:?}", &self.path)
-                })?;
+                .is_err()
+                {
+                    sudo::sudo_exec(
+                        "chmod",
+                        &["777", file_fs::path_to_string(&self.path)?.as_str()],
+                        Some("Adjusting permissions of system store DB directory"),
+                    )
+                    .await
+                    .with_context(|| {
+                        format!("Failed to change permissions of directory {:?}", &self.path)
+                    })?;
+                }
 
                 Ok(())
             }
@@ -179,6 +231,10 @@ impl Store {
 
     /// Creates the directory for a user-specific store.
     async fn create_user_dir(&self) -> Result<()> {
+        // The store of another user is never opened through a symbolic link
+        for file in ["store.sqlite", "store.sqlite-wal", "store.sqlite-shm"] {
+            crate::target_user::check_path(&self.path.join(file))?;
+        }
         match self.path.try_exists() {
             Ok(false) => {
                 debug!(
@@ -206,18 +262,35 @@ impl Store {
     /// This method creates the necessary directory, initializes the SQLite database, creates the
     /// required tables, and sets up the connection pool.
     ///
+    /// Ephemeral stores use an in-memory database instead, which starts as a copy of the database
+    /// on disk, if any. Nothing is written to the store directory.
+    ///
     /// # Returns
     /// * `Ok(())` if the initialization is successful.
     /// * `Err(SQLiteError)` if an error occurs during initialization.
     pub(crate) async fn init(&mut self) -> Result<(), SQLiteError> {
         // Create the directory if it doesn't exist
-        self.create_dir().await.map_err(SQLiteError::Other)?;
+        if !self.ephemeral {
+            self.create_dir().await.map_err(SQLiteError::Other)?;
+        }
 
         // Set the full path for the SQLite database file
         self.path = self.path.join("store.sqlite");
 
         // Create the connection pool
-        let pool = Config::new(&self.path)
+        let config = if self.ephemeral {
+            // Every connection to ":memory:" opens a database of its own, thus use only one
+            let mut config = Config::new(":memory:");
+            config.pool = Some(PoolConfig::new(1));
+            config
+        } else {
+            let mut config = Config::new(&self.path);
+            if let Some(size) = SETTINGS.read().unwrap().pool_size {
+                config.pool = Some(PoolConfig::new(size));
+            }
+            config
+        };
+        let pool = config
             .create_pool(Runtime::Tokio1)
             .with_context(|| {
                 format!("Failed to create pool for store database {:?}", &self.path)
@@ -228,6 +301,10 @@ impl Store {
             .await
             .with_context(|| format!("Failed to connect to store database {:?}", &self.path))?;
 
+        if self.ephemeral {
+            self.load_copy(&conn).await?;
+        }
+
         // Initialize the database with optimal settings
         conn.interact(move |conn| -> Result<(), SQLiteError> {
             prepare_connection(conn)?;
@@ -243,6 +320,42 @@ impl Store {
         Ok(())
     }
 
+    /// Copies the database on disk into the in-memory database of an ephemeral store.
+    ///
+    /// The database and its WAL are copied to a temporary directory first, so the store on disk is
+    /// only read. A missing database leaves the in-memory database empty.
+    async fn load_copy(&self, conn: &deadpool_sqlite::Object) -> Result<(), SQLiteError> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+
+        let temp_dir = tempfile::tempdir().context("Failed to create temporary directory")?;
+        let copy = temp_dir.path().join("store.sqlite");
+        for suffix in ["", "-wal"] {
+            let mut from = self.path.clone().into_os_string();
+            from.push(suffix);
+            let mut to = copy.clone().into_os_string();
+            to.push(suffix);
+            if Path::new(&from).exists() {
+                std::fs::copy(&from, &to)
+                    .with_context(|| format!("Failed to copy store database {:?}", from))?;
+            }
+        }
+
+        conn.interact(move |conn| -> Result<(), SQLiteError> {
+            let src = deadpool_sqlite::rusqlite::Connection::open(&copy)
+                .with_context(|| format!("Failed to open copy of store database {:?}", copy))?;
+            deadpool_sqlite::rusqlite::backup::Backup::new(&src, conn)
+                .context("Failed to start copying the store database into memory")?
+                .run_to_completion(100, Duration::ZERO, None)
+                .context("Failed to copy the store database into memory")?;
+            Ok(())
+        })
+        .await??;
+
+        Ok(())
+    }
+
     /// Creates the necessary tables in the SQLite database.
     async fn create_tables(&self, conn: &deadpool_sqlite::Object) -> Result<(), SQLiteError> {
         // Create MODULES table
@@ -311,6 +424,152 @@ impl Store {
         })
         .await??;
 
+        // Create MODULE_STATS table
+        conn.interact(|conn| -> Result<(), SQLiteError> {
+            prepare_connection(conn)?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS module_stats (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               module TEXT NOT NULL,
+               files INTEGER NOT NULL,
+               bytes INTEGER NOT NULL,
+               duration_ms INTEGER NOT NULL,
+               status TEXT NOT NULL,
+               date TEXT NOT NULL
+             );",
+                [],
+            )
+            .context("Failed to create MODULE_STATS table")?;
+            Ok(())
+        })
+        .await??;
+
+        // Create BASELINES table
+        conn.interact(|conn| -> Result<(), SQLiteError> {
+            prepare_connection(conn)?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS baselines (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               path TEXT NOT NULL UNIQUE,
+               content BLOB NOT NULL,
+               date TEXT NOT NULL
+             );",
+                [],
+            )
+            .context("Failed to create BASELINES table")?;
+            Ok(())
+        })
+        .await??;
+
+        // Create NOTES table
+        conn.interact(|conn| -> Result<(), SQLiteError> {
+            prepare_connection(conn)?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS notes (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               target TEXT NOT NULL,
+               text TEXT NOT NULL,
+               date TEXT NOT NULL
+             );",
+                [],
+            )
+            .context("Failed to create NOTES table")?;
+            Ok(())
+        })
+        .await??;
+
+        // Create EXCLUSIONS table
+        conn.interact(|conn| -> Result<(), SQLiteError> {
+            prepare_connection(conn)?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS exclusions (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               path TEXT NOT NULL UNIQUE,
+               date TEXT NOT NULL
+             );",
+                [],
+            )
+            .context("Failed to create EXCLUSIONS table")?;
+            Ok(())
+        })
+        .await??;
+
+        // Create PROFILES table
+        conn.interact(|conn| -> Result<(), SQLiteError> {
+            prepare_connection(conn)?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS profiles (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               name TEXT NOT NULL,
+               module TEXT NOT NULL,
+               date TEXT NOT NULL,
+               UNIQUE (name, module)
+             );",
+                [],
+            )
+            .context("Failed to create PROFILES table")?;
+            Ok(())
+        })
+        .await??;
+
+        // Create RUNS table
+        conn.interact(|conn| -> Result<(), SQLiteError> {
+            prepare_connection(conn)?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS runs (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               command TEXT NOT NULL,
+               modules TEXT NOT NULL,
+               start TEXT NOT NULL,
+               finished TEXT,
+               result TEXT
+             );",
+                [],
+            )
+            .context("Failed to create RUNS table")?;
+            Ok(())
+        })
+        .await??;
+
+        // Create EVENTS table
+        conn.interact(|conn| -> Result<(), SQLiteError> {
+            prepare_connection(conn)?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS events (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               run_id INTEGER NOT NULL,
+               module TEXT NOT NULL,
+               path TEXT NOT NULL,
+               action TEXT NOT NULL,
+               date TEXT NOT NULL,
+               FOREIGN KEY (run_id) REFERENCES runs(id)
+               ON DELETE CASCADE ON UPDATE CASCADE
+             );",
+                [],
+            )
+            .context("Failed to create EVENTS table")?;
+            Ok(())
+        })
+        .await??;
+
+        // Create FACTS table
+        conn.interact(|conn| -> Result<(), SQLiteError> {
+            prepare_connection(conn)?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS facts (
+               id INTEGER PRIMARY KEY AUTOINCREMENT,
+               name TEXT NOT NULL UNIQUE,
+               checksum TEXT NOT NULL,
+               value TEXT NOT NULL,
+               date TEXT NOT NULL
+             );",
+                [],
+            )
+            .context("Failed to create FACTS table")?;
+            Ok(())
+        })
+        .await??;
+
         Ok(())
     }
 
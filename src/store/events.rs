@@ -0,0 +1,428 @@
+//! This module provides functionality for managing the audit log in the dotdeploy store database.
+//!
+//! Every run changing the deployment (deploy, remove, adopt and add) is recorded in the runs table
+//! with its command, modules, start and end time and result. While a run is open, each change of a
+//! managed file is recorded in the events table, which allows to find out when a file last changed
+//! and by which run. Only the last [MAX_RUNS] runs and their events are kept.
+
+use std::sync::atomic::Ordering;
+
+use deadpool_sqlite::rusqlite::{params, Connection};
+
+use crate::store::db;
+use crate::store::errors::SQLiteError;
+
+/// Number of runs kept in the audit log. Older runs are deleted together with their events.
+pub(crate) const MAX_RUNS: i64 = 1000;
+
+/// Representation of a run entry (row) in the database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StoreRun {
+    /// Unique ID of the run
+    pub(crate) id: i64,
+    /// The command of the run, e.g. "deploy"
+    pub(crate) command: String,
+    /// The modules of the run, comma separated
+    pub(crate) modules: String,
+    /// The date and time when the run started
+    pub(crate) start: chrono::DateTime<chrono::Local>,
+    /// The date and time when the run finished (optional)
+    pub(crate) end: Option<chrono::DateTime<chrono::Local>>,
+    /// Result of the run ("success", "failed" or "aborted"), unset while the run is open
+    pub(crate) result: Option<String>,
+}
+
+/// Representation of a file event entry (row) in the database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StoreEvent {
+    /// The run which changed the file
+    pub(crate) run: StoreRun,
+    /// The module the file belongs to
+    pub(crate) module: String,
+    /// The destination path of the file
+    pub(crate) path: String,
+    /// The performed action, the operation of the file or "remove"
+    pub(crate) action: String,
+    /// The date and time of the change
+    pub(crate) date: chrono::DateTime<chrono::Local>,
+}
+
+/// Records a change of a file in a run.
+///
+/// # Arguments
+/// * `conn` - An open connection to the store database.
+/// * `run` - The ID of the run, nothing is recorded for 0.
+/// * `module` - The module the file belongs to.
+/// * `path` - The destination path of the file.
+/// * `action` - The performed action.
+pub(crate) fn record_event(
+    conn: &Connection,
+    run: i64,
+    module: &str,
+    path: &str,
+    action: &str,
+) -> Result<(), SQLiteError> {
+    if run == 0 {
+        return Ok(());
+    }
+    conn.execute(
+        "INSERT INTO events (run_id, module, path, action, date) VALUES ($1, $2, $3, $4, $5)",
+        params![run, module, path, action, chrono::offset::Local::now()],
+    )?;
+    Ok(())
+}
+
+/// Records the removal of all files of a module in a run.
+///
+/// # Arguments
+/// * `conn` - An open connection to the store database.
+/// * `run` - The ID of the run, nothing is recorded for 0.
+/// * `module` - The name of the module.
+pub(crate) fn record_module_removal(
+    conn: &Connection,
+    run: i64,
+    module: &str,
+) -> Result<(), SQLiteError> {
+    if run == 0 {
+        return Ok(());
+    }
+    conn.execute(
+        "INSERT INTO events (run_id, module, path, action, date)
+         SELECT $1, modules.name, files.destination, 'remove', $2
+         FROM files
+         INNER JOIN modules ON files.module_id = modules.id
+         WHERE modules.name = $3",
+        params![run, chrono::offset::Local::now(), module],
+    )?;
+    Ok(())
+}
+
+/// Deletes all but the last `keep` runs up to `latest` and their events.
+fn prune_runs(conn: &Connection, latest: i64, keep: i64) -> Result<(), SQLiteError> {
+    conn.execute(
+        "DELETE FROM events WHERE run_id <= $1",
+        params![latest - keep],
+    )?;
+    conn.execute("DELETE FROM runs WHERE id <= $1", params![latest - keep])?;
+    Ok(())
+}
+
+/// Maps a row starting with the columns of the runs table to a `StoreRun`.
+fn map_run_row(
+    row: &deadpool_sqlite::rusqlite::Row,
+) -> Result<StoreRun, deadpool_sqlite::rusqlite::Error> {
+    Ok(StoreRun {
+        id: row.get(0)?,
+        command: row.get(1)?,
+        modules: row.get(2)?,
+        start: row.get(3)?,
+        end: row.get(4)?,
+        result: row.get(5)?,
+    })
+}
+
+impl db::Store {
+    /// Returns the ID of the run opened in this store, 0 while no run is open.
+    pub(crate) fn current_run(&self) -> i64 {
+        self.run.load(Ordering::Relaxed)
+    }
+
+    /// Opens a new run. Runs left open by an earlier, interrupted run are closed as "aborted".
+    ///
+    /// Changes made through this store are recorded in the new run until [db::Store::finish_run]
+    /// is called.
+    ///
+    /// # Arguments
+    /// * `command` - The command of the run.
+    /// * `modules` - The modules of the run.
+    ///
+    /// # Returns
+    /// * `Ok(i64)` containing the ID of the new run.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn start_run(
+        &self,
+        command: &str,
+        modules: &[String],
+    ) -> Result<i64, SQLiteError> {
+        let command = command.to_owned();
+        let modules = modules.join(", ");
+        let conn = &self.get_con().await?;
+        let id = conn.interact(move |conn| -> Result<i64, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let tx = conn.transaction()?;
+            tx.execute(
+                "UPDATE runs SET result = 'aborted' WHERE result IS NULL",
+                params![],
+            )?;
+            tx.execute(
+                "INSERT INTO runs (command, modules, start) VALUES ($1, $2, $3)",
+                params![command, modules, chrono::offset::Local::now()],
+            )?;
+            let id = tx.last_insert_rowid();
+            prune_runs(&tx, id, MAX_RUNS)?;
+            tx.commit()?;
+            Ok(id)
+        })
+        .await??;
+        self.run.store(id, Ordering::Relaxed);
+        Ok(id)
+    }
+
+    /// Closes the run opened in this store, if any.
+    ///
+    /// # Arguments
+    /// * `success` - Whether the run was successful.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the operation is successful.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn finish_run(&self, success: bool) -> Result<(), SQLiteError> {
+        let run = self.run.swap(0, Ordering::Relaxed);
+        if run == 0 {
+            return Ok(());
+        }
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<(), SQLiteError> {
+            db::prepare_connection(conn)?;
+            conn.execute(
+                "UPDATE runs SET finished = $1, result = $2 WHERE id = $3",
+                params![
+                    chrono::offset::Local::now(),
+                    if success { "success" } else { "failed" },
+                    run
+                ],
+            )?;
+            Ok(())
+        })
+        .await??;
+        Ok(())
+    }
+
+    /// Retrieves the most recent runs.
+    ///
+    /// # Arguments
+    /// * `limit` - The maximum number of runs to retrieve.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<StoreRun>)` containing the runs, newest first.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_runs(&self, limit: usize) -> Result<Vec<StoreRun>, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<Vec<StoreRun>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let mut stmt = conn.prepare(
+                "SELECT id, command, modules, start, finished, result FROM runs
+                 ORDER BY id DESC
+                 LIMIT $1",
+            )?;
+
+            let rows: Vec<Result<StoreRun, deadpool_sqlite::rusqlite::Error>> = stmt
+                .query_map(params![limit as i64], map_run_row)?
+                .collect();
+
+            // Process the query results, handling any errors
+            let mut runs = Vec::with_capacity(rows.len());
+            for row in rows {
+                match row {
+                    Ok(r) => runs.push(r),
+                    Err(e) => eprintln!("Error processing run row: {:?}", e),
+                }
+            }
+            Ok(runs)
+        })
+        .await?
+    }
+
+    /// Retrieves the recorded changes of files.
+    ///
+    /// # Arguments
+    /// * `path` - Only retrieve changes of this destination path.
+    /// * `module` - Only retrieve changes of files of this module.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<StoreEvent>)` containing the changes, newest first.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_events(
+        &self,
+        path: Option<String>,
+        module: Option<String>,
+    ) -> Result<Vec<StoreEvent>, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<Vec<StoreEvent>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let mut stmt = conn.prepare(
+                "SELECT runs.id, runs.command, runs.modules, runs.start, runs.finished, runs.result,
+                        events.module, events.path, events.action, events.date
+                 FROM events
+                 INNER JOIN runs ON events.run_id = runs.id
+                 WHERE ($1 IS NULL OR events.path = $1) AND ($2 IS NULL OR events.module = $2)
+                 ORDER BY events.id DESC",
+            )?;
+
+            let rows: Vec<Result<StoreEvent, deadpool_sqlite::rusqlite::Error>> = stmt
+                .query_map(params![path, module], |row| {
+                    Ok(StoreEvent {
+                        run: map_run_row(row)?,
+                        module: row.get(6)?,
+                        path: row.get(7)?,
+                        action: row.get(8)?,
+                        date: row.get(9)?,
+                    })
+                })?
+                .collect();
+
+            // Process the query results, handling any errors
+            let mut events = Vec::with_capacity(rows.len());
+            for row in rows {
+                match row {
+                    Ok(e) => events.push(e),
+                    Err(e) => eprintln!("Error processing event row: {:?}", e),
+                }
+            }
+            Ok(events)
+        })
+        .await?
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    use crate::store::files::StoreFile;
+    use crate::store::tests::store_setup_helper;
+
+    #[tokio::test]
+    async fn test_events() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+        let file = |checksum: &str| StoreFile {
+            module: "test".to_string(),
+            source: Some("/dotfiles/foo0.txt".to_string()),
+            source_checksum: Some("source_checksum0".to_string()),
+            destination: "/home/foo0.txt".to_string(),
+            destination_checksum: Some(checksum.to_string()),
+            operation: "link".to_string(),
+            user: Some("user".to_string()),
+            date: chrono::offset::Local::now(),
+        };
+
+        // Files added by the helper were not part of a run
+        let events = store
+            .get_events(None, None)
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert!(events.is_empty());
+
+        // An unchanged file is not recorded
+        store
+            .start_run("deploy", &["test".to_string()])
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_file(file("dest_checksum0"))
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_file(file("changed"))
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store.finish_run(true).await.map_err(|e| e.into_anyhow())?;
+
+        // An interrupted run is aborted by the next one
+        store
+            .start_run("remove", &["test".to_string()])
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .remove_file("/home/foo0.txt")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .start_run("remove", &["test".to_string()])
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .remove_module("test")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store.finish_run(false).await.map_err(|e| e.into_anyhow())?;
+
+        let runs = store.get_runs(10).await.map_err(|e| e.into_anyhow())?;
+        assert_eq!(runs.len(), 3);
+        assert_eq!(runs[0].result.as_deref(), Some("failed"));
+        assert_eq!(runs[1].result.as_deref(), Some("aborted"));
+        assert!(runs[1].end.is_none());
+        assert_eq!(runs[2].command, "deploy");
+        assert_eq!(runs[2].result.as_deref(), Some("success"));
+
+        let events = store
+            .get_events(Some("/home/foo0.txt".to_string()), None)
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].action, "remove");
+        assert_eq!(events[0].run.id, runs[1].id);
+        assert_eq!(events[1].action, "link");
+        assert_eq!(events[1].run.id, runs[2].id);
+
+        // Removing the module records its remaining files
+        let events = store
+            .get_events(None, Some("test".to_string()))
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(events.len(), 6);
+        assert!(events[..4].iter().all(|e| e.run.id == runs[0].id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prune_runs() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+        let mut ids = vec![];
+        for i in 0..3 {
+            ids.push(
+                store
+                    .start_run("deploy", &["test".to_string()])
+                    .await
+                    .map_err(|e| e.into_anyhow())?,
+            );
+            store
+                .remove_file(format!("/home/foo{}.txt", i))
+                .await
+                .map_err(|e| e.into_anyhow())?;
+            store.finish_run(true).await.map_err(|e| e.into_anyhow())?;
+        }
+        // Without an open run, nothing is recorded
+        store
+            .remove_file("/home/foo4.txt")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        let conn = store.get_con().await.map_err(|e| e.into_anyhow())?;
+        let latest = ids[2];
+        conn.interact(move |conn| prune_runs(conn, latest, 2))
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| e.into_anyhow())?;
+
+        let runs = store.get_runs(10).await.map_err(|e| e.into_anyhow())?;
+        assert_eq!(runs.iter().map(|r| r.id).collect::<Vec<_>>(), vec![ids[2], ids[1]]);
+        let events = store
+            .get_events(None, None)
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].run.id, ids[2]);
+        assert_eq!(events[1].run.id, ids[1]);
+
+        Ok(())
+    }
+}
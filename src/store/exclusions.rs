@@ -0,0 +1,148 @@
+//! This module provides functionality for managing excluded targets in the dotdeploy store
+//! database.
+//!
+//! Excluded targets are skipped during deployment without the need to edit the module
+//! configuration.
+
+use std::path::Path;
+
+use deadpool_sqlite::rusqlite::params;
+
+use crate::store::db;
+use crate::store::errors::SQLiteError;
+use crate::utils::file_fs;
+
+impl db::Store {
+    /// Adds an excluded target to the database.
+    ///
+    /// # Arguments
+    /// * `path` - The absolute path of the target.
+    ///
+    /// # Returns
+    /// * `Ok(bool)` indicating whether the target was not excluded before.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn add_exclusion<P: AsRef<Path>>(&self, path: P) -> Result<bool, SQLiteError> {
+        let path = file_fs::path_to_string(path)?;
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<bool, SQLiteError> {
+            db::prepare_connection(conn)?;
+            Ok(conn.execute(
+                "INSERT OR IGNORE INTO exclusions (path, date) VALUES ($1, $2)",
+                params![path, chrono::offset::Local::now()],
+            )? > 0)
+        })
+        .await?
+    }
+
+    /// Removes an excluded target from the database.
+    ///
+    /// # Arguments
+    /// * `path` - The absolute path of the target.
+    ///
+    /// # Returns
+    /// * `Ok(bool)` indicating whether the target was excluded.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn remove_exclusion<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<bool, SQLiteError> {
+        let path = file_fs::path_to_string(path)?;
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<bool, SQLiteError> {
+            db::prepare_connection(conn)?;
+            Ok(conn.execute("DELETE FROM exclusions WHERE path = $1", params![path])? > 0)
+        })
+        .await?
+    }
+
+    /// Retrieves all excluded targets.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<(String, chrono::DateTime<chrono::Local>)>)` containing the path and date of each
+    ///   exclusion, ordered by path.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_exclusions(
+        &self,
+    ) -> Result<Vec<(String, chrono::DateTime<chrono::Local>)>, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(
+            move |conn| -> Result<Vec<(String, chrono::DateTime<chrono::Local>)>, SQLiteError> {
+                db::prepare_connection(conn)?;
+                let mut stmt = conn.prepare("SELECT path, date FROM exclusions ORDER BY path")?;
+
+                let rows: Vec<
+                    Result<
+                        (String, chrono::DateTime<chrono::Local>),
+                        deadpool_sqlite::rusqlite::Error,
+                    >,
+                > = stmt
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect();
+
+                // Process the query results, handling any errors
+                let mut exclusions = Vec::with_capacity(rows.len());
+                for row in rows {
+                    match row {
+                        Ok(e) => exclusions.push(e),
+                        Err(e) => eprintln!("Error processing exclusion row: {:?}", e),
+                    }
+                }
+                Ok(exclusions)
+            },
+        )
+        .await?
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::store::tests::store_setup_helper;
+
+    #[tokio::test]
+    async fn test_exclusions() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+
+        assert!(store
+            .add_exclusion("/home/foo1.txt")
+            .await
+            .map_err(|e| e.into_anyhow())?);
+        // Adding the same target twice is a no-op
+        assert!(!store
+            .add_exclusion("/home/foo1.txt")
+            .await
+            .map_err(|e| e.into_anyhow())?);
+        store
+            .add_exclusion("/etc/foo.conf")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        let exclusions = store.get_exclusions().await.map_err(|e| e.into_anyhow())?;
+        assert_eq!(exclusions.len(), 2);
+        assert_eq!(exclusions[0].0, "/etc/foo.conf");
+
+        assert!(store
+            .remove_exclusion("/etc/foo.conf")
+            .await
+            .map_err(|e| e.into_anyhow())?);
+        assert!(!store
+            .remove_exclusion("/etc/foo.conf")
+            .await
+            .map_err(|e| e.into_anyhow())?);
+        assert_eq!(
+            store
+                .get_exclusions()
+                .await
+                .map_err(|e| e.into_anyhow())?
+                .len(),
+            1
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! This module provides functionality for caching custom facts in the dotdeploy store database.
+//!
+//! Custom facts are the JSON output of the scripts in `facts.d`. The output is kept together with
+//! the checksum of the script, so scripts are only run again if they changed or their output is
+//! older than `facts_ttl`.
+
+use deadpool_sqlite::rusqlite::params;
+
+use crate::store::db;
+use crate::store::errors::SQLiteError;
+
+/// Representation of a cached fact entry (row) in the database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StoreFact {
+    /// Name of the fact, i.e. the file stem of its script
+    pub(crate) name: String,
+    /// Checksum of the script which produced the value
+    pub(crate) checksum: String,
+    /// The JSON output of the script
+    pub(crate) value: String,
+    /// The date and time when the script was run
+    pub(crate) date: chrono::DateTime<chrono::Local>,
+}
+
+impl db::Store {
+    /// Adds or updates a cached fact.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the fact.
+    /// * `checksum` - Checksum of the script which produced the value.
+    /// * `value` - The JSON output of the script.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the operation is successful.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn add_fact<S: AsRef<str>>(
+        &self,
+        name: S,
+        checksum: S,
+        value: S,
+    ) -> Result<(), SQLiteError> {
+        let name = name.as_ref().to_owned();
+        let checksum = checksum.as_ref().to_owned();
+        let value = value.as_ref().to_owned();
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<(), SQLiteError> {
+            db::prepare_connection(conn)?;
+            conn.execute(
+                "INSERT INTO facts (name, checksum, value, date)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT(name)
+                 DO UPDATE SET
+                   checksum = excluded.checksum,
+                   value = excluded.value,
+                   date = excluded.date",
+                params![name, checksum, value, chrono::offset::Local::now()],
+            )?;
+            Ok(())
+        })
+        .await??;
+        Ok(())
+    }
+
+    /// Retrieves a cached fact.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the fact.
+    ///
+    /// # Returns
+    /// * `Ok(Some(StoreFact))` containing the cached fact if found.
+    /// * `Ok(None)` if the fact is not cached.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_fact<S: AsRef<str>>(
+        &self,
+        name: S,
+    ) -> Result<Option<StoreFact>, SQLiteError> {
+        let name = name.as_ref().to_owned();
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<Option<StoreFact>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            match conn.query_row(
+                "SELECT name, checksum, value, date FROM facts WHERE name = $1",
+                params![name],
+                |row| {
+                    Ok(StoreFact {
+                        name: row.get(0)?,
+                        checksum: row.get(1)?,
+                        value: row.get(2)?,
+                        date: row.get(3)?,
+                    })
+                },
+            ) {
+                Ok(fact) => Ok(Some(fact)),
+                Err(deadpool_sqlite::rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await?
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::store::tests::store_setup_helper;
+
+    #[tokio::test]
+    async fn test_facts() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+
+        assert!(store
+            .get_fact("vpn")
+            .await
+            .map_err(|e| e.into_anyhow())?
+            .is_none());
+
+        store
+            .add_fact("vpn", "aaaa", "{\"profile\":\"home\"}")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_fact("vpn", "bbbb", "{\"profile\":\"work\"}")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        let fact = store
+            .get_fact("vpn")
+            .await
+            .map_err(|e| e.into_anyhow())?
+            .expect("fact should be cached");
+        assert_eq!(fact.name, "vpn");
+        assert_eq!(fact.checksum, "bbbb");
+        assert_eq!(fact.value, "{\"profile\":\"work\"}");
+
+        Ok(())
+    }
+}
@@ -5,10 +5,11 @@
 
 use std::path::Path;
 
-use deadpool_sqlite::rusqlite::params;
+use deadpool_sqlite::rusqlite::{params, OptionalExtension};
 
 use crate::store::db;
 use crate::store::errors::SQLiteError;
+use crate::store::events;
 use crate::utils::file_fs;
 
 /// Representation of a store file entry (row) in the database.
@@ -83,6 +84,8 @@ impl db::Store {
     /// * `Err(SQLiteError)` if there's an error during the database operation.
     pub(crate) async fn add_file(&self, file: StoreFile) -> Result<(), SQLiteError> {
         let conn = &self.get_con().await?;
+        let module = file.module.clone();
+        let run = self.current_run();
 
         // Retrieve the ID of the module
         let module_id = conn
@@ -98,6 +101,24 @@ impl db::Store {
 
         conn.interact(move |conn| -> Result<(), SQLiteError> {
             db::prepare_connection(conn)?;
+            // Only changed files are recorded in the audit log
+            let unchanged: bool = conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM files
+                 WHERE destination = $1 AND module_id = $2 AND source IS $3
+                   AND destination_checksum IS $4 AND operation = $5)",
+                params![
+                    &file.destination,
+                    module_id,
+                    &file.source,
+                    &file.destination_checksum,
+                    &file.operation
+                ],
+                |row| row.get(0),
+            )?;
+            if !unchanged {
+                events::record_event(conn, run, &module, &file.destination, &file.operation)?;
+            }
+
             let mut stmt = conn.prepare(
                 "INSERT INTO files (module_id, source, source_checksum, destination, destination_checksum, operation, user, date)
                  VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
@@ -128,7 +149,7 @@ impl db::Store {
         Ok(())
     }
 
-    /// Removes a single file entry from the database.
+    /// Removes a single file entry and its baseline from the database.
     ///
     /// # Arguments
     /// * `file` - The destination path of the file to be removed.
@@ -138,10 +159,24 @@ impl db::Store {
     /// * `Err(SQLiteError)` if there's an error during the database operation.
     pub(crate) async fn remove_file<S: AsRef<str>>(&self, file: S) -> Result<(), SQLiteError> {
         let file = file.as_ref().to_owned();
+        let run = self.current_run();
         let conn = &self.get_con().await?;
         conn.interact(move |conn| -> Result<(), SQLiteError> {
             db::prepare_connection(conn)?;
+            let module: Option<String> = conn
+                .query_row(
+                    "SELECT modules.name FROM files
+                     INNER JOIN modules ON files.module_id = modules.id
+                     WHERE files.destination = $1",
+                    params![file],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if let Some(module) = module {
+                events::record_event(conn, run, &module, &file, "remove")?;
+            }
             conn.execute("DELETE FROM files WHERE destination = $1", params![file])?;
+            conn.execute("DELETE FROM baselines WHERE path = $1", params![file])?;
             Ok(())
         })
         .await??;
@@ -198,6 +233,58 @@ impl db::Store {
         }).await?
     }
 
+    /// Retrieves file entries matching a destination pattern and/or module.
+    ///
+    /// # Arguments
+    /// * `pattern` - Optional glob pattern (SQLite GLOB syntax) matched against the destination.
+    /// * `module` - Optional name of the module owning the files.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<StoreFile>)` containing all matching files, ordered by destination.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn find_files(
+        &self,
+        pattern: Option<String>,
+        module: Option<String>,
+    ) -> Result<Vec<StoreFile>, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<Vec<StoreFile>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let mut stmt = conn.prepare(
+                "SELECT files.source, files.source_checksum, files.destination, files.destination_checksum, files.operation, files.user, files.date, modules.name AS module
+                 FROM files
+                 INNER JOIN modules ON files.module_id = modules.id
+                 WHERE ($1 IS NULL OR files.destination GLOB $1)
+                   AND ($2 IS NULL OR modules.name = $2)
+                 ORDER BY files.destination"
+            )?;
+
+            let rows: Vec<Result<StoreFile, deadpool_sqlite::rusqlite::Error>> = stmt.query_map(params![pattern, module], |row| {
+                Ok(StoreFile {
+                    module: row.get(7)?,
+                    source: row.get(0)?,
+                    source_checksum: row.get(1)?,
+                    destination: row.get(2)?,
+                    destination_checksum: row.get(3)?,
+                    operation: row.get(4)?,
+                    user: row.get(5)?,
+                    date: row.get(6)?,
+                })
+            })?.collect();
+
+            // Process the query results, handling any errors
+            let mut files = Vec::with_capacity(rows.len());
+            for row in rows {
+                match row {
+                    Ok(file) => files.push(file),
+                    Err(e) => eprintln!("Error processing file row: {:?}", e),
+                }
+            }
+            Ok(files)
+        }).await?
+    }
+
     /// Checks if a file exists in the store database.
     ///
     /// # Arguments
@@ -261,7 +348,7 @@ mod tests {
     async fn test_add_and_get_file() -> Result<()> {
         let temp_dir = tempdir()?;
         // Init store
-        let user_store = init_user_store(Some(temp_dir.into_path()))
+        let user_store = init_user_store(Some(temp_dir.into_path()), false)
             .await
             .map_err(|e| e.into_anyhow())?;
 
@@ -333,4 +420,30 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_find_files() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+
+        let result = store
+            .find_files(Some("/home/foo[12].txt".to_string()), None)
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].destination, "/home/foo1.txt");
+
+        let result = store
+            .find_files(None, Some("test".to_string()))
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(result.len(), 5);
+
+        let result = store
+            .find_files(Some("/home/*".to_string()), Some("Foobar".to_string()))
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert!(result.is_empty());
+
+        Ok(())
+    }
 }
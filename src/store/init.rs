@@ -3,14 +3,36 @@
 
 use std::env;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use tokio::fs;
 
 use crate::store::db::Store;
 use crate::store::errors::SQLiteError;
 
+/// Returns the default location of the user store.
+///
+/// This is `$XDG_DATA_HOME/dotdeploy` if set and `$HOME/.local/share/dotdeploy` otherwise.
+pub(crate) fn user_store_path() -> PathBuf {
+    if let Ok(xdg_dir) = env::var("XDG_DATA_HOME") {
+        // Use XDG_DATA_HOME if available
+        [xdg_dir.as_str(), "dotdeploy"].iter().collect()
+    } else {
+        // Fallback to HOME/.local/share/dotdeploy
+        [
+            env::var("HOME")
+                .expect("HOME environment variable not set")
+                .as_str(),
+            ".local",
+            "share",
+            "dotdeploy",
+        ]
+        .iter()
+        .collect()
+    }
+}
+
 /// Initialize the user store.
 ///
 /// This function creates and initializes a SQLite database for storing user-specific dotdeploy
@@ -21,36 +43,24 @@ use crate::store::errors::SQLiteError;
 /// 2. `$XDG_DATA_HOME/dotdeploy`
 /// 3. `$HOME/.local/share/dotdeploy`
 ///
+/// Unless a path is provided, the store is kept below the target root, if set.
+///
 /// # Arguments
 /// * `path` - An optional custom path for the user store.
+/// * `ephemeral` - Use an in-memory copy of the store instead of the database in `path`.
 ///
 /// # Returns
 /// * `Ok(Store)` if the store is successfully initialized.
 /// * `Err(SQLiteError)` if an error occurs during initialization.
-pub(crate) async fn init_user_store(path: Option<PathBuf>) -> Result<Store, SQLiteError> {
+pub(crate) async fn init_user_store(
+    path: Option<PathBuf>,
+    ephemeral: bool,
+) -> Result<Store, SQLiteError> {
     // Determine the store path based on the provided path or environment variables
-    let store_path: PathBuf = path.unwrap_or_else(|| {
-        if let Ok(xdg_dir) = env::var("XDG_DATA_HOME") {
-            // Use XDG_DATA_HOME if available
-            [xdg_dir.as_str(), "dotdeploy"].iter().collect()
-        } else {
-            // Fallback to HOME/.local/share/dotdeploy
-            [
-                env::var("HOME")
-                    .map_err(|e| SQLiteError::Other(e.into()))
-                    .expect("HOME environment variable not set")
-                    .as_str(),
-                ".local",
-                "share",
-                "dotdeploy",
-            ]
-            .iter()
-            .collect()
-        }
-    });
+    let store_path: PathBuf = path.unwrap_or_else(|| crate::target_root::rebase(user_store_path()));
 
     // Create a new Store instance and initialize it
-    let mut store = Store::new(store_path.clone(), false);
+    let mut store = Store::new(store_path.clone(), false, ephemeral);
     store
         .init()
         .await
@@ -67,22 +77,33 @@ pub(crate) async fn init_user_store(path: Option<PathBuf>) -> Result<Store, SQLi
 /// Initialize the system store.
 ///
 /// This function creates and initializes a SQLite database for storing system-wide dotdeploy data.
-/// The database is always created at `/var/lib/dotdeploy`.
+/// The database is always created at `/var/lib/dotdeploy`, below the target root if set.
+///
+/// # Arguments
+/// * `ephemeral` - Use an in-memory copy of the store instead of the database on disk.
 ///
 /// # Returns
 /// * `Ok(Store)` if the store is successfully initialized.
 /// * `Err(SQLiteError)` if an error occurs during initialization.
-pub(crate) async fn init_system_store() -> Result<Store, SQLiteError> {
+pub(crate) async fn init_system_store(ephemeral: bool) -> Result<Store, SQLiteError> {
     // Set the fixed path for the system store
-    let store_path: PathBuf = PathBuf::from("/var/lib/dotdeploy");
+    let store_path: PathBuf = crate::target_root::rebase("/var/lib/dotdeploy");
 
     // Create a new Store instance and initialize it
-    let mut store = Store::new(store_path.clone(), true);
+    let mut store = Store::new(store_path.clone(), true, ephemeral);
     store
         .init()
         .await
         .map_err(|e| e.into_anyhow())
-        .context("Failed to initialize system store in /var/lib/dotdeploy")?;
+        .with_context(|| {
+            format!(
+                "Failed to initialize system store in {}",
+                &store_path.display()
+            )
+        })?;
+    if ephemeral {
+        return Ok(store);
+    }
 
     // Set permissions for the store file to be readable and writable by all users
     fs::set_permissions(&store.path, std::fs::Permissions::from_mode(0o666))
@@ -91,6 +112,72 @@ pub(crate) async fn init_system_store() -> Result<Store, SQLiteError> {
     Ok(store)
 }
 
+/// Moves the run state and caches of earlier versions out of the user store directory.
+///
+/// Earlier versions kept the run lock and the clones of remote modules next to the user store. The
+/// stale lock is deleted once it can be acquired, so an older version still running is not
+/// disturbed. The clones are moved to `cache_dir`. Clones which can't be moved, e.g.
+/// to another file system, are left in place and cloned again when needed.
+///
+/// # Arguments
+///
+/// * `dotdeploy_config` - Configuration providing `state_dir` and `cache_dir`
+///
+/// # Returns
+///
+/// A Result indicating success or failure of the migration
+pub(crate) fn migrate_layout(dotdeploy_config: &crate::config::DotdeployConfig) -> Result<()> {
+    migrate(
+        &user_store_path(),
+        &dotdeploy_config.state_dir,
+        &crate::remotes::cache_dir(dotdeploy_config),
+    )
+}
+
+/// Moves the run lock and the clones of remote modules out of `data_dir`, see [migrate_layout].
+fn migrate(data_dir: &Path, state_dir: &Path, remotes_dir: &Path) -> Result<()> {
+    let old_lock = data_dir.join(crate::utils::lock::LOCK_FILE);
+    if data_dir != state_dir && old_lock.is_file() {
+        // An older version may still be running and hold the lock
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&old_lock)
+            .with_context(|| format!("Failed to open stale lock file {:?}", old_lock))?;
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(std::fs::TryLockError::WouldBlock) => bail!(
+                "An older version of dotdeploy is running and holds {:?}. Wait for it to finish",
+                old_lock
+            ),
+            Err(std::fs::TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {:?}", old_lock))
+            }
+        }
+        std::fs::remove_file(&old_lock)
+            .with_context(|| format!("Failed to delete stale lock file {:?}", old_lock))?;
+    }
+
+    let old_remotes = data_dir.join("remotes");
+    if old_remotes != remotes_dir && old_remotes.is_dir() && !remotes_dir.exists() {
+        if let Some(parent) = remotes_dir.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {:?}", parent))?;
+        }
+        match std::fs::rename(&old_remotes, remotes_dir) {
+            Ok(()) => info!(
+                "Moved remote modules from {:?} to {:?}",
+                old_remotes, remotes_dir
+            ),
+            Err(e) => warn!(
+                "Failed to move remote modules from {:?} to {:?}: {}",
+                old_remotes, remotes_dir, e
+            ),
+        }
+    }
+
+    Ok(())
+}
+
 //
 // Tests
 
@@ -107,7 +194,7 @@ mod tests {
         let temp_dir = tempdir().map_err(|e| SQLiteError::Other(e.into()))?;
 
         // Init store
-        let user_store = init_user_store(Some(temp_dir.into_path())).await?;
+        let user_store = init_user_store(Some(temp_dir.into_path()), false).await?;
 
         // Insert a module
         let test_module = StoreModule {
@@ -132,4 +219,70 @@ mod tests {
         assert_eq!(result, "test".to_string());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_init_ephemeral_store() -> Result<(), SQLiteError> {
+        let temp_dir = tempdir().map_err(|e| SQLiteError::Other(e.into()))?;
+        let module = |name: &str| StoreModule {
+            name: name.to_string(),
+            location: "/testpath".to_string(),
+            user: Some("user".to_string()),
+            reason: "manual".to_string(),
+            depends: None,
+            date: chrono::offset::Local::now(),
+        };
+
+        // An ephemeral store without a database on disk starts empty and creates nothing
+        let store = init_user_store(Some(temp_dir.path().join("missing")), true).await?;
+        assert!(store.get_all_modules().await?.is_empty());
+        store.close().await?;
+        assert!(!temp_dir.path().join("missing").exists());
+
+        let user_store = init_user_store(Some(temp_dir.path().to_path_buf()), false).await?;
+        user_store.add_module(module("disk")).await?;
+
+        // Changes to the copy are not written back
+        let store = init_user_store(Some(temp_dir.path().to_path_buf()), true).await?;
+        assert_eq!(store.get_module("disk").await?.name, "disk");
+        store.add_module(module("memory")).await?;
+        assert_eq!(store.get_all_modules().await?.len(), 2);
+        store.close().await?;
+
+        assert_eq!(user_store.get_all_modules().await?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let data_dir = temp_dir.path().join("data");
+        let state_dir = temp_dir.path().join("state");
+        let remotes_dir = temp_dir.path().join("cache/remotes");
+        std::fs::create_dir_all(data_dir.join("remotes/nvim"))?;
+        std::fs::write(data_dir.join("remotes/nvim/config.toml"), "")?;
+        std::fs::write(data_dir.join("dotdeploy.lock"), "1")?;
+        std::fs::write(data_dir.join("store.sqlite"), "")?;
+
+        migrate(&data_dir, &state_dir, &remotes_dir)?;
+        assert!(!data_dir.join("dotdeploy.lock").exists());
+        assert!(!data_dir.join("remotes").exists());
+        assert!(remotes_dir.join("nvim/config.toml").is_file());
+        assert!(data_dir.join("store.sqlite").is_file());
+
+        // Migrating again changes nothing
+        migrate(&data_dir, &state_dir, &remotes_dir)?;
+        assert!(remotes_dir.join("nvim/config.toml").is_file());
+
+        // A lock held by an older version is kept
+        std::fs::write(data_dir.join("dotdeploy.lock"), "1")?;
+        let held = std::fs::File::open(data_dir.join("dotdeploy.lock"))?;
+        held.lock()?;
+        assert!(migrate(&data_dir, &state_dir, &remotes_dir).is_err());
+        assert!(data_dir.join("dotdeploy.lock").is_file());
+        drop(held);
+        migrate(&data_dir, &state_dir, &remotes_dir)?;
+        assert!(!data_dir.join("dotdeploy.lock").exists());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,379 @@
+//! This module provides integrity checks for the dotdeploy store database.
+//!
+//! It includes operations for finding and removing file entries without an associated module,
+//! rows referring to modules, files or runs which no longer exist, and backups whose content does
+//! not match their metadata.
+
+use crate::store::backups::StoreBackup;
+use crate::store::db;
+use crate::store::errors::SQLiteError;
+use crate::utils::file_checksum;
+
+/// Tables whose rows refer to a module, file or run by name or path, with the key column and the
+/// condition selecting rows without a referenced entry.
+///
+/// Notes are only checked if attached to a module, notes attached to a path may refer to any file.
+/// Exclusions are not listed, as they may refer to files of the other store.
+const STALE_ROWS: [(&str, &str, &str); 5] = [
+    (
+        "notes",
+        "target",
+        "target NOT LIKE '/%' AND target NOT IN (SELECT name FROM modules)",
+    ),
+    (
+        "module_stats",
+        "module",
+        "module NOT IN (SELECT name FROM modules)",
+    ),
+    (
+        "profiles",
+        "module",
+        "module NOT IN (SELECT name FROM modules)",
+    ),
+    (
+        "baselines",
+        "path",
+        "path NOT IN (SELECT destination FROM files)",
+    ),
+    ("events", "path", "run_id NOT IN (SELECT id FROM runs)"),
+];
+
+impl db::Store {
+    /// Retrieves all file entries which are not associated with an existing module.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<String>)` containing the destinations of all orphaned file entries.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_orphaned_files(&self) -> Result<Vec<String>, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<Vec<String>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let mut stmt = conn.prepare(
+                "SELECT destination FROM files
+                 WHERE module_id IS NULL OR module_id NOT IN (SELECT id FROM modules)
+                 ORDER BY destination",
+            )?;
+
+            let rows: Vec<Result<String, deadpool_sqlite::rusqlite::Error>> =
+                stmt.query_map([], |row| row.get(0))?.collect();
+
+            // Process the query results, handling any errors
+            let mut files = Vec::with_capacity(rows.len());
+            for row in rows {
+                match row {
+                    Ok(file) => files.push(file),
+                    Err(e) => eprintln!("Error processing file row: {:?}", e),
+                }
+            }
+            Ok(files)
+        })
+        .await?
+    }
+
+    /// Removes all file entries which are not associated with an existing module.
+    ///
+    /// # Returns
+    /// * `Ok(usize)` containing the number of removed entries.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn remove_orphaned_files(&self) -> Result<usize, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<usize, SQLiteError> {
+            db::prepare_connection(conn)?;
+            Ok(conn.execute(
+                "DELETE FROM files
+                 WHERE module_id IS NULL OR module_id NOT IN (SELECT id FROM modules)",
+                [],
+            )?)
+        })
+        .await?
+    }
+
+    /// Verifies all backups against their metadata.
+    ///
+    /// Regular file backups must have content matching their stored checksum, symlink backups must
+    /// have a link source.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<(String, String)>)` containing path and problem description of each invalid
+    ///   backup.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_invalid_backups(&self) -> Result<Vec<(String, String)>, SQLiteError> {
+        let backups = self.get_all_backups().await?;
+
+        Ok(backups
+            .into_iter()
+            .filter_map(|b| verify_backup(&b).map(|problem| (b.path, problem)))
+            .collect())
+    }
+
+    /// Retrieves all rows which refer to a module, file or run that no longer exists.
+    ///
+    /// See [STALE_ROWS] for the checked tables.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<(String, String)>)` containing table and key of each stale row.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_stale_rows(&self) -> Result<Vec<(String, String)>, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<Vec<(String, String)>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let mut stale = vec![];
+            for (table, key, condition) in STALE_ROWS.iter() {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {} FROM {} WHERE {} ORDER BY {}",
+                    key, table, condition, key
+                ))?;
+
+                let rows: Vec<Result<String, deadpool_sqlite::rusqlite::Error>> =
+                    stmt.query_map([], |row| row.get(0))?.collect();
+
+                // Process the query results, handling any errors
+                for row in rows {
+                    match row {
+                        Ok(k) => stale.push((table.to_string(), k)),
+                        Err(e) => eprintln!("Error processing {} row: {:?}", table, e),
+                    }
+                }
+            }
+            Ok(stale)
+        })
+        .await?
+    }
+
+    /// Removes all rows which refer to a module, file or run that no longer exists.
+    ///
+    /// # Returns
+    /// * `Ok(usize)` containing the number of removed rows.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn remove_stale_rows(&self) -> Result<usize, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<usize, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let tx = conn.transaction()?;
+            let mut removed = 0;
+            for (table, _, condition) in STALE_ROWS.iter() {
+                removed += tx.execute(&format!("DELETE FROM {} WHERE {}", table, condition), [])?;
+            }
+            tx.commit()?;
+            Ok(removed)
+        })
+        .await?
+    }
+}
+
+/// Checks a single backup for consistency, returning a description of the problem if any.
+fn verify_backup(backup: &StoreBackup) -> Option<String> {
+    match backup.file_type.as_str() {
+        "regular" => match (&backup.content, &backup.checksum) {
+            (None, _) => Some("regular file backup without content".to_string()),
+            (Some(_), None) => Some("regular file backup without checksum".to_string()),
+            (Some(content), Some(checksum)) => {
+                let actual = file_checksum::calculate_sha256_checksum_bytes(content);
+                if &actual != checksum {
+                    Some(format!(
+                        "checksum mismatch (expected {}, found {})",
+                        checksum, actual
+                    ))
+                } else {
+                    None
+                }
+            }
+        },
+        "link" => {
+            if backup.link_source.is_none() {
+                Some("symlink backup without link source".to_string())
+            } else {
+                None
+            }
+        }
+        t => Some(format!("unknown file type '{}'", t)),
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use deadpool_sqlite::rusqlite::params;
+    use tempfile::tempdir;
+    use tokio::fs;
+
+    use crate::store::tests::store_setup_helper;
+
+    #[tokio::test]
+    async fn test_orphaned_files() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+
+        let result = store
+            .get_orphaned_files()
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert!(result.is_empty());
+
+        // Simulate a store written without foreign key enforcement, where removing the module
+        // leaves its files behind
+        let conn = store.get_con().await.map_err(|e| e.into_anyhow())?;
+        conn.interact(|conn| {
+            conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+            conn.execute("DELETE FROM modules WHERE name = $1", params!["test"])?;
+            conn.execute_batch("PRAGMA foreign_keys = ON")
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("{:?}", e))??;
+
+        let result = store
+            .get_orphaned_files()
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(result.len(), 5);
+
+        let removed = store
+            .remove_orphaned_files()
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(removed, 5);
+        assert!(store
+            .get_orphaned_files()
+            .await
+            .map_err(|e| e.into_anyhow())?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_backups() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+
+        let temp_path = tempdir()?;
+        fs::write(temp_path.path().join("foo.txt"), b"Hello World!").await?;
+        store
+            .add_backup(&temp_path.path().join("foo.txt"))
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        assert!(store
+            .get_invalid_backups()
+            .await
+            .map_err(|e| e.into_anyhow())?
+            .is_empty());
+
+        // Corrupt the backup content
+        let conn = store.get_con().await.map_err(|e| e.into_anyhow())?;
+        conn.interact(|conn| {
+            conn.execute(
+                "UPDATE backups SET content = $1",
+                params![b"Hello Moon!".to_vec()],
+            )
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("{:?}", e))??;
+
+        let result = store
+            .get_invalid_backups()
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(result.len(), 1);
+        assert!(result[0].1.starts_with("checksum mismatch"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_rows() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+
+        store
+            .add_note("test", "kept")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_note("/etc/unmanaged", "kept")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_profile_module("desktop", "test")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_baseline("/home/foo0.txt", b"Hello World!".to_vec())
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        assert!(store
+            .get_stale_rows()
+            .await
+            .map_err(|e| e.into_anyhow())?
+            .is_empty());
+
+        // Rows of a module which was removed and an event whose run is gone
+        store
+            .add_note("gone", "stale")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_profile_module("desktop", "gone")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_baseline("/home/gone.txt", b"Hello Moon!".to_vec())
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        let conn = store.get_con().await.map_err(|e| e.into_anyhow())?;
+        conn.interact(|conn| {
+            conn.execute(
+                "INSERT INTO module_stats (module, files, bytes, duration_ms, status, date)
+                 VALUES ('gone', 1, 1, 1, 'success', '2024-01-01T00:00:00+00:00')",
+                [],
+            )?;
+            conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+            conn.execute(
+                "INSERT INTO events (run_id, module, path, action, date)
+                 VALUES (42, 'gone', '/home/gone.txt', 'deploy', '2024-01-01T00:00:00+00:00')",
+                [],
+            )?;
+            conn.execute_batch("PRAGMA foreign_keys = ON")
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("{:?}", e))??;
+
+        let result = store.get_stale_rows().await.map_err(|e| e.into_anyhow())?;
+        assert_eq!(
+            result,
+            vec![
+                ("notes".to_string(), "gone".to_string()),
+                ("module_stats".to_string(), "gone".to_string()),
+                ("profiles".to_string(), "gone".to_string()),
+                ("baselines".to_string(), "/home/gone.txt".to_string()),
+                ("events".to_string(), "/home/gone.txt".to_string()),
+            ]
+        );
+
+        let removed = store
+            .remove_stale_rows()
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(removed, 5);
+        assert!(store
+            .get_stale_rows()
+            .await
+            .map_err(|e| e.into_anyhow())?
+            .is_empty());
+        assert_eq!(
+            store
+                .get_module_profiles("test")
+                .await
+                .map_err(|e| e.into_anyhow())?
+                .len(),
+            1
+        );
+
+        Ok(())
+    }
+}
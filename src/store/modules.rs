@@ -5,6 +5,7 @@ use deadpool_sqlite::rusqlite::{params, OptionalExtension};
 
 use crate::store::db;
 use crate::store::errors::SQLiteError;
+use crate::store::events;
 
 /// Representation of a store module entry (row) in the database.
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -67,10 +68,58 @@ impl db::Store {
     /// * `Err(SQLiteError)` if there's an error during the database operation.
     pub(crate) async fn remove_module<S: AsRef<str>>(&self, module: S) -> Result<(), SQLiteError> {
         let module = module.as_ref().to_owned();
+        let run = self.current_run();
         let conn = &self.get_con().await?;
         conn.interact(move |conn| -> Result<(), SQLiteError> {
             db::prepare_connection(conn)?;
+            events::record_module_removal(conn, run, &module)?;
             conn.execute("DELETE FROM modules WHERE name = $1", params![module])?;
+            conn.execute("DELETE FROM profiles WHERE module = $1", params![module])?;
+            Ok(())
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    /// Removes a module and everything associated with it from the database.
+    ///
+    /// Besides the module and its files, this deletes the backups, baselines and notes of the given
+    /// paths as well as the notes and deployment statistics of the module. All rows are deleted in
+    /// a single transaction.
+    ///
+    /// # Arguments
+    /// * `module` - The name of the module to be purged.
+    /// * `paths` - The destinations of the files of the module.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the operation is successful.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn purge_module<S: AsRef<str>>(
+        &self,
+        module: S,
+        paths: Vec<String>,
+    ) -> Result<(), SQLiteError> {
+        let module = module.as_ref().to_owned();
+        let run = self.current_run();
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<(), SQLiteError> {
+            db::prepare_connection(conn)?;
+            let tx = conn.transaction()?;
+            events::record_module_removal(&tx, run, &module)?;
+            for path in paths.iter() {
+                tx.execute("DELETE FROM backups WHERE path = $1", params![path])?;
+                tx.execute("DELETE FROM baselines WHERE path = $1", params![path])?;
+                tx.execute("DELETE FROM notes WHERE target = $1", params![path])?;
+            }
+            tx.execute("DELETE FROM notes WHERE target = $1", params![module])?;
+            tx.execute(
+                "DELETE FROM module_stats WHERE module = $1",
+                params![module],
+            )?;
+            tx.execute("DELETE FROM modules WHERE name = $1", params![module])?;
+            tx.execute("DELETE FROM profiles WHERE module = $1", params![module])?;
+            tx.commit()?;
             Ok(())
         })
         .await??;
@@ -157,3 +206,52 @@ impl db::Store {
         .await?
     }
 }
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::store::tests::store_setup_helper;
+
+    #[tokio::test]
+    async fn test_purge_module() -> Result<()> {
+        let store = store_setup_helper("copy").await?;
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("foo.txt");
+        tokio::fs::write(&path, "foo").await?;
+        let path = path.display().to_string();
+
+        store.add_backup(&path).await.map_err(|e| e.into_anyhow())?;
+        store
+            .add_note("test", "module note")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_note(path.as_str(), "file note")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_note("other", "unrelated note")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        store
+            .purge_module("test", vec![path.clone()])
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        assert!(!store
+            .check_backup_exists(&path)
+            .await
+            .map_err(|e| e.into_anyhow())?);
+        assert!(store.get_module("test").await.is_err());
+        let notes = store.get_notes(None).await.map_err(|e| e.into_anyhow())?;
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].target, "other");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+//! This module provides functionality for managing notes in the dotdeploy store database.
+//!
+//! Notes are free-form annotations attached to a module or a file path, keeping operational context
+//! next to the deployment state.
+
+use deadpool_sqlite::rusqlite::params;
+
+use crate::store::db;
+use crate::store::errors::SQLiteError;
+
+/// Representation of a store note entry (row) in the database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StoreNote {
+    /// Unique ID of the note
+    pub(crate) id: i64,
+    /// The module name or absolute file path the note is attached to
+    pub(crate) target: String,
+    /// The note text
+    pub(crate) text: String,
+    /// The date and time when the note was added
+    pub(crate) date: chrono::DateTime<chrono::Local>,
+}
+
+impl db::Store {
+    /// Adds a note to the database.
+    ///
+    /// # Arguments
+    /// * `target` - The module name or absolute file path the note is attached to.
+    /// * `text` - The note text.
+    ///
+    /// # Returns
+    /// * `Ok(i64)` containing the ID of the new note.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn add_note<S: AsRef<str>>(
+        &self,
+        target: S,
+        text: S,
+    ) -> Result<i64, SQLiteError> {
+        let target = target.as_ref().to_owned();
+        let text = text.as_ref().to_owned();
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<i64, SQLiteError> {
+            db::prepare_connection(conn)?;
+            conn.execute(
+                "INSERT INTO notes (target, text, date) VALUES ($1, $2, $3)",
+                params![target, text, chrono::offset::Local::now()],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await?
+    }
+
+    /// Retrieves notes from the database.
+    ///
+    /// # Arguments
+    /// * `target` - Only retrieve notes attached to this module name or file path.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<StoreNote>)` containing the notes, ordered by target and date.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_notes(
+        &self,
+        target: Option<String>,
+    ) -> Result<Vec<StoreNote>, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<Vec<StoreNote>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let mut stmt = conn.prepare(
+                "SELECT id, target, text, date FROM notes
+                 WHERE $1 IS NULL OR target = $1
+                 ORDER BY target, id",
+            )?;
+
+            let rows: Vec<Result<StoreNote, deadpool_sqlite::rusqlite::Error>> = stmt
+                .query_map(params![target], |row| {
+                    Ok(StoreNote {
+                        id: row.get(0)?,
+                        target: row.get(1)?,
+                        text: row.get(2)?,
+                        date: row.get(3)?,
+                    })
+                })?
+                .collect();
+
+            // Process the query results, handling any errors
+            let mut notes = Vec::with_capacity(rows.len());
+            for row in rows {
+                match row {
+                    Ok(n) => notes.push(n),
+                    Err(e) => eprintln!("Error processing note row: {:?}", e),
+                }
+            }
+            Ok(notes)
+        })
+        .await?
+    }
+
+    /// Removes a note from the database.
+    ///
+    /// # Arguments
+    /// * `id` - The ID of the note.
+    ///
+    /// # Returns
+    /// * `Ok(bool)` indicating whether a note with the given ID existed.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn remove_note(&self, id: i64) -> Result<bool, SQLiteError> {
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<bool, SQLiteError> {
+            db::prepare_connection(conn)?;
+            Ok(conn.execute("DELETE FROM notes WHERE id = $1", params![id])? > 0)
+        })
+        .await?
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::store::tests::store_setup_helper;
+
+    #[tokio::test]
+    async fn test_notes() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+
+        let id = store
+            .add_note("firewall", "rules disabled for debugging")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_note("/etc/hosts", "edited by hand")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        let notes = store.get_notes(None).await.map_err(|e| e.into_anyhow())?;
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].target, "/etc/hosts");
+
+        let notes = store
+            .get_notes(Some("firewall".to_string()))
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].id, id);
+        assert_eq!(notes[0].text, "rules disabled for debugging");
+
+        assert!(store.remove_note(id).await.map_err(|e| e.into_anyhow())?);
+        assert!(!store.remove_note(id).await.map_err(|e| e.into_anyhow())?);
+        assert_eq!(
+            store
+                .get_notes(None)
+                .await
+                .map_err(|e| e.into_anyhow())?
+                .len(),
+            1
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! This module provides functionality for managing profiles in the dotdeploy store database.
+//!
+//! A profile is a named set of modules defined in the config. When modules are deployed with a
+//! profile, the profile is recorded for each of them.
+
+use deadpool_sqlite::rusqlite::params;
+
+use crate::store::db;
+use crate::store::errors::SQLiteError;
+
+impl db::Store {
+    /// Records that a module was deployed as part of a profile.
+    ///
+    /// # Arguments
+    /// * `profile` - The name of the profile.
+    /// * `module` - The name of the module.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the operation is successful.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn add_profile_module<S: AsRef<str>>(
+        &self,
+        profile: S,
+        module: S,
+    ) -> Result<(), SQLiteError> {
+        let profile = profile.as_ref().to_owned();
+        let module = module.as_ref().to_owned();
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<(), SQLiteError> {
+            db::prepare_connection(conn)?;
+            conn.execute(
+                "INSERT INTO profiles (name, module, date) VALUES ($1, $2, $3)
+                 ON CONFLICT(name, module) DO UPDATE SET date = excluded.date",
+                params![profile, module, chrono::offset::Local::now()],
+            )?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Retrieves the profiles a module was deployed with.
+    ///
+    /// # Arguments
+    /// * `module` - The name of the module.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<String>)` containing the profile names, ordered by name.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_module_profiles<S: AsRef<str>>(
+        &self,
+        module: S,
+    ) -> Result<Vec<String>, SQLiteError> {
+        let module = module.as_ref().to_owned();
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<Vec<String>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let mut stmt =
+                conn.prepare("SELECT name FROM profiles WHERE module = $1 ORDER BY name")?;
+
+            let rows: Vec<Result<String, deadpool_sqlite::rusqlite::Error>> =
+                stmt.query_map(params![module], |row| row.get(0))?.collect();
+
+            // Process the query results, handling any errors
+            let mut profiles = Vec::with_capacity(rows.len());
+            for row in rows {
+                match row {
+                    Ok(p) => profiles.push(p),
+                    Err(e) => eprintln!("Error processing profile row: {:?}", e),
+                }
+            }
+            Ok(profiles)
+        })
+        .await?
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::store::tests::store_setup_helper;
+
+    #[tokio::test]
+    async fn test_profiles() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+
+        store
+            .add_profile_module("work", "test")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        // Recording the same profile twice is a no-op
+        store
+            .add_profile_module("work", "test")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        store
+            .add_profile_module("home", "test")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        assert_eq!(
+            store
+                .get_module_profiles("test")
+                .await
+                .map_err(|e| e.into_anyhow())?,
+            vec!["home", "work"]
+        );
+
+        // Removing the module removes its profiles
+        store
+            .remove_module("test")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert!(store
+            .get_module_profiles("test")
+            .await
+            .map_err(|e| e.into_anyhow())?
+            .is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+//! This module provides functionality for managing deployment statistics in the dotdeploy store
+//! database.
+//!
+//! After each run, aggregate statistics of every deployed module are recorded. The latest entry of a
+//! module can be retrieved without walking the files table, while the full history allows to follow
+//! trends over time.
+
+use deadpool_sqlite::rusqlite::params;
+
+use crate::store::db;
+use crate::store::errors::SQLiteError;
+
+/// Representation of a module statistics entry (row) in the database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StoreModuleStats {
+    /// The name of the module
+    pub(crate) module: String,
+    /// Number of files managed by the module
+    pub(crate) files: i64,
+    /// Total size of all managed files in bytes
+    pub(crate) bytes: i64,
+    /// Duration of the deployment in milliseconds
+    pub(crate) duration_ms: i64,
+    /// Status of the deployment ("success" or "failed")
+    pub(crate) status: String,
+    /// The date and time when the deployment finished
+    pub(crate) date: chrono::DateTime<chrono::Local>,
+}
+
+/// Maps a row of the module_stats table to a `StoreModuleStats`.
+fn map_stats_row(
+    row: &deadpool_sqlite::rusqlite::Row,
+) -> Result<StoreModuleStats, deadpool_sqlite::rusqlite::Error> {
+    Ok(StoreModuleStats {
+        module: row.get(0)?,
+        files: row.get(1)?,
+        bytes: row.get(2)?,
+        duration_ms: row.get(3)?,
+        status: row.get(4)?,
+        date: row.get(5)?,
+    })
+}
+
+impl db::Store {
+    /// Adds a statistics entry for a module to the database.
+    ///
+    /// # Arguments
+    /// * `stats` - The `StoreModuleStats` to be added.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the operation is successful.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn add_module_stats(
+        &self,
+        stats: StoreModuleStats,
+    ) -> Result<(), SQLiteError> {
+        let conn = &self.get_con().await?;
+        conn.interact(move |conn| -> Result<(), SQLiteError> {
+            db::prepare_connection(conn)?;
+            conn.execute(
+                "INSERT INTO module_stats (module, files, bytes, duration_ms, status, date)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+                params![
+                    stats.module,
+                    stats.files,
+                    stats.bytes,
+                    stats.duration_ms,
+                    stats.status,
+                    stats.date
+                ],
+            )?;
+            Ok(())
+        })
+        .await??;
+        Ok(())
+    }
+
+    /// Retrieves the most recent statistics entry of every module.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<StoreModuleStats>)` containing the latest entry per module, ordered by name.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_latest_module_stats(
+        &self,
+    ) -> Result<Vec<StoreModuleStats>, SQLiteError> {
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<Vec<StoreModuleStats>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let mut stmt = conn.prepare(
+                "SELECT module, files, bytes, duration_ms, status, date
+                 FROM module_stats
+                 WHERE id IN (SELECT MAX(id) FROM module_stats GROUP BY module)
+                 ORDER BY module",
+            )?;
+
+            let rows: Vec<Result<StoreModuleStats, deadpool_sqlite::rusqlite::Error>> =
+                stmt.query_map(params![], map_stats_row)?.collect();
+
+            // Process the query results, handling any errors
+            let mut stats = Vec::with_capacity(rows.len());
+            for row in rows {
+                match row {
+                    Ok(s) => stats.push(s),
+                    Err(e) => eprintln!("Error processing module stats row: {:?}", e),
+                }
+            }
+            Ok(stats)
+        })
+        .await?
+    }
+
+    /// Retrieves all statistics entries of a module.
+    ///
+    /// # Arguments
+    /// * `module` - The name of the module.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<StoreModuleStats>)` containing all entries of the module, ordered from oldest to
+    ///   newest.
+    /// * `Err(SQLiteError)` if there's an error during the database operation.
+    pub(crate) async fn get_module_stats_history<S: AsRef<str>>(
+        &self,
+        module: S,
+    ) -> Result<Vec<StoreModuleStats>, SQLiteError> {
+        let module = module.as_ref().to_owned();
+        let conn = &self.get_con().await?;
+
+        conn.interact(move |conn| -> Result<Vec<StoreModuleStats>, SQLiteError> {
+            db::prepare_connection(conn)?;
+            let mut stmt = conn.prepare(
+                "SELECT module, files, bytes, duration_ms, status, date
+                 FROM module_stats
+                 WHERE module = $1
+                 ORDER BY id",
+            )?;
+
+            let rows: Vec<Result<StoreModuleStats, deadpool_sqlite::rusqlite::Error>> =
+                stmt.query_map(params![module], map_stats_row)?.collect();
+
+            // Process the query results, handling any errors
+            let mut stats = Vec::with_capacity(rows.len());
+            for row in rows {
+                match row {
+                    Ok(s) => stats.push(s),
+                    Err(e) => eprintln!("Error processing module stats row: {:?}", e),
+                }
+            }
+            Ok(stats)
+        })
+        .await?
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    use crate::store::tests::store_setup_helper;
+
+    fn stats(module: &str, files: i64, status: &str) -> StoreModuleStats {
+        StoreModuleStats {
+            module: module.to_string(),
+            files,
+            bytes: files * 10,
+            duration_ms: 100,
+            status: status.to_string(),
+            date: chrono::offset::Local::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_module_stats() -> Result<()> {
+        let store = store_setup_helper("link").await?;
+
+        for s in [
+            stats("foo", 1, "success"),
+            stats("bar", 3, "success"),
+            stats("foo", 2, "failed"),
+        ] {
+            store
+                .add_module_stats(s)
+                .await
+                .map_err(|e| e.into_anyhow())?;
+        }
+
+        // Latest entry per module
+        let latest = store
+            .get_latest_module_stats()
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[0].module, "bar");
+        assert_eq!(latest[1].module, "foo");
+        assert_eq!(latest[1].files, 2);
+        assert_eq!(latest[1].status, "failed");
+
+        // Full history of a module
+        let history = store
+            .get_module_stats_history("foo")
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].files, 1);
+        assert_eq!(history[1].files, 2);
+
+        Ok(())
+    }
+}
@@ -10,7 +10,7 @@ pub(crate) async fn store_setup_helper(op_tye: &str) -> Result<Store> {
     let temp_dir = tempdir()?;
 
     // Initialize the user store, which sets up the database and tables
-    let pool = init_user_store(Some(temp_dir.into_path()))
+    let pool = init_user_store(Some(temp_dir.into_path()), false)
         .await
         .map_err(|e| e.into_anyhow())?;
 
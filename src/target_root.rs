@@ -0,0 +1,98 @@
+//! This module deploys modules below an alternate root.
+//!
+//! With `target_root` in the config or `dotdeploy --target-root <path>`, the targets of all files
+//! and generated files are prefixed with another root, e.g. `/mnt/newsys` while provisioning a
+//! chroot or a temporary directory while testing modules. Targets are still sorted into files in
+//! HOME and system files by their path on the live system, but written below the root. The stores
+//! are kept below the root as well and record the prefixed paths. Packages are not installed and
+//! actions and hooks are skipped, as they would change the live system.
+
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// The root targets are deployed below, if not the live system.
+    static ref TARGET_ROOT: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+/// Deploys all targets below `root` from now on.
+///
+/// # Arguments
+///
+/// * `root` - Absolute path of an existing directory
+///
+/// # Returns
+///
+/// A Result indicating success, or an error if the root is not an absolute path of a directory.
+pub(crate) fn set(root: &Path) -> Result<()> {
+    if !root.is_absolute() {
+        bail!("Target root {:?} is not an absolute path", root);
+    }
+    if !root.is_dir() {
+        bail!(
+            "Target root {:?} does not exist or is not a directory",
+            root
+        );
+    }
+    info!("Deploying below {}", root.display());
+
+    *TARGET_ROOT.write().unwrap() = Some(root.to_path_buf());
+    Ok(())
+}
+
+/// Returns true if targets are deployed below a target root.
+pub(crate) fn is_set() -> bool {
+    TARGET_ROOT.read().unwrap().is_some()
+}
+
+/// Prefixes an absolute path with `root`. Relative paths are returned unchanged.
+fn rebase_on(root: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix("/") {
+        Ok(relative) => root.join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Returns the path a target is deployed to.
+///
+/// # Arguments
+///
+/// * `path` - The target on the live system
+///
+/// # Returns
+///
+/// The target below the target root, or the target itself if no root is set
+pub(crate) fn rebase<P: AsRef<Path>>(path: P) -> PathBuf {
+    match TARGET_ROOT.read().unwrap().as_ref() {
+        Some(root) => rebase_on(root, path.as_ref()),
+        None => path.as_ref().to_path_buf(),
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rebase_on() {
+        let root = Path::new("/mnt/newsys");
+        assert_eq!(
+            rebase_on(root, Path::new("/etc/hosts")),
+            PathBuf::from("/mnt/newsys/etc/hosts")
+        );
+        assert_eq!(
+            rebase_on(root, Path::new("/home/user/.zshrc")),
+            PathBuf::from("/mnt/newsys/home/user/.zshrc")
+        );
+        assert_eq!(
+            rebase_on(root, Path::new("relative/path")),
+            PathBuf::from("relative/path")
+        );
+    }
+}
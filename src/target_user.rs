@@ -0,0 +1,387 @@
+//! This module deploys modules for another user.
+//!
+//! An administrator running dotdeploy as root can deploy modules for any account with
+//! `dotdeploy deploy --for-user <user>`. The config file and the dotfiles of the administrator are
+//! used, but HOME and USER are switched to the target user before the config is initialized. This
+//! way, targets in HOME are expanded against the home directory of the user, the run state and the
+//! user store of the user record the run and the deployed files are owned by the user.
+//!
+//! The home directory belongs to the user, who could replace any folder in it with a symbolic link
+//! to a system directory. Root therefore never follows symbolic links below HOME: folders are
+//! walked and created relative to the file descriptor of their parent, files are opened with
+//! `O_NOFOLLOW` and only the folders created by the run are handed over to the user.
+
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::RwLock;
+
+use anyhow::{anyhow, bail, Context, Result};
+use lazy_static::lazy_static;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::stat::Mode;
+use nix::unistd::{Gid, Uid};
+
+lazy_static! {
+    /// The user modules are deployed for, if not the user running dotdeploy.
+    static ref TARGET_USER: RwLock<Option<TargetUser>> = RwLock::new(None);
+}
+
+/// An account modules are deployed for.
+#[derive(Clone, Debug)]
+pub(crate) struct TargetUser {
+    pub(crate) name: String,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) home: PathBuf,
+    /// Home directory of the administrator running dotdeploy
+    pub(crate) admin_home: PathBuf,
+    /// Folder of the config file of the administrator running dotdeploy
+    pub(crate) admin_config_dir: PathBuf,
+}
+
+/// Looks up an account in the user database.
+fn lookup(name: &str) -> Result<TargetUser> {
+    let user = nix::unistd::User::from_name(name)
+        .with_context(|| format!("Failed to look up user {:?}", name))?
+        .ok_or_else(|| anyhow!("User {:?} does not exist", name))?;
+    Ok(TargetUser {
+        name: user.name,
+        uid: user.uid.as_raw(),
+        gid: user.gid.as_raw(),
+        home: user.dir,
+        admin_home: PathBuf::new(),
+        admin_config_dir: PathBuf::new(),
+    })
+}
+
+/// Switches the environment to another user.
+///
+/// HOME, USER and LOGNAME are set to the account of the user. The XDG base directories of the
+/// administrator are unset, so their defaults inside the home directory of the user apply. Must be
+/// called before the config is initialized, which keeps reading the config file of the
+/// administrator.
+///
+/// # Arguments
+///
+/// * `name` - Name of the user modules are deployed for
+///
+/// # Returns
+///
+/// A Result indicating success, or an error if the user does not exist or dotdeploy does not run
+/// as root.
+pub(crate) fn switch(name: &str) -> Result<()> {
+    if !nix::unistd::geteuid().is_root() {
+        bail!(
+            "Deploying for user {} requires running dotdeploy as root",
+            name
+        );
+    }
+    let mut user = lookup(name)?;
+
+    // The config and the dotfiles of the administrator are used, never the ones of the user
+    user.admin_home = PathBuf::from(std::env::var("HOME").context("HOME is not set")?);
+    user.admin_config_dir = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => user.admin_home.join(".config"),
+    };
+
+    unsafe {
+        std::env::set_var("HOME", &user.home);
+        std::env::set_var("USER", &user.name);
+        std::env::set_var("LOGNAME", &user.name);
+        for var in [
+            "XDG_CONFIG_HOME",
+            "XDG_DATA_HOME",
+            "XDG_STATE_HOME",
+            "XDG_CACHE_HOME",
+        ] {
+            std::env::remove_var(var);
+        }
+    }
+    info!(
+        "Deploying for user {} in {}",
+        user.name,
+        user.home.display()
+    );
+
+    *TARGET_USER.write().unwrap() = Some(user);
+    Ok(())
+}
+
+/// Returns the user modules are deployed for, if not the user running dotdeploy.
+pub(crate) fn get() -> Option<TargetUser> {
+    TARGET_USER.read().unwrap().clone()
+}
+
+/// Returns the home directory of the target user and the part of `path` below it.
+///
+/// Returns `None` if no target user is set or `path` is not inside the home directory.
+fn below_home(path: &Path) -> Option<(TargetUser, PathBuf, PathBuf)> {
+    let user = get()?;
+    let home = crate::target_root::rebase(&user.home);
+    let relative = path.strip_prefix(&home).ok()?.to_path_buf();
+    Some((user, home, relative))
+}
+
+/// Opens a folder relative to `dir` without following a symbolic link.
+fn open_dir_at(dir: Option<&OwnedFd>, name: &CString) -> nix::Result<OwnedFd> {
+    let fd = nix::fcntl::openat(
+        dir.map(|d| d.as_raw_fd()),
+        name.as_c_str(),
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    // SAFETY: The descriptor was just opened and is not owned by anything else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Walks the folders of `relative` below `home`, refusing symbolic links.
+///
+/// Missing folders are created and handed over to the user if `create` is true. Otherwise, the
+/// walk stops at the first missing folder.
+fn walk(user: &TargetUser, home: &Path, relative: &Path, create: bool) -> Result<()> {
+    // HOME itself may be a link, e.g. /home -> /var/home
+    let mut dir = OwnedFd::from(
+        std::fs::File::open(home).with_context(|| format!("Failed to open {:?}", home))?,
+    );
+    let mut current = home.to_path_buf();
+
+    for component in relative.components() {
+        let Component::Normal(name) = component else {
+            bail!(
+                "Refusing to access {:?}: not a normalized path",
+                home.join(relative)
+            );
+        };
+        current.push(name);
+        let name = CString::new(name.as_bytes())?;
+
+        dir = match open_dir_at(Some(&dir), &name) {
+            Ok(next) => next,
+            Err(Errno::ENOENT) if create => {
+                nix::sys::stat::mkdirat(
+                    Some(dir.as_raw_fd()),
+                    name.as_c_str(),
+                    Mode::from_bits_truncate(0o755),
+                )
+                .with_context(|| format!("Failed to create directory {:?}", current))?;
+                nix::unistd::fchownat(
+                    Some(dir.as_raw_fd()),
+                    name.as_c_str(),
+                    Some(Uid::from_raw(user.uid)),
+                    Some(Gid::from_raw(user.gid)),
+                    nix::fcntl::AtFlags::AT_SYMLINK_NOFOLLOW,
+                )
+                .with_context(|| format!("Failed to change ownership of {:?}", current))?;
+                open_dir_at(Some(&dir), &name)
+                    .with_context(|| format!("Failed to open directory {:?}", current))?
+            }
+            Err(Errno::ENOENT) => return Ok(()),
+            Err(Errno::ELOOP) | Err(Errno::ENOTDIR) => bail!(
+                "Refusing to access {:?}: {:?} is a symbolic link or not a directory",
+                home.join(relative),
+                current
+            ),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to open directory {:?}", current))
+            }
+        };
+    }
+    Ok(())
+}
+
+/// Creates a folder and its parents.
+///
+/// Inside the home directory of the target user, no symbolic link is followed and the created
+/// folders are handed over to the user. Folders which existed before keep their owner. Everywhere
+/// else, this is the same as [std::fs::create_dir_all].
+///
+/// # Arguments
+///
+/// * `path` - The folder to create
+///
+/// # Returns
+///
+/// A Result indicating success, or an error if a folder could not be created or a parent is a
+/// symbolic link
+pub(crate) fn create_dir_all(path: &Path) -> Result<()> {
+    match below_home(path) {
+        Some((user, home, relative)) => walk(&user, &home, &relative, true),
+        None => std::fs::create_dir_all(path)
+            .with_context(|| format!("Failed to create directory {:?}", path)),
+    }
+}
+
+/// Checks that neither `path` nor a folder leading to it inside the home directory of the target
+/// user is a symbolic link.
+///
+/// Does nothing if modules are deployed for the user running dotdeploy or `path` is outside of the
+/// home directory.
+///
+/// # Arguments
+///
+/// * `path` - A file or folder inside the home directory of the user
+///
+/// # Returns
+///
+/// A Result indicating success, or an error if `path` or one of its parents is a symbolic link
+pub(crate) fn check_path(path: &Path) -> Result<()> {
+    let Some((user, home, relative)) = below_home(path) else {
+        return Ok(());
+    };
+    if let Some(parent) = relative.parent() {
+        walk(&user, &home, parent, false)?;
+    }
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+        bail!("Refusing to access {:?}: it is a symbolic link", path);
+    }
+    Ok(())
+}
+
+/// Returns the flags for opening files which are written as root.
+///
+/// Files in the home directory of the target user are never opened through a symbolic link.
+pub(crate) fn open_flags() -> i32 {
+    match get() {
+        Some(_) => nix::libc::O_NOFOLLOW,
+        None => 0,
+    }
+}
+
+/// Changes the ownership of the entries of a folder recursively, without following links.
+fn chown_entries(dir: &OwnedFd, path: &Path, uid: Uid, gid: Gid) -> Result<()> {
+    let mut entries = nix::dir::Dir::from_fd(nix::unistd::dup(dir.as_raw_fd())?)
+        .with_context(|| format!("Failed to read directory {:?}", path))?;
+    let names: Vec<(CString, Option<nix::dir::Type>)> = entries
+        .iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| ![&b"."[..], &b".."[..]].contains(&e.file_name().to_bytes()))
+        .map(|e| (e.file_name().to_owned(), e.file_type()))
+        .collect();
+
+    for (name, file_type) in names.into_iter() {
+        let entry_path = path.join(std::ffi::OsStr::from_bytes(name.as_bytes()));
+        nix::unistd::fchownat(
+            Some(dir.as_raw_fd()),
+            name.as_c_str(),
+            Some(uid),
+            Some(gid),
+            nix::fcntl::AtFlags::AT_SYMLINK_NOFOLLOW,
+        )
+        .with_context(|| format!("Failed to change ownership of {:?}", entry_path))?;
+        if matches!(file_type, Some(nix::dir::Type::Directory) | None) {
+            match open_dir_at(Some(dir), &name) {
+                Ok(sub) => chown_entries(&sub, &entry_path, uid, gid)?,
+                // Not a directory or replaced by a link in the meantime
+                Err(Errno::ENOTDIR) | Err(Errno::ELOOP) => (),
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to open directory {:?}", entry_path))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hands a folder of dotdeploy in the home directory of the user over to the user.
+fn hand_over_tree(user: &TargetUser, home: &Path, relative: &Path) -> Result<()> {
+    let path = home.join(relative);
+    check_path(&path)?;
+    let Some(name) = relative.file_name() else {
+        return Ok(());
+    };
+    let parent = match relative.parent() {
+        Some(p) if !p.as_os_str().is_empty() => home.join(p),
+        _ => home.to_path_buf(),
+    };
+    let parent = OwnedFd::from(
+        std::fs::File::open(&parent).with_context(|| format!("Failed to open {:?}", parent))?,
+    );
+    let name = CString::new(name.as_bytes())?;
+    let dir = match open_dir_at(Some(&parent), &name) {
+        Ok(dir) => dir,
+        Err(Errno::ENOENT) => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open directory {:?}", path)),
+    };
+    let (uid, gid) = (Uid::from_raw(user.uid), Gid::from_raw(user.gid));
+    nix::unistd::fchown(dir.as_raw_fd(), Some(uid), Some(gid))
+        .with_context(|| format!("Failed to change ownership of {:?}", path))?;
+    chown_entries(&dir, &path, uid, gid)
+}
+
+/// Hands the folders dotdeploy wrote as root over to the user modules were deployed for.
+///
+/// These are the user store, the run state and the caches, as long as they are inside the home
+/// directory of the user. Does nothing if modules were deployed for the user running dotdeploy.
+///
+/// # Arguments
+///
+/// * `dotdeploy_config` - Configuration providing `state_dir` and `cache_dir`
+///
+/// # Returns
+///
+/// A Result indicating success or failure of changing the ownership
+pub(crate) fn restore_ownership(dotdeploy_config: &crate::config::DotdeployConfig) -> Result<()> {
+    for dir in [
+        crate::target_root::rebase(crate::store::init::user_store_path()),
+        crate::target_root::rebase(&dotdeploy_config.state_dir),
+        crate::target_root::rebase(&dotdeploy_config.cache_dir),
+    ] {
+        if let Some((user, home, relative)) = below_home(&dir) {
+            hand_over_tree(&user, &home, &relative)?;
+        }
+    }
+    Ok(())
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() -> Result<()> {
+        let root = lookup("root")?;
+        assert_eq!(root.uid, 0);
+        assert_eq!(root.gid, 0);
+        assert_eq!(root.home, PathBuf::from("/root"));
+
+        assert!(lookup("dotdeploy-no-such-user").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let home = temp_dir.path();
+        let user = TargetUser {
+            uid: nix::unistd::getuid().as_raw(),
+            gid: nix::unistd::getgid().as_raw(),
+            home: home.to_path_buf(),
+            ..lookup("root")?
+        };
+
+        // Missing folders are created
+        walk(&user, home, Path::new(".config/foo"), true)?;
+        assert!(home.join(".config/foo").is_dir());
+
+        // Symbolic links are refused
+        std::os::unix::fs::symlink(temp_dir.path().join(".config"), home.join("link"))?;
+        assert!(walk(&user, home, Path::new("link/bar"), true).is_err());
+        assert!(!home.join(".config/bar").exists());
+        assert!(walk(&user, home, Path::new("link"), false).is_err());
+
+        // Missing folders are not created without create
+        walk(&user, home, Path::new("missing/bar"), false)?;
+        assert!(!home.join("missing").exists());
+
+        Ok(())
+    }
+}
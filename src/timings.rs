@@ -0,0 +1,245 @@
+//! This module measures where the time of a deployment is spent.
+//!
+//! With `dotdeploy deploy --timings`, the time spent building the module queue, rendering
+//! templates, writing files, installing packages and running tasks is collected per module and
+//! printed as a breakdown at the end of the run. Operations of a module run concurrently, thus the
+//! times are cumulative and may exceed the total duration of the run.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+/// The measured parts of a deployment.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Category {
+    /// Reading the module configs and planning the phases
+    Queue,
+    /// Rendering templates of files
+    Render,
+    /// File operations, without rendering templates
+    Files,
+    /// Installing packages
+    Packages,
+    /// Running actions and hooks
+    Tasks,
+}
+
+impl std::fmt::Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Category::Queue => "queue",
+            Category::Render => "render",
+            Category::Files => "files",
+            Category::Packages => "packages",
+            Category::Tasks => "tasks",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+lazy_static! {
+    /// Collected times by category and module, set once timings are enabled.
+    static ref TIMINGS: Mutex<Option<BTreeMap<(Category, String), Duration>>> = Mutex::new(None);
+    /// Time spent on the operations of each module, always collected for the statistics.
+    static ref MODULE_TIMES: Mutex<BTreeMap<String, Duration>> = Mutex::new(BTreeMap::new());
+}
+
+tokio::task_local! {
+    /// The module of the operation running in the current task.
+    static MODULE: String;
+}
+
+/// Starts collecting timings.
+pub(crate) fn enable() {
+    *TIMINGS.lock().unwrap() = Some(BTreeMap::new());
+}
+
+/// Adds the time elapsed since `start` to a category.
+///
+/// The time of file operations and tasks is always added to the total of their module, see
+/// [take_module_times]. The breakdown by category is only kept once timings are enabled.
+///
+/// # Arguments
+///
+/// * `category` - The measured part of the deployment
+/// * `module` - The module the time is spent on. Defaults to the module of the current task, see
+///   [scope]. Times which do not belong to a module are collected under `-`.
+/// * `start` - Start of the measured operation
+pub(crate) fn record(category: Category, module: Option<&str>, start: Instant) {
+    let elapsed = start.elapsed();
+    let module = match module {
+        Some(m) => Some(m.to_string()),
+        None => MODULE.try_with(|m| m.clone()).ok(),
+    };
+
+    // Rendering is part of the file operations and would be counted twice
+    if let Some(m) = module.as_ref() {
+        if matches!(category, Category::Files | Category::Tasks) {
+            *MODULE_TIMES.lock().unwrap().entry(m.clone()).or_default() += elapsed;
+        }
+    }
+
+    if let Some(timings) = TIMINGS.lock().unwrap().as_mut() {
+        let module = module.unwrap_or_else(|| "-".to_string());
+        *timings.entry((category, module)).or_default() += elapsed;
+    }
+}
+
+/// Removes and returns the time spent on the operations of the given modules.
+///
+/// Operations of a module run concurrently, thus the time is cumulative and may exceed the wall
+/// clock time of the module.
+///
+/// # Arguments
+///
+/// * `modules` - Names of the modules
+///
+/// # Returns
+///
+/// The time of each module, modules without recorded operations are missing
+pub(crate) fn take_module_times(modules: &[String]) -> BTreeMap<String, Duration> {
+    let mut times = MODULE_TIMES.lock().unwrap();
+    modules
+        .iter()
+        .filter_map(|m| Some((m.clone(), times.remove(m)?)))
+        .collect()
+}
+
+/// Runs an operation of a module, attributing the times recorded by it to the module.
+pub(crate) async fn scope<F: Future>(module: String, f: F) -> F::Output {
+    MODULE.scope(module, f).await
+}
+
+/// Time spent on a part of the deployment of a module.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub(crate) struct TimingEntry {
+    pub(crate) category: Category,
+    pub(crate) module: String,
+    pub(crate) duration_ms: u128,
+}
+
+/// Returns the collected timings, or None unless timings are enabled.
+///
+/// Entries are ordered by category and module. The time of file operations excludes the time
+/// spent rendering their templates.
+pub(crate) fn collect() -> Option<Vec<TimingEntry>> {
+    let timings = TIMINGS.lock().unwrap().clone()?;
+    Some(
+        timings
+            .iter()
+            .map(|((category, module), duration)| {
+                let duration = match category {
+                    Category::Files => duration.saturating_sub(
+                        timings
+                            .get(&(Category::Render, module.clone()))
+                            .copied()
+                            .unwrap_or_default(),
+                    ),
+                    _ => *duration,
+                };
+                TimingEntry {
+                    category: *category,
+                    module: module.clone(),
+                    duration_ms: duration.as_millis(),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Prints a breakdown of the collected timings.
+///
+/// # Arguments
+///
+/// * `timings` - The collected timings, see [collect]
+/// * `total_ms` - Total duration of the run in milliseconds
+pub(crate) fn print(timings: &[TimingEntry], total_ms: u128) {
+    println!("{:<10}  {:<30}  {:>10}", "PHASE", "MODULE", "TIME (ms)");
+    let mut last = None;
+    for t in timings.iter() {
+        if last.is_some_and(|c| c != t.category) {
+            println!();
+        }
+        last = Some(t.category);
+        println!(
+            "{:<10}  {:<30}  {:>10}",
+            t.category.to_string(),
+            t.module,
+            t.duration_ms
+        );
+    }
+
+    println!();
+    let mut totals: BTreeMap<Category, u128> = BTreeMap::new();
+    for t in timings.iter() {
+        *totals.entry(t.category).or_default() += t.duration_ms;
+    }
+    for (category, ms) in totals.iter() {
+        println!("{:<10}  {:<30}  {:>10}", category.to_string(), "(all)", ms);
+    }
+    println!("{:<10}  {:<30}  {:>10}", "total", "(wall clock)", total_ms);
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_timings() {
+        assert!(collect().is_none());
+        enable();
+
+        let start = Instant::now() - Duration::from_millis(50);
+        scope("zsh".to_string(), async {
+            record(Category::Files, None, start);
+            record(
+                Category::Render,
+                None,
+                Instant::now() - Duration::from_millis(20),
+            );
+        })
+        .await;
+        record(Category::Packages, None, start);
+
+        // Other tests may record times concurrently
+        let timings = collect().unwrap();
+        let get = |category, module: &str| {
+            timings
+                .iter()
+                .find(|t| t.category == category && t.module == module)
+                .map(|t| t.duration_ms)
+        };
+        assert!(get(Category::Render, "zsh").is_some_and(|ms| ms >= 20));
+        // Rendering is not counted twice
+        assert!(get(Category::Files, "zsh").is_some_and(|ms| (30..50).contains(&ms)));
+        assert!(get(Category::Packages, "-").is_some_and(|ms| ms >= 50));
+        assert!(get(Category::Files, "-").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_module_times() {
+        let start = Instant::now() - Duration::from_millis(50);
+        scope("test_module_times".to_string(), async {
+            record(Category::Files, None, start);
+            // Part of the file operation
+            record(Category::Render, None, start);
+        })
+        .await;
+        record(Category::Tasks, Some("test_module_times"), start);
+
+        let times = take_module_times(&["test_module_times".to_string(), "missing".to_string()]);
+        assert_eq!(times.len(), 1);
+        let time = times["test_module_times"];
+        assert!(time >= Duration::from_millis(100) && time < Duration::from_millis(150));
+        // Times are taken only once
+        assert!(take_module_times(&["test_module_times".to_string()]).is_empty());
+    }
+}
@@ -5,7 +5,16 @@
 
 pub(crate) mod common;
 pub(crate) mod file_checksum;
+pub(crate) mod file_filter;
 pub(crate) mod file_fs;
+pub(crate) mod file_merge;
 pub(crate) mod file_metadata;
 pub(crate) mod file_permissions;
+pub(crate) mod journal;
+pub(crate) mod lock;
+pub(crate) mod platform;
+pub(crate) mod progress;
+pub(crate) mod render_cache;
+pub(crate) mod signal;
 pub(crate) mod sudo;
+pub(crate) mod task_log;
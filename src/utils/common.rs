@@ -1,10 +1,12 @@
 //! Common utility functions module.
 //!
 //! This module provides utility functions that are commonly used across the project. Currently, it
-//! includes functionality for user interaction, specifically for asking yes/no questions to the
-//! user via the command line.
+//! includes functionality for user interaction, specifically for asking yes/no questions and
+//! single choice questions to the user via the command line.
 
-use std::io::{stdin, stdout, Write};
+use std::io::{stdin, stdout, IsTerminal, Write};
+
+use anyhow::{bail, Context, Result};
 
 /// Asks the user for a yes/no confirmation.
 ///
@@ -23,7 +25,7 @@ use std::io::{stdin, stdout, Write};
 ///
 /// # Examples
 ///
-/// ```
+/// ```ignore
 /// let result = ask_boolean("Do you want to continue? [y/N] ");
 /// if result {
 ///     println!("User chose to continue");
@@ -55,3 +57,108 @@ pub(crate) fn ask_boolean(prompt: &str) -> bool {
     // Note: An empty input (just pressing Enter) defaults to 'no'
     buf.to_lowercase().starts_with('y')
 }
+
+/// Asks the user to pick one of several single character choices.
+///
+/// The prompt is repeated until the response starts with one of the characters in `choices`. An
+/// empty input selects the first choice.
+///
+/// # Arguments
+///
+/// * `prompt` - A string slice that holds the question to be asked to the user.
+/// * `choices` - The valid choices as lowercase characters, the first one being the default.
+///
+/// # Returns
+///
+/// * `char` - The selected choice.
+pub(crate) fn ask_choice(prompt: &str, choices: &str) -> char {
+    let default = choices
+        .chars()
+        .next()
+        .expect("At least one choice must be given");
+    loop {
+        eprintln!("{}", prompt);
+        stdout().flush().expect("Failed to flush stdout");
+
+        let mut buf = String::new();
+        stdin()
+            .read_line(&mut buf)
+            .expect("Failed to read line from stdin");
+
+        match buf.trim().to_lowercase().chars().next() {
+            None => return default,
+            Some(c) if choices.contains(c) => return c,
+            Some(_) => continue,
+        }
+    }
+}
+
+/// Asks the user to pick one of several options by its number.
+///
+/// The options are listed below the prompt, which is repeated until a valid number is given.
+///
+/// # Arguments
+///
+/// * `prompt` - A string slice that holds the question to be asked to the user.
+/// * `options` - The options to choose from, must not be empty.
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The zero based index of the selected option.
+/// * `Err` - If stdin is not a terminal or reached its end, as there is nobody to answer.
+pub(crate) fn ask_index(prompt: &str, options: &[String]) -> Result<usize> {
+    if !stdin().is_terminal() {
+        bail!("Can not ask for a choice, stdin is not a terminal");
+    }
+    loop {
+        eprintln!("{}", prompt);
+        for (i, option) in options.iter().enumerate() {
+            eprintln!("{:>3}) {}", i + 1, option);
+        }
+        stdout().flush().expect("Failed to flush stdout");
+
+        let mut buf = String::new();
+        if stdin()
+            .read_line(&mut buf)
+            .context("Failed to read line from stdin")?
+            == 0
+        {
+            bail!("Can not ask for a choice, stdin is closed");
+        }
+
+        match buf.trim().parse::<usize>() {
+            Ok(n) if n >= 1 && n <= options.len() => return Ok(n - 1),
+            _ => continue,
+        }
+    }
+}
+
+/// Computes the Levenshtein distance between two strings.
+///
+/// The distance is the minimal number of single character insertions, deletions or substitutions
+/// needed to turn `a` into `b`.
+///
+/// # Arguments
+///
+/// * `a` - The first string.
+/// * `b` - The second string.
+///
+/// # Returns
+///
+/// * `usize` - The edit distance.
+pub(crate) fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances of the previous row, starting with the empty prefix of `a`
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+
+    prev[b.len()]
+}
@@ -11,6 +11,7 @@ use anyhow::{anyhow, bail, Context, Result};
 use sha2::{Digest, Sha256};
 use tokio::fs;
 
+use crate::utils::file_fs;
 use crate::utils::sudo;
 
 /// Calculates the SHA256 checksum of a file, elevating privileges if necessary.
@@ -29,7 +30,7 @@ use crate::utils::sudo;
 ///
 /// # Examples
 ///
-/// ```no_run
+/// ```ignore
 /// use std::path::Path;
 ///
 /// #[tokio::main]
@@ -45,15 +46,9 @@ pub(crate) async fn calculate_sha256_checksum<P: AsRef<Path>>(path: P) -> Result
         Ok(content) => {
             // If successful, perform the hashing in a blocking task. This prevents blocking the
             // async executor with CPU-intensive work
-            tokio::task::spawn_blocking(move || {
-                let mut hasher = Sha256::new();
-                hasher.update(&content);
-                // Convert the hash to a hexadecimal string
-                format!("{:x}", hasher.finalize())
-            })
-            .await?
+            tokio::task::spawn_blocking(move || calculate_sha256_checksum_bytes(&content)).await?
         }
-        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+        Err(e) if file_fs::retry_with_sudo(&e, &path) => {
             // If permission is denied, attempt to calculate checksum using sudo
             let output = sudo::sudo_exec_output("sha256sum", &[path.as_ref()], None)
                 .await?
@@ -79,6 +74,61 @@ pub(crate) async fn calculate_sha256_checksum<P: AsRef<Path>>(path: P) -> Result
     Ok(checksum)
 }
 
+/// Calculates a SHA256 checksum over a whole directory tree.
+///
+/// The checksum covers the relative paths of all entries, the content of regular files and the
+/// targets of symbolic links, so it changes whenever a file is added, removed, renamed or
+/// modified.
+///
+/// # Arguments
+///
+/// * `path` - The directory for which to calculate the checksum.
+///
+/// # Returns
+///
+/// * `Ok(String)` - The SHA256 checksum of the tree as a hexadecimal string.
+/// * `Err` - If the tree could not be read.
+pub(crate) async fn calculate_dir_checksum<P: AsRef<Path>>(path: P) -> Result<String> {
+    let root = path.as_ref().to_path_buf();
+    tokio::task::spawn_blocking(move || -> Result<String> {
+        let mut hasher = Sha256::new();
+        for entry in file_fs::read_tree(&root)? {
+            let file_type = entry.symlink_metadata()?.file_type();
+            hasher.update(entry.strip_prefix(&root)?.as_os_str().as_encoded_bytes());
+            if file_type.is_symlink() {
+                hasher.update(b"\0link\0");
+                hasher.update(std::fs::read_link(&entry)?.as_os_str().as_encoded_bytes());
+            } else if file_type.is_file() {
+                hasher.update(b"\0file\0");
+                hasher.update(
+                    std::fs::read(&entry).with_context(|| format!("Failed to read {:?}", entry))?,
+                );
+            } else {
+                hasher.update(b"\0dir\0");
+            }
+            hasher.update(b"\n");
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await?
+}
+
+/// Calculates the SHA256 checksum of a byte slice.
+///
+/// # Arguments
+///
+/// * `content` - The bytes for which to calculate the checksum.
+///
+/// # Returns
+///
+/// The SHA256 checksum as a hexadecimal string.
+pub(crate) fn calculate_sha256_checksum_bytes(content: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(content);
+    // Convert the hash to a hexadecimal string
+    format!("{:x}", hasher.finalize())
+}
+
 //
 // Tests
 
@@ -107,4 +157,35 @@ mod tests {
         assert_eq!(checksum, checksum_sudo);
         Ok(())
     }
+
+    #[test]
+    fn test_calculate_sha256_checksum_bytes() {
+        assert_eq!(
+            calculate_sha256_checksum_bytes(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_calculate_dir_checksum() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        std::fs::create_dir(temp_dir.path().join("sub"))?;
+        std::fs::write(temp_dir.path().join("sub/file.txt"), "foo")?;
+        let checksum = calculate_dir_checksum(temp_dir.path()).await?;
+        assert_eq!(calculate_dir_checksum(temp_dir.path()).await?, checksum);
+
+        // Modified, added and renamed files change the checksum
+        std::fs::write(temp_dir.path().join("sub/file.txt"), "bar")?;
+        let modified = calculate_dir_checksum(temp_dir.path()).await?;
+        assert_ne!(modified, checksum);
+        std::fs::write(temp_dir.path().join("new.txt"), "")?;
+        let added = calculate_dir_checksum(temp_dir.path()).await?;
+        assert_ne!(added, modified);
+        std::fs::rename(
+            temp_dir.path().join("new.txt"),
+            temp_dir.path().join("old.txt"),
+        )?;
+        assert_ne!(calculate_dir_checksum(temp_dir.path()).await?, added);
+        Ok(())
+    }
 }
@@ -0,0 +1,107 @@
+//! Content filters of files.
+//!
+//! Filters are shell commands which transform the content of a file before it is deployed, e.g.
+//! `sops -d` to decrypt it or `envsubst` to substitute environment variables. The content is passed
+//! on stdin and replaced by the stdout of the command. Multiple filters form a pipeline.
+
+use std::process::Stdio;
+
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Pipes content through a single filter command.
+async fn run_filter(content: Vec<u8>, filter: &str) -> Result<Vec<u8>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(filter)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run filter {:?}", filter))?;
+
+    // Write stdin concurrently, the filter might not read all of it before writing its output
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("Failed to open stdin of filter {:?}", filter))?;
+    let writer = tokio::spawn(async move {
+        let result = stdin.write_all(&content).await;
+        drop(stdin);
+        result
+    });
+
+    let output = child
+        .wait_with_output()
+        .await
+        .with_context(|| format!("Failed to run filter {:?}", filter))?;
+    // A filter which ignores its input may close stdin early
+    if let Err(e) = writer.await? {
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            return Err(e).with_context(|| format!("Failed to write to filter {:?}", filter));
+        }
+    }
+
+    if !output.status.success() {
+        bail!(
+            "Filter {:?} exited with {}: {}",
+            filter,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Pipes content through a list of filter commands.
+///
+/// Each command is run with `sh -c`, receiving the output of the previous one on stdin.
+///
+/// # Arguments
+///
+/// * `content` - The original content
+/// * `filters` - The filter commands, applied in order
+///
+/// # Returns
+///
+/// A Result containing the filtered content, or an error if a filter fails or its output is not
+/// valid UTF-8.
+pub(crate) async fn apply_filters(content: Vec<u8>, filters: &[String]) -> Result<String> {
+    let mut content = content;
+    for filter in filters.iter() {
+        content = run_filter(content, filter).await?;
+    }
+    String::from_utf8(content).context("Output of filters is not valid UTF-8")
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_apply_filters() -> Result<()> {
+        let content = b"hello world\n".to_vec();
+        let filters = vec!["tr a-z A-Z".to_string(), "sed 's/WORLD/THERE/'".to_string()];
+        assert_eq!(
+            apply_filters(content.clone(), &filters).await?,
+            "HELLO THERE\n"
+        );
+
+        // Without filters, the content is unchanged
+        assert_eq!(apply_filters(content.clone(), &[]).await?, "hello world\n");
+
+        // Filters ignoring their input work as well
+        let filters = vec!["echo foo".to_string()];
+        assert_eq!(apply_filters(content.clone(), &filters).await?, "foo\n");
+
+        let filters = vec!["echo failed >&2; exit 2".to_string()];
+        let e = apply_filters(content, &filters).await.unwrap_err();
+        assert!(format!("{:#}", e).contains("failed"));
+
+        Ok(())
+    }
+}
@@ -5,6 +5,8 @@
 //! functionality to elevate privileges when necessary, using sudo for operations that might require
 //! higher permissions.
 
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail, Context, Result};
@@ -29,7 +31,7 @@ use crate::utils::sudo;
 ///
 /// # Examples
 ///
-/// ```
+/// ```ignore
 /// use std::path::Path;
 /// let path_str = path_to_string(Path::new("/some/path"))?;
 /// ```
@@ -48,6 +50,44 @@ pub(crate) fn path_to_string<P: AsRef<Path>>(path: P) -> Result<String> {
     Ok(path_str)
 }
 
+/// Checks whether an operation on a path, which failed with the given error, is retried with sudo.
+///
+/// Only permission errors are retried, and only for paths where privileges may be elevated, see
+/// [sudo::may_escalate]. Permission errors inside HOME are returned to the caller instead.
+///
+/// # Arguments
+///
+/// * `e` - The error the operation failed with.
+/// * `path` - The path the operation was performed on.
+pub(crate) fn retry_with_sudo<P: AsRef<Path>>(e: &std::io::Error, path: P) -> bool {
+    e.kind() == std::io::ErrorKind::PermissionDenied && sudo::may_escalate(path)
+}
+
+/// Checks whether the current user can create or replace a file without elevated privileges.
+///
+/// This is the case if the user may write to the parent directory or, if it does not exist yet,
+/// to its closest existing ancestor.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file.
+pub(crate) fn is_writable<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .ancestors()
+        .skip(1)
+        .find(|p| p.exists())
+        .is_some_and(|p| nix::unistd::access(p, nix::unistd::AccessFlags::W_OK).is_ok())
+}
+
+/// Checks whether a file has to be created or replaced with sudo.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file.
+pub(crate) fn needs_sudo<P: AsRef<Path>>(path: P) -> bool {
+    !is_writable(&path) && sudo::may_escalate(&path)
+}
+
 /// Checks if a file exists, using sudo if necessary due to permission issues.
 ///
 /// This function attempts to check file existence normally first, and if a permission error is
@@ -65,7 +105,7 @@ pub(crate) async fn check_file_exists<P: AsRef<Path>>(path: P) -> Result<bool> {
     match path.as_ref().try_exists() {
         Ok(false) => Ok(false),
         Ok(true) => Ok(true),
-        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+        Err(e) if retry_with_sudo(&e, &path) => {
             // If permission is denied, try using sudo
             Ok(sudo::sudo_exec_success("test", &["-e", &path_to_string(path)?], None).await?)
         }
@@ -104,7 +144,7 @@ pub(crate) async fn check_link_exists<P: AsRef<Path>>(path: P, source: Option<P>
             }
         }
 
-        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+        Err(e) if retry_with_sudo(&e, &path) => {
             // If permission is denied, use sudo for the check
             if let Some(s) = source {
                 if sudo::sudo_exec_success("test", &["-L", &path_to_string(&path)?], None).await? {
@@ -129,6 +169,40 @@ pub(crate) async fn check_link_exists<P: AsRef<Path>>(path: P, source: Option<P>
     }
 }
 
+/// Checks if a hard link to `source` exists at `path`.
+///
+/// Both paths have to refer to the same inode. A hard link breaks if either file is replaced
+/// instead of being modified in place, as many editors do when saving.
+///
+/// # Arguments
+///
+/// * `path` - The path of the potential hard link.
+/// * `source` - The file the hard link should point to.
+///
+/// # Returns
+///
+/// * `Ok(bool)` - True if both paths refer to the same file.
+/// * `Err` - If an error occurs during the check.
+pub(crate) async fn check_hardlink_exists<P: AsRef<Path>>(path: P, source: P) -> Result<bool> {
+    let source_meta = fs::metadata(&source)
+        .await
+        .with_context(|| format!("Failed to get metadata of {:?}", source.as_ref()))?;
+
+    match fs::symlink_metadata(&path).await {
+        Ok(meta) => Ok(meta.dev() == source_meta.dev() && meta.ino() == source_meta.ino()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) if retry_with_sudo(&e, &path) => Ok(sudo::sudo_exec_success(
+            "test",
+            &[&path_to_string(&path)?, "-ef", &path_to_string(&source)?],
+            None,
+        )
+        .await?),
+        Err(e) => {
+            Err(e).with_context(|| format!("Falied to check existence of {:?}", &path.as_ref()))?
+        }
+    }
+}
+
 /// Reads all files in a directory recursively.
 ///
 /// This function traverses the given directory and all its subdirectories, collecting the paths of
@@ -157,6 +231,156 @@ pub(crate) fn read_directory<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
     Ok(files)
 }
 
+/// Lists all entries of a directory tree.
+///
+/// Unlike [read_directory], directories and symbolic links are included as well. Symbolic links
+/// are not followed. Entries are sorted, and each directory precedes its content.
+///
+/// # Arguments
+///
+/// * `path` - The path of the directory to read.
+///
+/// # Returns
+///
+/// * `Ok(Vec<PathBuf>)` - The paths of all entries, excluding `path` itself.
+/// * `Err` - If an error occurs during the operation.
+pub(crate) fn read_tree<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+    let mut entries = std::fs::read_dir(&path)
+        .with_context(|| format!("Failed to read directory {:?}", path.as_ref()))?
+        .map(|e| Ok(e?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort();
+
+    let mut tree = Vec::new();
+    for entry in entries {
+        let is_dir = entry.symlink_metadata()?.is_dir();
+        tree.push(entry.clone());
+        if is_dir {
+            tree.extend(read_tree(&entry)?);
+        }
+    }
+    Ok(tree)
+}
+
+/// Copies a directory tree without elevated privileges.
+///
+/// Symbolic links are recreated instead of being followed. The destination must not exist yet.
+///
+/// # Arguments
+///
+/// * `source` - The directory to copy.
+/// * `dest` - The path of the copy.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the tree was copied.
+/// * `Err` - If an error occurs during the operation.
+pub(crate) fn copy_tree<P: AsRef<Path>>(source: P, dest: P) -> Result<()> {
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    std::fs::create_dir(dest).with_context(|| format!("Failed to create {:?}", dest))?;
+
+    for entry in read_tree(source)? {
+        let target = dest.join(entry.strip_prefix(source)?);
+        let file_type = entry.symlink_metadata()?.file_type();
+        let copied = if file_type.is_dir() {
+            std::fs::create_dir(&target)
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(&entry)?, &target)
+        } else {
+            reflink_or_copy(&entry, &target)
+        };
+        copied.with_context(|| format!("Failed to copy {:?} to {:?}", entry, target))?;
+    }
+    Ok(())
+}
+
+/// Copies the content and permissions of a file, preferring a reflink.
+fn reflink_or_copy(source: &Path, dest: &Path) -> std::io::Result<()> {
+    let mut src = std::fs::File::open(source)?;
+    let permissions = src.metadata()?.permissions();
+    let mut dst = std::fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(crate::target_user::open_flags())
+        .open(dest)?;
+
+    // SAFETY: Both file descriptors stay open for the duration of the call
+    let cloned =
+        unsafe { nix::libc::ioctl(dst.as_raw_fd(), nix::libc::FICLONE as _, src.as_raw_fd()) };
+    if cloned != 0 {
+        // Not supported by the file system, let the kernel copy the data instead. This falls back
+        // to a byte copy where copy_file_range is not available, e.g. across file systems on older
+        // kernels. As the file offsets advance, a partial in-kernel copy is continued correctly.
+        loop {
+            match nix::fcntl::copy_file_range(&src, None, &dst, None, 1 << 30) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(_) => {
+                    std::io::copy(&mut src, &mut dst)?;
+                    break;
+                }
+            }
+        }
+    }
+
+    dst.set_permissions(permissions)
+}
+
+/// Copies a file, sharing its data with the source if the file system supports it.
+///
+/// On btrfs and XFS, the copy is created as a reflink with the `FICLONE` ioctl, which is nearly
+/// instant regardless of the file size. Otherwise the data is copied with `copy_file_range` or,
+/// if that is not available either, with a plain byte copy. An existing file at `dest` is
+/// overwritten.
+///
+/// # Arguments
+///
+/// * `source` - The file to copy.
+/// * `dest` - The path of the copy.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the file was copied.
+/// * `Err` - If an error occurs during the operation.
+pub(crate) async fn copy_file<P: AsRef<Path>>(source: P, dest: P) -> Result<()> {
+    let (source, dest) = (source.as_ref().to_path_buf(), dest.as_ref().to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        reflink_or_copy(&source, &dest)
+            .with_context(|| format!("Failed to copy {:?} to {:?}", source, dest))
+    })
+    .await?
+}
+
+/// Writes a file, replacing its content.
+///
+/// Symbolic links in the home directory of another user are not followed, see
+/// [crate::target_user].
+///
+/// # Arguments
+///
+/// * `path` - The path of the file.
+/// * `content` - The new content of the file.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the file was written.
+/// * `Err` - If an error occurs during the operation.
+pub(crate) async fn write_file<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    content: C,
+) -> std::io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(crate::target_user::open_flags())
+        .open(path)
+        .await?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, content.as_ref()).await?;
+    tokio::io::AsyncWriteExt::flush(&mut file).await
+}
+
 /// Ensures that a directory exists, creating it if necessary, using sudo if needed.
 ///
 /// This function attempts to create a directory and all its parent directories. If a permission
@@ -171,9 +395,15 @@ pub(crate) fn read_directory<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
 /// * `Ok(())` - If the directory exists or was successfully created.
 /// * `Err` - If an error occurs during the operation.
 pub(crate) async fn ensure_dir_exists<P: AsRef<Path>>(path: P) -> Result<()> {
+    // Folders in the home directory of another user are created without following links
+    if crate::target_user::get().is_some() {
+        let path = path.as_ref().to_path_buf();
+        return tokio::task::spawn_blocking(move || crate::target_user::create_dir_all(&path))
+            .await?;
+    }
     match fs::create_dir_all(&path).await {
         Ok(_) => Ok(()),
-        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+        Err(e) if retry_with_sudo(&e, &path) => {
             // If permission is denied, use sudo to create the directory
             Ok(sudo::sudo_exec("mkdir", &["-p", &path_to_string(&path)?], None).await?)
         }
@@ -197,7 +427,7 @@ pub(crate) async fn ensure_dir_exists<P: AsRef<Path>>(path: P) -> Result<()> {
 pub(crate) async fn delete_file<P: AsRef<Path>>(path: P) -> Result<()> {
     match fs::remove_file(&path).await {
         Ok(_) => Ok(()),
-        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+        Err(e) if retry_with_sudo(&e, &path) => {
             Ok(sudo::sudo_exec("rm", &["-f", &path_to_string(&path)?], None).await?)
         }
         Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
@@ -208,6 +438,68 @@ pub(crate) async fn delete_file<P: AsRef<Path>>(path: P) -> Result<()> {
     }
 }
 
+/// Creates a hard link to a file, using sudo if necessary due to permission issues.
+///
+/// Hard links can not cross file systems, so `source` and `dest` have to be on the same one.
+///
+/// # Arguments
+///
+/// * `source` - The file to link to.
+/// * `dest` - The path of the hard link.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the hard link was created.
+/// * `Err` - If an error occurs during the operation.
+pub(crate) async fn hardlink_file<P: AsRef<Path>>(source: P, dest: P) -> Result<()> {
+    match fs::hard_link(&source, &dest).await {
+        Ok(_) => Ok(()),
+        Err(e) if retry_with_sudo(&e, &dest) => Ok(sudo::sudo_exec(
+            "ln",
+            &["-f", &path_to_string(&source)?, &path_to_string(&dest)?],
+            None,
+        )
+        .await?),
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => bail!(
+            "Failed to hard link {:?} to {:?}: they are on different file systems",
+            source.as_ref(),
+            dest.as_ref()
+        ),
+        Err(e) => Err(e).with_context(|| {
+            format!(
+                "Failed to hard link {:?} to {:?}",
+                source.as_ref(),
+                dest.as_ref()
+            )
+        })?,
+    }
+}
+
+/// Deletes a file, a symbolic link or a whole directory tree, using sudo if necessary.
+///
+/// Symbolic links to directories are removed without touching their target.
+///
+/// # Arguments
+///
+/// * `path` - The path to delete.
+///
+/// # Returns
+///
+/// * `Ok(())` - If the path was successfully deleted or didn't exist.
+/// * `Err` - If an error occurs during the deletion.
+pub(crate) async fn delete_path<P: AsRef<Path>>(path: P) -> Result<()> {
+    match fs::symlink_metadata(&path).await {
+        Ok(meta) if meta.is_dir() => match fs::remove_dir_all(&path).await {
+            Ok(_) => Ok(()),
+            Err(e) if retry_with_sudo(&e, &path) => {
+                Ok(sudo::sudo_exec("rm", &["-rf", &path_to_string(&path)?], None).await?)
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to delete {:?}", &path.as_ref()))?,
+        },
+        _ => delete_file(path).await,
+    }
+}
+
 /// Recursively deletes empty parent directories, optionally prompting for confirmation.
 ///
 /// This function walks up the directory tree from the given path, deleting empty directories. It
@@ -235,7 +527,7 @@ pub(crate) async fn delete_parents<P: AsRef<Path>>(path: P, no_ask: bool) -> Res
                 .read_dir()
                 .map(|mut i| i.next().is_none())
                 .unwrap_or(false),
-            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(e) if retry_with_sudo(&e, path) => {
                 // If permission is denied, use sudo to check if the directory is empty
                 let path_str = path_to_string(path)?;
                 let output = sudo::sudo_exec_output(
@@ -265,7 +557,7 @@ pub(crate) async fn delete_parents<P: AsRef<Path>>(path: P, no_ask: bool) -> Res
         {
             match fs::remove_dir(path).await {
                 Ok(_) => (),
-                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                Err(e) if retry_with_sudo(&e, path) => {
                     // If permission is denied, use sudo to remove the directory
                     sudo::sudo_exec("rmdir", &[&path_to_string(path)?], None).await?
                 }
@@ -287,6 +579,7 @@ pub(crate) async fn delete_parents<P: AsRef<Path>>(path: P, no_ask: bool) -> Res
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::os::unix::fs::PermissionsExt;
     use std::path::PathBuf;
 
     #[test]
@@ -517,4 +810,104 @@ mod tests {
         assert!(!&temp_dir.path().exists());
         Ok(())
     }
+
+    #[test]
+    fn test_is_writable() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        assert!(is_writable(temp_dir.path().join("file")));
+        // Missing parents are skipped
+        assert!(is_writable(temp_dir.path().join("a/b/file")));
+
+        std::fs::set_permissions(temp_dir.path(), std::fs::Permissions::from_mode(0o555))?;
+        if !nix::unistd::geteuid().is_root() {
+            assert!(!is_writable(temp_dir.path().join("a/b/file")));
+        }
+        std::fs::set_permissions(temp_dir.path(), std::fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy_and_delete_tree() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("source");
+        std::fs::create_dir_all(source.join("b/c"))?;
+        std::fs::write(source.join("a.txt"), "a")?;
+        std::fs::write(source.join("b/c/d.txt"), "d")?;
+        std::os::unix::fs::symlink("a.txt", source.join("b/link"))?;
+
+        let tree: Vec<_> = read_tree(&source)?
+            .into_iter()
+            .map(|p| p.strip_prefix(&source).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            tree,
+            ["a.txt", "b", "b/c", "b/c/d.txt", "b/link"].map(PathBuf::from)
+        );
+
+        let dest = temp_dir.path().join("dest");
+        copy_tree(&source, &dest)?;
+        assert_eq!(std::fs::read_to_string(dest.join("b/c/d.txt"))?, "d");
+        assert_eq!(
+            std::fs::read_link(dest.join("b/link"))?,
+            PathBuf::from("a.txt")
+        );
+        // The destination must not exist
+        assert!(copy_tree(&source, &dest).is_err());
+
+        // Links to directories are removed without their target
+        let link = temp_dir.path().join("link");
+        std::os::unix::fs::symlink(&dest, &link)?;
+        delete_path(&link).await?;
+        assert!(!link.exists() && dest.exists());
+
+        delete_path(&dest).await?;
+        assert!(!dest.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hardlink_file() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        std::fs::write(&source, "foo")?;
+
+        assert!(!check_hardlink_exists(&dest, &source).await?);
+        hardlink_file(&source, &dest).await?;
+        assert!(check_hardlink_exists(&dest, &source).await?);
+        assert_eq!(std::fs::read_to_string(&dest)?, "foo");
+
+        // Replacing the source breaks the hard link
+        std::fs::remove_file(&source)?;
+        std::fs::write(&source, "bar")?;
+        assert!(!check_hardlink_exists(&dest, &source).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_copy_file() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("source.txt");
+        let dest = temp_dir.path().join("dest.txt");
+        std::fs::write(&source, "foo".repeat(10000))?;
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o640))?;
+
+        // Existing files are overwritten
+        std::fs::write(&dest, "bar".repeat(20000))?;
+        copy_file(&source, &dest).await?;
+        assert_eq!(std::fs::read_to_string(&dest)?, "foo".repeat(10000));
+        assert_eq!(
+            std::fs::metadata(&dest)?.permissions().mode() & 0o777,
+            0o640
+        );
+
+        // The copy is independent of the source
+        std::fs::write(&source, "baz")?;
+        assert_eq!(std::fs::read_to_string(&dest)?, "foo".repeat(10000));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,327 @@
+//! Three-way merge of text files.
+//!
+//! This module implements a line based diff3 merge. Given a common ancestor (the baseline), a
+//! locally modified version and a new version, changes of both sides are combined. Regions changed
+//! differently on both sides are marked with conflict markers, similar to `git merge-file`.
+
+/// Result of a three-way merge.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct MergeResult {
+    /// The merged content, including conflict markers if any
+    pub(crate) content: String,
+    /// Number of conflicting regions
+    pub(crate) conflicts: usize,
+}
+
+/// Maximum number of cells of the table used to compare two files line by line. Time and memory
+/// grow with the product of both line counts, so larger regions are not compared at all.
+const MAX_LCS_CELLS: usize = 4_000_000;
+
+/// Computes the longest common subsequence of two slices of lines.
+///
+/// Lines at the start and end which are equal in both slices always match. If the remaining
+/// regions exceed [MAX_LCS_CELLS], they are treated as completely different, which makes a merge
+/// keep both versions as a conflict.
+///
+/// # Returns
+///
+/// A vector of index pairs `(a, b)` of matching lines, in ascending order.
+fn lcs_matches(a: &[&str], b: &[&str]) -> Vec<(usize, usize)> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (n, m) = (a.len() - prefix - suffix, b.len() - prefix - suffix);
+
+    let mut matches: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    if (n + 1).saturating_mul(m + 1) <= MAX_LCS_CELLS {
+        let (a, b) = (&a[prefix..prefix + n], &b[prefix..prefix + m]);
+        // lengths[i][j] holds the LCS length of a[i..] and b[j..]
+        let mut lengths = vec![vec![0usize; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lengths[i][j] = if a[i] == b[j] {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < n && j < m {
+            if a[i] == b[j] {
+                matches.push((prefix + i, prefix + j));
+                i += 1;
+                j += 1;
+            } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+    }
+    matches.extend((0..suffix).map(|k| (prefix + n + k, prefix + m + k)));
+    matches
+}
+
+/// Merges the changes from `base` to `local` and from `base` to `new`.
+///
+/// # Arguments
+///
+/// * `base` - The common ancestor of both versions
+/// * `local` - The locally modified version
+/// * `new` - The new version
+///
+/// # Returns
+///
+/// A [MergeResult] holding the merged content and the number of conflicts.
+pub(crate) fn merge3(base: &str, local: &str, new: &str) -> MergeResult {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let local: Vec<&str> = local.split_inclusive('\n').collect();
+    let new: Vec<&str> = new.split_inclusive('\n').collect();
+
+    // Map lines of the base to their counterparts in both versions
+    let mut to_local = vec![None; base.len()];
+    for (b, l) in lcs_matches(&base, &local) {
+        to_local[b] = Some(l);
+    }
+    let mut to_new = vec![None; base.len()];
+    for (b, n) in lcs_matches(&base, &new) {
+        to_new[b] = Some(n);
+    }
+
+    let mut result = MergeResult {
+        content: String::new(),
+        conflicts: 0,
+    };
+    let (mut b0, mut l0, mut n0) = (0, 0, 0);
+
+    // Lines unchanged in both versions split the files into chunks which are resolved
+    // independently. A final pseudo anchor flushes the remaining lines.
+    let anchors = (0..base.len())
+        .filter_map(|b| Some((b, to_local[b]?, to_new[b]?)))
+        .chain(std::iter::once((base.len(), local.len(), new.len())));
+
+    for (b, l, n) in anchors {
+        let chunk_base = &base[b0..b];
+        let chunk_local = &local[l0..l];
+        let chunk_new = &new[n0..n];
+
+        if chunk_local == chunk_base || chunk_local == chunk_new {
+            result.content.extend(chunk_new.iter().copied());
+        } else if chunk_new == chunk_base {
+            result.content.extend(chunk_local.iter().copied());
+        } else {
+            result.conflicts += 1;
+            push_conflict(&mut result.content, chunk_base, chunk_local, chunk_new);
+        }
+
+        if b < base.len() {
+            result.content.push_str(base[b]);
+        }
+        (b0, l0, n0) = (b + 1, l + 1, n + 1);
+    }
+
+    result
+}
+
+/// Creates a unified diff between two versions of a file.
+///
+/// Changed lines are prefixed with `-` if they were removed and `+` if they were added. Each hunk
+/// includes up to `context` unchanged lines before and after the changes and starts with a
+/// `@@ -start,len +start,len @@` header.
+///
+/// # Arguments
+///
+/// * `old` - The old version
+/// * `new` - The new version
+/// * `context` - Number of unchanged lines around each change
+///
+/// # Returns
+///
+/// The diff as a string, each line terminated by a newline. Empty if both versions are equal.
+pub(crate) fn diff(old: &str, new: &str, context: usize) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // Edit script as (prefix, old line number, new line number, line)
+    let mut ops = Vec::new();
+    let (mut o0, mut n0) = (0, 0);
+    for (o, n) in lcs_matches(&old, &new)
+        .into_iter()
+        .chain(std::iter::once((old.len(), new.len())))
+    {
+        ops.extend((o0..o).map(|i| ('-', i, n0, old[i])));
+        ops.extend((n0..n).map(|j| ('+', o, j, new[j])));
+        if o < old.len() {
+            ops.push((' ', o, n, old[o]));
+        }
+        (o0, n0) = (o + 1, n + 1);
+    }
+
+    // Group changes which are close to each other into hunks
+    let changes: Vec<usize> = (0..ops.len()).filter(|&i| ops[i].0 != ' ').collect();
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &i in changes.iter() {
+        let start = i.saturating_sub(context);
+        let end = (i + context + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = String::new();
+    for (start, end) in hunks {
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|op| op.0 != '+').count();
+        let new_len = hunk.iter().filter(|op| op.0 != '-').count();
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk[0].1 + 1,
+            old_len,
+            hunk[0].2 + 1,
+            new_len
+        ));
+        for (prefix, _, _, line) in hunk {
+            out.push_str(&format!("{}{}\n", prefix, line));
+        }
+    }
+    out
+}
+
+/// Colorizes a unified diff with ANSI escape codes.
+///
+/// Removed lines are shown in red, added lines in green and hunk headers in cyan.
+pub(crate) fn colorize_diff(diff: &str) -> String {
+    diff.lines()
+        .map(|line| {
+            let color = match line.chars().next() {
+                Some('-') => "\x1b[31m",
+                Some('+') => "\x1b[32m",
+                Some('@') => "\x1b[36m",
+                _ => return format!("{}\n", line),
+            };
+            format!("{}{}\x1b[0m\n", color, line)
+        })
+        .collect()
+}
+
+/// Appends a conflicting region with diff3 style markers.
+fn push_conflict(out: &mut String, base: &[&str], local: &[&str], new: &[&str]) {
+    for (marker, lines) in [
+        ("<<<<<<< local", local),
+        ("||||||| baseline", base),
+        ("=======", new),
+    ] {
+        out.push_str(marker);
+        out.push('\n');
+        for line in lines {
+            out.push_str(line);
+        }
+        // Keep markers on their own line if the last line lacks a newline
+        if lines.last().is_some_and(|l| !l.ends_with('\n')) {
+            out.push('\n');
+        }
+    }
+    out.push_str(">>>>>>> new\n");
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge3_clean() {
+        let base = "a\nb\nc\nd\n";
+        let local = "a\nB\nc\nd\n";
+        let new = "a\nb\nc\nD\n";
+
+        let result = merge3(base, local, new);
+        assert_eq!(result.content, "a\nB\nc\nD\n");
+        assert_eq!(result.conflicts, 0);
+
+        // Identical changes on both sides
+        let result = merge3(base, "a\nx\nc\nd\n", "a\nx\nc\nd\n");
+        assert_eq!(result.content, "a\nx\nc\nd\n");
+        assert_eq!(result.conflicts, 0);
+
+        // Insertions and deletions
+        let result = merge3(base, "z\na\nb\nc\nd\n", "a\nb\nc\n");
+        assert_eq!(result.content, "z\na\nb\nc\n");
+        assert_eq!(result.conflicts, 0);
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(
+            diff("a\nb\nc\n", "a\nB\nc\nd\n", 3),
+            "@@ -1,3 +1,4 @@\n a\n-b\n+B\n c\n+d\n"
+        );
+        assert_eq!(diff("a\n", "a\n", 3), "");
+
+        // Distant changes end up in separate hunks
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n";
+        let new = "one\n2\n3\n4\n5\n6\n7\neight\n";
+        assert_eq!(
+            diff(old, new, 1),
+            "@@ -1,2 +1,2 @@\n-1\n+one\n 2\n@@ -7,2 +7,2 @@\n 7\n-8\n+eight\n"
+        );
+    }
+
+    #[test]
+    fn test_colorize_diff() {
+        assert_eq!(
+            colorize_diff("@@ -1 +1 @@\n a\n-b\n+c\n"),
+            "\x1b[36m@@ -1 +1 @@\x1b[0m\n a\n\x1b[31m-b\x1b[0m\n\x1b[32m+c\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn test_merge3_conflict() {
+        let base = "a\nb\nc\n";
+        let local = "a\nlocal\nc\n";
+        let new = "a\nnew\nc\n";
+
+        let result = merge3(base, local, new);
+        assert_eq!(result.conflicts, 1);
+        assert_eq!(
+            result.content,
+            "a\n<<<<<<< local\nlocal\n||||||| baseline\nb\n=======\nnew\n>>>>>>> new\nc\n"
+        );
+
+        // Missing trailing newlines
+        let result = merge3("a\nb", "a\nl", "a\nn");
+        assert_eq!(result.conflicts, 1);
+        assert_eq!(
+            result.content,
+            "a\n<<<<<<< local\nl\n||||||| baseline\nb\n=======\nn\n>>>>>>> new\n"
+        );
+    }
+    #[test]
+    fn test_merge3_large() {
+        // Too large to compare line by line, both versions are kept
+        let base: String = (0..3000).map(|i| format!("{}\n", i)).collect();
+        let local = format!("local\n{}local\n", base);
+        let new = format!("new\n{}new\n", base);
+
+        let result = merge3(&base, &local, &new);
+        assert_eq!(result.conflicts, 1);
+        assert!(result.content.starts_with("<<<<<<< local\nlocal\n0\n"));
+        assert!(result.content.ends_with("new\n>>>>>>> new\n"));
+
+        // Unchanged lines at the start and end are still matched
+        let local = format!("{}local\n", base);
+        let new = format!("new\n{}", base);
+        let result = merge3(&base, &local, &new);
+        assert_eq!(result.conflicts, 0);
+        assert_eq!(result.content, format!("new\n{}local\n", base));
+    }
+}
@@ -49,7 +49,7 @@ pub(crate) struct FileMetadata {
 ///
 /// # Examples
 ///
-/// ```no_run
+/// ```ignore
 /// use std::path::Path;
 ///
 /// #[tokio::main]
@@ -62,7 +62,7 @@ pub(crate) struct FileMetadata {
 pub(crate) async fn get_file_metadata<P: AsRef<Path>>(path: P) -> Result<FileMetadata> {
     let metadata = match fs::symlink_metadata(&path).await {
         Ok(meta) => meta,
-        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+        Err(e) if file_fs::retry_with_sudo(&e, &path) => {
             // If permission is denied, create a temporary copy with elevated privileges
             let temp_file = tempfile::NamedTempFile::new()?;
             let temp_path_str = file_fs::path_to_string(&temp_file)?;
@@ -112,6 +112,24 @@ pub(crate) async fn get_file_metadata<P: AsRef<Path>>(path: P) -> Result<FileMet
     })
 }
 
+/// Changes the permissions of a file.
+///
+/// Files in the home directory of another user are changed through a file descriptor, so a
+/// symbolic link replacing the file is not followed (see [crate::target_user]).
+async fn set_permissions(path: &Path, permissions: u32) -> std::io::Result<()> {
+    let flags = crate::target_user::open_flags();
+    if flags == 0 {
+        return fs::set_permissions(path, std::fs::Permissions::from_mode(permissions)).await;
+    }
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(flags | nix::libc::O_NONBLOCK)
+        .open(path)
+        .await?;
+    file.set_permissions(std::fs::Permissions::from_mode(permissions))
+        .await
+}
+
 /// Sets file metadata, elevating privileges if necessary.
 ///
 /// This function attempts to set the metadata (permissions and ownership) of a file or symbolic
@@ -128,7 +146,7 @@ pub(crate) async fn get_file_metadata<P: AsRef<Path>>(path: P) -> Result<FileMet
 ///
 /// # Examples
 ///
-/// ```no_run
+/// ```ignore
 /// use std::path::Path;
 ///
 /// #[tokio::main]
@@ -151,9 +169,9 @@ pub(crate) async fn set_file_metadata<P: AsRef<Path>>(
 ) -> Result<()> {
     // Set file permissions if specified
     if let Some(permissions) = metadata.permissions {
-        match fs::set_permissions(&path, std::fs::Permissions::from_mode(permissions)).await {
+        match set_permissions(path.as_ref(), permissions).await {
             Ok(()) => (),
-            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(e) if file_fs::retry_with_sudo(&e, &path) => {
                 // Use sudo to set permissions if permission is denied
                 sudo::sudo_exec(
                     "chmod",
@@ -173,7 +191,7 @@ pub(crate) async fn set_file_metadata<P: AsRef<Path>>(
     if let (Some(uid), Some(gid)) = (metadata.uid, metadata.gid) {
         match std::os::unix::fs::lchown(&path, Some(uid), Some(gid)) {
             Ok(()) => (),
-            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(e) if file_fs::retry_with_sudo(&e, &path) => {
                 // Use sudo to set ownership if permission is denied
                 sudo::sudo_exec(
                     "chown",
@@ -4,7 +4,7 @@
 //! IDs. It includes functionality to convert between different representations of permissions and
 //! to resolve user and group names to their respective numeric IDs.
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
 /// Converts permissions from u32 to string format.
 ///
@@ -21,7 +21,7 @@ use anyhow::{Context, Result};
 ///
 /// # Examples
 ///
-/// ```
+/// ```ignore
 /// # use anyhow::Result;
 /// # fn main() -> Result<()> {
 /// assert_eq!(perms_int_to_str(0o644)?, "644");
@@ -50,7 +50,7 @@ pub(crate) fn perms_int_to_str(p: u32) -> Result<String> {
 ///
 /// # Examples
 ///
-/// ```
+/// ```ignore
 /// # use anyhow::Result;
 /// # fn main() -> Result<()> {
 /// assert_eq!(perms_str_to_int("644")?, 0o644);
@@ -61,31 +61,134 @@ pub(crate) fn perms_str_to_int<S: AsRef<str>>(p: S) -> Result<u32> {
     u32::from_str_radix(p.as_ref(), 8).context("Failed to convert permission string to u32")
 }
 
-/// Converts a username to its corresponding user ID (UID).
+/// Applies a permission mode to an existing mode.
 ///
-/// This function looks up the UID for a given username using the system's user database.
+/// The mode is either an octal string like "644", which replaces the existing mode, or a
+/// comma-separated list of symbolic clauses like "u+x,go-w" as understood by `chmod`. Each clause
+/// consists of the affected classes (`u`, `g`, `o` or `a`, defaulting to `a`), an operator (`+`,
+/// `-` or `=`) and the permissions (`r`, `w`, `x`, `X`, `s`, `t` or one of `u`, `g` and `o` to
+/// copy the permissions of that class). Unlike `chmod`, the umask is not taken into account.
 ///
 /// # Arguments
 ///
-/// * `u` - The username to look up.
+/// * `p` - The permissions as a string (e.g., "644" or "u+x").
+/// * `base` - The mode symbolic clauses are applied to.
 ///
 /// # Returns
 ///
-/// * `Result<u32>` - The UID corresponding to the username, or an error if the lookup fails.
+/// * `Result<u32>` - The resulting permissions, or an error if the mode is invalid.
 ///
 /// # Examples
 ///
+/// ```ignore
+/// # use anyhow::Result;
+/// # fn main() -> Result<()> {
+/// assert_eq!(apply_mode("644", 0o755)?, 0o644);
+/// assert_eq!(apply_mode("u+x,go-w", 0o666)?, 0o744);
+/// # Ok(())
+/// # }
 /// ```
+pub(crate) fn apply_mode<S: AsRef<str>>(p: S, base: u32) -> Result<u32> {
+    let p = p.as_ref();
+    if !p.is_empty() && p.chars().all(|c| c.is_digit(8)) {
+        return perms_str_to_int(p);
+    }
+
+    let invalid = || anyhow!("Invalid permission mode {:?}", p);
+    let mut mode = base & 0o7777;
+    for clause in p.split(',') {
+        let mut chars = clause.chars().peekable();
+
+        // Affected classes, including their special bits
+        let mut who = 0;
+        while let Some(c) = chars.peek() {
+            who |= match c {
+                'u' => 0o4700,
+                'g' => 0o2070,
+                'o' => 0o1007,
+                'a' => 0o7777,
+                _ => break,
+            };
+            chars.next();
+        }
+        if who == 0 {
+            who = 0o7777;
+        }
+
+        // One or more operators, each followed by the permissions it applies
+        let mut op = chars.next().ok_or_else(invalid)?;
+        loop {
+            if !matches!(op, '+' | '-' | '=') {
+                return Err(invalid());
+            }
+            let mut perms = 0;
+            let mut next = None;
+            for c in chars.by_ref() {
+                perms |= match c {
+                    'r' => 0o444,
+                    'w' => 0o222,
+                    'x' => 0o111,
+                    'X' if mode & 0o111 != 0 => 0o111,
+                    'X' => 0,
+                    's' => 0o6000,
+                    't' => 0o1000,
+                    'u' => ((mode >> 6) & 0o7) * 0o111,
+                    'g' => ((mode >> 3) & 0o7) * 0o111,
+                    'o' => (mode & 0o7) * 0o111,
+                    '+' | '-' | '=' => {
+                        next = Some(c);
+                        break;
+                    }
+                    _ => return Err(invalid()),
+                };
+            }
+
+            let perms = perms & who;
+            mode = match op {
+                '+' => mode | perms,
+                '-' => mode & !perms,
+                _ => (mode & !who) | perms,
+            };
+
+            match next {
+                Some(c) => op = c,
+                None => break,
+            }
+        }
+    }
+    Ok(mode)
+}
+
+/// Converts a username to its corresponding user ID (UID).
+///
+/// This function looks up the UID for a given username using the system's user database. Numeric
+/// values are taken as UID directly.
+///
+/// # Arguments
+///
+/// * `u` - The username or UID to look up.
+///
+/// # Returns
+///
+/// * `Result<u32>` - The UID corresponding to the username, or an error if the user does not exist.
+///
+/// # Examples
+///
+/// ```ignore
 /// # use anyhow::Result;
 /// # fn main() -> Result<()> {
 /// assert_eq!(user_to_uid("root")?, 0);
+/// assert_eq!(user_to_uid("1000")?, 1000);
 /// # Ok(())
 /// # }
 /// ```
 pub(crate) fn user_to_uid<S: AsRef<str>>(u: S) -> Result<u32> {
+    if let Ok(uid) = u.as_ref().parse::<u32>() {
+        return Ok(uid);
+    }
     Ok(nix::unistd::User::from_name(u.as_ref())
-        .unwrap()
-        .unwrap()
+        .with_context(|| format!("Failed to look up user {:?}", u.as_ref()))?
+        .ok_or_else(|| anyhow!("User {:?} does not exist", u.as_ref()))?
         .uid
         .as_raw())
 }
@@ -93,18 +196,20 @@ pub(crate) fn user_to_uid<S: AsRef<str>>(u: S) -> Result<u32> {
 /// Converts a group name to its corresponding group ID (GID).
 ///
 /// This function looks up the GID for a given group name using the system's group database.
+/// Numeric values are taken as GID directly.
 ///
 /// # Arguments
 ///
-/// * `u` - The group name to look up.
+/// * `u` - The group name or GID to look up.
 ///
 /// # Returns
 ///
-/// * `Result<u32>` - The GID corresponding to the group name, or an error if the lookup fails.
+/// * `Result<u32>` - The GID corresponding to the group name, or an error if the group does not
+///   exist.
 ///
 /// # Examples
 ///
-/// ```
+/// ```ignore
 /// # use anyhow::Result;
 /// # fn main() -> Result<()> {
 /// assert_eq!(group_to_gid("root")?, 0);
@@ -112,13 +217,101 @@ pub(crate) fn user_to_uid<S: AsRef<str>>(u: S) -> Result<u32> {
 /// # }
 /// ```
 pub(crate) fn group_to_gid<S: AsRef<str>>(u: S) -> Result<u32> {
+    if let Ok(gid) = u.as_ref().parse::<u32>() {
+        return Ok(gid);
+    }
     Ok(nix::unistd::Group::from_name(u.as_ref())
-        .unwrap()
-        .unwrap()
+        .with_context(|| format!("Failed to look up group {:?}", u.as_ref()))?
+        .ok_or_else(|| anyhow!("Group {:?} does not exist", u.as_ref()))?
         .gid
         .as_raw())
 }
 
+/// Returns the name of the user with the given UID, if there is one.
+fn uid_to_user(uid: u32) -> Option<String> {
+    nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid))
+        .ok()
+        .flatten()
+        .map(|u| u.name)
+}
+
+/// Returns the name of the group with the given GID, if there is one.
+fn gid_to_group(gid: u32) -> Option<String> {
+    nix::unistd::Group::from_gid(nix::unistd::Gid::from_raw(gid))
+        .ok()
+        .flatten()
+        .map(|g| g.name)
+}
+
+/// Formats the owner of a file for the store.
+///
+/// Both the IDs and the names are kept, as `uid:gid:user:group`. The names are empty if the IDs
+/// can't be resolved.
+///
+/// # Arguments
+///
+/// * `uid` - User ID of the owner.
+/// * `gid` - Group ID of the owner.
+pub(crate) fn format_owner(uid: u32, gid: u32) -> String {
+    format!(
+        "{}:{}:{}:{}",
+        uid,
+        gid,
+        uid_to_user(uid).unwrap_or_default(),
+        gid_to_group(gid).unwrap_or_default()
+    )
+}
+
+/// Resolves an owner stored with [format_owner] to a UID and GID.
+///
+/// The names take precedence, as the IDs of a user or group may differ after a reinstallation.
+/// The stored IDs are used if a name is missing or can't be resolved. Entries of older stores only
+/// contain `uid:gid`.
+///
+/// # Arguments
+///
+/// * `owner` - The stored owner.
+///
+/// # Returns
+///
+/// * `Result<(u32, u32)>` - The UID and GID, or an error if the stored IDs are invalid.
+pub(crate) fn parse_owner(owner: &str) -> Result<(u32, u32)> {
+    let parts: Vec<&str> = owner.split(':').collect();
+    let (uid, gid) = match parts[..] {
+        [uid, gid, ..] => (uid, gid),
+        _ => bail!("Invalid owner {:?}", owner),
+    };
+    let uid = uid
+        .parse::<u32>()
+        .with_context(|| format!("Invalid UID in owner {:?}", owner))?;
+    let gid = gid
+        .parse::<u32>()
+        .with_context(|| format!("Invalid GID in owner {:?}", owner))?;
+
+    let name = |i: usize| parts.get(i).filter(|n| !n.is_empty());
+    Ok((
+        name(2).and_then(|u| user_to_uid(u).ok()).unwrap_or(uid),
+        name(3).and_then(|g| group_to_gid(g).ok()).unwrap_or(gid),
+    ))
+}
+
+/// Formats an owner stored with [format_owner] for display, preferring the names.
+///
+/// # Arguments
+///
+/// * `owner` - The stored owner.
+pub(crate) fn display_owner(owner: &str) -> String {
+    let parts: Vec<&str> = owner.split(':').collect();
+    match parts[..] {
+        [uid, gid, user, group] => format!(
+            "{}:{}",
+            if user.is_empty() { uid } else { user },
+            if group.is_empty() { gid } else { group }
+        ),
+        _ => owner.to_string(),
+    }
+}
+
 //
 // Tests
 
@@ -140,6 +333,32 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_apply_mode() -> Result<()> {
+        assert_eq!(apply_mode("600", 0o755)?, 0o600);
+        assert_eq!(apply_mode("u+x", 0o644)?, 0o744);
+        assert_eq!(apply_mode("go-w", 0o666)?, 0o644);
+        assert_eq!(apply_mode("u+x,go-w", 0o664)?, 0o744);
+        assert_eq!(apply_mode("+x", 0o644)?, 0o755);
+        assert_eq!(apply_mode("a=r", 0o755)?, 0o444);
+        assert_eq!(apply_mode("g=u", 0o640)?, 0o660);
+        assert_eq!(apply_mode("o=", 0o777)?, 0o770);
+        assert_eq!(apply_mode("u=rw,go=r", 0o700)?, 0o644);
+        assert_eq!(apply_mode("u-w+x", 0o644)?, 0o544);
+        assert_eq!(apply_mode("a+X", 0o644)?, 0o644);
+        assert_eq!(apply_mode("a+X", 0o744)?, 0o755);
+        assert_eq!(apply_mode("u+s", 0o755)?, 0o4755);
+        assert_eq!(apply_mode("+t", 0o777)?, 0o1777);
+
+        assert!(apply_mode("", 0o644).is_err());
+        assert!(apply_mode("u", 0o644).is_err());
+        assert!(apply_mode("z+x", 0o644).is_err());
+        assert!(apply_mode("u+y", 0o644).is_err());
+        assert!(apply_mode("u+x,", 0o644).is_err());
+        assert!(apply_mode("999", 0o644).is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_user_to_uid() -> Result<()> {
         assert_eq!(user_to_uid("root")?, 0);
@@ -151,4 +370,28 @@ mod tests {
         assert_eq!(group_to_gid("root")?, 0);
         Ok(())
     }
+
+    #[test]
+    fn test_owner_names() -> Result<()> {
+        assert_eq!(user_to_uid("1234")?, 1234);
+        assert!(user_to_uid("dotdeploy-missing-user").is_err());
+        assert!(group_to_gid("dotdeploy-missing-group").is_err());
+
+        let owner = format_owner(0, 0);
+        assert_eq!(owner, "0:0:root:root");
+        assert_eq!(display_owner(&owner), "root:root");
+        assert_eq!(parse_owner(&owner)?, (0, 0));
+
+        // Names take precedence over the stored IDs
+        assert_eq!(parse_owner("1234:1234:root:root")?, (0, 0));
+        // IDs are used if the names are unknown or missing
+        assert_eq!(
+            parse_owner("1234:1234:dotdeploy-missing-user:")?,
+            (1234, 1234)
+        );
+        assert_eq!(parse_owner("1000:100")?, (1000, 100));
+        assert_eq!(display_owner("1000:100"), "1000:100");
+        assert!(parse_owner("root").is_err());
+        Ok(())
+    }
 }
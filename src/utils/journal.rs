@@ -0,0 +1,205 @@
+//! Journal logging module.
+//!
+//! On systemd systems, log messages can be sent to the journal with its native protocol, so
+//! automatic runs started by a timer or by the daemon show up in `journalctl -t dotdeploy`, or
+//! `journalctl --user -t dotdeploy` for runs of a user. Besides the message, each entry carries its
+//! priority, the syslog identifier and the source location as structured fields. Entries cannot be
+//! attributed to a unit by the client, `journalctl -u` only matches runs started by systemd. The
+//! logger is installed at startup, but stays silent until the journal is enabled with the `journal`
+//! option of the config.
+
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Socket of the journal for the native protocol.
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Maximum length of a message in bytes, longer messages are truncated.
+///
+/// Entries are sent as single datagrams, which the kernel rejects beyond the socket buffer size.
+const MAX_MESSAGE: usize = 64 * 1024;
+
+/// Suffix marking a truncated message.
+const TRUNCATED: &str = " [truncated]";
+
+lazy_static! {
+    /// Connection to the journal, set once the journal is enabled.
+    static ref JOURNAL: Mutex<Option<UnixDatagram>> = Mutex::new(None);
+}
+
+/// Connects to the journal, so log messages are sent to it from now on.
+///
+/// # Returns
+///
+/// A Result indicating success, or an error if the journal is not available.
+pub(crate) fn enable() -> Result<()> {
+    if !Path::new(SOCKET).exists() {
+        bail!(
+            "The systemd journal is not available, {} is missing",
+            SOCKET
+        );
+    }
+    let socket = UnixDatagram::unbound().context("Failed to create socket for the journal")?;
+    socket
+        .connect(SOCKET)
+        .with_context(|| format!("Failed to connect to the journal at {}", SOCKET))?;
+    *JOURNAL.lock().unwrap() = Some(socket);
+    Ok(())
+}
+
+/// Maps a log level to a syslog priority.
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Appends a field to a journal entry.
+///
+/// Values spanning multiple lines are prefixed with their length, as required by the protocol.
+fn append_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Truncates a message to at most [`MAX_MESSAGE`] bytes, keeping it valid UTF-8.
+fn truncate(mut message: String) -> String {
+    if message.len() > MAX_MESSAGE {
+        let mut end = MAX_MESSAGE - TRUNCATED.len();
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+        message.push_str(TRUNCATED);
+    }
+    message
+}
+
+/// Encodes a log record as a journal entry.
+fn encode(record: &Record) -> Vec<u8> {
+    let mut entry = Vec::new();
+    append_field(&mut entry, "MESSAGE", &truncate(record.args().to_string()));
+    append_field(
+        &mut entry,
+        "PRIORITY",
+        &priority(record.level()).to_string(),
+    );
+    append_field(&mut entry, "SYSLOG_IDENTIFIER", "dotdeploy");
+    append_field(&mut entry, "CODE_MODULE", record.target());
+    if let Some(file) = record.file() {
+        append_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        append_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    entry
+}
+
+/// Logger sending the messages of dotdeploy to the journal, once enabled.
+pub(crate) struct JournalLogger {
+    level: LevelFilter,
+}
+
+impl JournalLogger {
+    /// Creates a logger for messages of at least the given level.
+    pub(crate) fn new(level: LevelFilter) -> Box<Self> {
+        Box::new(JournalLogger { level })
+    }
+}
+
+impl Log for JournalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && metadata.target().starts_with("dotdeploy")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(socket) = JOURNAL.lock().unwrap().as_ref() {
+            // Losing messages is preferable to failing the run
+            let _ = socket.send(&encode(record));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl simplelog::SharedLogger for JournalLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&simplelog::Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_field() {
+        let mut entry = Vec::new();
+        append_field(&mut entry, "MESSAGE", "one line");
+        assert_eq!(entry, b"MESSAGE=one line\n");
+
+        let mut entry = Vec::new();
+        append_field(&mut entry, "MESSAGE", "two\nlines");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn test_encode() {
+        let entry = encode(
+            &Record::builder()
+                .args(format_args!("Deployed zsh"))
+                .level(Level::Warn)
+                .target("dotdeploy::deploy")
+                .line(Some(42))
+                .build(),
+        );
+        let entry = String::from_utf8(entry).unwrap();
+        assert!(entry.starts_with("MESSAGE=Deployed zsh\nPRIORITY=4\n"));
+        assert!(entry.contains("SYSLOG_IDENTIFIER=dotdeploy\n"));
+        assert!(!entry.contains("UNIT="));
+        assert!(entry.contains("CODE_MODULE=dotdeploy::deploy\n"));
+        assert!(entry.ends_with("CODE_LINE=42\n"));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("short".to_string()), "short");
+
+        let message = truncate("ä".repeat(MAX_MESSAGE));
+        assert!(message.len() <= MAX_MESSAGE);
+        assert!(message.ends_with(TRUNCATED));
+        assert!(message.starts_with("ää"));
+    }
+}
@@ -0,0 +1,139 @@
+//! Run lock module.
+//!
+//! This module provides an advisory lock which prevents multiple dotdeploy processes from running
+//! at the same time. Concurrent runs would otherwise corrupt each other's view of the stores and
+//! compete for sudo.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// Name of the lock file inside the lock directory.
+pub(crate) const LOCK_FILE: &str = "dotdeploy.lock";
+
+/// An exclusive advisory lock (flock) held for the lifetime of a dotdeploy run.
+///
+/// The lock file contains the PID of the process holding the lock. The lock is released when the
+/// value is dropped or the process exits.
+#[derive(Debug)]
+pub(crate) struct RunLock {
+    /// The locked file
+    file: File,
+    /// Location of the lock file
+    path: PathBuf,
+}
+
+impl RunLock {
+    /// Acquires the run lock in the given directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Directory where the lock file is created.
+    /// * `wait` - Wait for another instance to release the lock instead of failing.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(RunLock)` - The acquired lock.
+    /// * `Err` - If another instance holds the lock and `wait` is false, or the lock file could not
+    ///   be created.
+    pub(crate) fn acquire<P: AsRef<Path>>(dir: P, wait: bool) -> Result<Self> {
+        crate::target_user::create_dir_all(dir.as_ref())?;
+        let path = dir.as_ref().join(LOCK_FILE);
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .custom_flags(crate::target_user::open_flags())
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {:?}", &path))?;
+
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                let pid = Self::read_pid(&mut file);
+                if wait {
+                    info!(
+                        "Waiting for another instance of dotdeploy to finish (pid {})",
+                        pid
+                    );
+                    file.lock()
+                        .with_context(|| format!("Failed to lock {:?}", &path))?;
+                } else {
+                    bail!(
+                        "Another instance of dotdeploy is running (pid {}). Use --wait to wait for it to finish",
+                        pid
+                    )
+                }
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {:?}", &path))
+            }
+        }
+
+        // Record our own PID for other instances
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| write!(file, "{}", std::process::id()))
+            .and_then(|_| file.flush())
+            .with_context(|| format!("Failed to write PID to lock file {:?}", &path))?;
+
+        debug!("Acquired run lock {:?}", &path);
+
+        Ok(RunLock { file, path })
+    }
+
+    /// Reads the PID of the process holding the lock, "unknown" if it can not be determined.
+    fn read_pid(file: &mut File) -> String {
+        let mut pid = String::new();
+        match file.read_to_string(&mut pid) {
+            Ok(_) if !pid.trim().is_empty() => pid.trim().to_string(),
+            _ => "unknown".to_string(),
+        }
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.unlock() {
+            warn!("Failed to release run lock {:?}: {}", &self.path, e);
+        }
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_run_lock() -> Result<()> {
+        let temp_dir = tempdir()?;
+
+        let lock = RunLock::acquire(temp_dir.path(), false)?;
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join(LOCK_FILE))?,
+            std::process::id().to_string()
+        );
+
+        // A second lock fails while the first one is held
+        let err = RunLock::acquire(temp_dir.path(), false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("pid {}", std::process::id())));
+
+        // And succeeds once it is released
+        drop(lock);
+        assert!(RunLock::acquire(temp_dir.path(), false).is_ok());
+
+        Ok(())
+    }
+}
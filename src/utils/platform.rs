@@ -0,0 +1,158 @@
+//! Platform detection module.
+//!
+//! This module detects facts about the running system, like the CPU architecture, the kernel
+//! release or the graphical session. They are exposed to templates and conditions as `DOD_*`
+//! variables, so modules can branch on them without calling external commands.
+
+use std::path::Path;
+
+/// Returns the CPU architecture, e.g. `x86_64` or `aarch64`.
+fn arch() -> String {
+    std::env::consts::ARCH.to_string()
+}
+
+/// Returns the kernel release, e.g. `6.9.7-arch1-1`, or `unknown` if it can't be read.
+fn kernel() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map(|r| r.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Determines the type of the graphical session.
+///
+/// `XDG_SESSION_TYPE` takes precedence. Otherwise, the session type is guessed from the display
+/// variables.
+///
+/// # Arguments
+///
+/// * `xdg_session_type` - Value of `XDG_SESSION_TYPE`
+/// * `wayland_display` - Whether `WAYLAND_DISPLAY` is set
+/// * `display` - Whether `DISPLAY` is set
+///
+/// # Returns
+///
+/// `wayland`, `x11` or `tty` if there is no graphical session
+fn session_type(xdg_session_type: Option<&str>, wayland_display: bool, display: bool) -> String {
+    match xdg_session_type.map(str::to_lowercase).as_deref() {
+        Some("wayland") => "wayland".to_string(),
+        Some("x11") => "x11".to_string(),
+        _ if wayland_display => "wayland".to_string(),
+        _ if display => "x11".to_string(),
+        _ => "tty".to_string(),
+    }
+}
+
+/// Determines the desktop environment.
+///
+/// `XDG_CURRENT_DESKTOP` may contain a colon separated list, e.g. `ubuntu:GNOME`. The last entry
+/// is the actual desktop environment.
+///
+/// # Arguments
+///
+/// * `current_desktop` - Value of `XDG_CURRENT_DESKTOP`
+/// * `desktop_session` - Value of `DESKTOP_SESSION`, used as fallback
+///
+/// # Returns
+///
+/// The lowercase name of the desktop environment, or an empty string if there is none
+fn desktop(current_desktop: Option<&str>, desktop_session: Option<&str>) -> String {
+    current_desktop
+        .and_then(|d| d.rsplit(':').find(|d| !d.is_empty()))
+        .or(desktop_session.filter(|d| !d.is_empty()))
+        .map(str::to_lowercase)
+        .unwrap_or_default()
+}
+
+/// Checks whether dotdeploy runs inside a container.
+///
+/// # Arguments
+///
+/// * `root` - Root of the file system to inspect, `/` on a real system
+fn in_container(root: &Path) -> bool {
+    if std::env::var_os("container").is_some()
+        || root.join(".dockerenv").exists()
+        || root.join("run/.containerenv").exists()
+    {
+        return true;
+    }
+
+    std::fs::read_to_string(root.join("proc/1/cgroup"))
+        .map(|c| {
+            ["docker", "lxc", "kubepods", "containerd"]
+                .iter()
+                .any(|k| c.contains(k))
+        })
+        .unwrap_or(false)
+}
+
+/// Collects the platform facts.
+///
+/// # Returns
+///
+/// A list of variable names and their values, ready to be added to the template context and the
+/// environment
+pub(crate) fn facts() -> Vec<(&'static str, String)> {
+    let var = |name: &str| std::env::var(name).ok();
+
+    vec![
+        ("DOD_ARCH", arch()),
+        ("DOD_KERNEL", kernel()),
+        (
+            "DOD_SESSION_TYPE",
+            session_type(
+                var("XDG_SESSION_TYPE").as_deref(),
+                var("WAYLAND_DISPLAY").is_some(),
+                var("DISPLAY").is_some(),
+            ),
+        ),
+        (
+            "DOD_DE",
+            desktop(
+                var("XDG_CURRENT_DESKTOP").as_deref(),
+                var("DESKTOP_SESSION").as_deref(),
+            ),
+        ),
+        ("DOD_IN_CONTAINER", in_container(Path::new("/")).to_string()),
+    ]
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_type() {
+        assert_eq!(session_type(Some("wayland"), false, true), "wayland");
+        assert_eq!(session_type(Some("X11"), true, false), "x11");
+        assert_eq!(session_type(Some("tty"), true, true), "wayland");
+        assert_eq!(session_type(None, false, true), "x11");
+        assert_eq!(session_type(None, false, false), "tty");
+    }
+
+    #[test]
+    fn test_desktop() {
+        assert_eq!(desktop(Some("ubuntu:GNOME"), None), "gnome");
+        assert_eq!(desktop(Some("KDE"), Some("plasma")), "kde");
+        assert_eq!(desktop(None, Some("sway")), "sway");
+        assert_eq!(desktop(Some(""), None), "");
+    }
+
+    #[test]
+    fn test_in_container() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join("proc/1"))?;
+        std::fs::write(root.join("proc/1/cgroup"), "0::/init.scope\n")?;
+        if std::env::var_os("container").is_none() {
+            assert!(!in_container(root));
+        }
+
+        std::fs::write(root.join("proc/1/cgroup"), "0::/docker/0123abcd\n")?;
+        assert!(in_container(root));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+//! Progress reporting for long running operations.
+//!
+//! On a terminal, a single status line with the number of processed items, the current item and
+//! the estimated remaining time is redrawn on stderr. Otherwise, the progress is logged in steps of
+//! 10 percent. With `--quiet`, no progress is shown.
+
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Progress of a fixed number of items.
+#[derive(Debug)]
+pub(crate) struct Progress {
+    /// Describes the items, e.g. "files"
+    label: String,
+    /// Total number of items
+    total: usize,
+    /// Number of processed items
+    done: AtomicUsize,
+    /// Last logged step in percent, only used if stderr is not a terminal
+    last_step: Mutex<usize>,
+    /// Time the operation started
+    start: Instant,
+    /// Whether to draw a status line
+    tty: bool,
+}
+
+impl Progress {
+    /// Creates a new progress for `total` items.
+    pub(crate) fn new<S: Into<String>>(label: S, total: usize) -> Self {
+        Progress {
+            label: label.into(),
+            total,
+            done: AtomicUsize::new(0),
+            last_step: Mutex::new(0),
+            start: Instant::now(),
+            tty: std::io::stderr().is_terminal() && !crate::report::is_quiet(),
+        }
+    }
+
+    /// Marks an item as processed.
+    ///
+    /// # Arguments
+    ///
+    /// * `item` - Name of the processed item, shown on the status line
+    pub(crate) fn inc(&self, item: &str) {
+        let done = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+        let status = format_status(&self.label, done, self.total, self.start.elapsed());
+
+        if self.tty {
+            let mut stderr = std::io::stderr().lock();
+            // Clear the line before redrawing it
+            let _ = write!(stderr, "\r\x1b[2K{} {}", status, item);
+            let _ = stderr.flush();
+        } else if let Some(step) = (done * 10).checked_div(self.total) {
+            let step = step * 10;
+            let mut last_step = self.last_step.lock().unwrap();
+            if step > *last_step {
+                *last_step = step;
+                info!("{}", status);
+            }
+        }
+    }
+
+    /// Finishes the progress and clears the status line.
+    pub(crate) fn finish(&self) {
+        if self.tty {
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\r\x1b[2K");
+            let _ = stderr.flush();
+        }
+    }
+}
+
+/// Estimates the remaining time based on the average time per processed item.
+fn eta(done: usize, total: usize, elapsed: Duration) -> Option<Duration> {
+    if done == 0 || done > total {
+        return None;
+    }
+    Some(elapsed / done as u32 * (total - done) as u32)
+}
+
+/// Formats the status of a progress, e.g. "files 4/8 (50%), ETA 2s".
+fn format_status(label: &str, done: usize, total: usize, elapsed: Duration) -> String {
+    let percent = (done * 100).checked_div(total).unwrap_or(100);
+    match eta(done, total, elapsed) {
+        Some(eta) => format!(
+            "{} {}/{} ({}%), ETA {}s",
+            label,
+            done,
+            total,
+            percent,
+            eta.as_secs()
+        ),
+        None => format!("{} {}/{} ({}%)", label, done, total, percent),
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_status() {
+        assert_eq!(
+            format_status("files", 4, 8, Duration::from_secs(2)),
+            "files 4/8 (50%), ETA 2s"
+        );
+        assert_eq!(
+            format_status("files", 0, 8, Duration::from_secs(2)),
+            "files 0/8 (0%)"
+        );
+        assert_eq!(
+            format_status("files", 8, 8, Duration::from_secs(2)),
+            "files 8/8 (100%), ETA 0s"
+        );
+        assert_eq!(eta(9, 8, Duration::from_secs(1)), None);
+    }
+}
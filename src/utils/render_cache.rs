@@ -0,0 +1,223 @@
+//! Render cache module.
+//!
+//! Rendering templates with Handlebars is the most expensive part of deploying unchanged modules.
+//! The output of a template only depends on its content and the render context, unless it calls a
+//! helper which looks at the outside world, e.g. a script helper, `file_exists` or the store
+//! helpers, or includes a partial. Thus, the rendered output of all other templates is kept in the
+//! `templates` folder of `cache_dir`, keyed by the checksum of the template and the context, and
+//! reused on subsequent runs. Rendered templates may contain secrets, so the folder is only
+//! accessible by its owner and entries are removed after a week without use.
+
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use handlebars::{Handlebars, RenderError};
+use lazy_static::lazy_static;
+use serde_json::Value;
+
+use crate::utils::file_checksum::calculate_sha256_checksum_bytes;
+
+/// Helpers whose output does not only depend on their arguments.
+const UNCACHED_HELPERS: [&str; 5] = [
+    "file_exists",
+    "dir_exists",
+    "module_deployed",
+    "file_managed",
+    "file_content",
+];
+
+/// Entries not used for this long are removed.
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Location and settings of the render cache.
+#[derive(Debug)]
+struct RenderCache {
+    /// Folder of the cached output
+    dir: PathBuf,
+    /// Templates mentioning one of these helpers are not cached
+    uncached: Vec<String>,
+}
+
+lazy_static! {
+    /// The render cache, set with `init`. Templates are always rendered without it.
+    static ref CACHE: RwLock<Option<RenderCache>> = RwLock::new(None);
+}
+
+/// Enables the render cache and removes stale entries.
+///
+/// # Arguments
+///
+/// * `dir` - Folder of the cached output
+/// * `script_helpers` - Names of the helpers implemented by external scripts
+pub(crate) fn init<'a>(dir: PathBuf, script_helpers: impl Iterator<Item = &'a String>) {
+    // Restrict folders created by earlier versions as well
+    if dir.is_dir() {
+        let _ = std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700));
+    }
+    if let Ok(entries) = std::fs::read_dir(&dir) {
+        for entry in entries.flatten() {
+            let stale = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|m| m + MAX_AGE < SystemTime::now());
+            if stale {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    let uncached = UNCACHED_HELPERS
+        .iter()
+        .map(|h| h.to_string())
+        .chain(script_helpers.cloned())
+        .collect();
+    *CACHE.write().unwrap() = Some(RenderCache { dir, uncached });
+}
+
+/// Creates the cache folder, accessible only by its owner.
+fn create_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+}
+
+/// Checks whether a template includes a partial, e.g. `{{> header}}` or `{{~#> layout}}`.
+fn includes_partial(template: &str) -> bool {
+    template.match_indices("{{").any(|(i, _)| {
+        let tag = template[i + 2..].trim_start_matches('~').trim_start();
+        tag.starts_with('>') || tag.starts_with("#>")
+    })
+}
+
+/// Checks whether the output of a template may be cached.
+///
+/// Helper names are matched as plain text, so templates only mentioning them are not cached
+/// either. Partials are never cached, as they may change independently of the template.
+fn cacheable(template: &str, uncached: &[String]) -> bool {
+    !includes_partial(template)
+        && !uncached.iter().any(|h| {
+            template.match_indices(h.as_str()).any(|(i, _)| {
+                let before = template[..i].chars().next_back();
+                let after = template[i + h.len()..].chars().next();
+                !before.is_some_and(|c| c.is_alphanumeric() || c == '_')
+                    && !after.is_some_and(|c| c.is_alphanumeric() || c == '_')
+            })
+        })
+}
+
+/// Returns the cache key of a template rendered with a context.
+///
+/// Strict mode is part of the key, as it decides whether missing context keys fail rendering.
+fn key(template: &str, context: &Value, strict: bool) -> String {
+    let mut content = template.as_bytes().to_vec();
+    content.push(0);
+    content.push(strict as u8);
+    content.extend_from_slice(context.to_string().as_bytes());
+    calculate_sha256_checksum_bytes(&content)
+}
+
+/// Renders a template, reusing the cached output of an earlier run if possible.
+///
+/// Failing to read or write the cache is not an error, the template is rendered instead.
+///
+/// # Arguments
+///
+/// * `hb` - Handlebars instance used to render the template
+/// * `template` - The template
+/// * `context` - The render context
+///
+/// # Returns
+///
+/// The rendered template, or an error if rendering failed
+pub(crate) fn render(
+    hb: &Handlebars<'static>,
+    template: &str,
+    context: &Value,
+) -> Result<String, RenderError> {
+    let cache = CACHE.read().unwrap();
+    let Some(cache) = cache.as_ref().filter(|c| cacheable(template, &c.uncached)) else {
+        return hb.render_template(template, context);
+    };
+
+    let path = cache.dir.join(key(template, context, hb.strict_mode()));
+    if let Ok(rendered) = std::fs::read_to_string(&path) {
+        // Keep used entries from going stale
+        if let Ok(f) = std::fs::File::options().append(true).open(&path) {
+            let _ = f.set_modified(SystemTime::now());
+        }
+        return Ok(rendered);
+    }
+
+    let rendered = hb.render_template(template, context)?;
+    // Write to a temporary file first, as other tasks may render the same template
+    let stored = create_dir(&cache.dir)
+        .and_then(|_| tempfile::NamedTempFile::new_in(&cache.dir))
+        .and_then(|mut f| {
+            std::io::Write::write_all(&mut f, rendered.as_bytes())?;
+            f.persist(&path).map_err(|e| e.error)?;
+            Ok(())
+        });
+    if let Err(e) = stored {
+        debug!("Failed to cache rendered template in {:?}: {}", path, e);
+    }
+
+    Ok(rendered)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cacheable() {
+        let uncached = vec!["file_exists".to_string(), "secret".to_string()];
+        assert!(cacheable("Hello {{name}}", &uncached));
+        assert!(cacheable("{{#if (eq os \"linux\")}}x{{/if}}", &uncached));
+        assert!(cacheable("{{secret_name}} {{my_file_exists}}", &uncached));
+        assert!(!cacheable(
+            "{{#if (file_exists \"/etc/foo\")}}x{{/if}}",
+            &uncached
+        ));
+        assert!(!cacheable("{{secret \"token\"}}", &uncached));
+        assert!(!cacheable("{{> header}}", &uncached));
+        assert!(!cacheable("{{~> header}}", &uncached));
+        assert!(!cacheable("{{~#> layout}}x{{/layout}}", &uncached));
+        assert!(!cacheable("{{ > header }}", &uncached));
+    }
+
+    #[test]
+    fn test_create_dir() -> std::io::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let dir = temp_dir.path().join("cache/templates");
+        create_dir(&dir)?;
+        assert_eq!(std::fs::metadata(&dir)?.permissions().mode() & 0o777, 0o700);
+        Ok(())
+    }
+
+    #[test]
+    fn test_key() {
+        let context = serde_json::json!({"name": "foo"});
+        assert_eq!(
+            key("{{name}}", &context, true),
+            key("{{name}}", &context, true)
+        );
+        assert_ne!(
+            key("{{name}}", &context, true),
+            key("{{name}}!", &context, true)
+        );
+        assert_ne!(
+            key("{{name}}", &context, true),
+            key("{{name}}", &serde_json::json!({"name": "bar"}), true)
+        );
+        assert_ne!(
+            key("{{name}}", &context, true),
+            key("{{name}}", &context, false)
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Signal handling module.
+//!
+//! This module provides graceful cancellation on SIGINT and SIGTERM. The first signal marks the run
+//! as cancelled, which stops spawning new work while in-flight file operations are allowed to
+//! finish. A second signal terminates the process immediately.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::utils::sudo;
+
+lazy_static! {
+    /// Global variable, available to all threads, indicating if the run has been cancelled.
+    static ref CANCELLED: AtomicBool = AtomicBool::new(false);
+}
+
+/// Guards the installation of the handler, which is shared by all runs of the process.
+static INSTALL: Once = Once::new();
+
+/// Installs the handler for SIGINT and SIGTERM.
+///
+/// The handler runs as a background task on the tokio runtime and must therefore be installed from
+/// within the runtime. It is only installed once per process, later calls do nothing.
+///
+/// # Returns
+///
+/// * `Ok(())` if the handler was installed.
+/// * `Err` if registering the signal listeners fails.
+pub(crate) fn install_handler() -> Result<()> {
+    let mut result = Ok(());
+    INSTALL.call_once(|| result = spawn_handler());
+    result
+}
+
+/// Registers the signal listeners and spawns the handler task.
+fn spawn_handler() -> Result<()> {
+    let mut sigint = signal(SignalKind::interrupt()).context("Failed to listen for SIGINT")?;
+    let mut sigterm = signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+
+    tokio::spawn(async move {
+        loop {
+            let name = tokio::select! {
+                _ = sigint.recv() => "SIGINT",
+                _ = sigterm.recv() => "SIGTERM",
+            };
+
+            if CANCELLED.swap(true, Ordering::Relaxed) {
+                error!("Received {} again, aborting immediately", name);
+                std::process::exit(130);
+            }
+
+            warn!(
+                "Received {}, waiting for running operations to finish. Repeat to abort immediately.",
+                name
+            );
+            sudo::stop_sudo_loop();
+        }
+    });
+
+    Ok(())
+}
+
+/// Clears the cancellation of a previous run, so a new run of the same process can start.
+pub(crate) fn reset() {
+    CANCELLED.store(false, Ordering::Relaxed);
+}
+
+/// Returns `true` if the run has been cancelled by a signal.
+pub(crate) fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::Relaxed)
+}
+
+/// Returns an error if the run has been cancelled by a signal.
+///
+/// This should be called before starting new work, so the run stops at a consistent point.
+pub(crate) fn check_cancelled() -> Result<()> {
+    if is_cancelled() {
+        bail!("Cancelled by signal")
+    }
+    Ok(())
+}
@@ -7,6 +7,8 @@
 //! The module is adapted from: https://github.com/Morganamilo/paru/blob/5355012aa3529014145b8940dd0c62b21e53095a/src/exec.rs#L144
 
 use std::ffi::OsStr;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
@@ -20,20 +22,37 @@ use lazy_static::lazy_static;
 lazy_static! {
     /// Global variable, available to all threads, indicating if sudo is running.
     static ref SUDO_LOOP_RUNNING: AtomicBool = AtomicBool::new(false);
+    /// Global variable, available to all threads, requesting the sudo loop to stop.
+    static ref SUDO_LOOP_STOP: AtomicBool = AtomicBool::new(false);
     /// Mutex for synchronizing access to the sudo session.
     static ref SUDO_MUTEX: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
+    /// Askpass helper used by sudo if no terminal is attached, set with `sudo_askpass_cmd`.
+    static ref ASKPASS: std::sync::RwLock<Option<PathBuf>> = std::sync::RwLock::new(None);
+    /// Paths which are never accessed with elevated privileges, set with `never_sudo_paths`.
+    static ref NEVER_SUDO_PATHS: std::sync::RwLock<Vec<PathBuf>> =
+        std::sync::RwLock::new(Vec::new());
+    /// The privilege escalation command, selected with `sudo_cmd`.
+    static ref ROOT_CMD: std::sync::RwLock<GetRootCmd> =
+        std::sync::RwLock::new(GetRootCmd::use_sudo());
 }
 
-// NOTE 2024-09-24: If we ever want to support more than sudo, here would be the spot to implement
-//   it.
+/// Names of the supported privilege escalation commands, selected with `sudo_cmd`.
+pub(crate) const ROOT_CMDS: &[&str] = &["sudo", "pkexec", "run0"];
 
-#[derive(Debug, Clone)]
+/// Privilege escalation command.
+///
+/// Only sudo keeps a session which can be validated upfront and refreshed. pkexec and run0
+/// authenticate through polkit on every invocation, unless the polkit rules keep the
+/// authorization. pkexec resets the environment, so variables have to be passed with `env`.
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum GetRootCmd {
     Sudo {
         cmd: String,
         initial_flags: Vec<String>,
         keepalive_flags: Vec<String>,
     },
+    Pkexec,
+    Run0,
 }
 
 impl GetRootCmd {
@@ -45,15 +64,31 @@ impl GetRootCmd {
         }
     }
 
+    fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "sudo" => Ok(Self::use_sudo()),
+            "pkexec" => Ok(GetRootCmd::Pkexec),
+            "run0" => Ok(GetRootCmd::Run0),
+            _ => bail!(
+                "Unsupported sudo_cmd {:?}, expected one of {}",
+                name,
+                ROOT_CMDS.join(", ")
+            ),
+        }
+    }
+
     fn cmd(&self) -> &str {
         match self {
             GetRootCmd::Sudo { cmd, .. } => cmd,
+            GetRootCmd::Pkexec => "pkexec",
+            GetRootCmd::Run0 => "run0",
         }
     }
 
     fn initial_flags(&self) -> &[String] {
         match self {
             GetRootCmd::Sudo { initial_flags, .. } => initial_flags,
+            GetRootCmd::Pkexec | GetRootCmd::Run0 => &[],
         }
     }
 
@@ -62,10 +97,107 @@ impl GetRootCmd {
             GetRootCmd::Sudo {
                 keepalive_flags, ..
             } => keepalive_flags,
+            GetRootCmd::Pkexec | GetRootCmd::Run0 => &[],
+        }
+    }
+
+    /// Whether the command keeps a session which has to be refreshed.
+    fn keeps_session(&self) -> bool {
+        matches!(self, GetRootCmd::Sudo { .. })
+    }
+
+    /// Flags running the command as another user, followed by the command itself.
+    fn user_flags(&self, user: &str) -> Vec<String> {
+        match self {
+            GetRootCmd::Sudo { .. } => vec!["-u".to_string(), user.to_string(), "--".to_string()],
+            // pkexec stops parsing options at the program name and doesn't know `--`
+            GetRootCmd::Pkexec => vec!["--user".to_string(), user.to_string()],
+            GetRootCmd::Run0 => vec![format!("--user={}", user), "--".to_string()],
         }
     }
 }
 
+/// Selects the privilege escalation command.
+///
+/// # Arguments
+///
+/// * `name` - One of [ROOT_CMDS]
+///
+/// # Returns
+///
+/// * `Ok(())` if the command is supported.
+/// * `Err` if the command is unknown.
+pub(crate) fn set_root_cmd(name: &str) -> Result<()> {
+    *ROOT_CMD.write().unwrap() = GetRootCmd::from_name(name)?;
+    Ok(())
+}
+
+/// Returns the name of the selected privilege escalation command, e.g. `sudo`.
+pub(crate) fn root_cmd() -> String {
+    ROOT_CMD.read().unwrap().cmd().to_string()
+}
+
+/// Sets the askpass helper used to authenticate if no terminal is attached.
+///
+/// Only sudo uses the helper. pkexec and run0 rely on the polkit agent of the graphical session.
+///
+/// # Arguments
+///
+/// * `cmd` - Path of the helper, `None` to disable it
+pub(crate) fn set_askpass(cmd: Option<PathBuf>) {
+    *ASKPASS.write().unwrap() = cmd;
+}
+
+/// Sets the paths which are never accessed with elevated privileges, in addition to HOME.
+///
+/// # Arguments
+///
+/// * `paths` - Absolute paths, see `never_sudo_paths`
+pub(crate) fn set_never_sudo_paths(paths: Vec<PathBuf>) {
+    *NEVER_SUDO_PATHS.write().unwrap() = paths;
+}
+
+/// Checks whether `path` lies outside of all paths in `never`.
+fn escalation_allowed(path: &Path, never: &[PathBuf]) -> bool {
+    !never.iter().any(|p| path.starts_with(p))
+}
+
+/// Determines if privileges may be elevated to access a path.
+///
+/// Files inside HOME belong to the user, so a permission error there is a real error and must not
+/// be worked around with sudo, as that would leave root-owned files behind. The same holds for the
+/// paths in `never_sudo_paths`.
+///
+/// # Arguments
+///
+/// * `path` - The path to access
+///
+/// # Returns
+///
+/// `false` if the path is inside HOME or one of the `never_sudo_paths`, `true` otherwise
+pub(crate) fn may_escalate<P: AsRef<Path>>(path: P) -> bool {
+    let mut never = NEVER_SUDO_PATHS.read().unwrap().clone();
+    if let Some(home) = std::env::var_os("HOME").filter(|h| !h.is_empty()) {
+        never.push(PathBuf::from(home));
+    }
+    escalation_allowed(path.as_ref(), &never)
+}
+
+/// Builds the command validating the sudo session.
+///
+/// # Arguments
+///
+/// * `sudo` - The sudo command variant to execute.
+/// * `askpass` - Askpass helper asking for the password instead of the terminal.
+fn initial_command(sudo: &GetRootCmd, askpass: Option<&Path>) -> Command {
+    let mut cmd = Command::new(sudo.cmd());
+    if let Some(askpass) = askpass.filter(|_| sudo.keeps_session()) {
+        cmd.arg("-A").env("SUDO_ASKPASS", askpass);
+    }
+    cmd.args(sudo.initial_flags());
+    cmd
+}
+
 /// Conditionally spawns a new thread to maintain an active `sudo` session by periodically
 /// refreshing it.
 ///
@@ -85,6 +217,11 @@ impl GetRootCmd {
 pub(crate) async fn spawn_sudo_maybe<S: AsRef<str>>(reason: S) -> Result<()> {
     if crate::USE_SUDO.load(Ordering::Relaxed) {
         debug!("Requesting ROOT privileges. Reason: {}", reason.as_ref());
+        let sudo_cmd = ROOT_CMD.read().unwrap().clone();
+        if !sudo_cmd.keeps_session() {
+            // Every command authenticates on its own
+            return Ok(());
+        }
         let mut is_running = SUDO_LOOP_RUNNING.load(Ordering::Relaxed);
         if !is_running {
             let _guard = SUDO_MUTEX.lock().await;
@@ -94,9 +231,6 @@ pub(crate) async fn spawn_sudo_maybe<S: AsRef<str>>(reason: S) -> Result<()> {
                 // Yield to allow any pending output to complete
                 tokio::task::yield_now().await;
 
-                // NOTE 2024-09-24: For the time being, we only support sudo and hardcode it here.
-                let sudo_cmd = GetRootCmd::use_sudo();
-
                 // HACK 2024-09-24: I don't know so far how to fix the issue that the password
                 //   prompt gets interleaved into the programm output. This is a stupid hack but
                 //   works most of the time.
@@ -131,12 +265,20 @@ Check the value of the variable `use_sudo` in `$HOME/.config/dotdeploy/config.to
 ///
 /// # Returns
 ///
-/// * `Ok(())` if the loop runs indefinitely without error.
+/// * `Ok(())` if the loop has been stopped with [stop_sudo_loop].
 /// * `Err` if executing the sudo command fails.
 fn sudo_loop(sudo: &GetRootCmd) -> Result<()> {
     debug!("Executing privilege escalation command");
-    let status = Command::new(sudo.cmd())
-        .args(sudo.initial_flags())
+    // Without a terminal, the password can only be asked for by the askpass helper
+    let askpass = ASKPASS
+        .read()
+        .unwrap()
+        .clone()
+        .filter(|_| !std::io::stdin().is_terminal());
+    if let Some(askpass) = &askpass {
+        debug!("No terminal attached, using askpass helper {:?}", askpass);
+    }
+    let status = initial_command(sudo, askpass.as_deref())
         .status()
         .context("Failed to execute sudo command")?;
 
@@ -147,10 +289,24 @@ fn sudo_loop(sudo: &GetRootCmd) -> Result<()> {
     debug!("Running sudo loop");
     loop {
         update_sudo(sudo)?;
-        thread::sleep(Duration::from_secs(60));
+        // Sleep in short intervals to react to stop requests in time
+        for _ in 0..60 {
+            if SUDO_LOOP_STOP.load(Ordering::Relaxed) {
+                debug!("Stopping sudo loop");
+                return Ok(());
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
     }
 }
 
+/// Requests the `sudo` refresh loop to stop.
+///
+/// The loop terminates within a second. Commands already executed with sudo are not affected.
+pub(crate) fn stop_sudo_loop() {
+    SUDO_LOOP_STOP.store(true, Ordering::Relaxed);
+}
+
 /// Executes the `sudo` command with the specified flags once.
 ///
 /// This function is typically used to refresh the active sudo session or check that sudo privileges
@@ -193,6 +349,42 @@ fn format_args<S: AsRef<OsStr>>(args: &[S]) -> String {
         .join(" ")
 }
 
+/// Builds a command running as another user with `sudo -u`.
+///
+/// The sudo session is started if necessary. If `user` is the current user, the command is run
+/// directly.
+///
+/// # Arguments
+///
+/// * `user` - Name of the user to run the command as.
+/// * `cmd` - The command to execute.
+/// * `args` - Arguments for the command.
+///
+/// # Returns
+///
+/// * `Ok(Command)` with the command ready to be spawned.
+/// * `Err` if the sudo session can't be started.
+pub(crate) async fn run_as<S: AsRef<OsStr>>(user: &str, cmd: &str, args: &[S]) -> Result<Command> {
+    let current = nix::unistd::User::from_uid(nix::unistd::getuid())
+        .ok()
+        .flatten()
+        .map(|u| u.name);
+    if current.as_deref() == Some(user) {
+        let mut command = Command::new(cmd);
+        command.args(args);
+        return Ok(command);
+    }
+
+    spawn_sudo_maybe(format!("Running {} {} as {}", cmd, format_args(args), user))
+        .await
+        .context("Failed to spawn sudo")?;
+
+    let root = ROOT_CMD.read().unwrap().clone();
+    let mut command = Command::new(root.cmd());
+    command.args(root.user_flags(user)).arg(cmd).args(args);
+    Ok(command)
+}
+
 /// Executes a command with sudo privileges.
 ///
 /// # Arguments
@@ -210,25 +402,26 @@ pub(crate) async fn sudo_exec<S: AsRef<OsStr>>(
     args: &[S],
     reason: Option<&str>,
 ) -> Result<()> {
+    let cmd_line = format!("{} {} {}", root_cmd(), cmd, format_args(args));
     let reason = if let Some(reason) = reason {
         reason.to_string()
     } else {
-        format!("Executing: sudo {} {}", cmd, format_args(args))
+        format!("Executing: {}", cmd_line)
     };
     spawn_sudo_maybe(reason)
         .await
         .context("Failed to spawn sudo")?;
 
-    let mut exec = tokio::process::Command::new("sudo")
+    let mut exec = tokio::process::Command::new(root_cmd())
         .arg(cmd)
         .args(args)
         .spawn()
-        .with_context(|| format!("Failed to execute sudo {} {}", cmd, format_args(args)))?;
+        .with_context(|| format!("Failed to execute {}", cmd_line))?;
 
     if exec.wait().await?.success() {
         Ok(())
     } else {
-        bail!("Failed to execute sudo {} {}", cmd, format_args(args))
+        bail!("Failed to execute {}", cmd_line)
     }
 }
 
@@ -249,21 +442,22 @@ pub(crate) async fn sudo_exec_output<S: AsRef<OsStr>>(
     args: &[S],
     reason: Option<&str>,
 ) -> Result<std::process::Output> {
+    let cmd_line = format!("{} {} {}", root_cmd(), cmd, format_args(args));
     let reason = if let Some(reason) = reason {
         reason.to_string()
     } else {
-        format!("Executing: sudo {} {}", cmd, format_args(args))
+        format!("Executing: {}", cmd_line)
     };
     spawn_sudo_maybe(reason)
         .await
         .context("Failed to spawn sudo")?;
 
-    let output = tokio::process::Command::new("sudo")
+    let output = tokio::process::Command::new(root_cmd())
         .arg(cmd)
         .args(args)
         .output()
         .await
-        .with_context(|| format!("Failed to execute sudo {} {}", cmd, format_args(args)))?;
+        .with_context(|| format!("Failed to execute {}", cmd_line))?;
 
     Ok(output)
 }
@@ -285,21 +479,22 @@ pub(crate) async fn sudo_exec_success<S: AsRef<OsStr>>(
     args: &[S],
     reason: Option<&str>,
 ) -> Result<bool> {
+    let cmd_line = format!("{} {} {}", root_cmd(), cmd, format_args(args));
     let reason = if let Some(reason) = reason {
         reason.to_string()
     } else {
-        format!("Executing: sudo {} {}", cmd, format_args(args))
+        format!("Executing: {}", cmd_line)
     };
     spawn_sudo_maybe(reason)
         .await
         .context("Failed to spawn sudo")?;
 
-    let status = tokio::process::Command::new("sudo")
+    let status = tokio::process::Command::new(root_cmd())
         .arg(cmd)
         .args(args)
         .status()
         .await
-        .with_context(|| format!("Failed to execute sudo {} {}", cmd, format_args(args)))?;
+        .with_context(|| format!("Failed to execute {}", cmd_line))?;
 
     Ok(status.success())
 }
@@ -336,4 +531,72 @@ mod tests {
         assert!(!sudo_exec_success("test", &["4", "-eq", "0"], None).await?);
         Ok(())
     }
+
+    #[test]
+    fn test_initial_command() {
+        let sudo = GetRootCmd::use_sudo();
+        let cmd = initial_command(&sudo, None);
+        assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["-v"]);
+        assert_eq!(cmd.get_envs().count(), 0);
+
+        let cmd = initial_command(&sudo, Some(Path::new("/usr/bin/ksshaskpass")));
+        assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["-A", "-v"]);
+        assert_eq!(
+            cmd.get_envs().collect::<Vec<_>>(),
+            [(
+                OsStr::new("SUDO_ASKPASS"),
+                Some(OsStr::new("/usr/bin/ksshaskpass"))
+            )]
+        );
+
+        // polkit based commands don't use askpass helpers
+        let cmd = initial_command(&GetRootCmd::Run0, Some(Path::new("/usr/bin/ksshaskpass")));
+        assert_eq!(cmd.get_args().count(), 0);
+    }
+
+    #[test]
+    fn test_get_root_cmd() -> Result<()> {
+        let sudo = GetRootCmd::from_name("sudo")?;
+        assert!(sudo.keeps_session());
+        assert_eq!(sudo.initial_flags(), ["-v"]);
+
+        let pkexec = GetRootCmd::from_name("pkexec")?;
+        assert_eq!(pkexec.cmd(), "pkexec");
+        assert!(!pkexec.keeps_session());
+        assert_eq!(pkexec.user_flags("postgres"), ["--user", "postgres"]);
+
+        let run0 = GetRootCmd::from_name("run0")?;
+        assert_eq!(run0.user_flags("postgres"), ["--user=postgres", "--"]);
+        assert!(run0.keepalive_flags().is_empty());
+
+        assert!(GetRootCmd::from_name("doas").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_as() -> Result<()> {
+        // The current user doesn't need sudo
+        let user = nix::unistd::User::from_uid(nix::unistd::getuid())?.unwrap();
+        let cmd = run_as(&user.name, "echo", &["hello"]).await?;
+        assert_eq!(cmd.get_program(), "echo");
+        assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["hello"]);
+
+        crate::USE_SUDO.store(true, std::sync::atomic::Ordering::Relaxed);
+        let cmd = run_as("postgres", "echo", &["hello"]).await?;
+        assert_eq!(cmd.get_program(), "sudo");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            ["-u", "postgres", "--", "echo", "hello"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_escalation_allowed() {
+        let never = [PathBuf::from("/home/user"), PathBuf::from("/mnt/data")];
+        assert!(!escalation_allowed(Path::new("/home/user/.zshrc"), &never));
+        assert!(!escalation_allowed(Path::new("/mnt/data"), &never));
+        assert!(escalation_allowed(Path::new("/home/user2/.zshrc"), &never));
+        assert!(escalation_allowed(Path::new("/etc/hosts"), &never));
+    }
 }
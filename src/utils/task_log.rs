@@ -0,0 +1,308 @@
+//! Task log module.
+//!
+//! The output of actions is written to log files below `logs_dir`, so failures of long scripts can
+//! be diagnosed after the fact. Each run gets its own directory, named after its start time, which
+//! is created once the first action runs. The output is shown on the terminal as well, unless
+//! dotdeploy runs with `--quiet`. Only the logs of the last [MAX_RUNS] runs are kept.
+//!
+//! Runs changing the system additionally keep a log of dotdeploy itself in the run directory, see
+//! [RUN_LOG].
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+
+/// Name of the log of dotdeploy itself inside the run directory.
+pub(crate) const RUN_LOG: &str = "dotdeploy.log";
+
+/// Number of runs whose logs are kept.
+pub(crate) const MAX_RUNS: usize = 50;
+
+lazy_static! {
+    /// Log directory of the current run. Logging is disabled if unset.
+    static ref RUN_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+    /// The log of dotdeploy itself, opened on the first message once enabled.
+    static ref RUN_LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+    /// Whether messages are written to the log of dotdeploy itself.
+    static ref RUN_LOG_ENABLED: AtomicBool = AtomicBool::new(false);
+}
+
+/// Enables task logs for the current run.
+///
+/// # Arguments
+///
+/// * `logs_dir` - Directory containing the log directories of all runs
+pub(crate) fn init(logs_dir: &Path) {
+    let run_id = chrono::Local::now().format("%Y-%m-%dT%H-%M-%S").to_string();
+    *RUN_DIR.lock().unwrap() = Some(logs_dir.join(run_id));
+}
+
+/// Deletes the logs of all but the last `keep` runs.
+///
+/// # Arguments
+///
+/// * `logs_dir` - Directory containing the log directories of all runs
+/// * `keep` - Number of runs to keep
+///
+/// # Returns
+///
+/// A Result indicating success, or an error if a log directory could not be deleted
+pub(crate) fn prune(logs_dir: &Path, keep: usize) -> Result<()> {
+    let runs = crate::logs::runs(logs_dir)?;
+    for run in runs.iter().take(runs.len().saturating_sub(keep)) {
+        let run_dir = logs_dir.join(run);
+        std::fs::remove_dir_all(&run_dir)
+            .with_context(|| format!("Failed to delete logs of run {:?}", run_dir))?;
+    }
+    Ok(())
+}
+
+/// Enables the log of dotdeploy itself for the current run.
+///
+/// The log is written by the file logger through [RunLogWriter]. Messages logged before are not
+/// included.
+pub(crate) fn enable_run_log() {
+    RUN_LOG_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Writer of the file logger, appending to the log of dotdeploy itself once enabled.
+///
+/// The run directory is created with the first message, so runs without messages leave no traces.
+pub(crate) struct RunLogWriter;
+
+impl Write for RunLogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !RUN_LOG_ENABLED.load(Ordering::Relaxed) {
+            return Ok(buf.len());
+        }
+        let mut file = RUN_LOG_FILE.lock().unwrap();
+        if file.is_none() {
+            let Some(run_dir) = RUN_DIR.lock().unwrap().clone() else {
+                return Ok(buf.len());
+            };
+            crate::target_user::create_dir_all(&run_dir).map_err(std::io::Error::other)?;
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .custom_flags(crate::target_user::open_flags())
+                    .open(run_dir.join(RUN_LOG))?,
+            );
+        }
+        file.as_mut()
+            .expect("Run log should be open")
+            .write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match RUN_LOG_FILE.lock().unwrap().as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Replaces characters which are not safe in file names.
+pub(crate) fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Creates the log file of a task.
+///
+/// The file is named `<module>-<task>.log`. If that file exists already, a counter is appended.
+fn create(run_dir: &Path, module: Option<&str>, task: &str) -> Result<(PathBuf, File)> {
+    crate::target_user::create_dir_all(run_dir)
+        .with_context(|| format!("Failed to create log directory {:?}", run_dir))?;
+
+    let stem = format!(
+        "{}-{}",
+        sanitize(&module.unwrap_or("hooks").replace('/', "-")),
+        sanitize(task)
+    );
+    let mut n = 1;
+    loop {
+        let path = match n {
+            1 => run_dir.join(format!("{}.log", stem)),
+            _ => run_dir.join(format!("{}-{}.log", stem, n)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e).with_context(|| format!("Failed to create log {:?}", path)),
+        }
+    }
+}
+
+/// Copies the output of a child process into the log file and optionally to the terminal.
+fn tee<R: Read + Send + 'static>(
+    mut reader: R,
+    file: Arc<Mutex<File>>,
+    mut echo: Option<Box<dyn Write + Send>>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 8192];
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            if let Err(e) = file.lock().unwrap().write_all(&buf[..n]) {
+                warn!("Failed to write task log: {}", e);
+            }
+            if let Some(echo) = echo.as_mut() {
+                let _ = echo.write_all(&buf[..n]).and_then(|_| echo.flush());
+            }
+        }
+    })
+}
+
+/// Runs a command, logging its output into the given run directory.
+fn run_in(
+    run_dir: Option<&Path>,
+    mut cmd: Command,
+    module: Option<&str>,
+    task: &str,
+    echo: bool,
+) -> Result<(ExitStatus, Option<PathBuf>)> {
+    let Some(run_dir) = run_dir else {
+        return Ok((cmd.spawn()?.wait()?, None));
+    };
+
+    let (path, file) = create(run_dir, module, task)?;
+    let file = Arc::new(Mutex::new(file));
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+    let mut threads = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        let echo = echo.then(|| Box::new(std::io::stdout()) as Box<dyn Write + Send>);
+        threads.push(tee(stdout, Arc::clone(&file), echo));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        let echo = echo.then(|| Box::new(std::io::stderr()) as Box<dyn Write + Send>);
+        threads.push(tee(stderr, Arc::clone(&file), echo));
+    }
+
+    let status = child.wait()?;
+    for t in threads {
+        let _ = t.join();
+    }
+
+    Ok((status, Some(path)))
+}
+
+/// Runs the command of a task and waits for it to finish.
+///
+/// If task logs are enabled, stdout and stderr are written to the log file of the task and shown on
+/// the terminal unless info messages are suppressed, e.g. by `--quiet`. Otherwise, the output is
+/// inherited. The command runs on a blocking thread, so other tasks can proceed in the meantime.
+///
+/// # Arguments
+///
+/// * `cmd` - The command to run
+/// * `module` - The module the task belongs to, `None` for global hooks
+/// * `task` - Name of the task, used for the log file name
+///
+/// # Returns
+///
+/// A Result containing the exit status and the path of the log file, if any
+pub(crate) async fn run(
+    cmd: Command,
+    module: Option<&str>,
+    task: &str,
+) -> Result<(ExitStatus, Option<PathBuf>)> {
+    let run_dir = RUN_DIR.lock().unwrap().clone();
+    let module = module.map(str::to_string);
+    let task = task.to_string();
+    let echo = log::log_enabled!(log::Level::Info);
+
+    tokio::task::spawn_blocking(move || {
+        run_in(run_dir.as_deref(), cmd, module.as_deref(), &task, echo)
+    })
+    .await?
+}
+
+/// Formats a reference to a log file for error messages.
+pub(crate) fn hint(log: &Option<PathBuf>) -> String {
+    match log {
+        Some(path) => format!(", see {:?} for its output", path),
+        None => String::new(),
+    }
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_in() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let run_dir = temp_dir.path().join("run");
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo out; echo err >&2; exit 3"]);
+        let (status, log) = run_in(Some(&run_dir), cmd, Some("desktop/sway"), "sh", false)?;
+        assert_eq!(status.code(), Some(3));
+        let log = log.unwrap();
+        assert_eq!(log, run_dir.join("desktop-sway-sh.log"));
+        let output = std::fs::read_to_string(&log)?;
+        assert!(output.contains("out\n") && output.contains("err\n"));
+
+        // Names of later tasks don't clash
+        let mut cmd = Command::new("true");
+        cmd.arg("ignored");
+        let (status, log) = run_in(Some(&run_dir), cmd, Some("desktop/sway"), "sh", false)?;
+        assert!(status.success());
+        assert_eq!(log.unwrap(), run_dir.join("desktop-sway-sh-2.log"));
+
+        // Without a run directory, nothing is logged
+        let (status, log) = run_in(None, Command::new("true"), None, "true", false)?;
+        assert!(status.success());
+        assert!(log.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        for run in [
+            "2024-01-01T10-00-00",
+            "2024-01-02T10-00-00",
+            "2024-01-03T10-00-00",
+            "other",
+        ] {
+            std::fs::create_dir(temp_dir.path().join(run))?;
+        }
+
+        prune(temp_dir.path(), 2)?;
+        let mut left: Vec<_> = std::fs::read_dir(temp_dir.path())?
+            .map(|e| e.map(|e| e.file_name().to_string_lossy().to_string()))
+            .collect::<Result<_, _>>()?;
+        left.sort();
+        assert_eq!(
+            left,
+            vec!["2024-01-02T10-00-00", "2024-01-03T10-00-00", "other"]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,443 @@
+//! This module explains why a path is managed by dotdeploy.
+//!
+//! `dotdeploy why <path>` combines the stores and the module configs: for every store the path is
+//! recorded in, it shows the deploying module, the operation, the source, the date of the last
+//! deployment and whether the file on disk still matches the store. Files inside a deployed
+//! directory tree are explained by the deployment of the tree. In addition, all modules declaring
+//! the path in their `files` or `generate` section are listed, which also covers paths that were
+//! never deployed, e.g. because their condition is false.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::cli::OutputFormat;
+use crate::config::DotdeployConfig;
+use crate::modules::config::ModuleConfig;
+use crate::report::{emit, serialize_date, Report};
+use crate::store::db::Store;
+use crate::store::files::StoreFile;
+use crate::utils::file_checksum;
+use crate::Stores;
+
+/// A deployment of the path recorded in a store.
+#[derive(Serialize, Debug)]
+struct Deployment {
+    store: &'static str,
+    /// The deployed path, a parent directory for files inside a directory tree
+    destination: String,
+    module: String,
+    operation: String,
+    source: Option<String>,
+    #[serde(serialize_with = "serialize_date")]
+    date: chrono::DateTime<chrono::Local>,
+    /// Pending change of the file, see [crate::check], or `up to date`
+    status: &'static str,
+    /// Whether the checksum of the file on disk matches the store. Not set for links and
+    /// directories, or if the file is missing or can't be read.
+    checksum_matches: Option<bool>,
+}
+
+/// A declaration of the path in the config of a module.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Declaration {
+    module: String,
+    /// `file` or `generate`
+    kind: &'static str,
+    action: Option<String>,
+    source: Option<PathBuf>,
+    eval_when: Option<String>,
+}
+
+/// Result of explaining a path.
+#[derive(Serialize, Debug)]
+struct WhyReport {
+    path: String,
+    deployments: Vec<Deployment>,
+    declarations: Vec<Declaration>,
+}
+
+impl Report for WhyReport {
+    fn print_text(&self) {
+        println!("{}", self.path);
+        for d in self.deployments.iter() {
+            println!("  deployed by {} ({} store)", d.module, d.store);
+            if d.destination != self.path {
+                println!("    as part of: {}", d.destination);
+            }
+            println!("    operation: {}", d.operation);
+            if let Some(source) = &d.source {
+                println!("    source: {}", source);
+            }
+            println!("    last deployed: {}", d.date.format("%Y-%m-%d %H:%M:%S"));
+            println!("    status: {}", d.status);
+            match d.checksum_matches {
+                Some(true) => println!("    checksum: matches the store"),
+                Some(false) => println!("    checksum: differs from the store"),
+                None => (),
+            }
+        }
+        for d in self.declarations.iter() {
+            match &d.action {
+                Some(action) => println!("  declared by {} ({} {})", d.module, d.kind, action),
+                None => println!("  declared by {} ({})", d.module, d.kind),
+            }
+            if let Some(source) = &d.source {
+                println!("    source: {}", source.display());
+            }
+            if let Some(eval_when) = &d.eval_when {
+                println!("    eval_when: {}", eval_when);
+            }
+        }
+
+        if self.deployments.is_empty() && self.declarations.is_empty() {
+            info!("{} is not managed by dotdeploy", self.path);
+        }
+    }
+}
+
+/// Looks up the deployment of a path in a store.
+///
+/// A path without a deployment of its own may be part of a directory tree deployed to one of its
+/// parents.
+///
+/// # Arguments
+///
+/// * `store` - The store to search
+/// * `name` - Name of the store, e.g. `user`
+/// * `path` - The absolute path to look up
+async fn deployment(store: &Store, name: &'static str, path: &Path) -> Result<Option<Deployment>> {
+    let mut file: Option<StoreFile> = None;
+    for (i, ancestor) in path.ancestors().enumerate() {
+        let ancestor = crate::utils::file_fs::path_to_string(ancestor)?;
+        if !store
+            .check_file_exists(&ancestor)
+            .await
+            .map_err(|e| e.into_anyhow())?
+        {
+            continue;
+        }
+        let f = store
+            .get_file(&ancestor)
+            .await
+            .map_err(|e| e.into_anyhow())?;
+        if i == 0 || f.operation == "directory" {
+            file = Some(f);
+        }
+        break;
+    }
+    let Some(file) = file else {
+        return Ok(None);
+    };
+
+    let status = crate::check::pending_change(&file).await?;
+    let checksum_matches = match (file.operation.as_str(), &file.destination_checksum) {
+        ("link" | "directory", _) | (_, None) => None,
+        _ if status == Some("missing") => None,
+        // Files only root can read are explained without checksum
+        (_, Some(checksum)) => file_checksum::calculate_sha256_checksum(&file.destination)
+            .await
+            .map_err(|e| {
+                debug!(
+                    "Failed to calculate checksum of {}: {:?}",
+                    file.destination, e
+                )
+            })
+            .ok()
+            .map(|c| c == *checksum),
+    };
+
+    Ok(Some(Deployment {
+        store: name,
+        destination: file.destination,
+        module: file.module,
+        operation: file.operation,
+        source: file.source,
+        date: file.date,
+        status: status.unwrap_or("up to date"),
+        checksum_matches,
+    }))
+}
+
+/// Returns the path a config key of a module is deployed to.
+fn deployed_path(key: &Path) -> PathBuf {
+    crate::target_root::rebase(key.to_string_lossy().replace("##dot##", "."))
+}
+
+/// Finds the entries of a module config declaring a path.
+///
+/// Config keys are compared to the path and the destinations of its deployments, after applying
+/// the target root. A file entry of the deploying module with the recorded source matches as
+/// well, e.g. a file whose template extension was stripped.
+///
+/// # Arguments
+///
+/// * `module` - Name of the module
+/// * `config` - The module config
+/// * `path` - The path to look for
+/// * `deployments` - The deployments of the path recorded in the stores
+fn declarations(
+    module: &str,
+    config: &ModuleConfig,
+    path: &Path,
+    deployments: &[Deployment],
+) -> Vec<Declaration> {
+    let matches = |key: &Path| {
+        let deployed = deployed_path(key);
+        deployed == path
+            || deployments
+                .iter()
+                .any(|d| Path::new(&d.destination) == deployed)
+    };
+
+    let mut found = vec![];
+    for (_, file) in config.files.iter().flatten().filter(|(key, file)| {
+        matches(key)
+            || deployments.iter().any(|d| {
+                d.module == module
+                    && d.source.is_some()
+                    && d.source.as_deref().map(Path::new) == file.source.as_deref()
+            })
+    }) {
+        found.push(Declaration {
+            module: module.to_string(),
+            kind: "file",
+            action: file.action.clone(),
+            source: file.source.clone(),
+            eval_when: file.eval_when.clone(),
+        });
+    }
+    for (_, generate) in config
+        .generate
+        .iter()
+        .flatten()
+        .filter(|(key, _)| matches(key))
+    {
+        found.push(Declaration {
+            module: module.to_string(),
+            kind: "generate",
+            action: None,
+            // Name of the snippets concatenated into the file
+            source: Some(PathBuf::from(&generate.source)),
+            eval_when: generate.eval_when.clone(),
+        });
+    }
+    found
+}
+
+/// Collects the names and locations of all modules in the module and host roots and the stores.
+async fn all_modules(
+    stores: &Stores,
+    dotdeploy_config: &DotdeployConfig,
+) -> Result<BTreeMap<String, PathBuf>> {
+    let mut modules = BTreeMap::new();
+    for (root, prefix) in [
+        (&dotdeploy_config.modules_root, ""),
+        (&dotdeploy_config.hosts_root, "hosts/"),
+    ] {
+        if !root.is_dir() {
+            continue;
+        }
+        for name in crate::modules::find_modules(root)? {
+            modules.insert(format!("{}{}", prefix, name), root.join(&name));
+        }
+    }
+
+    // Deployed modules which are not part of the dotfiles anymore, or remote modules
+    let mut all_stores = vec![&stores.user_store];
+    if let Some(sys_store) = &stores.system_store {
+        all_stores.push(sys_store);
+    }
+    for store in all_stores {
+        for module in store.get_all_modules().await.map_err(|e| e.into_anyhow())? {
+            modules
+                .entry(module.name)
+                .or_insert_with(|| PathBuf::from(module.location));
+        }
+    }
+
+    Ok(modules)
+}
+
+/// Explains why a path is managed by dotdeploy.
+///
+/// # Arguments
+///
+/// * `stores` - Arc-wrapped tuple of database stores (user and optional system store)
+/// * `path` - The path to explain, `~` and environment variables are expanded
+/// * `dotdeploy_config` - Configuration used to locate the modules
+/// * `format` - Output format
+///
+/// # Returns
+///
+/// A Result containing `true` if the path is deployed or declared by a module
+pub(crate) async fn why(
+    stores: Arc<Stores>,
+    path: &str,
+    dotdeploy_config: &DotdeployConfig,
+    format: OutputFormat,
+) -> Result<bool> {
+    let expanded =
+        shellexpand::full(path).with_context(|| format!("Failed to expand path {:?}", path))?;
+    let path = std::path::absolute(expanded.as_ref())
+        .with_context(|| format!("Failed to get absolute path of {:?}", path))?;
+    let path_str = crate::utils::file_fs::path_to_string(&path)?;
+
+    let mut deployments = vec![];
+    if let Some(d) = deployment(&stores.user_store, "user", &path).await? {
+        deployments.push(d);
+    }
+    if let Some(sys_store) = &stores.system_store {
+        if let Some(d) = deployment(sys_store, "system", &path).await? {
+            deployments.push(d);
+        }
+    }
+
+    let mut found = vec![];
+    for (name, location) in all_modules(&stores, dotdeploy_config).await? {
+        // Relative sources are resolved against the module being read, see [crate::modules::queue]
+        unsafe {
+            std::env::set_var("DOD_CURRENT_MODULE", &location);
+        }
+        match ModuleConfig::read_config(&location) {
+            Ok(config) => found.extend(declarations(&name, &config, &path, &deployments)),
+            Err(e) => debug!("Failed to read config of module {}: {:?}", name, e),
+        }
+    }
+
+    let managed = !deployments.is_empty() || !found.is_empty();
+    emit(
+        &WhyReport {
+            path: path_str,
+            deployments,
+            declarations: found,
+        },
+        format,
+    )?;
+
+    Ok(managed)
+}
+
+//
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::modules::files::ModuleFile;
+    use crate::modules::generate::Generate;
+
+    #[test]
+    fn test_declarations() {
+        let config = ModuleConfig {
+            files: Some(BTreeMap::from([
+                (
+                    "/home/user/.zshrc".into(),
+                    ModuleFile {
+                        source: Some("/dotfiles/modules/zsh/zshrc".into()),
+                        action: Some("copy".to_string()),
+                        eval_when: Some("zsh".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                ("/home/user/.zprofile".into(), ModuleFile::default()),
+            ])),
+            generate: Some(BTreeMap::from([(
+                "/home/user/.zshrc".into(),
+                Generate {
+                    prepend: None,
+                    source: "zshrc.snippet".to_string(),
+                    append: None,
+                    eval_when: None,
+                    priority: None,
+                    template: None,
+                    priorities: BTreeMap::new(),
+                },
+            )])),
+            ..Default::default()
+        };
+        assert_eq!(
+            declarations("zsh", &config, Path::new("/home/user/.zshrc"), &[]),
+            vec![
+                Declaration {
+                    module: "zsh".to_string(),
+                    kind: "file",
+                    action: Some("copy".to_string()),
+                    source: Some("/dotfiles/modules/zsh/zshrc".into()),
+                    eval_when: Some("zsh".to_string()),
+                },
+                Declaration {
+                    module: "zsh".to_string(),
+                    kind: "generate",
+                    action: None,
+                    source: Some("zshrc.snippet".into()),
+                    eval_when: None,
+                },
+            ]
+        );
+        assert!(declarations("zsh", &config, Path::new("/home/user/.bashrc"), &[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_deployment() -> Result<()> {
+        let store = crate::store::tests::store_setup_helper("copy").await?;
+        let temp_dir = tempfile::tempdir()?;
+        let tree = temp_dir.path().join("tree");
+        std::fs::create_dir_all(&tree)?;
+        std::fs::write(tree.join("file"), "")?;
+        store
+            .add_file(StoreFile {
+                module: "test".to_string(),
+                source: Some("/dotfiles/tree".to_string()),
+                source_checksum: None,
+                destination: crate::utils::file_fs::path_to_string(&tree)?,
+                destination_checksum: None,
+                operation: "directory".to_string(),
+                user: None,
+                date: chrono::offset::Local::now(),
+            })
+            .await
+            .map_err(|e| e.into_anyhow())?;
+
+        // Missing files have no checksum to compare
+        let d = deployment(&store, "user", Path::new("/home/foo1.txt"))
+            .await?
+            .unwrap();
+        assert_eq!(d.destination, "/home/foo1.txt");
+        assert_eq!(d.checksum_matches, None);
+
+        // Files inside a tree belong to its deployment
+        let d = deployment(&store, "user", &tree.join("file"))
+            .await?
+            .unwrap();
+        assert_eq!(Path::new(&d.destination), tree);
+        assert_eq!(d.operation, "directory");
+        // Other parents don't
+        assert!(deployment(&store, "user", Path::new("/home/foo1.txt/file"))
+            .await?
+            .is_none());
+
+        // The tree is declared by the module deploying it
+        let config = ModuleConfig {
+            files: Some(BTreeMap::from([(
+                tree.clone(),
+                ModuleFile {
+                    source: Some("/dotfiles/tree".into()),
+                    action: Some("directory".to_string()),
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+        assert_eq!(
+            declarations("test", &config, &tree.join("file"), &[d]).len(),
+            1
+        );
+
+        Ok(())
+    }
+}
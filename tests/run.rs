@@ -0,0 +1,34 @@
+//! Drives the engine through its library API, like an embedding program would.
+
+use anyhow::Result;
+use clap::Parser;
+
+use dotdeploy::cli::Cli;
+
+#[tokio::test]
+async fn test_config_check() -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let root = temp_dir.path();
+    for dir in ["dotfiles/modules", "dotfiles/hosts", "dotdeploy"] {
+        std::fs::create_dir_all(root.join(dir))?;
+    }
+    let config = format!(
+        "config_root = \"{0}/dotfiles\"\nmodules_root = \"{0}/dotfiles/modules\"\n\
+         hosts_root = \"{0}/dotfiles/hosts\"\n",
+        root.display()
+    );
+    std::fs::write(root.join("dotdeploy/config.toml"), &config)?;
+    std::env::set_var("XDG_CONFIG_HOME", root);
+
+    let check = || Cli::try_parse_from(["dotdeploy", "--quiet", "config", "check"]);
+    assert!(dotdeploy::run(check()?).await?);
+
+    // Invalid values are reported as problems instead of failing the run
+    std::fs::write(
+        root.join("dotdeploy/config.toml"),
+        format!("{}use_sudo = \"yes\"\n", config),
+    )?;
+    assert!(!dotdeploy::run(check()?).await?);
+
+    Ok(())
+}